@@ -0,0 +1,150 @@
+// Benchmarks of provisioning and workspace operations, run with `cargo bench`. Only the local
+// provider is benchmarked by default, set DERRICK_BENCH_PROVIDERS=local,docker to include docker.
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use derrick::server::{Server, WorkspaceOptions};
+use derrick::WorkspaceContext;
+
+const FILE_SIZES: [usize; 2] = [64 * 1024, 16 * 1024 * 1024];
+
+fn providers() -> Vec<String> {
+    std::env::var("DERRICK_BENCH_PROVIDERS")
+        .unwrap_or_else(|_| "local".to_string())
+        .split(',')
+        .map(|provider| provider.trim().to_string())
+        .filter(|provider| !provider.is_empty())
+        .collect()
+}
+
+// A context without repositories, so the numbers do not depend on the network. The marker is part
+// of the setup step, so a new marker misses the image cache of the docker provider.
+fn context(marker: &str) -> WorkspaceContext {
+    serde_json::from_value(serde_json::json!({
+        "name": "derrick-bench",
+        "repositories": [],
+        "setup_script": format!("echo {} > /tmp/derrick-bench-marker", marker),
+    }))
+    .unwrap()
+}
+
+async fn server(provider: &str, marker: &str) -> Server {
+    let provider = derrick::get_provider(provider.to_string()).await.unwrap();
+    Server::create_server(context(marker), provider).unwrap()
+}
+
+// Time until a workspace is ready, excluding its destruction
+async fn time_provision(server: &Server) -> Duration {
+    let start = Instant::now();
+    let id = server
+        .create_workspace(WorkspaceOptions::default())
+        .await
+        .unwrap();
+    let elapsed = start.elapsed();
+    server.destroy_workspace(&id).await.unwrap();
+    elapsed
+}
+
+fn provisioning(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("provision");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(60));
+
+    for provider in providers() {
+        // Every workspace is created from a context that was not seen before
+        group.bench_function(BenchmarkId::new("cold", &provider), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let provider = provider.clone();
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let marker = uuid::Uuid::new_v4().to_string();
+                        elapsed += time_provision(&server(&provider, &marker).await).await;
+                    }
+                    elapsed
+                }
+            })
+        });
+
+        // Every workspace is created from the same context, after it was provisioned once
+        let warm = rt.block_on(async {
+            let server = server(&provider, "warm").await;
+            time_provision(&server).await;
+            server
+        });
+        group.bench_function(BenchmarkId::new("warm", &provider), |b| {
+            b.to_async(&rt).iter_custom(|iters| {
+                let warm = &warm;
+                async move {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        elapsed += time_provision(warm).await;
+                    }
+                    elapsed
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn workspace_operations(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+
+    for provider in providers() {
+        let (server, id) = rt.block_on(async {
+            let server = server(&provider, "operations").await;
+            let id = server
+                .create_workspace(WorkspaceOptions::default())
+                .await
+                .unwrap();
+            (server, id)
+        });
+
+        let mut group = c.benchmark_group(format!("workspace/{}", provider));
+        group.bench_function("cmd_round_trip", |b| {
+            b.to_async(&rt).iter(|| async {
+                server
+                    .cmd_with_output(&id, "true", None, HashMap::new(), None)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        for size in FILE_SIZES {
+            let content = vec![b'x'; size];
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(
+                BenchmarkId::new("write_file", size),
+                &content,
+                |b, content| {
+                    b.to_async(&rt).iter(|| async {
+                        server
+                            .write_file(&id, "derrick-bench.bin", content, None, None)
+                            .await
+                            .unwrap()
+                    })
+                },
+            );
+            group.bench_with_input(BenchmarkId::new("read_file", size), &size, |b, _| {
+                b.to_async(&rt).iter(|| async {
+                    server
+                        .read_file(&id, "derrick-bench.bin", None)
+                        .await
+                        .unwrap()
+                })
+            });
+        }
+        group.finish();
+
+        rt.block_on(server.destroy_workspace(&id)).unwrap();
+    }
+}
+
+criterion_group!(benches, provisioning, workspace_operations);
+criterion_main!(benches);
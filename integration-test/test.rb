@@ -48,7 +48,7 @@ end
 def run_tests(provisioner_mode:)
   file_dir = File.dirname(__FILE__)
 
-  options = ["-p", provisioner_mode, "-s", "http", "-w", "#{file_dir}/test_config.json"]
+  options = ["serve", "-p", provisioner_mode, "-s", "http", "-w", "#{file_dir}/test_config.json"]
   run_with_derrick(options) do |pid|
     puts "Running tests..."
 
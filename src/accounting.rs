@@ -0,0 +1,383 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use chrono::{Datelike, TimeZone, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::workspace_controllers::ResourceCounters;
+
+// Label the usage of workspaces is grouped by and quotas apply to, unless the server is given
+// another
+pub const DEFAULT_USAGE_LABEL: &str = "tenant";
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+
+// How long the usage of destroyed workspaces is kept, a bit over a year
+const RETENTION_MS: u64 = 400 * 24 * HOUR_MS;
+
+// Unix time in milliseconds
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// Start of the calendar month (in UTC) of the time, which monthly quotas count from
+pub(crate) fn month_start_ms(at_ms: u64) -> u64 {
+    let at = Utc
+        .timestamp_millis_opt(at_ms as i64)
+        .single()
+        .unwrap_or_default();
+    Utc.with_ymd_and_hms(at.year(), at.month(), 1, 0, 0, 0)
+        .single()
+        .map(|start| start.timestamp_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Unix time in milliseconds or an RFC 3339 timestamp, like `2024-03-01T00:00:00Z`
+pub(crate) fn parse_time(value: &str) -> Result<u64> {
+    if let Ok(ms) = value.parse::<u64>() {
+        return Ok(ms);
+    }
+    let time = chrono::DateTime::parse_from_rfc3339(value)
+        .with_context(|| format!("Invalid time {}", value))?;
+    u64::try_from(time.timestamp_millis()).with_context(|| format!("Invalid time {}", value))
+}
+
+#[derive(Debug)]
+struct WorkspaceUsage {
+    labels: HashMap<String, String>,
+    created_at: u64,
+    destroyed_at: Option<u64>,
+    // Last counters read from the workspace, to tell how much they grew since
+    last: Option<ResourceCounters>,
+    // How much the counters grew, by the start of the hour they were read in
+    hours: BTreeMap<u64, ResourceCounters>,
+}
+
+impl WorkspaceUsage {
+    fn hours_between(&self, from: u64, to: u64, now: u64) -> f64 {
+        let start = self.created_at.max(from);
+        let end = self.destroyed_at.unwrap_or(now).min(to);
+        end.saturating_sub(start) as f64 / HOUR_MS as f64
+    }
+
+    fn counters_between(&self, from: u64, to: u64) -> ResourceCounters {
+        // Hours are counted whole, from the one `from` is in
+        let from = from - from % HOUR_MS;
+        self.hours
+            .range(from..to)
+            .fold(ResourceCounters::default(), |total, (_, counters)| {
+                add(&total, counters)
+            })
+    }
+}
+
+// Workspace time, CPU time and network traffic of every workspace, from when it was created until
+// a while after it was destroyed. It is kept in memory, so restarting the server starts over.
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    workspaces: HashMap<String, WorkspaceUsage>,
+}
+
+impl Ledger {
+    pub fn start(&mut self, id: &str, labels: HashMap<String, String>, at: u64) {
+        self.workspaces.insert(
+            id.to_string(),
+            WorkspaceUsage {
+                labels,
+                created_at: at,
+                destroyed_at: None,
+                last: None,
+                hours: BTreeMap::new(),
+            },
+        );
+    }
+
+    pub fn finish(&mut self, id: &str, at: u64) {
+        if let Some(usage) = self.workspaces.get_mut(id) {
+            usage.destroyed_at.get_or_insert(at);
+        }
+    }
+
+    // Books how much the counters of the workspace grew since they were last read
+    pub fn record(&mut self, id: &str, counters: ResourceCounters, at: u64) {
+        let Some(usage) = self.workspaces.get_mut(id) else {
+            return;
+        };
+        let grown = match &usage.last {
+            Some(last) => growth(&counters, last),
+            None => counters,
+        };
+        usage.last = Some(counters);
+        let hour = usage.hours.entry(at - at % HOUR_MS).or_default();
+        *hour = add(hour, &grown);
+    }
+
+    // Forgets the workspaces that were destroyed longer ago than the retention
+    pub fn prune(&mut self, now: u64) {
+        self.workspaces.retain(|_, usage| {
+            usage
+                .destroyed_at
+                .is_none_or(|destroyed_at| destroyed_at + RETENTION_MS > now)
+        });
+    }
+
+    // Usage between `from` and `to` of the workspaces grouped by the value of their `group_by`
+    // label, workspaces without it are grouped together
+    pub fn report(&self, group_by: &str, from: u64, to: u64, now: u64) -> UsageReport {
+        let mut groups: BTreeMap<Option<String>, GroupUsage> = BTreeMap::new();
+        for usage in self.workspaces.values() {
+            let alive_in_range =
+                usage.created_at < to && usage.destroyed_at.is_none_or(|at| at > from);
+            if !alive_in_range {
+                continue;
+            }
+            let value = usage.labels.get(group_by).cloned();
+            let group = groups.entry(value.clone()).or_insert_with(|| GroupUsage {
+                value,
+                ..Default::default()
+            });
+            let counters = usage.counters_between(from, to);
+            group.workspaces += 1;
+            group.workspace_hours += usage.hours_between(from, to, now);
+            group.cpu_seconds += counters.cpu_time.as_secs_f64();
+            group.network_bytes += counters.network_bytes;
+        }
+        UsageReport {
+            group_by: group_by.to_string(),
+            from,
+            to,
+            groups: groups.into_values().collect(),
+        }
+    }
+
+    // The quota of the tenant the workspace is labeled with is exceeded this month
+    pub fn check_quota(
+        &self,
+        group_by: &str,
+        labels: &HashMap<String, String>,
+        quotas: &[UsageQuota],
+        now: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let Some(value) = labels.get(group_by) else {
+            return Ok(());
+        };
+        let Some(quota) = quotas.iter().find(|quota| &quota.value == value) else {
+            return Ok(());
+        };
+        let report = self.report(group_by, month_start_ms(now), now, now);
+        let Some(usage) = report
+            .groups
+            .iter()
+            .find(|group| group.value.as_ref() == Some(value))
+        else {
+            return Ok(());
+        };
+        quota.check(usage)
+    }
+}
+
+fn add(a: &ResourceCounters, b: &ResourceCounters) -> ResourceCounters {
+    ResourceCounters {
+        cpu_time: a.cpu_time + b.cpu_time,
+        network_bytes: a.network_bytes + b.network_bytes,
+    }
+}
+
+// The counters start over when a workspace is restarted, all of their value is new then
+fn growth(current: &ResourceCounters, last: &ResourceCounters) -> ResourceCounters {
+    ResourceCounters {
+        cpu_time: match current.cpu_time.checked_sub(last.cpu_time) {
+            Some(grown) => grown,
+            None => current.cpu_time,
+        },
+        network_bytes: match current.network_bytes.checked_sub(last.network_bytes) {
+            Some(grown) => grown,
+            None => current.network_bytes,
+        },
+    }
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, PartialEq)]
+pub struct UsageReport {
+    // The label the workspaces are grouped by
+    pub group_by: String,
+    // Unix time in milliseconds
+    pub from: u64,
+    pub to: u64,
+    pub groups: Vec<GroupUsage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, JsonSchema, PartialEq)]
+pub struct GroupUsage {
+    // Value of the label, None for the workspaces without it
+    pub value: Option<String>,
+    // Workspaces that existed in the range
+    pub workspaces: usize,
+    pub workspace_hours: f64,
+    // CPU time and network traffic are read every minute, and booked by the hour, for docker
+    // workspaces only
+    pub cpu_seconds: f64,
+    pub network_bytes: u64,
+}
+
+// Monthly quota of the workspaces with a value of the usage label, like
+// `{"value": "team-a", "workspace_hours": 500, "cpu_hours": 100}`. New workspaces are refused
+// once one of the limits is reached, until the next month.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct UsageQuota {
+    pub value: String,
+    #[serde(default)]
+    pub workspace_hours: Option<f64>,
+    #[serde(default)]
+    pub cpu_hours: Option<f64>,
+    #[serde(default)]
+    pub network_bytes: Option<u64>,
+}
+
+impl UsageQuota {
+    // Reads a JSON list of quotas
+    pub fn from_file(path: &str) -> Result<Vec<UsageQuota>> {
+        let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Invalid quotas in {}", path))
+    }
+
+    fn check(&self, usage: &GroupUsage) -> Result<(), QuotaExceeded> {
+        let exceeded = |limit: &str| QuotaExceeded {
+            value: self.value.clone(),
+            limit: limit.to_string(),
+        };
+        if self
+            .workspace_hours
+            .is_some_and(|max| usage.workspace_hours >= max)
+        {
+            return Err(exceeded("workspace_hours"));
+        }
+        if self
+            .cpu_hours
+            .is_some_and(|max| usage.cpu_seconds >= max * 3600.0)
+        {
+            return Err(exceeded("cpu_hours"));
+        }
+        if self
+            .network_bytes
+            .is_some_and(|max| usage.network_bytes >= max)
+        {
+            return Err(exceeded("network_bytes"));
+        }
+        Ok(())
+    }
+}
+
+// A workspace was refused because its tenant used up a monthly quota
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub value: String,
+    pub limit: String,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} used up its {} quota for this month",
+            self.value, self.limit
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn labels(tenant: &str) -> HashMap<String, String> {
+        HashMap::from([("tenant".to_string(), tenant.to_string())])
+    }
+
+    fn counters(cpu_secs: u64, network_bytes: u64) -> ResourceCounters {
+        ResourceCounters {
+            cpu_time: Duration::from_secs(cpu_secs),
+            network_bytes,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut ledger = Ledger::default();
+        ledger.start("a", labels("team-a"), 0);
+        ledger.start("b", labels("team-a"), HOUR_MS);
+        ledger.start("c", HashMap::new(), 0);
+        ledger.record("a", counters(60, 1000), HOUR_MS / 2);
+        // Restarted in the second hour, its counters start over
+        ledger.record("a", counters(30, 500), HOUR_MS + 1);
+        ledger.finish("a", 2 * HOUR_MS);
+
+        let report = ledger.report("tenant", 0, 3 * HOUR_MS, 3 * HOUR_MS);
+        assert_eq!(report.groups.len(), 2);
+        assert_eq!(report.groups[0].value, None);
+        assert_eq!(report.groups[0].workspace_hours, 3.0);
+        let team = &report.groups[1];
+        assert_eq!(team.value.as_deref(), Some("team-a"));
+        assert_eq!(team.workspaces, 2);
+        assert_eq!(team.workspace_hours, 4.0);
+        assert_eq!(team.cpu_seconds, 90.0);
+        assert_eq!(team.network_bytes, 1500);
+
+        // Only the second hour
+        let report = ledger.report("tenant", HOUR_MS, 2 * HOUR_MS, 3 * HOUR_MS);
+        let team = &report.groups[1];
+        assert_eq!(team.workspace_hours, 2.0);
+        assert_eq!(team.cpu_seconds, 30.0);
+    }
+
+    #[test]
+    fn test_check_quota() {
+        let quotas = vec![UsageQuota {
+            value: "team-a".to_string(),
+            workspace_hours: None,
+            cpu_hours: Some(1.0),
+            network_bytes: None,
+        }];
+        let now = now_ms();
+        let mut ledger = Ledger::default();
+        ledger.start("a", labels("team-a"), now - 1000);
+        ledger.record("a", counters(1800, 0), now);
+        assert!(ledger
+            .check_quota("tenant", &labels("team-a"), &quotas, now)
+            .is_ok());
+
+        ledger.record("a", counters(3600, 0), now);
+        let error = ledger
+            .check_quota("tenant", &labels("team-a"), &quotas, now)
+            .unwrap_err();
+        assert_eq!(error.limit, "cpu_hours");
+        assert!(ledger
+            .check_quota("tenant", &labels("team-b"), &quotas, now)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_times() {
+        // 2024-03-15T12:00:00Z
+        assert_eq!(month_start_ms(1_710_504_000_000), 1_709_251_200_000);
+        assert_eq!(
+            parse_time("2024-03-01T00:00:00Z").unwrap(),
+            1_709_251_200_000
+        );
+        assert_eq!(
+            parse_time("2024-03-01T01:00:00+01:00").unwrap(),
+            1_709_251_200_000
+        );
+        assert_eq!(parse_time("1709251200000").unwrap(), 1_709_251_200_000);
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_time("1969-12-31T00:00:00Z").is_err());
+    }
+}
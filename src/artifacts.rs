@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+
+use crate::{Compression, WorkspaceController};
+
+// Collects the files matching the patterns into a compressed tarball, relative to the working
+// directory. The tarball is compressed by the server, so workspaces do not need gzip or zstd. Patterns are shell globs where `**` matches any number of directories, directories
+// are included with everything in them.
+pub(crate) async fn collect_artifacts(
+    controller: &dyn WorkspaceController,
+    patterns: &[String],
+    working_dir: Option<&str>,
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    if patterns.is_empty() {
+        anyhow::bail!("No artifacts configured for the workspace");
+    }
+
+    // The patterns are left unquoted so the shell expands them. Patterns that match nothing are
+    // kept as is by shells without nullglob, hence the existence check. Only files are listed, so
+    // overlapping patterns like `dist` and `dist/**` do not add files twice.
+    let list = format!("/tmp/derrick-artifacts-{}.list", uuid::Uuid::new_v4());
+    let cmd = format!(
+        r#"shopt -s globstar nullglob 2>/dev/null
+for match in {patterns}; do [ -e "$match" ] && find "$match" ! -type d; done | sort -u > {list}
+if [ ! -s {list} ]; then rm -f {list}; echo "No artifacts match" >&2; exit 1; fi
+tar -cf - --no-recursion -T {list} | base64; code=$?; rm -f {list}; exit $code"#,
+        patterns = patterns.join(" "),
+        list = list
+    );
+
+    let output = controller
+        .cmd_with_output(&cmd, working_dir, HashMap::new(), None)
+        .await
+        .context("Could not collect artifacts")?;
+    if output.exit_code != 0 {
+        anyhow::bail!("Could not collect artifacts: {}", output.output.trim());
+    }
+
+    let encoded: String = output.output.split_whitespace().collect();
+    let tarball = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    compression.compress(&tarball)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[tokio::test]
+    async fn test_collects_matching_files() {
+        let controller = TestingController::new("artifacts");
+        controller
+            .cmd(
+                "mkdir -p target/release dist/assets && touch target/release/app target/release/app.d dist/index.js dist/assets/logo.svg",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let patterns = vec![
+            "target/release/app".to_string(),
+            "dist".to_string(),
+            "dist/**".to_string(),
+            "missing/*".to_string(),
+        ];
+        let tarball = collect_artifacts(&controller, &patterns, None, Compression::Gzip)
+            .await
+            .unwrap();
+        controller
+            .write_file("artifacts.tar.gz", &tarball, None)
+            .await
+            .unwrap();
+
+        let listing = controller
+            .cmd_with_output("tar -tzf artifacts.tar.gz", None, HashMap::new(), None)
+            .await
+            .unwrap()
+            .output;
+        assert_eq!(
+            listing.lines().collect::<Vec<_>>(),
+            vec![
+                "dist/assets/logo.svg",
+                "dist/index.js",
+                "target/release/app"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_matches() {
+        let controller = TestingController::new("artifacts-none");
+        let patterns = vec!["target/release/app".to_string()];
+        assert!(collect_artifacts(&controller, &patterns, None)
+            .await
+            .is_err());
+        assert!(collect_artifacts(&controller, &[], None).await.is_err());
+    }
+}
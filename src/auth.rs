@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// What a token allows, every role can do what the ones before it can
+#[derive(
+    Debug, Clone, Copy, Deserialize, Serialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Lists workspaces and reads their files, status and events
+    Viewer,
+    // Creates workspaces, runs commands and writes files
+    Operator,
+    // Destroys and adopts workspaces and reloads the context
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+// A token clients send as `Authorization: Bearer <token>`, the name is only used in logs and
+// errors
+#[derive(Clone, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+impl ApiToken {
+    // Reads a JSON list of tokens, like `[{"name": "ci", "token": "...", "role": "operator"}]`
+    pub fn from_file(path: &str) -> Result<Vec<ApiToken>> {
+        let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path))?;
+        let tokens: Vec<ApiToken> = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Invalid tokens in {}", path))?;
+        if let Some(token) = tokens.iter().find(|token| token.token.is_empty()) {
+            anyhow::bail!("Token {} in {} is empty", token.name, path);
+        }
+        Ok(tokens)
+    }
+}
+
+// A request without a valid token, or with one whose role does not allow the operation
+#[derive(Debug, PartialEq)]
+pub enum Unauthorized {
+    MissingToken,
+    InvalidToken,
+    Forbidden {
+        name: String,
+        role: Role,
+        required: Role,
+    },
+}
+
+impl fmt::Display for Unauthorized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unauthorized::MissingToken => write!(f, "A bearer token is required"),
+            Unauthorized::InvalidToken => write!(f, "The token is not valid"),
+            Unauthorized::Forbidden {
+                name,
+                role,
+                required,
+            } => write!(
+                f,
+                "Token {} has the {} role, this requires {}",
+                name, role, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Unauthorized {}
+
+// Tokens are kept hashed, so that looking one up takes the same time however much of it matches
+#[derive(Default)]
+pub(crate) struct ApiTokens {
+    tokens: HashMap<[u8; 32], (String, Role)>,
+}
+
+impl ApiTokens {
+    pub(crate) fn new(tokens: Vec<ApiToken>) -> Self {
+        Self {
+            tokens: tokens
+                .into_iter()
+                .map(|token| (hash(&token.token), (token.name, token.role)))
+                .collect(),
+        }
+    }
+
+    // Without tokens every request is allowed
+    pub(crate) fn authorize(
+        &self,
+        token: Option<&str>,
+        required: Role,
+    ) -> Result<(), Unauthorized> {
+        if self.tokens.is_empty() {
+            return Ok(());
+        }
+        let token = token.ok_or(Unauthorized::MissingToken)?;
+        let (name, role) = self
+            .tokens
+            .get(&hash(token))
+            .ok_or(Unauthorized::InvalidToken)?;
+        if *role < required {
+            return Err(Unauthorized::Forbidden {
+                name: name.clone(),
+                role: *role,
+                required,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn hash(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        assert!(ApiTokens::default().authorize(None, Role::Admin).is_ok());
+
+        let tokens = ApiTokens::new(vec![ApiToken {
+            name: "dashboard".to_string(),
+            token: "secret".to_string(),
+            role: Role::Viewer,
+        }]);
+        assert!(tokens.authorize(Some("secret"), Role::Viewer).is_ok());
+        assert_eq!(
+            tokens.authorize(Some("secret"), Role::Operator),
+            Err(Unauthorized::Forbidden {
+                name: "dashboard".to_string(),
+                role: Role::Viewer,
+                required: Role::Operator,
+            })
+        );
+        assert_eq!(
+            tokens.authorize(Some("other"), Role::Viewer),
+            Err(Unauthorized::InvalidToken)
+        );
+        assert_eq!(
+            tokens.authorize(None, Role::Viewer),
+            Err(Unauthorized::MissingToken)
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::config::Config;
+use crate::events::Event;
+use crate::server::{Server, SizeLimits};
+use crate::{ApiToken, Schedule, UsageQuota, WorkspaceContext, WorkspaceProvider};
+
+type Subscriber = Box<dyn Fn(Event) + Send + Sync>;
+
+// Sets up a server for programs that embed derrick, from a context and providers given in code
+// rather than the config file, flags and environment `derrick serve` reads. The server it builds
+// can be used directly, or served with `serve_http` or `serve_nats`.
+pub struct DerrickBuilder {
+    server: Server,
+    context: WorkspaceContext,
+    modes: Vec<String>,
+    schedules: Vec<Schedule>,
+    subscribers: Vec<Subscriber>,
+    prepare_env: Option<HashMap<String, String>>,
+    background_tasks: bool,
+}
+
+impl DerrickBuilder {
+    /// Provisions the workspaces of the context with the provider, which contexts and profiles
+    /// that ask for `mode` or no mode at all use.
+    pub fn new(
+        context: WorkspaceContext,
+        mode: impl Into<String>,
+        provider: Box<dyn WorkspaceProvider>,
+    ) -> Self {
+        let mode = mode.into();
+        Self {
+            server: Server::new(context.clone(), provider).with_provisioning_mode(&mode),
+            context,
+            modes: vec![mode],
+            schedules: Vec::new(),
+            subscribers: Vec::new(),
+            prepare_env: None,
+            background_tasks: true,
+        }
+    }
+
+    /// Provisions the workspaces of contexts and profiles that ask for this mode with the
+    /// provider.
+    pub fn with_provider(
+        mut self,
+        mode: impl Into<String>,
+        provider: Box<dyn WorkspaceProvider>,
+    ) -> Self {
+        let mode = mode.into();
+        self.modes.push(mode.clone());
+        self.server = self.server.with_provider(mode, provider);
+        self
+    }
+
+    /// Requires API requests to carry one of the tokens, see `Server::with_api_tokens`.
+    pub fn with_api_tokens(mut self, tokens: Vec<ApiToken>) -> Self {
+        self.server = self.server.with_api_tokens(tokens);
+        self
+    }
+
+    /// Uses the Github app and NATS settings of the config instead of the ones of the process.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.server = self.server.with_config(Arc::new(config));
+        self
+    }
+
+    /// Accepts Github webhook deliveries signed with the secret.
+    pub fn with_github_webhook_secret(mut self, secret: impl Into<String>) -> Self {
+        self.server = self.server.with_github_webhook_secret(Some(secret.into()));
+        self
+    }
+
+    /// Limits how many workspaces are provisioned at the same time, see
+    /// `Server::with_provisioning_limits`.
+    pub fn with_provisioning_limits(
+        mut self,
+        max_concurrent_provisions: usize,
+        queue_timeout: Duration,
+    ) -> Self {
+        self.server = self
+            .server
+            .with_provisioning_limits(max_concurrent_provisions, queue_timeout);
+        self
+    }
+
+    pub fn with_idempotency_key_ttl(mut self, ttl: Duration) -> Self {
+        self.server = self.server.with_idempotency_key_ttl(ttl);
+        self
+    }
+
+    pub fn with_failed_status_ttl(mut self, ttl: Duration) -> Self {
+        self.server = self.server.with_failed_status_ttl(ttl);
+        self
+    }
+
+    pub fn with_max_inline_output(mut self, max_bytes: usize) -> Self {
+        self.server = self.server.with_max_inline_output(Some(max_bytes));
+        self
+    }
+
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.server = self.server.with_size_limits(size_limits);
+        self
+    }
+
+    pub fn with_command_history_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.server = self.server.with_command_history_dir(Some(dir.into()));
+        self
+    }
+
+    /// Reports usage and enforces the quotas by this label, see `Server::with_usage_quotas`.
+    pub fn with_usage_quotas(mut self, label: impl Into<String>, quotas: Vec<UsageQuota>) -> Self {
+        self.server = self
+            .server
+            .with_usage_label(label)
+            .with_usage_quotas(quotas);
+        self
+    }
+
+    /// Runs the command of the schedule in a fresh workspace every interval.
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedules.push(schedule);
+        self
+    }
+
+    /// Calls the function with every event the server publishes, from a task of its own. Events
+    /// are skipped when it falls behind.
+    pub fn with_subscriber(mut self, subscriber: impl Fn(Event) + Send + Sync + 'static) -> Self {
+        self.subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    /// Builds the images of the context and its profiles in the background, with the given
+    /// environment for the setup steps, see `Server::prepare_contexts`.
+    pub fn with_prepared_images(mut self, env: HashMap<String, String>) -> Self {
+        self.prepare_env = Some(env);
+        self
+    }
+
+    /// Whether the server checks disk quotas, health and usage of the workspaces in the
+    /// background, which it does by default. Without them, quotas are not enforced.
+    pub fn with_background_tasks(mut self, enabled: bool) -> Self {
+        self.background_tasks = enabled;
+        self
+    }
+
+    /// Creates the server and starts its background tasks, which need a tokio runtime. Fails
+    /// when the context or one of its profiles asks for a provisioning mode without a provider.
+    pub async fn build(self) -> Result<Arc<Server>> {
+        for mode in self.context.provisioning_modes() {
+            if !self.modes.contains(&mode) {
+                anyhow::bail!("Provisioning mode {} has no provider", mode);
+            }
+        }
+        let server = Arc::new(self.server);
+
+        for subscriber in self.subscribers {
+            let mut events = server.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(event) => subscriber(event),
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!(skipped, "Event subscriber is too slow, skipping events")
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+        if self.background_tasks {
+            server.enforce_disk_quotas(Duration::from_secs(60));
+            server.watch_health(Duration::from_secs(10));
+            server.record_usage(Duration::from_secs(60));
+        }
+        for schedule in self.schedules {
+            server.add_schedule(schedule).await?;
+        }
+        if let Some(env) = self.prepare_env {
+            server.prepare_contexts(env);
+        }
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LocalTempSyncProvider;
+
+    fn context(mode: Option<&str>) -> WorkspaceContext {
+        WorkspaceContext {
+            name: "embedded".to_string(),
+            provisioning_mode: mode.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn provider() -> Box<dyn WorkspaceProvider> {
+        let root = std::env::temp_dir().join(format!("derrick-builder-{}", uuid::Uuid::new_v4()));
+        Box::new(LocalTempSyncProvider::new().with_root(root))
+    }
+
+    #[tokio::test]
+    async fn test_build() {
+        let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let server = DerrickBuilder::new(context(Some("sandboxed")), "local", provider())
+            .with_provider("sandboxed", provider())
+            .with_background_tasks(false)
+            .with_subscriber(move |event| {
+                let _ = sender.send(event);
+            })
+            .build()
+            .await
+            .unwrap();
+
+        server.publish(Event::WorkspaceDestroyed {
+            workspace_id: "3f0c".to_string(),
+        });
+        assert!(matches!(
+            received.recv().await,
+            Some(Event::WorkspaceDestroyed { workspace_id }) if workspace_id == "3f0c"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_config() {
+        let config = |github_app_id| Config {
+            github_app_id: Some(github_app_id),
+            ..Default::default()
+        };
+        let first = DerrickBuilder::new(context(None), "local", provider())
+            .with_config(config(1))
+            .with_background_tasks(false)
+            .build()
+            .await
+            .unwrap();
+        let second = DerrickBuilder::new(context(None), "local", provider())
+            .with_config(config(2))
+            .with_background_tasks(false)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(first.config().github_app_id, Some(1));
+        assert_eq!(second.config().github_app_id, Some(2));
+    }
+
+    #[tokio::test]
+    async fn test_missing_provider() {
+        let result = DerrickBuilder::new(context(Some("docker")), "local", provider())
+            .build()
+            .await;
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            "Provisioning mode docker has no provider"
+        );
+    }
+}
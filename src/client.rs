@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+pub static DEFAULT_URL: &str = "http://127.0.0.1:50080";
+
+// Client for the HTTP API of a running derrick server
+#[derive(Debug, Clone)]
+pub struct Client {
+    base_url: String,
+    http: reqwest::Client,
+    // Sent with every request when the server requires a token
+    authorization: Option<HeaderValue>,
+}
+
+// The server answered with an error status, like a 404 for a file that does not exist
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: reqwest::StatusCode,
+    pub message: String,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Request failed with {}: {}", self.status, self.message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+#[derive(Debug, Deserialize)]
+pub struct CommandOutput {
+    pub output: String,
+    pub exit_code: i32,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceResponse {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WorkspaceSummary {
+    pub id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct WorkspaceListResponse {
+    workspaces: Vec<WorkspaceSummary>,
+}
+
+#[derive(Deserialize)]
+struct MatrixResponse {
+    workspaces: Vec<crate::MatrixWorkspace>,
+}
+
+#[derive(Deserialize)]
+struct DestroyWorkspacesResponse {
+    destroyed: Vec<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+            authorization: None,
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Result<Self> {
+        let mut authorization =
+            HeaderValue::from_str(&format!("Bearer {}", token)).context("Invalid token")?;
+        authorization.set_sensitive(true);
+        self.http = reqwest::Client::builder()
+            .default_headers(HeaderMap::from_iter([(
+                AUTHORIZATION,
+                authorization.clone(),
+            )]))
+            .build()?;
+        self.authorization = Some(authorization);
+        Ok(self)
+    }
+
+    pub async fn create_workspace(
+        &self,
+        env: HashMap<String, String>,
+        labels: HashMap<String, String>,
+        name: Option<&str>,
+        profile: Option<&str>,
+        variables: HashMap<String, String>,
+        run_async: bool,
+    ) -> Result<String> {
+        let response: WorkspaceResponse = self
+            .post(
+                &format!("/workspaces?async={}", run_async),
+                json!({
+                    "env": env,
+                    "labels": labels,
+                    "name": name,
+                    "profile": profile,
+                    "variables": variables,
+                }),
+            )
+            .await?
+            .json()
+            .await?;
+        Ok(response.id)
+    }
+
+    // Creates a workspace for every combination of the values of the variables in the matrix.
+    // Workspaces that could not be created have an error instead of an id.
+    pub async fn create_matrix(
+        &self,
+        matrix: &BTreeMap<String, Vec<String>>,
+        env: HashMap<String, String>,
+        labels: HashMap<String, String>,
+        profile: Option<&str>,
+        run_async: bool,
+    ) -> Result<Vec<crate::MatrixWorkspace>> {
+        let response: MatrixResponse = self
+            .post(
+                &format!("/matrix?async={}", run_async),
+                json!({ "matrix": matrix, "env": env, "labels": labels, "profile": profile }),
+            )
+            .await?
+            .json()
+            .await?;
+        Ok(response.workspaces)
+    }
+
+    // Registers a running container, by id, name or `key=value` label, as a workspace
+    pub async fn adopt_workspace(
+        &self,
+        container: &str,
+        labels: HashMap<String, String>,
+        name: Option<&str>,
+    ) -> Result<String> {
+        let response: WorkspaceResponse = self
+            .post(
+                "/adopt",
+                json!({ "container": container, "labels": labels, "name": name }),
+            )
+            .await?
+            .json()
+            .await?;
+        Ok(response.id)
+    }
+
+    // Lists the workspaces, only the ones with all labels of the selector (`key=value,...`) when
+    // one is given
+    pub async fn list_workspaces(&self, label: Option<&str>) -> Result<Vec<WorkspaceSummary>> {
+        let response = self
+            .http
+            .get(self.url("/workspaces"))
+            .query(&[("label", label)])
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        let response: WorkspaceListResponse = check(response).await?.json().await?;
+        Ok(response.workspaces)
+    }
+
+    // Destroys the workspaces with all labels of the selector, returning their ids
+    pub async fn destroy_workspaces(&self, label: &str) -> Result<Vec<String>> {
+        let response = self
+            .http
+            .delete(self.url("/workspaces"))
+            .query(&[("label", label)])
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        let response: DestroyWorkspacesResponse = check(response).await?.json().await?;
+        Ok(response.destroyed)
+    }
+
+    // Returns whether the workspace existed
+    pub async fn destroy_workspace(&self, id: &str) -> Result<bool> {
+        let response = self
+            .http
+            .delete(self.url(&format!("/workspaces/{}", id)))
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    pub async fn cmd_with_output(
+        &self,
+        id: &str,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+    ) -> Result<CommandOutput> {
+        Ok(self
+            .post(
+                &format!("/workspaces/{}/cmd_with_output", id),
+                json!({ "cmd": cmd, "working_dir": working_dir, "env": env }),
+            )
+            .await?
+            .json()
+            .await?)
+    }
+
+    // Provenance to attach to commits made in the workspace with `Workspace::set_provenance`
+    pub async fn provenance(&self, id: &str) -> Result<crate::Provenance> {
+        let response = self
+            .http
+            .get(self.url(&format!("/workspaces/{}/provenance", id)))
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        Ok(check(response).await?.json().await?)
+    }
+
+    pub async fn write_file(&self, id: &str, path: &str, content: &[u8]) -> Result<()> {
+        self.post(
+            &format!("/workspaces/{}/write_file", id),
+            json!({
+                "path": path,
+                "content": base64::engine::general_purpose::STANDARD.encode(content),
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn read_file(&self, id: &str, path: &str) -> Result<Vec<u8>> {
+        Ok(self
+            .post(
+                &format!("/workspaces/{}/read_file", id),
+                json!({ "path": path }),
+            )
+            .await?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+
+    // Connects to an interactive shell in the workspace. Binary messages carry the terminal
+    // input and output.
+    pub async fn shell(
+        &self,
+        id: &str,
+        cols: u16,
+        rows: u16,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        self.websocket(&format!(
+            "/workspaces/{}/shell?cols={}&rows={}",
+            id, cols, rows
+        ))
+        .await
+        .context("Could not open shell")
+    }
+
+    // Opens a TCP connection to a port of the workspace. Binary messages carry the bytes of the
+    // connection.
+    pub async fn tunnel(
+        &self,
+        id: &str,
+        port: u16,
+    ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        self.websocket(&format!("/workspaces/{}/tunnel/{}", id, port))
+            .await
+            .with_context(|| format!("Could not open a tunnel to port {}", port))
+    }
+
+    async fn websocket(&self, path: &str) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+        let url = self
+            .url(path)
+            .replacen("http://", "ws://", 1)
+            .replacen("https://", "wss://", 1);
+        let mut request = url.into_client_request()?;
+        if let Some(authorization) = &self.authorization {
+            request
+                .headers_mut()
+                .insert(AUTHORIZATION, authorization.clone());
+        }
+        let (stream, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(stream)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub(crate) async fn post(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<reqwest::Response> {
+        let response = self
+            .http
+            .post(self.url(path))
+            .json(&body)
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        check(response).await
+    }
+
+    pub(crate) async fn get(&self, path: &str) -> Result<reqwest::Response> {
+        let response = self
+            .http
+            .get(self.url(path))
+            .send()
+            .await
+            .context("Could not reach derrick")?;
+        check(response).await
+    }
+}
+
+// Turns error responses into errors with the message of the server
+async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        message: String,
+    }
+    let message = match response.json::<ErrorResponse>().await {
+        Ok(error) => error.message,
+        Err(_) => "no details".to_string(),
+    };
+    Err(ApiError { status, message }.into())
+}
@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::credential_providers::{credential_provider, CredentialSource};
+
+const DEFAULT_AWS_DURATION_SECONDS: u32 = 3600;
+const DEFAULT_GCP_LIFETIME_SECONDS: u32 = 3600;
+const GCP_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+// Short-lived cloud credentials every workspace gets its own of, like
+// `{"provider": "aws", "role_arn": "arn:aws:iam::123456789012:role/preview-deployer"}`. They are
+// minted by the server with its own cloud credentials once the workspace is provisioned, and
+// revoked when it is destroyed.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum CloudCredentials {
+    // A session of the role from AWS STS, in AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and
+    // AWS_SESSION_TOKEN. Assumed with the aws cli and its usual credentials.
+    Aws {
+        role_arn: String,
+        #[serde(default)]
+        region: Option<String>,
+        // Between 15 minutes and the maximum session duration of the role
+        #[serde(default)]
+        duration_seconds: Option<u32>,
+        // Session policy that scopes the session down further than the role
+        #[serde(default)]
+        policy: Option<serde_json::Value>,
+        #[serde(default)]
+        external_id: Option<String>,
+    },
+    // An access token of the service account, in CLOUDSDK_AUTH_ACCESS_TOKEN and
+    // GOOGLE_OAUTH_ACCESS_TOKEN. Generated through impersonation with the token `gcloud` prints,
+    // e.g. of the workload identity of the server.
+    Gcp {
+        service_account: String,
+        #[serde(default)]
+        scopes: Vec<String>,
+        // At most an hour, unless the organization allows longer
+        #[serde(default)]
+        lifetime_seconds: Option<u32>,
+        #[serde(default)]
+        project: Option<String>,
+    },
+}
+
+impl CloudCredentials {
+    pub(crate) fn validate(&self) -> Result<()> {
+        match self {
+            CloudCredentials::Aws {
+                role_arn,
+                duration_seconds,
+                ..
+            } => {
+                if !role_arn.starts_with("arn:") || !role_arn.contains(":role/") {
+                    anyhow::bail!("Invalid role ARN {}", role_arn)
+                }
+                if duration_seconds.is_some_and(|duration| !(900..=43200).contains(&duration)) {
+                    anyhow::bail!("AWS sessions last between 900 and 43200 seconds")
+                }
+            }
+            CloudCredentials::Gcp {
+                service_account,
+                lifetime_seconds,
+                ..
+            } => {
+                if !service_account.contains('@') {
+                    anyhow::bail!("Invalid service account {}", service_account)
+                }
+                if lifetime_seconds.is_some_and(|lifetime| !(1..=43200).contains(&lifetime)) {
+                    anyhow::bail!("GCP tokens last between 1 and 43200 seconds")
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Mints the credentials for the workspace, returning their environment and the session to
+    // revoke them with
+    #[tracing::instrument(skip_all, fields(workspace_id = workspace_id))]
+    async fn mint(&self, workspace_id: &str) -> Result<(HashMap<String, String>, CloudSession)> {
+        match self {
+            CloudCredentials::Aws {
+                role_arn,
+                region,
+                duration_seconds,
+                policy,
+                external_id,
+            } => {
+                let session_name = session_name(workspace_id);
+                let mut args = vec![
+                    "sts".to_string(),
+                    "assume-role".to_string(),
+                    "--role-arn".to_string(),
+                    role_arn.clone(),
+                    "--role-session-name".to_string(),
+                    session_name.clone(),
+                    "--duration-seconds".to_string(),
+                    duration_seconds
+                        .unwrap_or(DEFAULT_AWS_DURATION_SECONDS)
+                        .to_string(),
+                ];
+                if let Some(policy) = policy {
+                    args.extend(["--policy".to_string(), policy.to_string()]);
+                }
+                if let Some(external_id) = external_id {
+                    args.extend(["--external-id".to_string(), external_id.clone()]);
+                }
+                let output = aws(&args, region.as_deref())
+                    .await
+                    .with_context(|| format!("Could not assume role {}", role_arn))?;
+                let (env, expires_at) = parse_assume_role(&output, region.as_deref())?;
+                Ok((
+                    env,
+                    CloudSession::Aws {
+                        role_arn: role_arn.clone(),
+                        session_name,
+                        expires_at,
+                    },
+                ))
+            }
+            CloudCredentials::Gcp {
+                service_account,
+                scopes,
+                lifetime_seconds,
+                project,
+            } => {
+                let token = generate_access_token(
+                    service_account,
+                    scopes,
+                    lifetime_seconds.unwrap_or(DEFAULT_GCP_LIFETIME_SECONDS),
+                )
+                .await
+                .with_context(|| format!("Could not impersonate {}", service_account))?;
+                let mut env = HashMap::from([
+                    ("CLOUDSDK_AUTH_ACCESS_TOKEN".to_string(), token.clone()),
+                    ("GOOGLE_OAUTH_ACCESS_TOKEN".to_string(), token.clone()),
+                ]);
+                if let Some(project) = project {
+                    env.insert("CLOUDSDK_CORE_PROJECT".to_string(), project.clone());
+                    env.insert("GOOGLE_CLOUD_PROJECT".to_string(), project.clone());
+                }
+                Ok((env, CloudSession::Gcp { token }))
+            }
+        }
+    }
+}
+
+// Credentials minted for a workspace, until they are revoked
+pub(crate) enum CloudSession {
+    Aws {
+        role_arn: String,
+        session_name: String,
+        expires_at: DateTime<Utc>,
+    },
+    Gcp {
+        token: String,
+    },
+}
+
+impl CloudSession {
+    // STS sessions cannot be revoked themselves, so the role gets a policy that denies
+    // everything to the session until it has expired. The policy is removed after that, or left
+    // behind when the server stops before.
+    #[tracing::instrument(skip_all)]
+    async fn revoke(self) -> Result<()> {
+        match self {
+            CloudSession::Aws {
+                role_arn,
+                session_name,
+                expires_at,
+            } => {
+                let role_name = role_name(&role_arn)?;
+                let policy_name = format!("{}-revoked", session_name);
+                aws(
+                    &[
+                        "iam".to_string(),
+                        "put-role-policy".to_string(),
+                        "--role-name".to_string(),
+                        role_name.clone(),
+                        "--policy-name".to_string(),
+                        policy_name.clone(),
+                        "--policy-document".to_string(),
+                        revocation_policy(&session_name).to_string(),
+                    ],
+                    None,
+                )
+                .await
+                .with_context(|| format!("Could not revoke session {}", session_name))?;
+
+                let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+                tokio::spawn(async move {
+                    // Some slack for clocks that are off
+                    tokio::time::sleep(remaining + Duration::from_secs(300)).await;
+                    let deleted = aws(
+                        &[
+                            "iam".to_string(),
+                            "delete-role-policy".to_string(),
+                            "--role-name".to_string(),
+                            role_name,
+                            "--policy-name".to_string(),
+                            policy_name,
+                        ],
+                        None,
+                    )
+                    .await;
+                    if let Err(e) = deleted {
+                        tracing::warn!("Failed to remove revocation policy: {:?}", e);
+                    }
+                });
+                Ok(())
+            }
+            CloudSession::Gcp { token } => {
+                let response = reqwest::Client::new()
+                    .post("https://oauth2.googleapis.com/revoke")
+                    .form(&[("token", token)])
+                    .send()
+                    .await
+                    .context("Could not reach Google to revoke token")?;
+                if !response.status().is_success() {
+                    anyhow::bail!("Google could not revoke token ({})", response.status())
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Mints all the credentials, revoking the ones already minted when one of them fails
+pub(crate) async fn mint(
+    workspace_id: &str,
+    credentials: &[CloudCredentials],
+) -> Result<(HashMap<String, String>, Vec<CloudSession>)> {
+    let mut env = HashMap::new();
+    let mut sessions = Vec::new();
+    for credentials in credentials {
+        match credentials.mint(workspace_id).await {
+            Ok((credentials_env, session)) => {
+                env.extend(credentials_env);
+                sessions.push(session);
+            }
+            Err(e) => {
+                revoke(sessions).await;
+                return Err(e);
+            }
+        }
+    }
+    Ok((env, sessions))
+}
+
+// Revokes the sessions, failures are only logged as the workspace is gone either way
+pub(crate) async fn revoke(sessions: Vec<CloudSession>) {
+    for session in sessions {
+        if let Err(e) = session.revoke().await {
+            tracing::warn!("Failed to revoke cloud credentials: {:?}", e);
+        }
+    }
+}
+
+// Shows up in CloudTrail, so sessions can be traced back to their workspace
+fn session_name(workspace_id: &str) -> String {
+    let name = format!("derrick-{}", workspace_id)
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || "+=,.@-".contains(c) => c,
+            _ => '-',
+        })
+        .collect::<String>();
+    name.chars().take(64).collect()
+}
+
+// Name of the role without its path, `arn:aws:iam::123456789012:role/path/name`
+fn role_name(role_arn: &str) -> Result<String> {
+    role_arn
+        .split_once(":role/")
+        .and_then(|(_, path)| path.rsplit('/').next())
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .with_context(|| format!("Invalid role ARN {}", role_arn))
+}
+
+// Denies everything to sessions with the name, whose user id is `<role id>:<session name>`
+fn revocation_policy(session_name: &str) -> serde_json::Value {
+    serde_json::json!({
+        "Version": "2012-10-17",
+        "Statement": [{
+            "Effect": "Deny",
+            "Action": "*",
+            "Resource": "*",
+            "Condition": {"StringLike": {"aws:userid": format!("*:{}", session_name)}}
+        }]
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AssumeRoleOutput {
+    credentials: AssumeRoleCredentials,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AssumeRoleCredentials {
+    access_key_id: String,
+    secret_access_key: String,
+    session_token: String,
+    expiration: String,
+}
+
+fn parse_assume_role(
+    output: &str,
+    region: Option<&str>,
+) -> Result<(HashMap<String, String>, DateTime<Utc>)> {
+    let output: AssumeRoleOutput =
+        serde_json::from_str(output).context("Invalid output of aws sts assume-role")?;
+    let credentials = output.credentials;
+    let expires_at = DateTime::parse_from_rfc3339(&credentials.expiration)
+        .context("Invalid expiration of AWS session")?
+        .with_timezone(&Utc);
+    let mut env = HashMap::from([
+        ("AWS_ACCESS_KEY_ID".to_string(), credentials.access_key_id),
+        (
+            "AWS_SECRET_ACCESS_KEY".to_string(),
+            credentials.secret_access_key,
+        ),
+        ("AWS_SESSION_TOKEN".to_string(), credentials.session_token),
+    ]);
+    if let Some(region) = region {
+        env.insert("AWS_REGION".to_string(), region.to_string());
+        env.insert("AWS_DEFAULT_REGION".to_string(), region.to_string());
+    }
+    Ok((env, expires_at))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+}
+
+async fn generate_access_token(
+    service_account: &str,
+    scopes: &[String],
+    lifetime_seconds: u32,
+) -> Result<String> {
+    // The token of the server itself, which is cached until it is about to expire
+    let server_token = credential_provider(&CredentialSource::Exec {
+        command: vec![
+            "gcloud".to_string(),
+            "auth".to_string(),
+            "print-access-token".to_string(),
+        ],
+    })
+    .credential()
+    .await?;
+    let scopes = match scopes {
+        [] => vec![GCP_SCOPE.to_string()],
+        scopes => scopes.to_vec(),
+    };
+    let response = reqwest::Client::new()
+        .post(format!(
+            "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+            service_account
+        ))
+        .bearer_auth(server_token.secret)
+        .json(&serde_json::json!({
+            "scope": scopes,
+            "lifetime": format!("{}s", lifetime_seconds),
+        }))
+        .send()
+        .await
+        .context("Could not reach the IAM credentials API")?;
+    let status = response.status();
+    if !status.is_success() {
+        let message = response.text().await.unwrap_or_default();
+        anyhow::bail!("Could not generate access token ({}): {}", status, message)
+    }
+    let response: GenerateAccessTokenResponse = response
+        .json()
+        .await
+        .context("Invalid response of the IAM credentials API")?;
+    Ok(response.access_token)
+}
+
+async fn aws(args: &[String], region: Option<&str>) -> Result<String> {
+    let mut command = tokio::process::Command::new("aws");
+    command
+        .args(args)
+        .args(["--output", "json"])
+        .stdin(std::process::Stdio::null());
+    if let Some(region) = region {
+        command.args(["--region", region]);
+    }
+    let output = command.output().await.context("Could not run aws cli")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "aws {} failed: {}",
+            args.iter().take(2).cloned().collect::<Vec<_>>().join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_assume_role() {
+        let output = r#"{
+            "Credentials": {
+                "AccessKeyId": "ASIAEXAMPLE",
+                "SecretAccessKey": "secret",
+                "SessionToken": "token",
+                "Expiration": "2030-01-01T00:00:00+00:00"
+            },
+            "AssumedRoleUser": {"AssumedRoleId": "AROAEXAMPLE:derrick-3f0c", "Arn": "arn"}
+        }"#;
+        let (env, expires_at) = parse_assume_role(output, Some("eu-west-1")).unwrap();
+        assert_eq!(env["AWS_ACCESS_KEY_ID"], "ASIAEXAMPLE");
+        assert_eq!(env["AWS_SECRET_ACCESS_KEY"], "secret");
+        assert_eq!(env["AWS_SESSION_TOKEN"], "token");
+        assert_eq!(env["AWS_REGION"], "eu-west-1");
+        assert_eq!(expires_at.to_rfc3339(), "2030-01-01T00:00:00+00:00");
+        assert!(parse_assume_role("{}", None).is_err());
+    }
+
+    #[test]
+    fn test_session_and_role_name() {
+        assert_eq!(session_name("3f0c/a b"), "derrick-3f0c-a-b");
+        assert_eq!(session_name(&"a".repeat(100)).len(), 64);
+        assert_eq!(
+            role_name("arn:aws:iam::123456789012:role/ci/preview-deployer").unwrap(),
+            "preview-deployer"
+        );
+        assert!(role_name("arn:aws:iam::123456789012:user/bot").is_err());
+        assert_eq!(
+            revocation_policy("derrick-3f0c")["Statement"][0]["Condition"]["StringLike"]
+                ["aws:userid"],
+            "*:derrick-3f0c"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let credentials: CloudCredentials = serde_json::from_str(
+            r#"{"provider": "aws", "role_arn": "arn:aws:iam::123456789012:role/deployer", "duration_seconds": 900}"#,
+        )
+        .unwrap();
+        assert!(credentials.validate().is_ok());
+
+        let credentials: CloudCredentials = serde_json::from_str(
+            r#"{"provider": "aws", "role_arn": "deployer", "duration_seconds": 60}"#,
+        )
+        .unwrap();
+        assert!(credentials.validate().is_err());
+
+        let credentials: CloudCredentials =
+            serde_json::from_str(r#"{"provider": "gcp", "service_account": "deployer"}"#).unwrap();
+        assert!(credentials.validate().is_err());
+    }
+}
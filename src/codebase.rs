@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::file_tree::{list_entries, TreeOptions};
+use crate::replace::read_files;
+use crate::{WorkspaceContext, WorkspaceController};
+
+// Files exported per request, the rest are returned on the next pages
+const MAX_CHUNK_FILES: usize = 200;
+// Larger files are skipped, they are usually generated or minified
+const MAX_CHUNK_FILE_SIZE: usize = 1024 * 1024;
+const DEFAULT_CHUNK_LINES: usize = 80;
+
+// Languages the code tooling of the server (like the symbol index) understands, recognized by the
+// extensions of their files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SupportedLanguages {
+    Rust,
+    Python,
+    Javascript,
+    Typescript,
+    Go,
+    Ruby,
+    Java,
+    C,
+    Cpp,
+    Csharp,
+    Php,
+}
+
+impl SupportedLanguages {
+    pub const ALL: [SupportedLanguages; 11] = [
+        SupportedLanguages::Rust,
+        SupportedLanguages::Python,
+        SupportedLanguages::Javascript,
+        SupportedLanguages::Typescript,
+        SupportedLanguages::Go,
+        SupportedLanguages::Ruby,
+        SupportedLanguages::Java,
+        SupportedLanguages::C,
+        SupportedLanguages::Cpp,
+        SupportedLanguages::Csharp,
+        SupportedLanguages::Php,
+    ];
+
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            SupportedLanguages::Rust => &["rs"],
+            SupportedLanguages::Python => &["py", "pyi"],
+            SupportedLanguages::Javascript => &["js", "jsx", "mjs", "cjs"],
+            SupportedLanguages::Typescript => &["ts", "tsx", "mts", "cts"],
+            SupportedLanguages::Go => &["go"],
+            SupportedLanguages::Ruby => &["rb"],
+            SupportedLanguages::Java => &["java"],
+            SupportedLanguages::C => &["c", "h"],
+            SupportedLanguages::Cpp => &["cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+            SupportedLanguages::Csharp => &["cs"],
+            SupportedLanguages::Php => &["php"],
+        }
+    }
+
+    // The language of a file by its extension
+    pub fn from_path(path: &str) -> Option<Self> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let (_, extension) = name.rsplit_once('.')?;
+        Self::ALL
+            .into_iter()
+            .find(|language| language.extensions().contains(&extension))
+    }
+
+    // Name of the language for universal-ctags
+    pub(crate) fn ctags_name(&self) -> &'static str {
+        match self {
+            SupportedLanguages::Rust => "Rust",
+            SupportedLanguages::Python => "Python",
+            SupportedLanguages::Javascript => "JavaScript",
+            SupportedLanguages::Typescript => "TypeScript",
+            SupportedLanguages::Go => "Go",
+            SupportedLanguages::Ruby => "Ruby",
+            SupportedLanguages::Java => "Java",
+            SupportedLanguages::C => "C",
+            SupportedLanguages::Cpp => "C++",
+            SupportedLanguages::Csharp => "C#",
+            SupportedLanguages::Php => "PHP",
+        }
+    }
+
+    pub(crate) fn from_ctags_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|language| language.ctags_name() == name)
+    }
+}
+
+// A range of lines of a source file, sized for embedding
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct CodeChunk {
+    // Relative to the working directory
+    pub path: String,
+    pub language: SupportedLanguages,
+    // Counted from 1, both included
+    pub start_line: usize,
+    pub end_line: usize,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct ChunkExport {
+    pub chunks: Vec<CodeChunk>,
+    // Pass as `after` to get the next page, not set on the last one
+    pub next: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChunkOptions {
+    // Lines per chunk
+    pub max_lines: usize,
+    // Lines a chunk repeats of the one before it, so that code at the boundary keeps its context
+    pub overlap: usize,
+    // Only files of this language, all supported languages by default
+    pub language: Option<SupportedLanguages>,
+    // Only files after this path, the `next` of the previous page
+    pub after: Option<String>,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            max_lines: DEFAULT_CHUNK_LINES,
+            overlap: 0,
+            language: None,
+            after: None,
+        }
+    }
+}
+
+impl ChunkOptions {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_lines == 0 {
+            anyhow::bail!("Chunks must have at least one line");
+        }
+        if self.overlap >= self.max_lines {
+            anyhow::bail!("Overlap must be less than the lines per chunk");
+        }
+        Ok(())
+    }
+}
+
+// The source code in the repositories of a workspace
+#[derive(Debug, Clone)]
+pub(crate) struct Codebase {
+    // Relative to the working directory
+    paths: Vec<String>,
+}
+
+impl Codebase {
+    // The repositories of the context, or the working directory when it has none
+    pub(crate) fn from_context(context: &WorkspaceContext) -> Self {
+        let paths = if context.repositories.is_empty() {
+            vec![".".to_string()]
+        } else {
+            context
+                .repositories
+                .iter()
+                .map(|repository| repository.path.clone())
+                .collect()
+        };
+        Self { paths }
+    }
+
+    pub(crate) fn paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    // Extensions of the files of all supported languages, like `rs`
+    pub(crate) fn supported_extensions() -> Vec<&'static str> {
+        SupportedLanguages::ALL
+            .iter()
+            .flat_map(|language| language.extensions().iter().copied())
+            .collect()
+    }
+
+    // The files of the repositories that git does not ignore, relative to the working directory and
+    // in order of their path
+    pub(crate) async fn files(
+        &self,
+        controller: &dyn WorkspaceController,
+        working_dir: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let options = TreeOptions {
+            depth: None,
+            glob: None,
+            gitignore: true,
+        };
+        let mut files = Vec::new();
+        for dir in &self.paths {
+            for (file, is_dir) in list_entries(controller, dir, &options, working_dir).await? {
+                if is_dir {
+                    continue;
+                }
+                files.push(match dir.as_str() {
+                    "." => file,
+                    _ => format!("{}/{}", dir.trim_end_matches('/'), file),
+                });
+            }
+        }
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    // Splits the source files into chunks, a page of files at a time
+    #[tracing::instrument(skip(controller))]
+    pub(crate) async fn chunks(
+        &self,
+        controller: &dyn WorkspaceController,
+        options: &ChunkOptions,
+        working_dir: Option<&str>,
+    ) -> Result<ChunkExport> {
+        options.validate()?;
+        let extensions = Self::supported_extensions();
+        let mut files = Vec::new();
+        for path in self.files(controller, working_dir).await? {
+            let extension = path.rsplit_once('.').map(|(_, extension)| extension);
+            if !extension.is_some_and(|extension| extensions.contains(&extension)) {
+                continue;
+            }
+            let Some(language) = SupportedLanguages::from_path(&path) else {
+                continue;
+            };
+            if options.language.is_none_or(|only| only == language) {
+                files.push((path, language));
+            }
+        }
+        if let Some(after) = &options.after {
+            files.retain(|(path, _)| path > after);
+        }
+        let next = (files.len() > MAX_CHUNK_FILES).then(|| files[MAX_CHUNK_FILES - 1].0.clone());
+        files.truncate(MAX_CHUNK_FILES);
+
+        let paths = files
+            .iter()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        let languages = files.into_iter().collect::<HashMap<_, _>>();
+        let mut contents = read_files(controller, ".", &paths, working_dir).await?;
+        contents.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let mut chunks = Vec::new();
+        for (path, content) in contents {
+            if content.len() > MAX_CHUNK_FILE_SIZE {
+                continue;
+            }
+            let (Some(language), Ok(content)) = (languages.get(&path), String::from_utf8(content))
+            else {
+                continue;
+            };
+            chunks.extend(
+                chunk_lines(&content, options.max_lines, options.overlap).map(
+                    |(start_line, end_line, content)| CodeChunk {
+                        path: path.clone(),
+                        language: *language,
+                        start_line,
+                        end_line,
+                        content,
+                    },
+                ),
+            );
+        }
+        Ok(ChunkExport { chunks, next })
+    }
+}
+
+// Line ranges of the content and their text, each starting `max_lines - overlap` lines after the
+// one before it
+fn chunk_lines(
+    content: &str,
+    max_lines: usize,
+    overlap: usize,
+) -> impl Iterator<Item = (usize, usize, String)> + '_ {
+    let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+    let step = max_lines - overlap;
+    (0..lines.len())
+        .step_by(step)
+        .take_while(move |&start| start == 0 || start + overlap < lines.len())
+        .map(move |start| {
+            let end = (start + max_lines).min(lines.len());
+            (start + 1, end, lines[start..end].concat())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            SupportedLanguages::from_path("src/main.rs"),
+            Some(SupportedLanguages::Rust)
+        );
+        assert_eq!(
+            SupportedLanguages::from_path("web/app.test.tsx"),
+            Some(SupportedLanguages::Typescript)
+        );
+        assert_eq!(SupportedLanguages::from_path("README.md"), None);
+        assert_eq!(SupportedLanguages::from_path(".rs/Makefile"), None);
+    }
+
+    #[test]
+    fn test_chunk_lines() {
+        let content = "1\n2\n3\n4\n5\n";
+        assert_eq!(
+            chunk_lines(content, 2, 0).collect::<Vec<_>>(),
+            vec![
+                (1, 2, "1\n2\n".to_string()),
+                (3, 4, "3\n4\n".to_string()),
+                (5, 5, "5\n".to_string()),
+            ]
+        );
+        assert_eq!(
+            chunk_lines(content, 3, 1)
+                .map(|(start, end, _)| (start, end))
+                .collect::<Vec<_>>(),
+            vec![(1, 3), (3, 5)]
+        );
+        assert_eq!(chunk_lines("", 3, 1).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chunks() {
+        let controller = TestingController::new("codebase-chunks");
+        controller
+            .cmd(
+                "git init -q . && mkdir -p src target && printf 'fn a() {}\\nfn b() {}\\nfn c() {}\\n' > src/lib.rs && printf 'print(1)\\n' > main.py && touch README.md target/gen.rs && echo target > .gitignore",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        let codebase = Codebase {
+            paths: vec![".".to_string()],
+        };
+
+        let options = ChunkOptions {
+            max_lines: 2,
+            ..Default::default()
+        };
+        let export = codebase.chunks(&controller, &options, None).await.unwrap();
+        assert_eq!(
+            export
+                .chunks
+                .iter()
+                .map(|chunk| (chunk.path.as_str(), chunk.start_line, chunk.end_line))
+                .collect::<Vec<_>>(),
+            vec![
+                ("main.py", 1, 1),
+                ("src/lib.rs", 1, 2),
+                ("src/lib.rs", 3, 3)
+            ]
+        );
+        assert_eq!(export.chunks[2].content, "fn c() {}\n");
+        assert_eq!(export.chunks[2].language, SupportedLanguages::Rust);
+        assert_eq!(export.next, None);
+
+        let options = ChunkOptions {
+            after: Some("main.py".to_string()),
+            language: Some(SupportedLanguages::Python),
+            ..Default::default()
+        };
+        let export = codebase.chunks(&controller, &options, None).await.unwrap();
+        assert!(export.chunks.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::{HashMap, VecDeque};
+
+use sha2::{Digest, Sha256};
+use shell_escape::escape;
+
+use crate::workspace_controllers::CommandOutput;
+use crate::WorkspaceController;
+
+// Older entries are dropped when a workspace caches more commands than this
+const MAX_ENTRIES: usize = 256;
+
+// Describes the files in the git repository of the working directory, including uncommitted
+// changes and the contents of untracked files. Ignored files are left out.
+const GIT_STATE_CMD: &str = "git rev-parse HEAD && git status --porcelain=v1 -z && git diff HEAD && git ls-files -z --others --exclude-standard | xargs -0 -r sha256sum --";
+
+// Successful outputs of idempotent commands of a workspace, see `cache_key`
+#[derive(Debug, Default)]
+pub(crate) struct CommandCache {
+    entries: HashMap<String, CommandOutput>,
+    order: VecDeque<String>,
+}
+
+impl CommandCache {
+    pub(crate) fn get(&self, key: &str) -> Option<CommandOutput> {
+        self.entries.get(key).cloned()
+    }
+
+    pub(crate) fn insert(&mut self, key: String, output: CommandOutput) {
+        if self.entries.insert(key.clone(), output).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > MAX_ENTRIES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Hashes the command with its working directory, environment and the state of the files it
+// depends on. Without `files` that is everything in the git repository of the working directory.
+// Returns None when the state can not be determined, the command should then not be cached.
+pub(crate) async fn cache_key(
+    controller: &dyn WorkspaceController,
+    cmd: &str,
+    working_dir: Option<&str>,
+    env: &HashMap<String, String>,
+    files: &[String],
+) -> Option<String> {
+    let state_cmd = if files.is_empty() {
+        GIT_STATE_CMD.to_string()
+    } else {
+        // Missing files are part of the state as well
+        let files = files
+            .iter()
+            .map(|file| escape(file.into()).to_string())
+            .collect::<Vec<_>>();
+        format!("sha256sum -- {} 2>&1; true", files.join(" "))
+    };
+
+    let state = match controller
+        .cmd_with_output(&state_cmd, working_dir, HashMap::new(), None)
+        .await
+    {
+        Ok(output) if output.exit_code == 0 => output.output,
+        _ => return None,
+    };
+
+    let mut env = env.iter().collect::<Vec<_>>();
+    env.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(cmd);
+    hasher.update([0]);
+    hasher.update(working_dir.unwrap_or_default());
+    for (key, value) in env {
+        hasher.update([0]);
+        hasher.update(format!("{}={}", key, value));
+    }
+    hasher.update([0]);
+    hasher.update(state);
+    Some(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    fn output(output: &str) -> CommandOutput {
+        CommandOutput {
+            output: output.to_string(),
+            stdout: output.as_bytes().to_vec(),
+            stderr: Vec::new(),
+            exit_code: 0,
+            limit_exceeded: None,
+        }
+    }
+
+    #[test]
+    fn test_drops_oldest_entries() {
+        let mut cache = CommandCache::default();
+        for i in 0..=MAX_ENTRIES {
+            cache.insert(i.to_string(), output(&i.to_string()));
+        }
+        assert!(cache.get("0").is_none());
+        assert_eq!(cache.get("1").unwrap().output, "1");
+        assert_eq!(cache.entries.len(), MAX_ENTRIES);
+    }
+
+    #[tokio::test]
+    async fn test_key_changes_with_repository_state() {
+        let controller = TestingController::new("command-cache");
+        controller
+            .cmd(
+                "git init -q . && echo a > file.txt && git add . && git commit -q -m initial",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        let env = HashMap::new();
+
+        let key = cache_key(&controller, "cat file.txt", None, &env, &[]).await;
+        assert!(key.is_some());
+        assert_eq!(
+            cache_key(&controller, "cat file.txt", None, &env, &[]).await,
+            key
+        );
+        assert_ne!(
+            cache_key(&controller, "wc file.txt", None, &env, &[]).await,
+            key
+        );
+
+        controller
+            .cmd("echo b > file.txt", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        let key_after_change = cache_key(&controller, "cat file.txt", None, &env, &[]).await;
+        assert_ne!(key_after_change, key);
+
+        // Untracked files count with their contents, not just their names
+        controller
+            .cmd("echo a > untracked.txt", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        let key_with_untracked = cache_key(&controller, "cat file.txt", None, &env, &[]).await;
+        assert_ne!(key_with_untracked, key_after_change);
+        controller
+            .cmd("echo b > untracked.txt", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_ne!(
+            cache_key(&controller, "cat file.txt", None, &env, &[]).await,
+            key_with_untracked
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_with_files() {
+        let controller = TestingController::new("command-cache-files");
+        let files = vec!["Cargo.lock".to_string()];
+        let env = HashMap::new();
+
+        // Not a git repository, but the files are given
+        let missing = cache_key(&controller, "cargo metadata", None, &env, &files).await;
+        assert!(missing.is_some());
+        assert!(cache_key(&controller, "cargo metadata", None, &env, &[])
+            .await
+            .is_none());
+
+        controller
+            .write_file("Cargo.lock", b"version = 3", None)
+            .await
+            .unwrap();
+        assert_ne!(
+            cache_key(&controller, "cargo metadata", None, &env, &files).await,
+            missing
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::credentials::scrub;
+use crate::workspace_controllers::LimitExceeded;
+
+// Older commands are dropped when a workspace ran more than this
+const MAX_RECORDS: usize = 100;
+
+// What a command was run with, to run it again
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CommandRequest {
+    pub(crate) cmd: String,
+    pub(crate) working_dir: Option<String>,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) timeout: Option<u64>,
+    pub(crate) cpu_time: Option<u64>,
+    pub(crate) max_output_bytes: Option<usize>,
+}
+
+// A command run through the API, with its output
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct CommandRecord {
+    pub id: String,
+    // Credentials in the command and its output are scrubbed
+    pub cmd: String,
+    pub working_dir: Option<String>,
+    // Names of the environment variables, their values may be secrets
+    pub env: Vec<String>,
+    // Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    // Not set when the command could not be run, the output is the error then
+    pub exit_code: Option<i32>,
+    pub output: String,
+    pub limit_exceeded: Option<LimitExceeded>,
+    // Set when the output was spilled, the whole output can be read from
+    // /workspaces/{id}/outputs/{cmd_id}
+    pub cmd_id: Option<String>,
+    // The command this one replayed
+    pub replay_of: Option<String>,
+    // Only kept in memory, as the environment may have secrets
+    #[serde(skip)]
+    pub(crate) request: Option<CommandRequest>,
+}
+
+// A command that is being run, which becomes a record when it finishes
+pub(crate) struct RunningCommand {
+    id: String,
+    request: CommandRequest,
+    replay_of: Option<String>,
+    started_at: u64,
+    started: Instant,
+}
+
+impl RunningCommand {
+    pub(crate) fn start(request: CommandRequest, replay_of: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            request,
+            replay_of,
+            started_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub(crate) fn request(&self) -> &CommandRequest {
+        &self.request
+    }
+
+    fn status(&self) -> CommandStatus {
+        CommandStatus::Running {
+            id: self.id.clone(),
+            cmd: scrub(&self.request.cmd),
+            started_at: self.started_at,
+        }
+    }
+
+    pub(crate) fn finish(
+        self,
+        exit_code: Option<i32>,
+        output: &str,
+        limit_exceeded: Option<LimitExceeded>,
+        cmd_id: Option<String>,
+    ) -> CommandRecord {
+        let mut env = self.request.env.keys().cloned().collect::<Vec<_>>();
+        env.sort();
+        CommandRecord {
+            id: self.id,
+            cmd: scrub(&self.request.cmd),
+            working_dir: self.request.working_dir.clone(),
+            env,
+            started_at: self.started_at,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            exit_code,
+            output: scrub(output),
+            limit_exceeded,
+            cmd_id,
+            replay_of: self.replay_of,
+            request: Some(self.request),
+        }
+    }
+}
+
+// The command is not in the history of the workspace, or was dropped from it
+#[derive(Debug)]
+pub struct CommandNotFound {
+    pub id: String,
+}
+
+impl std::fmt::Display for CommandNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Command {} is not in the history", self.id)
+    }
+}
+
+impl std::error::Error for CommandNotFound {}
+
+// A command started with `cmd_async`, which is running or is in the history with its result
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CommandStatus {
+    Running {
+        id: String,
+        cmd: String,
+        // Unix time in milliseconds
+        started_at: u64,
+    },
+    Finished(CommandRecord),
+}
+
+// The most recent commands of a workspace, oldest first, and the commands that run in the
+// background
+#[derive(Debug, Default)]
+pub(crate) struct CommandHistory {
+    records: VecDeque<CommandRecord>,
+    running: HashMap<String, CommandStatus>,
+}
+
+impl CommandHistory {
+    pub(crate) fn start(&mut self, command: &RunningCommand) {
+        self.running.insert(command.id.clone(), command.status());
+    }
+
+    pub(crate) fn push(&mut self, record: CommandRecord) {
+        self.running.remove(&record.id);
+        self.records.push_back(record);
+        while self.records.len() > MAX_RECORDS {
+            self.records.pop_front();
+        }
+    }
+
+    pub(crate) fn records(&self) -> Vec<CommandRecord> {
+        self.records.iter().cloned().collect()
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<&CommandRecord> {
+        self.records.iter().find(|record| record.id == id)
+    }
+
+    pub(crate) fn status(&self, id: &str) -> Option<CommandStatus> {
+        self.running
+            .get(id)
+            .cloned()
+            .or_else(|| self.get(id).cloned().map(CommandStatus::Finished))
+    }
+}
+
+// The history of a workspace on disk, one record per line. It is kept after the workspace is
+// destroyed.
+fn history_file(dir: &Path, workspace_id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", workspace_id))
+}
+
+pub(crate) async fn persist(dir: &Path, workspace_id: &str, record: &CommandRecord) -> Result<()> {
+    tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("Could not create {}", dir.display()))?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    let path = history_file(dir, workspace_id);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+// The most recent records of a workspace on disk, None when it has no history there
+pub(crate) async fn load(dir: &Path, workspace_id: &str) -> Result<Option<Vec<CommandRecord>>> {
+    let path = history_file(dir, workspace_id);
+    let content = match tokio::fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Could not read {}", path.display())),
+    };
+    let mut history = CommandHistory::default();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        // A line may be cut short when the server stopped while writing it
+        match serde_json::from_str(line) {
+            Ok(record) => history.push(record),
+            Err(e) => tracing::warn!("Skipping invalid record in {}: {:?}", path.display(), e),
+        }
+    }
+    Ok(Some(history.records()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(cmd: &str) -> CommandRecord {
+        let request = CommandRequest {
+            cmd: cmd.to_string(),
+            env: HashMap::from([("TOKEN".to_string(), "secret".to_string())]),
+            ..Default::default()
+        };
+        RunningCommand::start(request, None).finish(Some(0), "ok", None, None)
+    }
+
+    #[test]
+    fn test_drops_oldest_records() {
+        let mut history = CommandHistory::default();
+        let first = record("echo 0");
+        history.push(first.clone());
+        for i in 1..=MAX_RECORDS {
+            history.push(record(&format!("echo {}", i)));
+        }
+        assert!(history.get(&first.id).is_none());
+        assert_eq!(history.records().len(), MAX_RECORDS);
+        assert_eq!(history.records()[0].cmd, "echo 1");
+    }
+
+    #[test]
+    fn test_status_of_background_command() {
+        let mut history = CommandHistory::default();
+        let running = RunningCommand::start(
+            CommandRequest {
+                cmd: "cargo build".to_string(),
+                ..Default::default()
+            },
+            None,
+        );
+        let id = running.id().to_string();
+        history.start(&running);
+        assert!(matches!(
+            history.status(&id),
+            Some(CommandStatus::Running { cmd, .. }) if cmd == "cargo build"
+        ));
+
+        history.push(running.finish(Some(0), "done", None, None));
+        assert!(matches!(
+            history.status(&id),
+            Some(CommandStatus::Finished(record)) if record.id == id && record.output == "done"
+        ));
+        assert!(history.status("other").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_persisted_records_leave_out_the_environment() {
+        let dir = std::env::temp_dir().join(format!("derrick-history-{}", uuid::Uuid::new_v4()));
+        assert!(load(&dir, "ws").await.unwrap().is_none());
+
+        let record = record("cargo test");
+        persist(&dir, "ws", &record).await.unwrap();
+        let content = std::fs::read_to_string(history_file(&dir, "ws")).unwrap();
+        assert!(!content.contains("secret"));
+
+        let loaded = load(&dir, "ws").await.unwrap().unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].id, record.id);
+        assert_eq!(loaded[0].env, vec!["TOKEN"]);
+        assert!(loaded[0].request.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
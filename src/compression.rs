@@ -0,0 +1,112 @@
+use std::io::{Read, Write};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Compression of file content and archives sent over the API
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    // Name used in `Content-Encoding` and `Accept-Encoding` headers
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    // Media type and file extension of a tarball compressed with it
+    pub(crate) fn tarball(&self) -> (&'static str, &'static str) {
+        match self {
+            Compression::Gzip => ("application/gzip", "tar.gz"),
+            Compression::Zstd => ("application/zstd", "tar.zst"),
+        }
+    }
+
+    // Picks the compression the client accepts, zstd over gzip as it is faster for the same
+    // ratio. Encodings the client refuses with `q=0` are left out.
+    pub fn negotiate(accept_encoding: &str) -> Option<Compression> {
+        let accepted = accept_encoding
+            .split(',')
+            .filter_map(|encoding| {
+                let mut parts = encoding.split(';').map(str::trim);
+                let name = parts.next()?.to_ascii_lowercase();
+                let refused = parts.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect::<Vec<_>>();
+        [Compression::Zstd, Compression::Gzip]
+            .into_iter()
+            .find(|compression| accepted.iter().any(|name| name == compression.encoding()))
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(data, 0)?),
+        }
+    }
+
+    // Fails when the content is larger than `limit` once decompressed, so that a small request
+    // cannot fill up the memory of the server
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(data)?),
+        };
+        let mut content = Vec::new();
+        decoder
+            .take(limit as u64 + 1)
+            .read_to_end(&mut content)
+            .with_context(|| format!("Could not decompress {} content", self.encoding()))?;
+        if content.len() > limit {
+            anyhow::bail!("Decompressed content is larger than {} bytes", limit);
+        }
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Compression::negotiate("gzip, deflate, br, zstd"),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::negotiate("zstd;q=0, gzip;q=0.5"),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(Compression::negotiate("identity"), None);
+    }
+
+    #[test]
+    fn test_round_trip_with_limit() {
+        let content = "derrick ".repeat(1000).into_bytes();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(&content).unwrap();
+            assert!(compressed.len() < content.len());
+            assert_eq!(compression.decompress(&compressed, 8000).unwrap(), content);
+            assert!(compression.decompress(&compressed, 7999).is_err());
+        }
+    }
+}
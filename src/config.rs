@@ -4,60 +4,79 @@ use std::sync::OnceLock;
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
 
+// Settings of the Github app and the NATS server, among others. Servers and sessions are given
+// one, `from_env` is the one of the process for those that are not.
+#[derive(Debug, Clone, Default)]
 pub struct Config {
     pub port: String,
     pub openai_api_key: String,
     pub otel_enabled: bool,
     pub openai_endpoint: Option<String>,
     pub github_app_id: Option<u64>,
+    // Private key of the Github app, a base64 encoded PEM
+    pub github_private_key: Option<String>,
     pub qdrant_url: Option<String>,
     pub qdrant_api_key: Option<String>,
     pub redis_url: Option<String>,
     pub github_endpoint: Option<String>,
     pub nats_endpoint: Option<String>,
     pub nats_creds: Option<String>,
+    pub gitea_url: Option<String>,
+    pub gitea_token: Option<String>,
+    // Signs the provenance of workspaces, see `Provenance`
+    pub provenance_key: Option<String>,
 }
 
 impl Config {
     pub fn from_env() -> &'static Config {
-        CONFIG.get_or_init(|| {
-            tracing::info!("Loading config from environment");
-            dotenv().ok();
+        CONFIG.get_or_init(Self::load)
+    }
+
+    // Reads the environment and .env file every time, unlike `from_env`
+    pub fn load() -> Config {
+        tracing::info!("Loading config from environment");
+        dotenv().ok();
 
-            let port = env::var("PORT").expect("PORT env var not set");
-            let openai_api_key =
-                env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY env var not set");
-            let otel_enabled = env::var("OTEL_ENABLED")
-                .expect("OTEL_ENABLED env var not set")
-                .parse::<bool>()
-                .expect("OTEL_ENABLED env var must be a boolean");
-            let openai_endpoint = env::var("OPENAI_ENDPOINT").ok();
-            let github_endpoint = env::var("GITHUB_ENDPOINT").ok();
+        let port = env::var("PORT").expect("PORT env var not set");
+        let openai_api_key = env::var("OPENAI_API_KEY").expect("OPENAI_API_KEY env var not set");
+        let otel_enabled = env::var("OTEL_ENABLED")
+            .expect("OTEL_ENABLED env var not set")
+            .parse::<bool>()
+            .expect("OTEL_ENABLED env var must be a boolean");
+        let openai_endpoint = env::var("OPENAI_ENDPOINT").ok();
+        let github_endpoint = env::var("GITHUB_ENDPOINT").ok();
 
-            let github_app_id: Option<u64> = env::var("GITHUB_APP_ID")
-                .map(|s| s.parse::<u64>().expect("GITHUB_APP_ID must be a number"))
-                .ok();
+        let github_app_id: Option<u64> = env::var("GITHUB_APP_ID")
+            .map(|s| s.parse::<u64>().expect("GITHUB_APP_ID must be a number"))
+            .ok();
+        let github_private_key = env::var("GITHUB_PRIVATE_KEY").ok();
 
-            let qdrant_url = env::var("QDRANT_URL").ok();
-            let qdrant_api_key = env::var("QDRANT_API_KEY").ok();
-            let redis_url = env::var("REDIS_URL").ok();
-            let nats_endpoint = env::var("NATS_ENDPOINT").ok();
-            let nats_creds = env::var("NATS_CREDS").ok();
+        let qdrant_url = env::var("QDRANT_URL").ok();
+        let qdrant_api_key = env::var("QDRANT_API_KEY").ok();
+        let redis_url = env::var("REDIS_URL").ok();
+        let nats_endpoint = env::var("NATS_ENDPOINT").ok();
+        let nats_creds = env::var("NATS_CREDS").ok();
+        let gitea_url = env::var("GITEA_URL").ok();
+        let gitea_token = env::var("GITEA_TOKEN").ok();
+        let provenance_key = env::var("DERRICK_PROVENANCE_KEY").ok();
 
-            Self {
-                port,
-                openai_api_key,
-                otel_enabled,
-                openai_endpoint,
-                github_app_id,
-                qdrant_url,
-                qdrant_api_key,
-                redis_url,
-                github_endpoint,
-                nats_endpoint,
-                nats_creds,
-            }
-        })
+        Self {
+            port,
+            openai_api_key,
+            otel_enabled,
+            openai_endpoint,
+            github_app_id,
+            github_private_key,
+            qdrant_url,
+            qdrant_api_key,
+            redis_url,
+            github_endpoint,
+            nats_endpoint,
+            nats_creds,
+            gitea_url,
+            gitea_token,
+            provenance_key,
+        }
     }
 
     pub fn otel_enabled(&self) -> bool {
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use shell_escape::escape as escape_cow;
+
+use crate::git_status::git_status;
+use crate::workspace_controllers::git::try_output;
+use crate::WorkspaceController;
+
+fn escape(s: &str) -> String {
+    escape_cow(std::borrow::Cow::Borrowed(s)).to_string()
+}
+
+// Outcome of a rebase or merge that git could start
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum MergeResult {
+    Completed,
+    // The rebase or merge stopped and waits for the conflicts to be resolved with
+    // `resolve_and_continue`
+    Conflicted { conflicts: Vec<Conflict> },
+}
+
+// A conflicted file with the content of each side, None when the file does not exist on that side.
+// While rebasing, `ours` is the branch that is rebased onto and `theirs` the commit being replayed.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Conflict {
+    pub path: String,
+    pub base: Option<String>,
+    pub ours: Option<String>,
+    pub theirs: Option<String>,
+}
+
+pub(crate) async fn rebase(
+    controller: &dyn WorkspaceController,
+    onto: &str,
+) -> Result<MergeResult> {
+    run_and_collect_conflicts(controller, &format!("git rebase {}", escape(onto))).await
+}
+
+pub(crate) async fn merge(
+    controller: &dyn WorkspaceController,
+    branch: &str,
+) -> Result<MergeResult> {
+    run_and_collect_conflicts(
+        controller,
+        &format!("git merge --no-edit {}", escape(branch)),
+    )
+    .await
+}
+
+// Creates `branch` from `base` on origin and cherry-picks the commits onto it, recording the
+// original commit in each message
+pub(crate) async fn cherry_pick(
+    controller: &dyn WorkspaceController,
+    commits: &[&str],
+    base: &str,
+    branch: &str,
+) -> Result<MergeResult> {
+    if commits.is_empty() {
+        anyhow::bail!("No commits to cherry-pick");
+    }
+
+    controller
+        .cmd(
+            &format!("git fetch origin {}", escape(base)),
+            None,
+            HashMap::new(),
+            None,
+        )
+        .await?;
+    controller
+        .cmd(
+            &format!(
+                "git switch --no-track -c {} {}",
+                escape(branch),
+                escape(&format!("origin/{}", base))
+            ),
+            None,
+            HashMap::new(),
+            None,
+        )
+        .await?;
+
+    let commits = commits.iter().map(|c| escape(c)).collect::<Vec<_>>();
+    run_and_collect_conflicts(
+        controller,
+        &format!("git cherry-pick -x {}", commits.join(" ")),
+    )
+    .await
+}
+
+// Writes the resolved content of the conflicted files, stages them and continues the rebase,
+// merge or cherry-pick in progress. A rebase or cherry-pick of several commits can stop again on
+// the conflicts of a later commit.
+pub(crate) async fn resolve_and_continue(
+    controller: &dyn WorkspaceController,
+    resolutions: &HashMap<String, String>,
+) -> Result<MergeResult> {
+    for (path, content) in resolutions {
+        controller
+            .write_file(path, content.as_bytes(), None)
+            .await?;
+        controller
+            .cmd(
+                &format!("git add -- {}", escape(path)),
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await?;
+    }
+
+    let remaining = git_status(controller, None).await?.conflicted;
+    if !remaining.is_empty() {
+        anyhow::bail!("Unresolved conflicts remain in {}", remaining.join(", "));
+    }
+
+    let rebasing = try_output(
+        controller,
+        r#"test -d "$(git rev-parse --git-path rebase-merge)" -o -d "$(git rev-parse --git-path rebase-apply)""#,
+    )
+    .await
+    .is_some();
+    let merging = try_output(controller, "git rev-parse -q --verify MERGE_HEAD")
+        .await
+        .is_some();
+    let cherry_picking = try_output(controller, "git rev-parse -q --verify CHERRY_PICK_HEAD")
+        .await
+        .is_some();
+
+    // The editor would otherwise wait for a confirmation of the commit message
+    let cmd = if rebasing {
+        "GIT_EDITOR=true git rebase --continue"
+    } else if cherry_picking {
+        "GIT_EDITOR=true git cherry-pick --continue"
+    } else if merging {
+        "git commit --no-edit"
+    } else {
+        anyhow::bail!("There is no rebase, merge or cherry-pick in progress")
+    };
+    run_and_collect_conflicts(controller, cmd).await
+}
+
+// Runs a command that can stop on conflicts. Failures without conflicts are returned as errors.
+async fn run_and_collect_conflicts(
+    controller: &dyn WorkspaceController,
+    cmd: &str,
+) -> Result<MergeResult> {
+    // Depending on the controller a failed command is an error or a non-zero exit code
+    let failure = match controller
+        .cmd_with_output(cmd, None, HashMap::new(), None)
+        .await
+    {
+        Ok(output) if output.exit_code == 0 => return Ok(MergeResult::Completed),
+        Ok(output) => output.output,
+        Err(e) => format!("{:#}", e),
+    };
+
+    let conflicted = git_status(controller, None).await?.conflicted;
+    if conflicted.is_empty() {
+        anyhow::bail!("{} failed: {}", cmd, failure);
+    }
+
+    let mut conflicts = Vec::with_capacity(conflicted.len());
+    for path in conflicted {
+        conflicts.push(Conflict {
+            base: conflict_stage(controller, 1, &path).await,
+            ours: conflict_stage(controller, 2, &path).await,
+            theirs: conflict_stage(controller, 3, &path).await,
+            path,
+        });
+    }
+    Ok(MergeResult::Conflicted { conflicts })
+}
+
+// Stage 1 is the common ancestor, 2 is ours and 3 is theirs
+async fn conflict_stage(
+    controller: &dyn WorkspaceController,
+    stage: u8,
+    path: &str,
+) -> Option<String> {
+    try_output(controller, &format!("git show :{}:{}", stage, escape(path))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    // A repository on `feature` that conflicts with `main` in file.txt
+    async fn conflicting_branches(name: &str) -> TestingController {
+        let controller = TestingController::new(name);
+        controller
+            .cmd(
+                "git init -q -b main . && echo base > file.txt && git add . && git commit -q -m base && git switch -q -c feature && echo feature > file.txt && git commit -q -am feature && git switch -q main && echo main > file.txt && git commit -q -am main && git switch -q feature",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        controller
+    }
+
+    #[tokio::test]
+    async fn test_rebase_conflicts_and_resolve() {
+        let controller = conflicting_branches("rebase").await;
+
+        let result = rebase(&controller, "main").await.unwrap();
+        assert_eq!(
+            result,
+            MergeResult::Conflicted {
+                conflicts: vec![Conflict {
+                    path: "file.txt".to_string(),
+                    base: Some("base\n".to_string()),
+                    ours: Some("main\n".to_string()),
+                    theirs: Some("feature\n".to_string()),
+                }]
+            }
+        );
+
+        let resolutions =
+            HashMap::from([("file.txt".to_string(), "main and feature\n".to_string())]);
+        assert_eq!(
+            resolve_and_continue(&controller, &resolutions)
+                .await
+                .unwrap(),
+            MergeResult::Completed
+        );
+
+        let status = git_status(&controller, None).await.unwrap();
+        assert_eq!(status.branch.as_deref(), Some("feature"));
+        assert!(status.is_clean());
+    }
+
+    #[tokio::test]
+    async fn test_merge_conflicts_and_resolve() {
+        let controller = conflicting_branches("merge").await;
+
+        let MergeResult::Conflicted { conflicts } = merge(&controller, "main").await.unwrap()
+        else {
+            panic!("Expected conflicts");
+        };
+        assert_eq!(conflicts[0].ours.as_deref(), Some("feature\n"));
+        assert_eq!(conflicts[0].theirs.as_deref(), Some("main\n"));
+
+        let resolutions = HashMap::from([("file.txt".to_string(), "merged\n".to_string())]);
+        assert_eq!(
+            resolve_and_continue(&controller, &resolutions)
+                .await
+                .unwrap(),
+            MergeResult::Completed
+        );
+        assert!(resolve_and_continue(&controller, &HashMap::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cherry_pick_conflicts_and_resolve() {
+        let controller = conflicting_branches("cherry-pick").await;
+        // Cherry-picks fetch the base from origin, which is the repository itself here
+        controller
+            .cmd(
+                "git remote add origin . && git switch -q main && echo unrelated > other.txt && git add . && git commit -q -m unrelated",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let unrelated = try_output(&controller, "git rev-parse main").await.unwrap();
+        let feature = try_output(&controller, "git rev-parse feature")
+            .await
+            .unwrap();
+
+        let result = cherry_pick(&controller, &[unrelated.trim()], "feature", "backport-1")
+            .await
+            .unwrap();
+        assert_eq!(result, MergeResult::Completed);
+
+        let MergeResult::Conflicted { conflicts } =
+            cherry_pick(&controller, &[feature.trim()], "main", "backport-2")
+                .await
+                .unwrap()
+        else {
+            panic!("Expected conflicts");
+        };
+        assert_eq!(conflicts[0].path, "file.txt");
+
+        let resolutions = HashMap::from([("file.txt".to_string(), "backported\n".to_string())]);
+        assert_eq!(
+            resolve_and_continue(&controller, &resolutions)
+                .await
+                .unwrap(),
+            MergeResult::Completed
+        );
+        let message = try_output(&controller, "git log -1 --format=%B")
+            .await
+            .unwrap();
+        assert!(message.contains(&format!("(cherry picked from commit {})", feature.trim())));
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::test_runner::{run_to_completion, tail, MAX_REPORT_OUTPUT};
+use crate::WorkspaceController;
+
+// How coverage is collected for the repositories of a context
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct CoverageConfig {
+    // Runs the tests with coverage, e.g. `cargo llvm-cov --lcov --output-path lcov.info`
+    pub command: String,
+    // Report written by the command in lcov or cobertura format, relative to the working directory
+    pub report: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, PartialEq)]
+pub struct FileCoverage {
+    pub path: String,
+    // Lines with code that is instrumented, and the ones of those that ran
+    pub lines_found: usize,
+    pub lines_hit: usize,
+    pub uncovered_lines: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct CoverageReport {
+    pub lines_found: usize,
+    pub lines_hit: usize,
+    // Percentage of the found lines that were hit, 0 when no lines were found
+    pub percent: f64,
+    pub files: Vec<FileCoverage>,
+    pub exit_code: i32,
+    // End of the output of the coverage command
+    pub output: String,
+}
+
+// Hits per line per file
+type LineHits = BTreeMap<String, BTreeMap<u32, u64>>;
+
+// Runs the coverage command and parses the report it wrote. The command may fail because tests
+// fail, the report is still used when it was written.
+pub(crate) async fn collect_coverage(
+    controller: &dyn WorkspaceController,
+    config: &CoverageConfig,
+    working_dir: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<CoverageReport> {
+    // An earlier report would otherwise be mistaken for the result of this run
+    let report_path = shell_escape::escape(config.report.as_str().into()).to_string();
+    let cmd = format!("rm -f {}\n{}", report_path, config.command);
+    let (output, exit_code) = run_to_completion(controller, &cmd, working_dir, timeout).await?;
+
+    let report = controller
+        .read_file(&config.report, working_dir)
+        .await
+        .with_context(|| {
+            format!(
+                "Coverage report {} was not written (exit code {}): {}",
+                config.report,
+                exit_code,
+                tail(&output, 2048)
+            )
+        })?;
+    let hits = parse_report(&String::from_utf8_lossy(&report))?;
+
+    let files = summarize(hits);
+    let lines_found = files.iter().map(|file| file.lines_found).sum();
+    let lines_hit = files.iter().map(|file| file.lines_hit).sum();
+    Ok(CoverageReport {
+        lines_found,
+        lines_hit,
+        percent: percent(lines_hit, lines_found),
+        files,
+        exit_code,
+        output: tail(&output, MAX_REPORT_OUTPUT).to_string(),
+    })
+}
+
+fn percent(hit: usize, found: usize) -> f64 {
+    if found == 0 {
+        return 0.0;
+    }
+    hit as f64 * 100.0 / found as f64
+}
+
+// Cobertura reports are XML, anything else is read as lcov
+fn parse_report(report: &str) -> Result<LineHits> {
+    if report.trim_start().starts_with('<') {
+        parse_cobertura(report)
+    } else {
+        Ok(parse_lcov(report))
+    }
+}
+
+fn parse_lcov(report: &str) -> LineHits {
+    let mut hits = LineHits::new();
+    let mut file = None;
+
+    for line in report.lines().map(str::trim) {
+        if let Some(path) = line.strip_prefix("SF:") {
+            file = Some(path.to_string());
+        } else if line == "end_of_record" {
+            file = None;
+        } else if let (Some(file), Some(data)) = (&file, line.strip_prefix("DA:")) {
+            // DA:<line>,<hits>[,<checksum>]
+            let mut fields = data.split(',');
+            let (Some(Ok(number)), Some(Ok(count))) = (
+                fields.next().map(str::parse::<u32>),
+                fields.next().map(str::parse::<u64>),
+            ) else {
+                continue;
+            };
+            *hits
+                .entry(file.clone())
+                .or_default()
+                .entry(number)
+                .or_default() += count;
+        }
+    }
+    hits
+}
+
+// A file can be split over several classes, e.g. for inner classes
+fn parse_cobertura(report: &str) -> Result<LineHits> {
+    let document = roxmltree::Document::parse(report).context("Invalid cobertura report")?;
+    let mut hits = LineHits::new();
+
+    for class in document
+        .descendants()
+        .filter(|node| node.has_tag_name("class"))
+    {
+        let Some(file) = class.attribute("filename") else {
+            continue;
+        };
+        let lines = hits.entry(file.to_string()).or_default();
+        // Lines of methods are repeated in the lines of the class
+        for line in class
+            .children()
+            .filter(|node| node.has_tag_name("lines"))
+            .flat_map(|lines| lines.children())
+            .filter(|node| node.has_tag_name("line"))
+        {
+            let (Some(Ok(number)), Some(Ok(count))) = (
+                line.attribute("number").map(str::parse::<u32>),
+                line.attribute("hits").map(str::parse::<u64>),
+            ) else {
+                continue;
+            };
+            *lines.entry(number).or_default() += count;
+        }
+    }
+    Ok(hits)
+}
+
+fn summarize(hits: LineHits) -> Vec<FileCoverage> {
+    hits.into_iter()
+        .map(|(path, lines)| {
+            let uncovered_lines = lines
+                .iter()
+                .filter(|(_, count)| **count == 0)
+                .map(|(number, _)| *number)
+                .collect::<Vec<_>>();
+            FileCoverage {
+                path,
+                lines_found: lines.len(),
+                lines_hit: lines.len() - uncovered_lines.len(),
+                uncovered_lines,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    const LCOV: &str = "TN:
+SF:src/lib.rs
+DA:1,4
+DA:2,0
+DA:3,1
+end_of_record
+SF:src/main.rs
+DA:1,0
+end_of_record
+";
+
+    #[test]
+    fn test_parse_lcov() {
+        assert_eq!(
+            summarize(parse_lcov(LCOV)),
+            vec![
+                FileCoverage {
+                    path: "src/lib.rs".to_string(),
+                    lines_found: 3,
+                    lines_hit: 2,
+                    uncovered_lines: vec![2],
+                },
+                FileCoverage {
+                    path: "src/main.rs".to_string(),
+                    lines_found: 1,
+                    lines_hit: 0,
+                    uncovered_lines: vec![1],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cobertura() {
+        let report = r#"<?xml version="1.0" ?>
+<coverage line-rate="0.5">
+  <packages>
+    <package name="app">
+      <classes>
+        <class name="calc.py" filename="app/calc.py">
+          <methods>
+            <method name="add"><lines><line number="2" hits="1"/></lines></method>
+          </methods>
+          <lines>
+            <line number="1" hits="1"/>
+            <line number="2" hits="1"/>
+            <line number="4" hits="0"/>
+          </lines>
+        </class>
+      </classes>
+    </package>
+  </packages>
+</coverage>"#;
+
+        let files = summarize(parse_report(report).unwrap());
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "app/calc.py");
+        assert_eq!(files[0].lines_found, 3);
+        assert_eq!(files[0].lines_hit, 2);
+        assert_eq!(files[0].uncovered_lines, vec![4]);
+    }
+
+    #[tokio::test]
+    async fn test_collect_coverage() {
+        let controller = TestingController::new("coverage");
+        let config = CoverageConfig {
+            command: format!("printf '{}' > lcov.info; exit 1", LCOV.replace('\n', "\\n")),
+            report: "lcov.info".to_string(),
+        };
+
+        let report = collect_coverage(&controller, &config, None, None)
+            .await
+            .unwrap();
+        assert_eq!(report.exit_code, 1);
+        assert_eq!(report.lines_found, 4);
+        assert_eq!(report.lines_hit, 2);
+        assert_eq!(report.percent, 50.0);
+
+        let config = CoverageConfig {
+            command: "true".to_string(),
+            ..config
+        };
+        // The report of the earlier run is removed first
+        assert!(collect_coverage(&controller, &config, None, None)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::github::GithubSession;
+
+// Credentials are fetched again this long before they expire, so that one handed out is still
+// valid for the duration of a (long) git command
+const REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(10);
+
+// Credentials that do not say when they expire are fetched again after this long, so that
+// rotated secrets are picked up
+const MAX_AGE: TimeDelta = TimeDelta::minutes(15);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credential {
+    pub secret: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Credential {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at
+            .is_none_or(|expires_at| expires_at - REFRESH_MARGIN > now)
+    }
+}
+
+// Where a token or other secret comes from, like an environment variable or a secret store
+#[async_trait]
+pub trait CredentialProvider: Send + Sync + Debug {
+    async fn credential(&self) -> Result<Credential>;
+}
+
+// Secret stores a credential can be read from, as configured in contexts
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CredentialSource {
+    // Environment variable of the server
+    Env {
+        env: String,
+    },
+    // Field of a secret in Vault, at VAULT_ADDR (or `address`) with the token in VAULT_TOKEN. Both
+    // KV version 1 and 2 are supported.
+    Vault {
+        path: String,
+        field: String,
+        #[serde(default)]
+        address: Option<String>,
+    },
+    // Secret string of a secret in AWS Secrets Manager, or a field of it when it is JSON. Read with
+    // the aws cli and its usual credentials.
+    AwsSecretsManager {
+        secret_id: String,
+        #[serde(default)]
+        region: Option<String>,
+        #[serde(default)]
+        field: Option<String>,
+    },
+    // Output of a command, either the secret itself or JSON with `secret` and optionally
+    // `expires_at` (RFC3339)
+    Exec {
+        command: Vec<String>,
+    },
+}
+
+impl CredentialSource {
+    pub fn provider(&self) -> Box<dyn CredentialProvider> {
+        match self.clone() {
+            CredentialSource::Env { env } => Box::new(EnvCredential { env }),
+            CredentialSource::Vault {
+                path,
+                field,
+                address,
+            } => Box::new(VaultCredential {
+                path,
+                field,
+                address,
+            }),
+            CredentialSource::AwsSecretsManager {
+                secret_id,
+                region,
+                field,
+            } => {
+                let mut command = vec![
+                    "aws".to_string(),
+                    "secretsmanager".to_string(),
+                    "get-secret-value".to_string(),
+                    "--secret-id".to_string(),
+                    secret_id,
+                    "--query".to_string(),
+                    "SecretString".to_string(),
+                    "--output".to_string(),
+                    "text".to_string(),
+                ];
+                if let Some(region) = region {
+                    command.extend(["--region".to_string(), region]);
+                }
+                Box::new(ExecCredential { command, field })
+            }
+            CredentialSource::Exec { command } => Box::new(ExecCredential {
+                command,
+                field: None,
+            }),
+        }
+    }
+}
+
+// The provider of the source, shared by everything in the process that uses the same source so
+// that it is only fetched again when it is about to expire
+pub fn credential_provider(source: &CredentialSource) -> Arc<dyn CredentialProvider> {
+    static PROVIDERS: OnceLock<Mutex<HashMap<CredentialSource, Arc<dyn CredentialProvider>>>> =
+        OnceLock::new();
+    let mut providers = PROVIDERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    Arc::clone(
+        providers
+            .entry(source.clone())
+            .or_insert_with(|| Arc::new(CachedCredential::new(source.provider()))),
+    )
+}
+
+// Keeps the credential of another provider until it is about to expire
+#[derive(Debug)]
+pub struct CachedCredential {
+    provider: Box<dyn CredentialProvider>,
+    // With the time it was fetched. Held while fetching, so that it is fetched once at a time.
+    cached: tokio::sync::Mutex<Option<(Credential, DateTime<Utc>)>>,
+}
+
+impl CachedCredential {
+    pub fn new(provider: Box<dyn CredentialProvider>) -> Self {
+        Self {
+            provider,
+            cached: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for CachedCredential {
+    async fn credential(&self) -> Result<Credential> {
+        let mut cached = self.cached.lock().await;
+        let now = Utc::now();
+        if let Some((credential, fetched_at)) = &*cached {
+            if credential.is_fresh(now)
+                && (credential.expires_at.is_some() || *fetched_at + MAX_AGE > now)
+            {
+                return Ok(credential.clone());
+            }
+        }
+        let credential = self.provider.credential().await?;
+        *cached = Some((credential.clone(), now));
+        Ok(credential)
+    }
+}
+
+// A token given in code, e.g. by a program embedding derrick
+#[derive(Debug)]
+pub struct StaticCredential(pub String);
+
+#[async_trait]
+impl CredentialProvider for StaticCredential {
+    async fn credential(&self) -> Result<Credential> {
+        Ok(Credential {
+            secret: self.0.clone(),
+            expires_at: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct EnvCredential {
+    pub env: String,
+}
+
+#[async_trait]
+impl CredentialProvider for EnvCredential {
+    async fn credential(&self) -> Result<Credential> {
+        let secret = std::env::var(&self.env)
+            .with_context(|| format!("Could not find {} in environment", self.env))?;
+        Ok(Credential {
+            secret,
+            expires_at: None,
+        })
+    }
+}
+
+// Installation token of the configured Github app for a repository. The session keeps the
+// tokens until they are about to expire.
+#[derive(Debug)]
+pub struct GithubAppCredential {
+    session: Arc<GithubSession>,
+    repo_url: String,
+}
+
+impl GithubAppCredential {
+    pub fn new(session: Arc<GithubSession>, repo_url: impl Into<String>) -> Self {
+        Self {
+            session,
+            repo_url: repo_url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CredentialProvider for GithubAppCredential {
+    async fn credential(&self) -> Result<Credential> {
+        self.session.installation_credential(&self.repo_url).await
+    }
+}
+
+#[derive(Debug)]
+pub struct VaultCredential {
+    path: String,
+    field: String,
+    address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct VaultResponse {
+    data: serde_json::Value,
+    #[serde(default)]
+    lease_duration: i64,
+}
+
+#[async_trait]
+impl CredentialProvider for VaultCredential {
+    #[tracing::instrument(skip_all, fields(path = self.path))]
+    async fn credential(&self) -> Result<Credential> {
+        let address = match &self.address {
+            Some(address) => address.clone(),
+            None => {
+                std::env::var("VAULT_ADDR").context("Could not find VAULT_ADDR in environment")?
+            }
+        };
+        let token =
+            std::env::var("VAULT_TOKEN").context("Could not find VAULT_TOKEN in environment")?;
+        let url = format!(
+            "{}/v1/{}",
+            address.trim_end_matches('/'),
+            self.path.trim_start_matches('/')
+        );
+        let response = reqwest::Client::new()
+            .get(&url)
+            .header("X-Vault-Token", token)
+            .send()
+            .await
+            .with_context(|| format!("Could not reach Vault at {}", address))?;
+        if !response.status().is_success() {
+            anyhow::bail!("Vault could not read {} ({})", self.path, response.status())
+        }
+        let response: VaultResponse = response.json().await.context("Invalid Vault response")?;
+        let secret = vault_field(&response.data, &self.field)
+            .with_context(|| format!("Secret {} has no field {}", self.path, self.field))?;
+        Ok(Credential {
+            secret,
+            expires_at: (response.lease_duration > 0)
+                .then(|| Utc::now() + TimeDelta::seconds(response.lease_duration)),
+        })
+    }
+}
+
+// KV version 2 nests the fields of the secret in another `data`
+fn vault_field(data: &serde_json::Value, field: &str) -> Option<String> {
+    data.get("data")
+        .and_then(|data| data.get(field))
+        .or_else(|| data.get(field))
+        .and_then(|value| value.as_str())
+        .map(str::to_string)
+}
+
+#[derive(Debug)]
+pub struct ExecCredential {
+    command: Vec<String>,
+    // Field of the JSON object in the output that is the secret
+    field: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExecOutput {
+    secret: String,
+    #[serde(default)]
+    expires_at: Option<String>,
+}
+
+#[async_trait]
+impl CredentialProvider for ExecCredential {
+    #[tracing::instrument(skip_all, fields(command = ?self.command.first()))]
+    async fn credential(&self) -> Result<Credential> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .context("Credential helper has no command")?;
+        let output = tokio::process::Command::new(program)
+            .args(args)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("Could not run credential helper {}", program))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Credential helper {} failed: {}",
+                program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        }
+        parse_exec_output(&String::from_utf8(output.stdout)?, self.field.as_deref())
+    }
+}
+
+fn parse_exec_output(output: &str, field: Option<&str>) -> Result<Credential> {
+    let output = output.trim();
+    if let Some(field) = field {
+        let value: serde_json::Value =
+            serde_json::from_str(output).context("Secret is not a JSON object")?;
+        let secret = value
+            .get(field)
+            .and_then(|value| value.as_str())
+            .with_context(|| format!("Secret has no field {}", field))?;
+        return Ok(Credential {
+            secret: secret.to_string(),
+            expires_at: None,
+        });
+    }
+    match serde_json::from_str::<ExecOutput>(output) {
+        Ok(output) => Ok(Credential {
+            secret: output.secret,
+            expires_at: output
+                .expires_at
+                .map(|expires_at| DateTime::parse_from_rfc3339(&expires_at))
+                .transpose()
+                .context("Invalid expires_at of credential")?
+                .map(|expires_at| expires_at.with_timezone(&Utc)),
+        }),
+        Err(_) => Ok(Credential {
+            secret: output.to_string(),
+            expires_at: None,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Counting {
+        fetched: AtomicUsize,
+        expires_in: Option<TimeDelta>,
+    }
+
+    #[async_trait]
+    impl CredentialProvider for Arc<Counting> {
+        async fn credential(&self) -> Result<Credential> {
+            let fetched = self.fetched.fetch_add(1, Ordering::SeqCst);
+            Ok(Credential {
+                secret: format!("token-{}", fetched),
+                expires_at: self.expires_in.map(|expires_in| Utc::now() + expires_in),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_credential() {
+        let counting = Arc::new(Counting {
+            expires_in: Some(TimeDelta::hours(1)),
+            ..Default::default()
+        });
+        let cached = CachedCredential::new(Box::new(Arc::clone(&counting)));
+        assert_eq!(cached.credential().await.unwrap().secret, "token-0");
+        assert_eq!(cached.credential().await.unwrap().secret, "token-0");
+
+        // Credentials about to expire are rotated on every use
+        let counting = Arc::new(Counting {
+            expires_in: Some(TimeDelta::minutes(1)),
+            ..Default::default()
+        });
+        let cached = CachedCredential::new(Box::new(Arc::clone(&counting)));
+        assert_eq!(cached.credential().await.unwrap().secret, "token-0");
+        assert_eq!(cached.credential().await.unwrap().secret, "token-1");
+    }
+
+    #[tokio::test]
+    async fn test_exec_credential() {
+        let provider = CredentialSource::Exec {
+            command: vec!["echo".to_string(), "secret".to_string()],
+        }
+        .provider();
+        assert_eq!(provider.credential().await.unwrap().secret, "secret");
+
+        let provider = CredentialSource::Exec {
+            command: vec!["false".to_string()],
+        }
+        .provider();
+        assert!(provider.credential().await.is_err());
+    }
+
+    #[test]
+    fn test_parse_exec_output() {
+        let credential = parse_exec_output(
+            r#"{"secret": "abc", "expires_at": "2030-01-01T00:00:00Z"}"#,
+            None,
+        )
+        .unwrap();
+        assert_eq!(credential.secret, "abc");
+        assert_eq!(
+            credential.expires_at.unwrap().to_rfc3339(),
+            "2030-01-01T00:00:00+00:00"
+        );
+
+        assert_eq!(parse_exec_output("abc\n", None).unwrap().secret, "abc");
+        assert_eq!(
+            parse_exec_output(r#"{"token": "abc"}"#, Some("token"))
+                .unwrap()
+                .secret,
+            "abc"
+        );
+        assert!(parse_exec_output("abc", Some("token")).is_err());
+    }
+
+    #[test]
+    fn test_vault_field() {
+        let kv1 = serde_json::json!({"token": "abc"});
+        let kv2 = serde_json::json!({"data": {"token": "abc"}, "metadata": {"version": 3}});
+        assert_eq!(vault_field(&kv1, "token").unwrap(), "abc");
+        assert_eq!(vault_field(&kv2, "token").unwrap(), "abc");
+        assert!(vault_field(&kv2, "password").is_none());
+    }
+
+    #[test]
+    fn test_deserialize_source() {
+        let source: CredentialSource = serde_json::from_str(
+            r#"{"type": "vault", "path": "secret/data/git", "field": "token"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            source,
+            CredentialSource::Vault {
+                path: "secret/data/git".to_string(),
+                field: "token".to_string(),
+                address: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+
+use crate::workspace_controllers::git::try_output;
+use crate::WorkspaceController;
+
+// Pid, log and supervisor files of the daemons in the workspace
+const DAEMONS_DIR: &str = "/tmp/derrick-daemons";
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// Time a daemon gets to exit after its shutdown signal, before it is killed
+const SHUTDOWN_GRACE_SECS: u32 = 10;
+
+// A long running process started with every workspace, like a language server
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Daemon {
+    // Names the log file, /tmp/derrick-daemons/<name>.log in the workspace
+    pub name: String,
+    pub command: String,
+    // Succeeds once the daemon is ready, e.g. `curl -sf localhost:8080/health`. Creating the
+    // workspace waits for it.
+    #[serde(default)]
+    pub ready_check: Option<String>,
+    #[serde(default = "default_ready_timeout")]
+    pub ready_timeout: u64,
+    // Signal the daemon is stopped with when the workspace is destroyed
+    #[serde(default = "default_shutdown_signal")]
+    pub shutdown_signal: String,
+    // Starts the daemon again when it exits, with a backoff when it keeps crashing
+    #[serde(default = "default_restart")]
+    pub restart: bool,
+}
+
+fn default_ready_timeout() -> u64 {
+    60
+}
+
+fn default_shutdown_signal() -> String {
+    "TERM".to_string()
+}
+
+fn default_restart() -> bool {
+    true
+}
+
+impl Daemon {
+    // The name and signal end up in paths and commands
+    pub(crate) fn validate(&self) -> Result<()> {
+        let valid = |value: &str| {
+            !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if !valid(&self.name) {
+            anyhow::bail!("Invalid daemon name: {:?}", self.name);
+        }
+        if !valid(&self.shutdown_signal) {
+            anyhow::bail!("Invalid shutdown signal: {:?}", self.shutdown_signal);
+        }
+        Ok(())
+    }
+
+    fn path(&self, extension: &str) -> String {
+        format!("{}/{}.{}", DAEMONS_DIR, self.name, extension)
+    }
+
+    // Runs the command in a loop in the background. The pid of the current run is kept in a file,
+    // and a stop file ends the loop. Each run gets its own process group, so that stopping it
+    // also stops the processes it started.
+    fn supervisor(&self) -> String {
+        let restart = if self.restart {
+            format!(
+                r#"  [ $(( $(date +%s) - started )) -ge 30 ] && delay=1
+  echo "[derrick] {name} exited with $code, restarting in ${{delay}}s" >> {log}
+  sleep $delay
+  [ $delay -lt 30 ] && delay=$((delay * 2))"#,
+                name = self.name,
+                log = self.path("log"),
+            )
+        } else {
+            format!(
+                r#"  echo "[derrick] {name} exited with $code" >> {log}
+  exit 0"#,
+                name = self.name,
+                log = self.path("log"),
+            )
+        };
+
+        format!(
+            r#"if command -v setsid > /dev/null 2>&1; then detach=setsid; else detach=; set -m; fi
+delay=1
+while :; do
+  started=$(date +%s)
+  $detach "${{BASH:-sh}}" {script} >> {log} 2>&1 &
+  echo $! > {pid}
+  wait $!
+  code=$?
+  [ -e {stop} ] && exit 0
+{restart}
+done
+"#,
+            script = self.path("sh"),
+            log = self.path("log"),
+            pid = self.path("pid"),
+            stop = self.path("stop"),
+            restart = restart,
+        )
+    }
+
+    fn start_cmd(&self) -> String {
+        format!(
+            r#"mkdir -p {dir} && rm -f {stop}
+printf '%s\n' {command} > {script}
+printf '%s' {supervisor} > {supervisor_path}
+nohup "${{BASH:-sh}}" {supervisor_path} > /dev/null 2>&1 &"#,
+            dir = DAEMONS_DIR,
+            stop = self.path("stop"),
+            command = escape(self.command.as_str().into()),
+            script = self.path("sh"),
+            supervisor = escape(self.supervisor().into()),
+            supervisor_path = self.path("supervisor"),
+        )
+    }
+
+    fn stop_cmd(&self) -> String {
+        format!(
+            r#"touch {stop}
+pid=$(cat {pid} 2>/dev/null) || exit 0
+kill -{signal} -- "-$pid" 2>/dev/null || kill -{signal} "$pid" 2>/dev/null
+i=0
+while kill -0 "$pid" 2>/dev/null && [ $i -lt {grace} ]; do sleep 0.5; i=$((i + 1)); done
+kill -9 -- "-$pid" 2>/dev/null || kill -9 "$pid" 2>/dev/null
+true"#,
+            stop = self.path("stop"),
+            pid = self.path("pid"),
+            signal = self.shutdown_signal,
+            grace = SHUTDOWN_GRACE_SECS * 2,
+        )
+    }
+}
+
+// Starts the daemons and waits until they are ready
+pub(crate) async fn start_daemons(
+    controller: &dyn WorkspaceController,
+    daemons: &[Daemon],
+    env: HashMap<String, String>,
+) -> Result<()> {
+    for daemon in daemons {
+        daemon.validate()?;
+        controller
+            .cmd(&daemon.start_cmd(), None, env.clone(), None)
+            .await
+            .with_context(|| format!("Could not start daemon {}", daemon.name))?;
+    }
+
+    for daemon in daemons {
+        let Some(ready_check) = &daemon.ready_check else {
+            continue;
+        };
+        let deadline = Instant::now() + Duration::from_secs(daemon.ready_timeout);
+        while try_output(controller, ready_check).await.is_none() {
+            if Instant::now() > deadline {
+                let log = try_output(controller, &format!("tail -c 2048 {}", daemon.path("log")))
+                    .await
+                    .unwrap_or_default();
+                anyhow::bail!(
+                    "Daemon {} was not ready after {}s: {}",
+                    daemon.name,
+                    daemon.ready_timeout,
+                    log
+                );
+            }
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+    Ok(())
+}
+
+// Stops the daemons with their shutdown signal, killing the ones that do not exit in time
+pub(crate) async fn stop_daemons(
+    controller: &dyn WorkspaceController,
+    daemons: &[Daemon],
+) -> Result<()> {
+    for daemon in daemons {
+        daemon.validate()?;
+        controller
+            .cmd(&daemon.stop_cmd(), None, HashMap::new(), None)
+            .await
+            .with_context(|| format!("Could not stop daemon {}", daemon.name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    fn daemon(name: &str, command: &str) -> Daemon {
+        serde_json::from_value(serde_json::json!({ "name": name, "command": command })).unwrap()
+    }
+
+    async fn pid(controller: &TestingController, daemon: &Daemon) -> String {
+        try_output(controller, &format!("cat {}", daemon.path("pid")))
+            .await
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_rejects_names_used_in_paths() {
+        assert!(daemon("../lsp", "true").validate().is_err());
+        assert!(daemon("rust-analyzer", "true").validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_restarts_crashed_daemon_until_stopped() {
+        let controller = TestingController::new("daemons");
+        let name = format!("test-{}", uuid::Uuid::new_v4());
+        let mut crashing = daemon(&name, "echo started; sleep 30");
+        crashing.ready_check = Some(format!("grep -q started {}", crashing.path("log")));
+
+        start_daemons(&controller, &[crashing.clone()], HashMap::new())
+            .await
+            .unwrap();
+        let first = pid(&controller, &crashing).await;
+        assert!(!first.is_empty());
+
+        controller
+            .cmd(
+                &format!("kill -9 -- -{}", first.trim()),
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let second = pid(&controller, &crashing).await;
+        assert_ne!(first, second);
+
+        stop_daemons(&controller, &[crashing.clone()])
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(
+            try_output(&controller, &format!("kill -0 {}", second.trim()))
+                .await
+                .is_none()
+        );
+        assert_eq!(pid(&controller, &crashing).await, second);
+    }
+
+    #[tokio::test]
+    async fn test_fails_when_not_ready() {
+        let controller = TestingController::new("daemons-not-ready");
+        let name = format!("test-{}", uuid::Uuid::new_v4());
+        let mut never_ready = daemon(&name, "echo starting; sleep 30");
+        never_ready.ready_check = Some("false".to_string());
+        never_ready.ready_timeout = 1;
+
+        let error = start_daemons(&controller, &[never_ready.clone()], HashMap::new())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("starting"));
+        stop_daemons(&controller, &[never_ready]).await.unwrap();
+    }
+}
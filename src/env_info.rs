@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::WorkspaceController;
+
+// Toolchains whose versions are reported, with the flag that prints the version
+const TOOLCHAINS: [(&str, &str); 8] = [
+    ("rustc", "--version"),
+    ("cargo", "--version"),
+    ("node", "--version"),
+    ("npm", "--version"),
+    ("python3", "--version"),
+    ("python", "--version"),
+    ("java", "-version"),
+    ("go", "version"),
+];
+
+#[derive(Debug, Clone, Serialize, JsonSchema, PartialEq)]
+pub struct Toolchain {
+    pub name: String,
+    // Where the executable was found on the PATH
+    pub path: String,
+    // Parsed from the version output, e.g. `1.82.0`
+    pub version: Option<String>,
+    // First line the executable printed for its version
+    pub version_output: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, PartialEq)]
+pub struct EnvInfo {
+    // Kernel and architecture as reported by uname, e.g. `Linux` and `x86_64`
+    pub os: String,
+    pub arch: String,
+    // Pretty name from /etc/os-release, e.g. `Debian GNU/Linux 12 (bookworm)`
+    pub distribution: Option<String>,
+    pub cpus: Option<usize>,
+    pub memory_total_bytes: Option<u64>,
+    pub memory_available_bytes: Option<u64>,
+    pub path: Vec<String>,
+    // Only the toolchains that are installed
+    pub toolchains: Vec<Toolchain>,
+}
+
+// Prints `key<TAB>value` lines. Everything is optional, as images differ in what they have.
+fn probe_script() -> String {
+    let mut script = String::from(
+        r#"printf 'os\t%s\n' "$(uname -s)"
+printf 'arch\t%s\n' "$(uname -m)"
+[ -r /etc/os-release ] && printf 'distribution\t%s\n' "$(. /etc/os-release && echo "$PRETTY_NAME")"
+printf 'cpus\t%s\n' "$(nproc 2>/dev/null || getconf _NPROCESSORS_ONLN 2>/dev/null)"
+[ -r /proc/meminfo ] && awk '/^MemTotal:/ { printf "memory_total\t%.0f\n", $2 * 1024 } /^MemAvailable:/ { printf "memory_available\t%.0f\n", $2 * 1024 }' /proc/meminfo
+printf 'path\t%s\n' "$PATH"
+"#,
+    );
+    for (name, flag) in TOOLCHAINS {
+        script.push_str(&format!(
+            "bin=$(command -v {name}) && printf 'toolchain\\t{name}\\t%s\\t%s\\n' \"$bin\" \"$({name} {flag} 2>&1 | head -n 1)\"\n",
+            name = name,
+            flag = flag
+        ));
+    }
+    // The last check may have failed, which is not a failure of the probe
+    script.push_str("true\n");
+    script
+}
+
+pub(crate) async fn env_info(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+) -> Result<EnvInfo> {
+    let output = controller
+        .cmd_with_output(&probe_script(), working_dir, HashMap::new(), None)
+        .await?;
+    Ok(parse_probe(&output.output))
+}
+
+fn parse_probe(output: &str) -> EnvInfo {
+    let version = regex::Regex::new(r"\d+\.\d+(\.\d+)?").unwrap();
+    let mut info = EnvInfo {
+        os: String::new(),
+        arch: String::new(),
+        distribution: None,
+        cpus: None,
+        memory_total_bytes: None,
+        memory_available_bytes: None,
+        path: Vec::new(),
+        toolchains: Vec::new(),
+    };
+
+    for line in output.lines() {
+        let mut fields = line.split('\t');
+        let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "os" => info.os = value.to_string(),
+            "arch" => info.arch = value.to_string(),
+            "distribution" if !value.is_empty() => info.distribution = Some(value.to_string()),
+            "cpus" => info.cpus = value.parse().ok(),
+            "memory_total" => info.memory_total_bytes = value.parse().ok(),
+            "memory_available" => info.memory_available_bytes = value.parse().ok(),
+            "path" => {
+                info.path = value
+                    .split(':')
+                    .filter(|dir| !dir.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "toolchain" => {
+                let (Some(path), Some(output)) = (fields.next(), fields.next()) else {
+                    continue;
+                };
+                info.toolchains.push(Toolchain {
+                    name: value.to_string(),
+                    path: path.to_string(),
+                    version: version.find(output).map(|m| m.as_str().to_string()),
+                    version_output: output.trim().to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_parse_probe() {
+        let output = "os\tLinux\narch\taarch64\ndistribution\tDebian GNU/Linux 12 (bookworm)\ncpus\t8\nmemory_total\t16777216000\npath\t/usr/local/cargo/bin:/usr/bin\ntoolchain\trustc\t/usr/local/cargo/bin/rustc\trustc 1.82.0 (f6e511eec 2024-10-15)\ntoolchain\tjava\t/usr/bin/java\topenjdk version \"17.0.2\" 2022-01-18\n";
+
+        let info = parse_probe(output);
+        assert_eq!(info.os, "Linux");
+        assert_eq!(info.arch, "aarch64");
+        assert_eq!(info.cpus, Some(8));
+        assert_eq!(info.memory_total_bytes, Some(16777216000));
+        assert_eq!(info.memory_available_bytes, None);
+        assert_eq!(info.path, vec!["/usr/local/cargo/bin", "/usr/bin"]);
+        assert_eq!(info.toolchains[0].version.as_deref(), Some("1.82.0"));
+        assert_eq!(info.toolchains[1].name, "java");
+        assert_eq!(info.toolchains[1].version.as_deref(), Some("17.0.2"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_runs_in_workspace() {
+        let controller = TestingController::new("env-info");
+        let info = env_info(&controller, None).await.unwrap();
+        assert!(!info.os.is_empty());
+        assert!(info.cpus.is_some_and(|cpus| cpus > 0));
+    }
+}
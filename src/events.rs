@@ -0,0 +1,135 @@
+use serde::Serialize;
+
+use crate::credentials::scrub;
+use crate::usage::{DiskUsage, QuotaAction};
+use crate::webhooks::GithubEvent;
+use crate::workspace_controllers::Health;
+
+// Output in command events is cut to its end beyond this many bytes
+const MAX_COMMAND_OUTPUT: usize = 64 * 1024;
+
+// Events published by the server. Subscribe through `Server::subscribe` to react to them, for
+// example to create a workspace when an issue is labeled.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Github(GithubEvent),
+    // A workspace that finished provisioning
+    WorkspaceReady {
+        workspace_id: String,
+    },
+    WorkspaceFailed {
+        workspace_id: String,
+        error: String,
+    },
+    // A line printed by a setup step or script while the workspace is provisioned
+    ProvisioningOutput {
+        workspace_id: String,
+        line: String,
+    },
+    WorkspaceDestroyed {
+        workspace_id: String,
+    },
+    // The health check of a workspace started passing or failing
+    WorkspaceHealthChanged {
+        workspace_id: String,
+        health: Health,
+    },
+    DiskQuotaExceeded {
+        workspace_id: String,
+        usage: DiskUsage,
+        action: QuotaAction,
+    },
+    // A command run through the API, published when it starts and when it finishes
+    CommandStarted {
+        workspace_id: String,
+        cmd: String,
+    },
+    CommandFinished {
+        workspace_id: String,
+        cmd: String,
+        // Not set when the command could not be run, the output is the error then
+        exit_code: Option<i32>,
+        output: String,
+    },
+}
+
+impl Event {
+    // The workspace of events that are delivered to the callback the workspace was created with
+    pub(crate) fn callback_workspace(&self) -> Option<&str> {
+        match self {
+            Event::WorkspaceReady { workspace_id }
+            | Event::WorkspaceFailed { workspace_id, .. }
+            | Event::WorkspaceDestroyed { workspace_id }
+            | Event::CommandFinished { workspace_id, .. } => Some(workspace_id),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn workspace_failed(workspace_id: &str, error: &anyhow::Error) -> Event {
+        Event::WorkspaceFailed {
+            workspace_id: workspace_id.to_string(),
+            error: scrub(&format!("{:#}", error)),
+        }
+    }
+
+    pub(crate) fn command_started(workspace_id: &str, cmd: &str) -> Event {
+        Event::CommandStarted {
+            workspace_id: workspace_id.to_string(),
+            cmd: scrub(cmd),
+        }
+    }
+
+    pub(crate) fn command_finished(
+        workspace_id: &str,
+        cmd: &str,
+        exit_code: Option<i32>,
+        output: &str,
+    ) -> Event {
+        let mut start = output.len().saturating_sub(MAX_COMMAND_OUTPUT);
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        Event::CommandFinished {
+            workspace_id: workspace_id.to_string(),
+            cmd: scrub(cmd),
+            exit_code,
+            output: scrub(&output[start..]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_output_is_cut_to_its_end() {
+        let output = format!("{}é{}", "a".repeat(10), "b".repeat(MAX_COMMAND_OUTPUT - 1));
+        let Event::CommandFinished { output, .. } = Event::command_finished(
+            "ws",
+            "git clone x-access-token:123@github.com",
+            Some(0),
+            &output,
+        ) else {
+            unreachable!()
+        };
+        assert_eq!(output, "b".repeat(MAX_COMMAND_OUTPUT - 1));
+    }
+
+    #[test]
+    fn test_callback_workspace() {
+        let ready = Event::WorkspaceReady {
+            workspace_id: "ws".to_string(),
+        };
+        assert_eq!(ready.callback_workspace(), Some("ws"));
+        assert_eq!(
+            Event::command_finished("ws", "make", Some(0), "").callback_workspace(),
+            Some("ws")
+        );
+        assert_eq!(
+            Event::command_started("ws", "make").callback_workspace(),
+            None
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::Serialize;
+use shell_escape::escape;
+
+use crate::workspace_controllers::FileNotFound;
+use crate::WorkspaceController;
+
+// Larger trees are cut off, agents should narrow them down with a path, depth or glob
+const MAX_TREE_ENTRIES: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct TreeEntry {
+    pub name: String,
+    // Relative to the directory of the tree
+    pub path: String,
+    pub kind: EntryKind,
+    // Empty for files, and for directories deeper than the depth of the tree
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TreeEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct FileTree {
+    pub path: String,
+    // Directories first, both sorted by name
+    pub entries: Vec<TreeEntry>,
+    // Set when the tree had more entries than are returned
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TreeOptions {
+    // Levels of the tree, 1 only lists the entries of the directory. Unlimited by default.
+    pub depth: Option<usize>,
+    // Only files that match, with `*`, `?` and `**`. Patterns without a slash match file names,
+    // like `*.rs`, others the path relative to the directory of the tree, like `src/**/*.rs`.
+    // Directories are only listed when they contain a match.
+    pub glob: Option<String>,
+    // Leaves out what git ignores, in directories that are in a git repository
+    pub gitignore: bool,
+}
+
+// Lists the directory (relative to the working directory) as a tree. The .git directory is never
+// part of it.
+#[tracing::instrument(skip(controller))]
+pub(crate) async fn file_tree(
+    controller: &dyn WorkspaceController,
+    path: &str,
+    options: &TreeOptions,
+    working_dir: Option<&str>,
+) -> Result<FileTree> {
+    let depth = options.depth.unwrap_or(usize::MAX);
+    let mut root = Node::default();
+    let mut count = 0;
+    let mut truncated = false;
+    for (entry, is_dir) in list_entries(controller, path, options, working_dir).await? {
+        if count == MAX_TREE_ENTRIES {
+            truncated = true;
+            break;
+        }
+        count += 1;
+        root.insert(&entry.split('/').collect::<Vec<_>>(), is_dir, depth);
+    }
+
+    Ok(FileTree {
+        path: path.to_string(),
+        entries: root.entries(""),
+        truncated,
+    })
+}
+
+// The files and directories in the directory that the options select, relative to it, with
+// whether they are directories. Only files are listed with a glob, and with gitignore in git
+// repositories.
+pub(crate) async fn list_entries(
+    controller: &dyn WorkspaceController,
+    path: &str,
+    options: &TreeOptions,
+    working_dir: Option<&str>,
+) -> Result<Vec<(String, bool)>> {
+    let glob = options.glob.as_deref().map(glob_regex).transpose()?;
+    let matches_path = options
+        .glob
+        .as_deref()
+        .is_some_and(|glob| glob.contains('/'));
+    let max_depth = match options.depth {
+        Some(depth) => format!("-maxdepth {}", depth),
+        None => String::new(),
+    };
+    let cmd = format!(
+        r#"[ -d {path} ] || exit 3
+cd {path} || exit 1
+if [ {gitignore} = 1 ] && git rev-parse --is-inside-work-tree >/dev/null 2>&1; then
+  git -c core.quotePath=false ls-files --cached --others --exclude-standard | sed 's/^/f /'
+else
+  find . -mindepth 1 {max_depth} -name .git -prune -o -type d -print | sed 's/^/d /'
+  find . -mindepth 1 {max_depth} -name .git -prune -o ! -type d -print | sed 's/^/f /'
+fi"#,
+        path = escape(path.into()),
+        gitignore = options.gitignore as u8,
+        max_depth = max_depth,
+    );
+    let output = controller
+        .cmd_with_output(&cmd, working_dir, HashMap::new(), None)
+        .await
+        .context("Could not list files")?;
+    match output.exit_code {
+        0 => {}
+        3 => {
+            return Err(FileNotFound {
+                path: path.to_string(),
+            }
+            .into())
+        }
+        _ => anyhow::bail!("Could not list files: {}", output.output.trim()),
+    }
+
+    let mut entries = Vec::new();
+    for line in output.output.lines() {
+        let Some((kind, entry)) = line.split_once(' ') else {
+            continue;
+        };
+        let entry = entry.strip_prefix("./").unwrap_or(entry);
+        let is_dir = kind == "d";
+        if entry.is_empty() || (glob.is_some() && is_dir) {
+            continue;
+        }
+        if let Some(glob) = &glob {
+            let name = entry.rsplit('/').next().unwrap_or(entry);
+            let subject = if matches_path { entry } else { name };
+            if !glob.is_match(subject) {
+                continue;
+            }
+        }
+        entries.push((entry.to_string(), is_dir));
+    }
+    Ok(entries)
+}
+
+#[derive(Default)]
+struct Node {
+    dirs: BTreeMap<String, Node>,
+    files: BTreeSet<String>,
+}
+
+impl Node {
+    // Directories of files deeper than the depth are listed without them
+    fn insert(&mut self, components: &[&str], is_dir: bool, depth: usize) {
+        if depth == 0 {
+            return;
+        }
+        match components {
+            [] => {}
+            [name] if !is_dir => {
+                self.files.insert(name.to_string());
+            }
+            [name, rest @ ..] => {
+                self.dirs
+                    .entry(name.to_string())
+                    .or_default()
+                    .insert(rest, is_dir, depth - 1);
+            }
+        }
+    }
+
+    fn entries(self, prefix: &str) -> Vec<TreeEntry> {
+        let path = |name: &str| match prefix {
+            "" => name.to_string(),
+            _ => format!("{}/{}", prefix, name),
+        };
+        let dirs = self.dirs.into_iter().map(|(name, node)| {
+            let path = path(&name);
+            TreeEntry {
+                children: node.entries(&path),
+                name,
+                path,
+                kind: EntryKind::Directory,
+            }
+        });
+        let files = self.files.into_iter().map(|name| TreeEntry {
+            path: path(&name),
+            name,
+            kind: EntryKind::File,
+            children: Vec::new(),
+        });
+        dirs.chain(files).collect()
+    }
+}
+
+// `**/` matches any number of directories, `*` and `?` anything but a slash
+pub(crate) fn glob_regex(glob: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).with_context(|| format!("Invalid glob {}", glob))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    fn paths(entries: &[TreeEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .flat_map(|entry| std::iter::once(entry.path.clone()).chain(paths(&entry.children)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_file_tree() {
+        let controller = TestingController::new("file-tree");
+        controller
+            .cmd(
+                "git init -q . && mkdir -p src/bin target/debug && touch README.md src/lib.rs src/bin/main.rs target/debug/app && echo target > .gitignore",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let tree = file_tree(&controller, ".", &TreeOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            paths(&tree.entries),
+            vec![
+                "src",
+                "src/bin",
+                "src/bin/main.rs",
+                "src/lib.rs",
+                "target",
+                "target/debug",
+                "target/debug/app",
+                ".gitignore",
+                "README.md",
+            ]
+        );
+        assert!(!tree.truncated);
+
+        let options = TreeOptions {
+            depth: Some(2),
+            gitignore: true,
+            ..Default::default()
+        };
+        let tree = file_tree(&controller, ".", &options, None).await.unwrap();
+        assert_eq!(
+            paths(&tree.entries),
+            vec!["src", "src/bin", "src/lib.rs", ".gitignore", "README.md"]
+        );
+
+        let options = TreeOptions {
+            glob: Some("*.rs".to_string()),
+            ..Default::default()
+        };
+        let tree = file_tree(&controller, "src", &options, None).await.unwrap();
+        assert_eq!(paths(&tree.entries), vec!["bin", "bin/main.rs", "lib.rs"]);
+
+        let error = file_tree(&controller, "missing", &TreeOptions::default(), None)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<FileNotFound>().is_some());
+    }
+
+    #[test]
+    fn test_glob_regex() {
+        let glob = glob_regex("src/**/*.rs").unwrap();
+        assert!(glob.is_match("src/lib.rs"));
+        assert!(glob.is_match("src/bin/main.rs"));
+        assert!(!glob.is_match("tests/it.rs"));
+        assert!(!glob.is_match("src/lib.rsx"));
+
+        let glob = glob_regex("?.txt").unwrap();
+        assert!(glob.is_match("a.txt"));
+        assert!(!glob.is_match("ab.txt"));
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::WorkspaceController;
+
+// Parsed `git status --porcelain=v2 --branch`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct GitStatus {
+    // None when HEAD is detached
+    pub branch: Option<String>,
+    // None before the first commit
+    pub commit: Option<String>,
+    pub upstream: Option<String>,
+    // Commits ahead of and behind the upstream, zero without one
+    pub ahead: u32,
+    pub behind: u32,
+    pub staged: Vec<StatusEntry>,
+    pub unstaged: Vec<StatusEntry>,
+    pub untracked: Vec<String>,
+    // Paths with unresolved merge conflicts
+    pub conflicted: Vec<String>,
+}
+
+impl GitStatus {
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty()
+            && self.unstaged.is_empty()
+            && self.untracked.is_empty()
+            && self.conflicted.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct StatusEntry {
+    pub path: String,
+    pub change: FileChange,
+    // Path before a rename or copy
+    pub original_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FileChange {
+    Added,
+    Modified,
+    Deleted,
+    Renamed,
+    Copied,
+    TypeChanged,
+}
+
+impl FileChange {
+    // Status letter of one side of the XY field, `.` means unchanged
+    fn from_code(code: char) -> Option<Self> {
+        match code {
+            'A' => Some(Self::Added),
+            'M' => Some(Self::Modified),
+            'D' => Some(Self::Deleted),
+            'R' => Some(Self::Renamed),
+            'C' => Some(Self::Copied),
+            'T' => Some(Self::TypeChanged),
+            _ => None,
+        }
+    }
+}
+
+// Status of the repository in `working_dir`, or the default directory of the controller
+pub(crate) async fn git_status(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+) -> Result<GitStatus> {
+    let output = controller
+        .cmd_with_output(
+            "git status --porcelain=v2 --branch -z",
+            working_dir,
+            HashMap::new(),
+            None,
+        )
+        .await?;
+    if output.exit_code != 0 {
+        anyhow::bail!("Failed to get git status: {}", output.output);
+    }
+    parse_porcelain_v2(&output.output)
+}
+
+// Parses the NUL separated output of `git status --porcelain=v2 --branch -z`
+pub(crate) fn parse_porcelain_v2(output: &str) -> Result<GitStatus> {
+    let mut status = GitStatus::default();
+    let mut records = output.split('\0').filter(|record| !record.is_empty());
+
+    while let Some(record) = records.next() {
+        let (kind, rest) = record.split_once(' ').unwrap_or((record, ""));
+        match kind {
+            "#" => parse_header(&mut status, rest)?,
+            "1" => {
+                let fields: Vec<&str> = rest.splitn(8, ' ').collect();
+                let [xy, _, _, _, _, _, _, path] = fields[..] else {
+                    anyhow::bail!("Invalid status entry: {}", record);
+                };
+                push_entries(&mut status, xy, path, None);
+            }
+            "2" => {
+                let fields: Vec<&str> = rest.splitn(9, ' ').collect();
+                let [xy, _, _, _, _, _, _, _, path] = fields[..] else {
+                    anyhow::bail!("Invalid status entry: {}", record);
+                };
+                // The original path is the next record
+                let original_path = records
+                    .next()
+                    .with_context(|| format!("Missing original path for {}", path))?;
+                push_entries(&mut status, xy, path, Some(original_path));
+            }
+            "u" => {
+                let path = rest
+                    .splitn(10, ' ')
+                    .nth(9)
+                    .with_context(|| format!("Invalid status entry: {}", record))?;
+                status.conflicted.push(path.to_string());
+            }
+            "?" => status.untracked.push(rest.to_string()),
+            // Ignored files are only listed with --ignored
+            "!" => {}
+            _ => anyhow::bail!("Unknown status entry: {}", record),
+        }
+    }
+
+    Ok(status)
+}
+
+fn parse_header(status: &mut GitStatus, header: &str) -> Result<()> {
+    let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+    match key {
+        "branch.oid" if value != "(initial)" => status.commit = Some(value.to_string()),
+        "branch.head" if value != "(detached)" => status.branch = Some(value.to_string()),
+        "branch.upstream" => status.upstream = Some(value.to_string()),
+        "branch.ab" => {
+            let (ahead, behind) = value
+                .split_once(' ')
+                .with_context(|| format!("Invalid ahead/behind header: {}", value))?;
+            status.ahead = ahead.trim_start_matches('+').parse()?;
+            status.behind = behind.trim_start_matches('-').parse()?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn push_entries(status: &mut GitStatus, xy: &str, path: &str, original_path: Option<&str>) {
+    let mut codes = xy.chars();
+    let entry = |change| StatusEntry {
+        path: path.to_string(),
+        change,
+        original_path: original_path.map(str::to_string),
+    };
+
+    if let Some(change) = codes.next().and_then(FileChange::from_code) {
+        status.staged.push(entry(change));
+    }
+    if let Some(change) = codes.next().and_then(FileChange::from_code) {
+        status.unstaged.push(entry(change));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_porcelain_v2() {
+        let output = [
+            "# branch.oid 2a4f3c1e9b7d",
+            "# branch.head feature",
+            "# branch.upstream origin/feature",
+            "# branch.ab +2 -1",
+            "1 M. N... 100644 100644 100644 aaaa bbbb src/lib.rs",
+            "1 .M N... 100644 100644 100644 aaaa aaaa README.md",
+            "1 AM N... 000000 100644 100644 0000 cccc src/with space.rs",
+            "2 R. N... 100644 100644 100644 dddd dddd R100 src/new.rs",
+            "src/old.rs",
+            "u UU N... 100644 100644 100644 100644 eeee ffff 1111 Cargo.toml",
+            "? notes.txt",
+            "",
+        ]
+        .join("\0");
+
+        let status = parse_porcelain_v2(&output).unwrap();
+        assert_eq!(status.branch.as_deref(), Some("feature"));
+        assert_eq!(status.commit.as_deref(), Some("2a4f3c1e9b7d"));
+        assert_eq!(status.upstream.as_deref(), Some("origin/feature"));
+        assert_eq!((status.ahead, status.behind), (2, 1));
+
+        let staged: Vec<_> = status
+            .staged
+            .iter()
+            .map(|e| (e.path.as_str(), e.change))
+            .collect();
+        assert_eq!(
+            staged,
+            vec![
+                ("src/lib.rs", FileChange::Modified),
+                ("src/with space.rs", FileChange::Added),
+                ("src/new.rs", FileChange::Renamed),
+            ]
+        );
+        assert_eq!(
+            status.staged[2].original_path.as_deref(),
+            Some("src/old.rs")
+        );
+
+        let unstaged: Vec<_> = status.unstaged.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(unstaged, vec!["README.md", "src/with space.rs"]);
+        assert_eq!(status.untracked, vec!["notes.txt"]);
+        assert_eq!(status.conflicted, vec!["Cargo.toml"]);
+        assert!(!status.is_clean());
+    }
+
+    #[test]
+    fn test_parse_porcelain_v2_initial_detached() {
+        let output = "# branch.oid (initial)\0# branch.head (detached)\0";
+        let status = parse_porcelain_v2(output).unwrap();
+        assert_eq!(status.commit, None);
+        assert_eq!(status.branch, None);
+        assert!(status.is_clean());
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
 use tokio::sync::RwLock;
 
 use anyhow::{Context, Result};
@@ -5,37 +8,66 @@ use base64::prelude::*;
 use itertools::Itertools;
 use jsonwebtoken::EncodingKey;
 use octocrab::models::issues::{Comment, Issue};
-use octocrab::models::pulls::PullRequest;
+use octocrab::models::pulls::{Comment as ReviewCommentResponse, PullRequest, Review};
+use octocrab::models::repos::DiffEntry;
 use octocrab::models::{Installation, InstallationId};
+use octocrab::params::State;
 use octocrab::Octocrab;
 use octocrab::{models::InstallationToken, params::apps::CreateInstallationAccessToken};
-use url::Url;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::credential_providers::Credential;
 
-fn generate_jwt_key() -> Result<EncodingKey> {
-    let mut app_private_key = std::env::var("GITHUB_PRIVATE_KEY").context(
-        "Could not find GITHUB_PRIVATE_KEY in environment. Make sure to set it in the .env file",
+fn generate_jwt_key(config: &Config) -> Result<EncodingKey> {
+    let app_private_key = config.github_private_key.as_deref().context(
+        "Could not find GITHUB_PRIVATE_KEY in config. Make sure to set it in the .env file",
     )?;
-    app_private_key = String::from_utf8(BASE64_STANDARD.decode(app_private_key)?)?;
+    let app_private_key = String::from_utf8(BASE64_STANDARD.decode(app_private_key)?)?;
 
     jsonwebtoken::EncodingKey::from_rsa_pem(app_private_key.as_bytes())
         .context("Could not generate jwt token")
 }
 
-fn extract_owner_and_repo(repo_url: &str) -> Result<(String, String)> {
-    let url = url::Url::parse(repo_url)?;
-    if let Some((owner, repo)) = url.path_segments().and_then(|s| s.take(2).collect_tuple()) {
+// Self-hosted servers like Gitea can be served from a subpath, so outside of Github the owner and
+// repository are the end of the path rather than the start
+pub(crate) fn extract_owner_and_repo(repo_url: &str) -> Result<(String, String)> {
+    let (host, path) = match url::Url::parse(repo_url) {
+        Ok(url) => (
+            url.host_str().unwrap_or_default().to_string(),
+            url.path().to_string(),
+        ),
+        // scp-like ssh urls, e.g. git@github.com:owner/repo.git
+        Err(_) => repo_url
+            .split_once(':')
+            .map(|(host, path)| {
+                let host = host.rsplit('@').next().unwrap_or(host);
+                (host.to_string(), path.to_string())
+            })
+            .context("Could not parse repository url")?,
+    };
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let owner_and_repo = if host == "github.com" {
+        segments.iter().take(2).collect_tuple()
+    } else {
+        segments.iter().rev().take(2).rev().collect_tuple()
+    };
+    if let Some((owner, repo)) = owner_and_repo {
         Ok((owner.to_string(), repo.trim_end_matches(".git").to_string()))
     } else {
         anyhow::bail!("Could not extract owner and repo from url")
     }
 }
 
-fn get_octocrab() -> Result<Octocrab> {
+fn get_octocrab(config: &Config) -> Result<Octocrab> {
     if cfg!(feature = "integration_testing") {
-        let key = generate_jwt_key()?;
+        let key = generate_jwt_key(config)?;
         return Octocrab::builder()
             .base_uri(
-                crate::config()
+                config
                     .github_endpoint
                     .clone()
                     .expect("Need GITHUB_ENDPOINT during integration tests"),
@@ -44,9 +76,9 @@ fn get_octocrab() -> Result<Octocrab> {
             .build()
             .context("Failed to build octocrab");
     }
-    let jwt = generate_jwt_key()?;
+    let jwt = generate_jwt_key(config)?;
 
-    let app_id = crate::config()
+    let app_id = config
         .github_app_id
         .ok_or_else(|| anyhow::anyhow!("GITHUB_APP_ID not set"))?
         .into();
@@ -57,17 +89,76 @@ fn get_octocrab() -> Result<Octocrab> {
         .context("Failed to build octocrab")
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ReviewEvent {
+    Approve,
+    RequestChanges,
+    Comment,
+}
+
+// A comment on a line of the diff of a pull request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReviewComment {
+    pub path: String,
+    pub line: u64,
+    // LEFT for deleted lines, RIGHT (the default) for added or unchanged lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub side: Option<String>,
+    pub body: String,
+}
+
+// Filters for listing issues, unset fields are not filtered on
+#[derive(Debug, Clone, Default)]
+pub struct IssueFilter {
+    pub state: Option<State>,
+    pub labels: Vec<String>,
+    pub assignee: Option<String>,
+}
+
+// Installation tokens are refreshed this long before they expire, so that a token handed out is
+// still valid for the duration of a (long) git command
+const TOKEN_REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(10);
+
+#[derive(Debug, Clone)]
+struct CachedToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl CachedToken {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at - TOKEN_REFRESH_MARGIN > now
+    }
+}
+
 #[derive(Debug)]
 pub struct GithubSession {
     octocrab: Octocrab,
-    installation_id: RwLock<Option<InstallationId>>,
+    // Installations of the app per repository, as owner/repo. Repositories of different
+    // organizations, or not selected for the same installation, have installations of their own.
+    installations: RwLock<HashMap<String, InstallationId>>,
+    // Installation tokens per installation
+    tokens: RwLock<HashMap<InstallationId, CachedToken>>,
+}
+
+// Github names are case insensitive
+fn repository_key(repo_url: &str) -> Result<String> {
+    let (owner, repo) = extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+    Ok(format!("{}/{}", owner, repo).to_lowercase())
 }
 
 impl GithubSession {
+    // A session of the Github app of the process config
     pub fn try_new() -> Result<Self> {
+        Self::from_config(crate::config())
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self> {
         Ok(Self {
-            octocrab: get_octocrab()?,
-            installation_id: RwLock::new(None),
+            octocrab: get_octocrab(config)?,
+            installations: RwLock::new(HashMap::new()),
+            tokens: RwLock::new(HashMap::new()),
         })
     }
 
@@ -82,14 +173,25 @@ impl GithubSession {
 
     #[tracing::instrument(skip_all)]
     async fn with_installation_for_repo(&self, repo_url: &str) -> Result<Octocrab> {
-        if let Some(installation_id) = *self.installation_id.read().await {
-            return self.octocrab.installation(installation_id).map_err(anyhow::Error::from);
+        let installation_id = self.installation_id(repo_url).await?;
+        self.octocrab
+            .installation(installation_id)
+            .map_err(anyhow::Error::from)
+    }
+
+    // The installation of the app for the repository, looked up once per repository
+    async fn installation_id(&self, repo_url: &str) -> Result<InstallationId> {
+        let key = repository_key(repo_url)?;
+        if let Some(installation_id) = self.installations.read().await.get(&key) {
+            return Ok(*installation_id);
         }
 
         let installation = self.get_installation(repo_url).await?;
-        *self.installation_id.write().await = Some(installation.id);
-
-        self.octocrab.installation(installation.id).map_err(anyhow::Error::from)
+        self.installations
+            .write()
+            .await
+            .insert(key, installation.id);
+        Ok(installation.id)
     }
 
     #[tracing::instrument(skip_all)]
@@ -108,13 +210,13 @@ impl GithubSession {
     #[tracing::instrument(skip_all)]
     async fn create_installation_token(
         &self,
-        installation: Installation,
+        installation_id: InstallationId,
     ) -> Result<InstallationToken> {
         let create_access_token = CreateInstallationAccessToken::default();
-        let access_token_url = Url::parse(installation.access_tokens_url.as_ref().unwrap())?;
+        let access_token_path = format!("/app/installations/{}/access_tokens", installation_id);
 
         self.octocrab
-            .post(access_token_url.path(), Some(&create_access_token))
+            .post(access_token_path, Some(&create_access_token))
             .await
             .map_err(anyhow::Error::msg)
     }
@@ -132,6 +234,72 @@ impl GithubSession {
             .map_err(anyhow::Error::msg)
     }
 
+    // Note that Github also lists pull requests as issues
+    #[tracing::instrument(skip_all)]
+    pub async fn list_issues(&self, repo_url: &str, filter: &IssueFilter) -> Result<Vec<Issue>> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let octocrab = self.with_installation_for_repo(repo_url).await?;
+        let issues = octocrab.issues(owner, repo);
+        let mut request = issues.list().per_page(100);
+        if let Some(state) = filter.state {
+            request = request.state(state);
+        }
+        if !filter.labels.is_empty() {
+            request = request.labels(&filter.labels);
+        }
+        if let Some(assignee) = &filter.assignee {
+            request = request.assignee(assignee.as_str());
+        }
+
+        let page = request.send().await.map_err(anyhow::Error::msg)?;
+        octocrab.all_pages(page).await.map_err(anyhow::Error::msg)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn list_issue_comments(
+        &self,
+        repo_url: &str,
+        issue_number: u64,
+    ) -> Result<Vec<Comment>> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let octocrab = self.with_installation_for_repo(repo_url).await?;
+        let page = octocrab
+            .issues(owner, repo)
+            .list_comments(issue_number)
+            .per_page(100)
+            .send()
+            .await
+            .map_err(anyhow::Error::msg)?;
+        octocrab.all_pages(page).await.map_err(anyhow::Error::msg)
+    }
+
+    // Open and closed pull requests with the given branch as head
+    #[tracing::instrument(skip_all)]
+    pub async fn list_merge_requests_for_branch(
+        &self,
+        repo_url: &str,
+        branch_name: &str,
+    ) -> Result<Vec<PullRequest>> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let octocrab = self.with_installation_for_repo(repo_url).await?;
+        let page = octocrab
+            .pulls(&owner, &repo)
+            .list()
+            .state(State::All)
+            .head(format!("{}:{}", owner, branch_name))
+            .per_page(100)
+            .send()
+            .await
+            .map_err(anyhow::Error::msg)?;
+        octocrab.all_pages(page).await.map_err(anyhow::Error::msg)
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn create_issue(&self, repo_url: &str, title: &str, body: &str) -> Result<Issue> {
         let (owner, repo) =
@@ -205,6 +373,99 @@ impl GithubSession {
             .map_err(anyhow::Error::msg)
     }
 
+    #[tracing::instrument(skip_all)]
+    pub async fn get_merge_request_files(
+        &self,
+        repo_url: &str,
+        merge_request_number: u64,
+    ) -> Result<Vec<DiffEntry>> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let octocrab = self.with_installation_for_repo(repo_url).await?;
+        let page = octocrab
+            .pulls(owner, repo)
+            .list_files(merge_request_number)
+            .await
+            .map_err(anyhow::Error::msg)?;
+        octocrab.all_pages(page).await.map_err(anyhow::Error::msg)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn get_merge_request_diff(
+        &self,
+        repo_url: &str,
+        merge_request_number: u64,
+    ) -> Result<String> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        self.with_installation_for_repo(repo_url)
+            .await?
+            .pulls(owner, repo)
+            .get_diff(merge_request_number)
+            .await
+            .map_err(anyhow::Error::msg)
+    }
+
+    // Comments on a single line of the latest commit of the pull request
+    #[tracing::instrument(skip_all)]
+    pub async fn add_review_comment(
+        &self,
+        repo_url: &str,
+        merge_request_number: u64,
+        comment: &ReviewComment,
+    ) -> Result<ReviewCommentResponse> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let octocrab = self.with_installation_for_repo(repo_url).await?;
+        let merge_request = octocrab
+            .pulls(&owner, &repo)
+            .get(merge_request_number)
+            .await
+            .map_err(anyhow::Error::msg)?;
+
+        let mut body = serde_json::to_value(comment)?;
+        body["commit_id"] = merge_request.head.sha.into();
+
+        octocrab
+            .post(
+                format!("/repos/{owner}/{repo}/pulls/{merge_request_number}/comments"),
+                Some(&body),
+            )
+            .await
+            .map_err(anyhow::Error::msg)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn submit_review(
+        &self,
+        repo_url: &str,
+        merge_request_number: u64,
+        event: ReviewEvent,
+        body: &str,
+        comments: &[ReviewComment],
+    ) -> Result<Review> {
+        let (owner, repo) =
+            extract_owner_and_repo(repo_url).context("Could not find owner or repo")?;
+
+        let review = serde_json::json!({
+            "event": event,
+            "body": body,
+            "comments": comments,
+        });
+
+        self.with_installation_for_repo(repo_url)
+            .await?
+            .post(
+                format!("/repos/{owner}/{repo}/pulls/{merge_request_number}/reviews"),
+                Some(&review),
+            )
+            .await
+            .map_err(anyhow::Error::msg)
+    }
+
     #[tracing::instrument(skip_all)]
     pub async fn add_token_to_url(&self, repo_url: &str) -> Result<String> {
         if !repo_url.starts_with("https://") {
@@ -212,37 +473,163 @@ impl GithubSession {
         }
 
         let mut parsed = url::Url::parse(repo_url).context("Failed to parse url")?;
-
-        let installation = self
-            .get_installation(repo_url)
-            .await
-            .context("Failed to get installation")?;
-        let installation_id = installation.id.to_string();
-        let token = self
-            .create_installation_token(installation)
-            .await
-            .context("Failed to create installation token")?;
+        let token = self.installation_token(repo_url).await?;
 
         let result1 = parsed.set_username("x-access-token");
-        let result2 = parsed.set_password(Some(&token.token));
+        let result2 = parsed.set_password(Some(&token));
         if result1.is_err() || result2.is_err() {
             anyhow::bail!("Could not set token on url")
         }
 
-        tracing::info!(installation_id = installation_id, "Token added to url");
+        tracing::info!("Token added to url");
         Ok(parsed.to_string())
     }
+
+    async fn installation_token(&self, repo_url: &str) -> Result<String> {
+        Ok(self.installation_credential(repo_url).await?.secret)
+    }
+
+    // Returns the cached token of the installation of the repository, creating a new one when
+    // there is none or it is about to expire
+    #[tracing::instrument(skip_all)]
+    pub(crate) async fn installation_credential(&self, repo_url: &str) -> Result<Credential> {
+        let installation_id = self
+            .installation_id(repo_url)
+            .await
+            .context("Failed to get installation")?;
+
+        if let Some(cached) = self.tokens.read().await.get(&installation_id) {
+            if cached.is_fresh(Utc::now()) {
+                return Ok(Credential {
+                    secret: cached.token.clone(),
+                    expires_at: Some(cached.expires_at),
+                });
+            }
+        }
+
+        let token = self
+            .create_installation_token(installation_id)
+            .await
+            .context("Failed to create installation token")?;
+
+        // Tokens are valid for an hour if Github does not tell us otherwise
+        let expires_at = token
+            .expires_at
+            .as_deref()
+            .and_then(|expires_at| DateTime::parse_from_rfc3339(expires_at).ok())
+            .map(|expires_at| expires_at.with_timezone(&Utc))
+            .unwrap_or_else(|| Utc::now() + TimeDelta::hours(1));
+
+        tracing::info!(%installation_id, %expires_at, "Created installation token");
+        self.tokens.write().await.insert(
+            installation_id,
+            CachedToken {
+                token: token.token.clone(),
+                expires_at,
+            },
+        );
+        Ok(Credential {
+            secret: token.token,
+            expires_at: Some(expires_at),
+        })
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_serialize_review() {
+        let comment = ReviewComment {
+            path: "src/lib.rs".to_string(),
+            line: 12,
+            side: None,
+            body: "Consider handling the error".to_string(),
+        };
+
+        assert_eq!(
+            serde_json::to_value(&comment).unwrap(),
+            serde_json::json!({
+                "path": "src/lib.rs",
+                "line": 12,
+                "body": "Consider handling the error"
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ReviewEvent::RequestChanges).unwrap(),
+            "REQUEST_CHANGES"
+        );
+    }
+
+    #[test]
+    fn test_cached_token_is_refreshed_before_it_expires() {
+        let now = Utc::now();
+        let token = |expires_in| CachedToken {
+            token: "token".to_string(),
+            expires_at: now + expires_in,
+        };
+
+        assert!(token(TimeDelta::minutes(59)).is_fresh(now));
+        assert!(!token(TimeDelta::minutes(5)).is_fresh(now));
+        assert!(!token(TimeDelta::minutes(-1)).is_fresh(now));
+    }
+
+    // Repositories of two organizations, one of which has two repositories in one installation
+    #[tokio::test]
+    async fn test_installations_per_repository() {
+        let session = GithubSession {
+            octocrab: Octocrab::default(),
+            installations: RwLock::new(HashMap::from([
+                ("bosun-ai/fluyt".to_string(), InstallationId(1)),
+                ("bosun-ai/derrick".to_string(), InstallationId(1)),
+                ("acme/app".to_string(), InstallationId(2)),
+            ])),
+            tokens: RwLock::new(HashMap::new()),
+        };
+        let expires_at = Utc::now() + TimeDelta::hours(1);
+        for (installation_id, token) in [(1, "bosun"), (2, "acme")] {
+            session.tokens.write().await.insert(
+                InstallationId(installation_id),
+                CachedToken {
+                    token: token.to_string(),
+                    expires_at,
+                },
+            );
+        }
+
+        for (repo_url, installation_id, token) in [
+            ("https://github.com/bosun-ai/fluyt", 1, "bosun"),
+            ("git@github.com:Bosun-AI/derrick.git", 1, "bosun"),
+            ("https://github.com/acme/app.git", 2, "acme"),
+        ] {
+            assert_eq!(
+                session.installation_id(repo_url).await.unwrap(),
+                InstallationId(installation_id)
+            );
+            assert_eq!(session.installation_token(repo_url).await.unwrap(), token);
+        }
+    }
+
+    #[test]
+    fn test_repository_key() {
+        assert_eq!(
+            repository_key("https://github.com/Bosun-AI/Fluyt.git").unwrap(),
+            "bosun-ai/fluyt"
+        );
+        assert_ne!(
+            repository_key("https://github.com/bosun-ai/app").unwrap(),
+            repository_key("https://github.com/acme/app").unwrap()
+        );
+    }
+
     #[test]
     fn test_extract_owner_and_repo() {
         let inputs = [
             "https://github.com/bosun-ai/fluyt",
             "https://github.com/bosun-ai/fluyt.git",
+            "git@github.com:bosun-ai/fluyt.git",
+            "ssh://git@github.com/bosun-ai/fluyt.git",
         ];
 
         for input in inputs {
@@ -253,4 +640,26 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn test_extract_owner_and_repo_self_hosted() {
+        let inputs = [
+            "https://git.example.com:3000/bosun-ai/fluyt.git",
+            "https://example.com/gitea/bosun-ai/fluyt",
+            "https://example.com/gitea/bosun-ai/fluyt.git/",
+            "ssh://git@git.example.com:2222/bosun-ai/fluyt.git",
+            "git@git.example.com:gitea/bosun-ai/fluyt.git",
+        ];
+
+        for input in inputs {
+            let owner_and_repo = extract_owner_and_repo(input).expect("Failed to extract");
+            assert_eq!(
+                owner_and_repo,
+                ("bosun-ai".to_string(), "fluyt".to_string()),
+                "{}",
+                input
+            );
+        }
+        assert!(extract_owner_and_repo("https://git.example.com/fluyt").is_err());
+    }
 }
@@ -1,24 +1,86 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::Result;
 
 use dropshot::{
-    endpoint, ApiDescription, ApiEndpointResponse, Body, ConfigDropshot, ConfigLogging,
-    ConfigLoggingLevel, HandlerTaskMode, HttpError, HttpResponse, HttpResponseOk,
-    HttpServerStarter, Path, RequestContext, TypedBody,
+    channel, endpoint, ApiDescription, ApiEndpointResponse, Body, ConfigDropshot, ConfigLogging,
+    ConfigLoggingLevel, ConfigTls, HandlerTaskMode, HttpError, HttpResponse, HttpResponseOk,
+    HttpServerStarter, Path, Query, RequestContext, TypedBody, UntypedBody, WebsocketChannelResult,
+    WebsocketConnection,
 };
 
 use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
 use http::{Response, StatusCode};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::protocol::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+use tracing::Instrument;
 
-use crate::server::Server;
-use crate::workspace_controllers::CommandOutput;
+use crate::accounting::parse_time;
+use crate::auth::{Role, Unauthorized};
+use crate::command_history::{CommandRequest, RunningCommand};
+use crate::egress_proxy::EgressRequest;
+use crate::events::Event;
+use crate::labels::validate_labels;
+use crate::lsp::{frame, MessageReader};
+use crate::names::validate_name;
+use crate::outputs::parse_range;
+use crate::preview;
+use crate::replace::TooManyFiles;
+use crate::server::{Metrics, Server, WorkspaceOptions, WorkspaceSummary};
+use crate::tls::TlsConfig;
+use crate::webhooks;
+use crate::workspace_controllers::{
+    CommandLimits, CommandOutput, ContainerStopped, FileEdit, FileNotFound, FileTooLarge,
+    FileWrite, LimitExceeded,
+};
+use crate::{
+    ChunkExport, ChunkOptions, CommandNotFound, CommandRecord, CommandStatus, Compression,
+    CoverageConfig, CoverageReport, DependencyReport, DiskUsage, EnvInfo, FileTree, GitStatus,
+    ImageScan, LabelSelector, LintReport, Linter, MatrixWorkspace, NameTaken, Notifications,
+    Provenance, ProvisioningStatus, QueueTimeout, QuotaExceeded, ReplaceResult, Replacement,
+    RepositorySync, Schedule, ScheduleStatus, SpilledOutput, SupportedLanguages, Symbol,
+    TestFramework, TestReport, TreeOptions, UsageReport,
+};
+
+// Where the HTTP server listens, and with TLS, the certificates it uses
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpConfig {
+    pub bind_address: SocketAddr,
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:50080".parse().unwrap(),
+            tls: None,
+        }
+    }
+}
+
+pub async fn serve_http(server: impl Into<Arc<Server>>) -> Result<()> {
+    serve_http_with_config(server, HttpConfig::default()).await
+}
 
-pub async fn serve_http(server: Server) -> Result<()> {
+pub async fn serve_http_with_config(
+    server: impl Into<Arc<Server>>,
+    config: HttpConfig,
+) -> Result<()> {
+    let server = server.into();
+    // Fails on missing or invalid certificates before anything listens
+    let tls = config
+        .tls
+        .as_ref()
+        .map(TlsConfig::server_config)
+        .transpose()?;
     let log = ConfigLogging::StderrTerminal {
         level: ConfigLoggingLevel::Info,
     }
@@ -27,49 +89,204 @@ pub async fn serve_http(server: Server) -> Result<()> {
 
     let mut api = ApiDescription::new();
     api.register(create_workspace)?;
+    api.register(create_matrix)?;
+    api.register(add_schedule)?;
+    api.register(list_schedules)?;
+    api.register(remove_schedule)?;
+    api.register(clone_workspace)?;
+    api.register(adopt_workspace)?;
     api.register(destroy_workspace)?;
+    api.register(destroy_workspaces)?;
     api.register(list_workspaces)?;
+    api.register(workspace_status)?;
+    api.register(reload_context)?;
     api.register(cmd)?;
     api.register(cmd_with_output)?;
+    api.register(command_history)?;
+    api.register(replay_command)?;
+    api.register(provenance)?;
+    api.register(cmd_async)?;
+    api.register(command_status)?;
     api.register(write_file)?;
+    api.register(write_files)?;
+    api.register(append_file)?;
+    api.register(edit_file)?;
     api.register(read_file)?;
+    api.register(read_output)?;
+    api.register(disk_usage)?;
+    api.register(egress_requests)?;
+    api.register(file_tree)?;
+    api.register(replace)?;
+    api.register(symbols)?;
+    api.register(chunks)?;
+    api.register(dependencies)?;
+    api.register(git_status)?;
+    api.register(git_sync)?;
+    api.register(env_info)?;
+    api.register(run_tests)?;
+    api.register(coverage)?;
+    api.register(lint)?;
+    api.register(artifacts)?;
+    api.register(shell)?;
+    api.register(logs)?;
+    api.register(preview_get)?;
+    api.register(preview_post)?;
+    api.register(tunnel)?;
+    api.register(lsp)?;
     api.register(health)?;
+    api.register(ready)?;
+    api.register(metrics)?;
+    api.register(usage_report)?;
+    api.register(image_scans)?;
+    api.register(context_schema)?;
+    api.register(github_webhook)?;
+    api.register(events)?;
+    #[cfg(feature = "ui")]
+    api.register(ui)?;
 
-    let server_mutex = Mutex::new(server);
-
-    let server = HttpServerStarter::new(
+    let server = HttpServerStarter::new_with_tls(
         &ConfigDropshot {
-      bind_address: "127.0.0.1:50080".parse().unwrap(),
-      default_request_body_max_bytes: /* 100MB */ 100 * 1024 * 1024,
-      default_handler_task_mode: HandlerTaskMode::Detached,
-      log_headers: Default::default(),
-  },
+            bind_address: config.bind_address,
+            // Larger bodies are refused with a 413
+            default_request_body_max_bytes: server.size_limits().request_body,
+            default_handler_task_mode: HandlerTaskMode::Detached,
+            log_headers: Default::default(),
+        },
         api,
-        server_mutex,
+        server,
         &log,
+        // Client certificates are checked during the handshake, before any request is read
+        tls.map(ConfigTls::Dynamic),
     )
     .map_err(|error| anyhow::anyhow!("Failed to start server: {:?}", error))?;
 
     server
         .start()
         .await
-        .map_err(|error| anyhow::anyhow!("Server failed: {:?}", error))?;
+        .map_err(|error| anyhow::anyhow!("Server failed: {:?}", error))
+}
 
-    Ok(())
+// Runs a request in a span with its request id, see `request_span`
+async fn traced<T>(
+    rqctx: &RequestContext<Arc<Server>>,
+    request: impl Future<Output = Result<T, HttpError>>,
+) -> Result<T, HttpError> {
+    request.instrument(request_span(rqctx)).await
+}
+
+// Tokens are sent in the Authorization header
+fn bearer_token(rqctx: &RequestContext<Arc<Server>>) -> Option<String> {
+    rqctx
+        .request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+// Browsers cannot set headers on websockets or on the pages of a preview, so those also take the
+// token in the `access_token` query parameter. Other endpoints do not, as urls end up in logs and
+// Referer headers.
+fn browser_token(rqctx: &RequestContext<Arc<Server>>) -> Option<String> {
+    bearer_token(rqctx).or_else(|| {
+        url::form_urlencoded::parse(rqctx.request.uri().query()?.as_bytes())
+            .find(|(key, _)| key == "access_token")
+            .map(|(_, value)| value.into_owned())
+    })
+}
+
+fn authorize(rqctx: &RequestContext<Arc<Server>>, required: Role) -> Result<(), HttpError> {
+    rqctx
+        .context()
+        .authorize(bearer_token(rqctx).as_deref(), required)
+        .map_err(|e| {
+            tracing::warn!("Refused request: {}", e);
+            let status = match e {
+                Unauthorized::Forbidden { .. } => dropshot::ClientErrorStatusCode::FORBIDDEN,
+                _ => dropshot::ClientErrorStatusCode::UNAUTHORIZED,
+            };
+            HttpError::for_client_error(None, status, e.to_string())
+        })
 }
 
+// Longer ids sent by clients are not used in logs
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// The request id of the span is the x-request-id the client sent, so that it finds its requests
+// in the logs with the id it chose. Dropshot always returns an id of its own in the x-request-id
+// header of the response, which is recorded as `server_request_id`.
+fn request_span(rqctx: &RequestContext<Arc<Server>>) -> tracing::Span {
+    let client_request_id = rqctx
+        .request
+        .headers()
+        .get("x-request-id")
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN);
+    tracing::info_span!(
+        "request",
+        request_id = client_request_id.unwrap_or(rqctx.request_id.as_str()),
+        server_request_id = %rqctx.request_id,
+        method = %rqctx.request.method(),
+        path = rqctx.request.uri().path(),
+    )
+}
+
+// Requests carry a bearer token when the server has tokens. Viewers can list workspaces and read
+// their status, files and events, operators can also create workspaces, run commands and write files, and
+// admins can also adopt and destroy workspaces and reload the context. Health, readiness, the
+// context schema, the dashboard and Github webhooks need no token.
+
 // HTTP Server endpoints:
 // POST /workspaces                                 creates a new workspace
+//                                                  (?async=true returns before it is provisioned)
+// POST /matrix                                    creates a workspace per combination of variable values
+// POST /schedules                                 runs a command in a fresh workspace every interval
+// GET /schedules                                  lists the schedules with their recent runs
+// DELETE /schedules/:name                         removes a schedule
 // DELETE /workspaces/:workspace_id                 destroys a workspace
 // GET /workspaces                                  lists existing workspaces
+// GET /workspaces/:workspace_id/status             returns the provisioning status of a workspace
+// POST /contexts/reload                            reloads the context for new workspaces
+// POST /webhooks/github                            receives Github webhooks and publishes them as events
 //
 // Workspace actions
 // POST /workspaces/:workspace_id/cmd               runs a command in the workspace
 // POST /workspaces/:workspace_id/cmd_with_output   runs a command in the workspace and returns the output
+// POST /workspaces/:workspace_id/cmd_async         starts a command in the background and returns its id
+// GET /workspaces/:workspace_id/cmd/:command_id    returns whether the command runs, or its result
+// GET /workspaces/:workspace_id/commands           returns the recent commands with their output
+// POST /workspaces/:workspace_id/commands/:command_id/replay  runs a command of the history again
+// GET /workspaces/:workspace_id/provenance         returns the provenance for commits made in it
 // POST /workspaces/:workspace_id/write_file        writes a file in the workspace
 // POST /workspaces/:workspace_id/read_file         reads a file in the workspace
+// GET /workspaces/:workspace_id/outputs/:cmd_id   returns the spilled output of a command (supports Range)
+// GET /workspaces/:workspace_id/usage              returns the disk usage of a workspace
+// GET /workspaces/:workspace_id/egress             returns the requests recorded by the egress proxy
+// GET /workspaces/:workspace_id/tree               returns a directory of the workspace as a tree
+// POST /workspaces/:workspace_id/replace           replaces a pattern across files (or returns the diff)
+// GET /workspaces/:workspace_id/symbols            looks up symbols of the repositories by name
+// GET /workspaces/:workspace_id/chunks             returns the source files split into chunks for embedding
+// GET /workspaces/:workspace_id/dependencies       returns the dependencies parsed from the manifests
+// GET /workspaces/:workspace_id/git/status         returns the parsed git status of a repository
+// POST /workspaces/:workspace_id/git/sync          fast-forwards the repositories and returns what changed
+// GET /workspaces/:workspace_id/env_info           returns toolchain versions, OS and resources
+// POST /workspaces/:workspace_id/run_tests         runs the tests and returns a structured report
+// POST /workspaces/:workspace_id/coverage          collects test coverage and returns it per file
+// POST /workspaces/:workspace_id/lint              runs linters and returns their diagnostics
+// GET /workspaces/:workspace_id/artifacts          downloads the artifacts of the context as a tarball
+// GET /workspaces/:workspace_id/shell              opens an interactive shell over a websocket
+// GET /workspaces/:workspace_id/logs               streams the container logs over a websocket
+// GET|POST /workspaces/:workspace_id/preview/:port/...  proxies HTTP to a port of the workspace
+// GET /workspaces/:workspace_id/tunnel/:port       forwards TCP to a port of the workspace over a websocket
+// GET /workspaces/:workspace_id/lsp                talks to a language server over a websocket
 
 // GET /health                                    returns the health of the workspace provider
+// GET /ready                                     returns 503 until the contexts are prepared on startup
+// GET /metrics                                   returns workspace and provisioning queue counts
+// GET /usage                                     returns workspace hours, CPU time and traffic per label value
+// GET /images/scans                              returns the vulnerability scans of the prepared images
+// GET /schemas/context                           returns the JSON Schema of context files
 
 #[derive(Serialize, JsonSchema)]
 struct HealthResponse {
@@ -81,11 +298,117 @@ struct HealthResponse {
     path = "/health",
 }]
 async fn health(
-    _rqctx: RequestContext<Mutex<Server>>,
+    _rqctx: RequestContext<Arc<Server>>,
 ) -> Result<HttpResponseOk<HealthResponse>, HttpError> {
     Ok(HttpResponseOk(HealthResponse { healthy: true }))
 }
 
+#[derive(Serialize, JsonSchema)]
+struct ReadyResponse {
+    ready: bool,
+}
+
+// Unavailable while the contexts are prepared on startup, for readiness probes
+#[endpoint {
+    method = GET,
+    path = "/ready",
+}]
+async fn ready(
+    rqctx: RequestContext<Arc<Server>>,
+) -> Result<HttpResponseOk<ReadyResponse>, HttpError> {
+    if !rqctx.context().is_ready() {
+        return Err(HttpError::for_unavail(
+            None,
+            "Preparing the images of the context".to_string(),
+        ));
+    }
+    Ok(HttpResponseOk(ReadyResponse { ready: true }))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/metrics",
+}]
+async fn metrics(rqctx: RequestContext<Arc<Server>>) -> Result<HttpResponseOk<Metrics>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        Ok(HttpResponseOk(rqctx.context().metrics().await))
+    })
+    .await
+}
+
+// For editors and CI to check context files against, which are not secret
+#[endpoint {
+    method = GET,
+    path = "/schemas/context",
+}]
+async fn context_schema(
+    _rqctx: RequestContext<Arc<Server>>,
+) -> Result<HttpResponseOk<serde_json::Value>, HttpError> {
+    let schema = serde_json::to_value(crate::context_schema())
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))?;
+    Ok(HttpResponseOk(schema))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct UsageQuery {
+    // Label to group the workspaces by, the usage label of the server by default
+    group_by: Option<String>,
+    // Unix time in milliseconds or RFC 3339, by default from the start of the month until now
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/usage",
+}]
+async fn usage_report(
+    rqctx: RequestContext<Arc<Server>>,
+    query: Query<UsageQuery>,
+) -> Result<HttpResponseOk<UsageReport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let query = query.into_inner();
+        let time = |value: Option<String>| {
+            value
+                .as_deref()
+                .map(parse_time)
+                .transpose()
+                .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))
+        };
+        let (from, to) = (time(query.from)?, time(query.to)?);
+        let report = rqctx
+            .context()
+            .usage_report(query.group_by.as_deref(), from, to)
+            .await
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Serialize, JsonSchema)]
+struct ImageScansResponse {
+    scans: Vec<ImageScan>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/images/scans",
+}]
+async fn image_scans(
+    rqctx: RequestContext<Arc<Server>>,
+) -> Result<HttpResponseOk<ImageScansResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        Ok(HttpResponseOk(ImageScansResponse {
+            scans: rqctx.context().image_scans(),
+        }))
+    })
+    .await
+}
+
 #[derive(Serialize, JsonSchema)]
 struct WorkspaceResponse {
     id: String,
@@ -93,12 +416,149 @@ struct WorkspaceResponse {
 
 #[derive(Serialize, JsonSchema)]
 struct WorkspaceListResponse {
-    workspaces: Vec<WorkspaceResponse>,
+    workspaces: Vec<WorkspaceSummary>,
 }
 
 #[derive(Deserialize, JsonSchema)]
 struct CreateWorkspaceRequest {
     env: Option<HashMap<String, String>>,
+    // Arbitrary key/value pairs to find the workspace by, e.g. `{"job_id": "123"}`
+    labels: Option<HashMap<String, String>>,
+    // Retried requests with the same key return the workspace of the first request
+    idempotency_key: Option<String>,
+    // Unique name that can be used in place of the id, e.g. `fix-login-bug`
+    name: Option<String>,
+    // Profile of the context with the sandbox, network and limits of the workspace, e.g. `safe`
+    profile: Option<String>,
+    // Values of the variables of the context, e.g. `{"branch": "fix-login"}`
+    variables: Option<HashMap<String, String>>,
+    // Webhook or NATS subject the workspace_ready, workspace_failed, workspace_destroyed and
+    // command_finished events of the workspace are delivered to
+    callback: Option<Notifications>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CreateMatrixRequest {
+    // Values of each variable, e.g. `{"toolchain": ["1.74", "1.78"], "features": ["default", "all"]}`
+    matrix: BTreeMap<String, Vec<String>>,
+    env: Option<HashMap<String, String>>,
+    labels: Option<HashMap<String, String>>,
+    // Each workspace gets the key with the index of its combination appended, like `key/0`
+    idempotency_key: Option<String>,
+    profile: Option<String>,
+    // Values of the variables that are the same for every combination
+    variables: Option<HashMap<String, String>>,
+    // Receives the events of every workspace of the matrix
+    callback: Option<Notifications>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct MatrixResponse {
+    // In the order of the combinations, the last variable by name changes fastest
+    workspaces: Vec<MatrixWorkspace>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/matrix",
+}]
+async fn create_matrix(
+    rqctx: RequestContext<Arc<Server>>,
+    query: Query<CreateWorkspaceQuery>,
+    body: TypedBody<CreateMatrixRequest>,
+) -> Result<HttpResponseOk<MatrixResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let options = WorkspaceOptions {
+            env: body.env.unwrap_or_default(),
+            labels: body.labels.unwrap_or_default(),
+            idempotency_key: body.idempotency_key,
+            name: None,
+            profile: body.profile,
+            variables: body.variables.unwrap_or_default(),
+            callback: body.callback,
+        };
+        // Failures of single workspaces are in the response, anything else is a bad matrix
+        let workspaces = rqctx
+            .context()
+            .create_matrix(
+                options,
+                &body.matrix,
+                query.into_inner().run_async.unwrap_or(false),
+            )
+            .await
+            .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(MatrixResponse { workspaces }))
+    })
+    .await
+}
+
+#[endpoint {
+    method = POST,
+    path = "/schedules",
+}]
+async fn add_schedule(
+    rqctx: RequestContext<Arc<Server>>,
+    body: TypedBody<Schedule>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        rqctx
+            .context()
+            .add_schedule(body.into_inner())
+            .await
+            .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(()))
+    })
+    .await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/schedules",
+}]
+async fn list_schedules(
+    rqctx: RequestContext<Arc<Server>>,
+) -> Result<HttpResponseOk<Vec<ScheduleStatus>>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        Ok(HttpResponseOk(rqctx.context().schedules().await))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct SchedulePathParam {
+    name: String,
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/schedules/{name}",
+}]
+async fn remove_schedule(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SchedulePathParam>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let name = path.into_inner().name;
+        if !rqctx.context().remove_schedule(&name).await {
+            return Err(HttpError::for_not_found(
+                None,
+                format!("Schedule not found: {}", name),
+            ));
+        }
+        Ok(HttpResponseOk(()))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CreateWorkspaceQuery {
+    #[serde(rename = "async")]
+    run_async: Option<bool>,
 }
 
 #[endpoint {
@@ -106,20 +566,69 @@ struct CreateWorkspaceRequest {
     path = "/workspaces",
 }]
 async fn create_workspace(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
+    query: Query<CreateWorkspaceQuery>,
     body: TypedBody<CreateWorkspaceRequest>,
 ) -> Result<HttpResponseOk<WorkspaceResponse>, HttpError> {
-    let id = rqctx
-        .context()
-        .lock()
-        .await
-        .create_workspace(body.into_inner().env.unwrap_or_default())
-        .await
-        .map_err(|e| {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let server = rqctx.context();
+        let body = body.into_inner();
+        let options = WorkspaceOptions {
+            env: body.env.unwrap_or_default(),
+            labels: body.labels.unwrap_or_default(),
+            idempotency_key: body.idempotency_key,
+            name: body.name,
+            profile: body.profile,
+            variables: body.variables.unwrap_or_default(),
+            callback: body.callback,
+        };
+        validate_labels(&options.labels)
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        if let Some(name) = &options.name {
+            validate_name(name).map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        }
+        if let Some(callback) = &options.callback {
+            callback.validate().map_err(|e| {
+                HttpError::for_bad_request(None, format!("Invalid callback: {:#}", e))
+            })?;
+        }
+        let context = server
+            .resolve_context(options.profile.as_deref(), &options.variables)
+            .await
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        server
+            .provider(&context)
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        let result = if query.into_inner().run_async.unwrap_or(false) {
+            server.create_workspace_async(options).await
+        } else {
+            server.create_workspace(options).await
+        };
+        let id = result.map_err(|e| {
             tracing::error!("Failed to create workspace: {:?}", e);
+            if let Some(timeout) = e.downcast_ref::<QueueTimeout>() {
+                return HttpError::for_unavail(None, timeout.to_string());
+            }
+            if let Some(taken) = e.downcast_ref::<NameTaken>() {
+                return HttpError::for_client_error(
+                    None,
+                    dropshot::ClientErrorStatusCode::CONFLICT,
+                    taken.to_string(),
+                );
+            }
+            if let Some(exceeded) = e.downcast_ref::<QuotaExceeded>() {
+                return HttpError::for_client_error(
+                    None,
+                    dropshot::ClientErrorStatusCode::TOO_MANY_REQUESTS,
+                    exceeded.to_string(),
+                );
+            }
             HttpError::for_internal_error("Failed to create workspace".to_string())
         })?;
-    Ok(HttpResponseOk(WorkspaceResponse { id }))
+        Ok(HttpResponseOk(WorkspaceResponse { id }))
+    })
+    .await
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -127,25 +636,153 @@ struct SinglePathIdParam {
     id: String,
 }
 
+#[derive(Deserialize, JsonSchema)]
+struct CloneWorkspaceRequest {
+    // Environment for the daemons started in the clone
+    env: Option<HashMap<String, String>>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/clone",
+}]
+async fn clone_workspace(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<CloneWorkspaceRequest>,
+) -> Result<HttpResponseOk<WorkspaceResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let id = rqctx
+            .context()
+            .clone_workspace(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                body.into_inner().env.unwrap_or_default(),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to clone workspace: {:?}", e);
+                HttpError::for_internal_error("Failed to clone workspace".to_string())
+            })?;
+        Ok(HttpResponseOk(WorkspaceResponse { id }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct AdoptWorkspaceRequest {
+    // Id or name of a running container, or a `key=value` label of one
+    container: String,
+    labels: Option<HashMap<String, String>>,
+    name: Option<String>,
+}
+
+// Lives outside of /workspaces, where the path segment after it is the id of a workspace
+#[endpoint {
+    method = POST,
+    path = "/adopt",
+}]
+async fn adopt_workspace(
+    rqctx: RequestContext<Arc<Server>>,
+    body: TypedBody<AdoptWorkspaceRequest>,
+) -> Result<HttpResponseOk<WorkspaceResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Admin)?;
+        let body = body.into_inner();
+        let labels = body.labels.unwrap_or_default();
+        validate_labels(&labels).map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        if let Some(name) = &body.name {
+            validate_name(name).map_err(|e| HttpError::for_bad_request(None, e.to_string()))?;
+        }
+        let id = rqctx
+            .context()
+            .adopt_workspace(&body.container, labels, body.name.as_deref())
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to adopt workspace: {:?}", e);
+                if let Some(taken) = e.downcast_ref::<NameTaken>() {
+                    return HttpError::for_client_error(
+                        None,
+                        dropshot::ClientErrorStatusCode::CONFLICT,
+                        taken.to_string(),
+                    );
+                }
+                HttpError::for_bad_request(None, format!("{:#}", e))
+            })?;
+        Ok(HttpResponseOk(WorkspaceResponse { id }))
+    })
+    .await
+}
+
 #[endpoint {
     method = DELETE,
     path = "/workspaces/{id}",
 }]
 async fn destroy_workspace(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
     path: Path<SinglePathIdParam>,
 ) -> Result<HttpResponseOk<bool>, HttpError> {
-    let success = rqctx
-        .context()
-        .lock()
-        .await
-        .destroy_workspace(&path.into_inner().id)
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to destroy workspace: {:?}", e);
-            HttpError::for_internal_error("Failed to destroy workspace".to_string())
-        })?;
-    Ok(HttpResponseOk(success))
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Admin)?;
+        let success = rqctx
+            .context()
+            .destroy_workspace(&rqctx.context().workspace_id(&path.into_inner().id).await)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to destroy workspace: {:?}", e);
+                HttpError::for_internal_error("Failed to destroy workspace".to_string())
+            })?;
+        Ok(HttpResponseOk(success))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct LabelQuery {
+    // Comma separated `key=value` pairs the workspaces must all have, e.g. `job_id=123,stage=test`
+    label: Option<String>,
+}
+
+impl LabelQuery {
+    fn selector(&self) -> Result<LabelSelector, HttpError> {
+        LabelSelector::parse(self.label.as_deref().unwrap_or_default())
+            .map_err(|e| HttpError::for_bad_request(None, e.to_string()))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+struct DestroyWorkspacesResponse {
+    destroyed: Vec<String>,
+}
+
+#[endpoint {
+    method = DELETE,
+    path = "/workspaces",
+}]
+async fn destroy_workspaces(
+    rqctx: RequestContext<Arc<Server>>,
+    query: Query<LabelQuery>,
+) -> Result<HttpResponseOk<DestroyWorkspacesResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Admin)?;
+        let selector = query.into_inner().selector()?;
+        if selector.is_empty() {
+            return Err(HttpError::for_bad_request(
+                None,
+                "Destroying workspaces requires a label selector".to_string(),
+            ));
+        }
+        let destroyed = rqctx
+            .context()
+            .destroy_workspaces(&selector)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to destroy workspaces: {:?}", e);
+                HttpError::for_internal_error("Failed to destroy workspaces".to_string())
+            })?;
+        Ok(HttpResponseOk(DestroyWorkspacesResponse { destroyed }))
+    })
+    .await
 }
 
 #[endpoint {
@@ -153,24 +790,61 @@ async fn destroy_workspace(
     path = "/workspaces",
 }]
 async fn list_workspaces(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
+    query: Query<LabelQuery>,
 ) -> Result<HttpResponseOk<WorkspaceListResponse>, HttpError> {
-    let workspaces = rqctx
-        .context()
-        .lock()
-        .await
-        .list_workspaces()
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to list workspaces: {:?}", e);
-            HttpError::for_internal_error("Failed to list workspaces".to_string())
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let selector = query.into_inner().selector()?;
+        let workspaces = rqctx
+            .context()
+            .list_workspaces(&selector)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to list workspaces: {:?}", e);
+                HttpError::for_internal_error("Failed to list workspaces".to_string())
+            })?;
+        Ok(HttpResponseOk(WorkspaceListResponse { workspaces }))
+    })
+    .await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/status",
+}]
+async fn workspace_status(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<ProvisioningStatus>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
+        let status = rqctx.context().workspace_status(&id).await.ok_or_else(|| {
+            HttpError::for_not_found(None, format!("Workspace not found: {}", id))
+        })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+// Existing workspaces keep the context they were created with
+#[endpoint {
+    method = POST,
+    path = "/contexts/reload",
+}]
+async fn reload_context(
+    rqctx: RequestContext<Arc<Server>>,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Admin)?;
+        rqctx.context().reload_context().await.map_err(|e| {
+            tracing::error!("Failed to reload context: {:?}", e);
+            HttpError::for_bad_request(None, format!("Failed to reload context: {:#}", e))
         })?;
-    Ok(HttpResponseOk(WorkspaceListResponse {
-        workspaces: workspaces
-            .iter()
-            .map(|id| WorkspaceResponse { id: id.clone() })
-            .collect(),
-    }))
+        Ok(HttpResponseOk(()))
+    })
+    .await
 }
 
 #[derive(Deserialize, JsonSchema)]
@@ -179,6 +853,44 @@ struct CmdRequest {
     working_dir: Option<String>,
     env: Option<HashMap<String, String>>,
     timeout: Option<u64>,
+    // Seconds of CPU time the command may use
+    cpu_time: Option<u64>,
+    // Output after which the command is killed
+    max_output_bytes: Option<usize>,
+    // Output returned inline by cmd_with_output, the whole output is kept in the workspace
+    max_inline_output: Option<usize>,
+    // Returns the earlier output of cmd_with_output when the command, its environment and the
+    // files it depends on did not change
+    #[serde(default)]
+    cache: bool,
+    // Files the cached output depends on, by default the files in the git repository of the
+    // working directory. Files ignored by git are not part of the default, commands that depend
+    // on them (like build outputs) have to list them.
+    #[serde(default)]
+    cache_files: Vec<String>,
+}
+
+impl CmdRequest {
+    fn limits(&self) -> Option<CommandLimits> {
+        if self.cpu_time.is_none() && self.max_output_bytes.is_none() {
+            return None;
+        }
+        Some(CommandLimits {
+            cpu_time: self.cpu_time.map(Duration::from_secs),
+            max_output_bytes: self.max_output_bytes,
+        })
+    }
+
+    fn command_request(&self) -> CommandRequest {
+        CommandRequest {
+            cmd: self.cmd.clone(),
+            working_dir: self.working_dir.clone(),
+            env: self.env.clone().unwrap_or_default(),
+            timeout: self.timeout,
+            cpu_time: self.cpu_time,
+            max_output_bytes: self.max_output_bytes,
+        }
+    }
 }
 
 #[endpoint {
@@ -186,34 +898,66 @@ struct CmdRequest {
     path = "/workspaces/{id}/cmd",
 }]
 async fn cmd(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
     path: Path<SinglePathIdParam>,
     body: TypedBody<CmdRequest>,
 ) -> Result<HttpResponseOk<()>, HttpError> {
-    let body = body.into_inner();
-    rqctx
-        .context()
-        .lock()
-        .await
-        .cmd(
-            &path.into_inner().id,
-            &body.cmd,
-            body.working_dir.as_deref(),
-            body.env.unwrap_or_default(),
-            body.timeout.map(|t| Duration::from_secs(t)),
-        )
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to run command: {:?}", e);
-            HttpError::for_internal_error("Failed to run command".to_string())
-        })?;
-    Ok(HttpResponseOk(()))
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let server = rqctx.context();
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
+        let working_dir = body.working_dir.as_deref();
+        let env = body.env.clone().unwrap_or_default();
+        let (timeout, limits) = server
+            .command_limits(&id, body.timeout.map(Duration::from_secs), body.limits())
+            .await;
+        server.publish(Event::command_started(&id, &body.cmd));
+        let running = RunningCommand::start(body.command_request(), None);
+        let result = match limits {
+            Some(limits) => server
+                .cmd_with_limits(&id, &body.cmd, working_dir, env, timeout, &limits)
+                .await
+                .and_then(|output| match output.limit_exceeded {
+                    Some(limit) => Err(anyhow::anyhow!("Command exceeded its {:?} limit", limit)),
+                    None if output.exit_code != 0 => Err(anyhow::anyhow!(
+                        "Command failed with exit code {}: {}",
+                        output.exit_code,
+                        output.output
+                    )),
+                    None => Ok(()),
+                }),
+            None => server.cmd(&id, &body.cmd, working_dir, env, timeout).await,
+        };
+        server.publish(match &result {
+            Ok(()) => Event::command_finished(&id, &body.cmd, Some(0), ""),
+            Err(e) => Event::command_finished(&id, &body.cmd, None, &format!("{:#}", e)),
+        });
+        let record = match &result {
+            Ok(()) => running.finish(Some(0), "", None, None),
+            Err(e) => running.finish(None, &format!("{:#}", e), None, None),
+        };
+        server.record_command(&id, record).await;
+        result.map_err(|e| command_error(e, "Failed to run command"))?;
+        Ok(HttpResponseOk(()))
+    })
+    .await
 }
 
 #[derive(Serialize, JsonSchema)]
 struct CommandOutputResponse {
     output: String,
     exit_code: i32,
+    // Set when the command was killed for exceeding one of its limits
+    limit_exceeded: Option<LimitExceeded>,
+    // Set when the output was spilled, the whole output can be read from
+    // /workspaces/{id}/outputs/{cmd_id}
+    cmd_id: Option<String>,
+    // Size of the whole output, when it was spilled
+    output_size: Option<u64>,
+    truncated: bool,
+    // Whether the output was returned from the cache
+    cached: bool,
 }
 
 impl From<CommandOutput> for CommandOutputResponse {
@@ -221,6 +965,25 @@ impl From<CommandOutput> for CommandOutputResponse {
         Self {
             output: output.output,
             exit_code: output.exit_code,
+            limit_exceeded: output.limit_exceeded,
+            cmd_id: None,
+            output_size: None,
+            truncated: false,
+            cached: false,
+        }
+    }
+}
+
+impl From<SpilledOutput> for CommandOutputResponse {
+    fn from(output: SpilledOutput) -> Self {
+        Self {
+            output: output.output,
+            exit_code: output.exit_code,
+            limit_exceeded: None,
+            cmd_id: Some(output.cmd_id),
+            output_size: Some(output.size),
+            truncated: output.truncated,
+            cached: false,
         }
     }
 }
@@ -230,35 +993,240 @@ impl From<CommandOutput> for CommandOutputResponse {
     path = "/workspaces/{id}/cmd_with_output",
 }]
 async fn cmd_with_output(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
     path: Path<SinglePathIdParam>,
     body: TypedBody<CmdRequest>,
 ) -> Result<HttpResponseOk<CommandOutputResponse>, HttpError> {
-    let body = body.into_inner();
-    let output = rqctx
-        .context()
-        .lock()
-        .await
-        .cmd_with_output(
-            &path.into_inner().id,
-            &body.cmd,
-            body.working_dir.as_deref(),
-            body.env.unwrap_or_default(),
-            body.timeout.map(|t| Duration::from_secs(t)),
-        )
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to run command with output: {:?}", e);
-            HttpError::for_internal_error("Failed to run command with output".to_string())
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let server = rqctx.context();
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
+        let working_dir = body.working_dir.as_deref();
+        let env = body.env.clone().unwrap_or_default();
+        let (timeout, limits) = server
+            .command_limits(&id, body.timeout.map(Duration::from_secs), body.limits())
+            .await;
+        let max_inline = body.max_inline_output.or(server.max_inline_output());
+        if body.cache && (limits.is_some() || body.max_inline_output.is_some()) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "Cached commands can not have limits or spill their output".to_string(),
+            ));
+        }
+
+        if body.cache {
+            let (output, cached) = server
+                .cmd_with_cached_output(
+                    &id,
+                    &body.cmd,
+                    working_dir,
+                    env,
+                    timeout,
+                    &body.cache_files,
+                )
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to run command with output: {:?}", e);
+                    HttpError::for_internal_error("Failed to run command with output".to_string())
+                })?;
+            return Ok(HttpResponseOk(CommandOutputResponse {
+                cached,
+                ..output.into()
+            }));
+        }
+
+        // Limits bound the output already, so it is not spilled as well
+        server.publish(Event::command_started(&id, &body.cmd));
+        let running = RunningCommand::start(body.command_request(), None);
+        let output = match (limits, max_inline) {
+            (Some(limits), _) => server
+                .cmd_with_limits(&id, &body.cmd, working_dir, env, timeout, &limits)
+                .await
+                .map(CommandOutputResponse::from),
+            (None, Some(max_inline)) => server
+                .cmd_with_spilled_output(&id, &body.cmd, working_dir, env, timeout, max_inline)
+                .await
+                .map(CommandOutputResponse::from),
+            (None, None) => server
+                .cmd_with_output(&id, &body.cmd, working_dir, env, timeout)
+                .await
+                .map(CommandOutputResponse::from),
+        };
+        server.publish(match &output {
+            Ok(output) => {
+                Event::command_finished(&id, &body.cmd, Some(output.exit_code), &output.output)
+            }
+            Err(e) => Event::command_finished(&id, &body.cmd, None, &format!("{:#}", e)),
+        });
+        let record = match &output {
+            Ok(output) => running.finish(
+                Some(output.exit_code),
+                &output.output,
+                output.limit_exceeded,
+                output.cmd_id.clone(),
+            ),
+            Err(e) => running.finish(None, &format!("{:#}", e), None, None),
+        };
+        server.record_command(&id, record).await;
+        let output = output.map_err(|e| command_error(e, "Failed to run command with output"))?;
+        Ok(HttpResponseOk(output))
+    })
+    .await
+}
+
+#[derive(Serialize, JsonSchema)]
+struct CmdAsyncResponse {
+    // Id of the command in the history, to poll /workspaces/{id}/cmd/{command_id} with
+    command_id: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/cmd_async",
+}]
+async fn cmd_async(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<CmdRequest>,
+) -> Result<HttpResponseOk<CmdAsyncResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        if body.cache || body.max_inline_output.is_some() {
+            return Err(HttpError::for_bad_request(
+                None,
+                "Commands run in the background can not be cached or spill their output"
+                    .to_string(),
+            ));
+        }
+        let server = rqctx.context();
+        let id = server.workspace_id(&path.into_inner().id).await;
+        let command_id = server
+            .start_command(&id, body.command_request())
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(CmdAsyncResponse { command_id }))
+    })
+    .await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/cmd/{command_id}",
+}]
+async fn command_status(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<CommandPathParams>,
+) -> Result<HttpResponseOk<CommandStatus>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let CommandPathParams { id, command_id } = path.into_inner();
+        let server = rqctx.context();
+        let id = server.workspace_id(&id).await;
+        let status = server
+            .command_status(&id, &command_id)
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+#[derive(Serialize, JsonSchema)]
+struct CommandHistoryResponse {
+    // Oldest first
+    commands: Vec<CommandRecord>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/commands",
+}]
+async fn command_history(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<CommandHistoryResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
+        let commands = rqctx
+            .context()
+            .command_history(&id)
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(CommandHistoryResponse { commands }))
+    })
+    .await
+}
+
+// Workspace id, context hash and command history digest for commit trailers, signed when the
+// server has a provenance key
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/provenance",
+}]
+async fn provenance(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<Provenance>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
+        let provenance = rqctx
+            .context()
+            .provenance(&id)
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(provenance))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CommandPathParams {
+    id: String,
+    command_id: String,
+}
+
+// Runs a command of the history again, to tell whether it fails every time
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/commands/{command_id}/replay",
+}]
+async fn replay_command(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<CommandPathParams>,
+) -> Result<HttpResponseOk<CommandRecord>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let CommandPathParams { id, command_id } = path.into_inner();
+        let server = rqctx.context();
+        let id = server.workspace_id(&id).await;
+        let record = server.replay_command(&id, &command_id).await.map_err(|e| {
+            if e.downcast_ref::<CommandNotFound>().is_some() {
+                return HttpError::for_not_found(None, e.to_string());
+            }
+            tracing::error!("Failed to replay command: {:?}", e);
+            HttpError::for_internal_error("Failed to replay command".to_string())
         })?;
-    Ok(HttpResponseOk(output.into()))
+        Ok(HttpResponseOk(record))
+    })
+    .await
 }
 
 #[derive(Deserialize, JsonSchema)]
 struct WriteFileRequest {
     path: String,
     working_dir: Option<String>,
+    #[serde(default)]
     content: String, // Base64 encoded
+    // Octal permissions, e.g. "755". Existing files keep theirs when not set.
+    mode: Option<String>,
+    // Creates a symlink to this target instead, the content is ignored
+    symlink: Option<String>,
+    // Compression of the content before it was base64 encoded
+    content_encoding: Option<Compression>,
 }
 
 #[derive(Serialize, JsonSchema)]
@@ -271,52 +1239,274 @@ struct WriteFileResponse {
     path = "/workspaces/{id}/write_file",
 }]
 async fn write_file(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
     path: Path<SinglePathIdParam>,
     body: TypedBody<WriteFileRequest>,
 ) -> Result<HttpResponseOk<WriteFileResponse>, HttpError> {
-    let body = body.into_inner();
-    let content = base64::engine::general_purpose::STANDARD
-        .decode(&body.content.trim_end())
-        .map_err(|e| {
-            tracing::debug!("Failed to decode base64 content: {:?}", body.content);
-            tracing::error!("Failed to decode base64 content: {:?}", e);
-            HttpError::for_internal_error("Failed to decode base64 content".to_string())
-        })?;
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let id = rqctx.context().workspace_id(&path.into_inner().id).await;
 
-    rqctx
-        .context()
-        .lock()
-        .await
-        .write_file(
-            &path.into_inner().id,
-            &body.path,
-            content.as_slice(),
-            body.working_dir.as_deref(),
-        )
-        .await
-        .map_err(|e| {
-            tracing::error!("Failed to write file: {:?}", e);
-            HttpError::for_internal_error("Failed to write file".to_string())
-        })?;
-    Ok(HttpResponseOk(WriteFileResponse { success: true }))
-}
+        if let Some(target) = &body.symlink {
+            rqctx
+                .context()
+                .symlink(&id, &body.path, target, body.working_dir.as_deref())
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to create symlink: {:?}", e);
+                    HttpError::for_internal_error("Failed to create symlink".to_string())
+                })?;
+            return Ok(HttpResponseOk(WriteFileResponse { success: true }));
+        }
 
-#[derive(Deserialize, JsonSchema)]
-struct ReadFileRequest {
-    path: String,
-    working_dir: Option<String>,
+        let mode = body.mode.as_deref().map(parse_mode).transpose()?;
+        let content = base64::engine::general_purpose::STANDARD
+            .decode(&body.content.trim_end())
+            .map_err(|e| {
+                tracing::debug!("Failed to decode base64 content: {:?}", body.content);
+                tracing::error!("Failed to decode base64 content: {:?}", e);
+                HttpError::for_internal_error("Failed to decode base64 content".to_string())
+            })?;
+        let content = decompress(rqctx.context(), content, body.content_encoding)?;
+
+        rqctx
+            .context()
+            .write_file(
+                &id,
+                &body.path,
+                content.as_slice(),
+                body.working_dir.as_deref(),
+                mode,
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to write file"))?;
+        Ok(HttpResponseOk(WriteFileResponse { success: true }))
+    })
+    .await
 }
 
-#[derive()]
-struct ReadFileResponse {
+// Decompresses written content, up to the size a file can be written with. Without a limit on
+// files, it is the limit on request bodies, so that a small body cannot expand without bounds.
+fn decompress(
+    server: &Server,
     content: Vec<u8>,
+    compression: Option<Compression>,
+) -> Result<Vec<u8>, HttpError> {
+    let Some(compression) = compression else {
+        return Ok(content);
+    };
+    let limits = server.size_limits();
+    compression
+        .decompress(&content, limits.write_file.unwrap_or(limits.request_body))
+        .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))
 }
 
-impl HttpResponse for ReadFileResponse {
-    fn to_result(self) -> Result<Response<Body>, HttpError> {
-        Response::builder()
+// A workspace whose container exited is unavailable, the error tells whether it was restarted
+fn command_error(e: anyhow::Error, message: &str) -> HttpError {
+    tracing::error!("{}: {:?}", message, e);
+    if e.downcast_ref::<ContainerStopped>().is_some() {
+        return HttpError::for_unavail(None, format!("{:#}", e));
+    }
+    HttpError::for_internal_error(message.to_string())
+}
+
+// Missing files are a 404 and files over the size limits a 413
+fn file_error(e: anyhow::Error, message: &str) -> HttpError {
+    if let Some(not_found) = e.downcast_ref::<FileNotFound>() {
+        return HttpError::for_not_found(None, not_found.to_string());
+    }
+    if let Some(too_large) = e.downcast_ref::<FileTooLarge>() {
+        return HttpError::for_client_error(
+            None,
+            dropshot::ClientErrorStatusCode::PAYLOAD_TOO_LARGE,
+            too_large.to_string(),
+        );
+    }
+    tracing::error!("{}: {:?}", message, e);
+    HttpError::for_internal_error(message.to_string())
+}
+
+// Permissions given as an octal string, e.g. "755"
+fn parse_mode(mode: &str) -> Result<u32, HttpError> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| HttpError::for_bad_request(None, format!("Invalid file mode: {}", mode)))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct WriteFilesEntry {
+    path: String,
+    content: String, // Base64 encoded
+    // Octal permissions, e.g. "755"
+    mode: Option<String>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct WriteFilesRequest {
+    working_dir: Option<String>,
+    files: Vec<WriteFilesEntry>,
+    // Compression of the content of every file before it was base64 encoded
+    content_encoding: Option<Compression>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/write_files",
+}]
+async fn write_files(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<WriteFilesRequest>,
+) -> Result<HttpResponseOk<WriteFileResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let files = body
+            .files
+            .into_iter()
+            .map(|file| {
+                let content = base64::engine::general_purpose::STANDARD
+                    .decode(file.content.trim_end())
+                    .map_err(|e| {
+                        tracing::error!("Failed to decode base64 content: {:?}", e);
+                        HttpError::for_bad_request(
+                            None,
+                            format!("Failed to decode base64 content of {}", file.path),
+                        )
+                    })?;
+                let content = decompress(rqctx.context(), content, body.content_encoding)?;
+                Ok(FileWrite {
+                    mode: file.mode.as_deref().map(parse_mode).transpose()?,
+                    path: file.path,
+                    content,
+                })
+            })
+            .collect::<Result<Vec<_>, HttpError>>()?;
+
+        rqctx
+            .context()
+            .write_files(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &files,
+                body.working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to write files"))?;
+        Ok(HttpResponseOk(WriteFileResponse { success: true }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct AppendFileRequest {
+    path: String,
+    working_dir: Option<String>,
+    content: String, // Base64 encoded
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/append_file",
+}]
+async fn append_file(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<AppendFileRequest>,
+) -> Result<HttpResponseOk<WriteFileResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let content = base64::engine::general_purpose::STANDARD
+            .decode(body.content.trim_end())
+            .map_err(|e| {
+                tracing::error!("Failed to decode base64 content: {:?}", e);
+                HttpError::for_bad_request(None, "Failed to decode base64 content".to_string())
+            })?;
+
+        rqctx
+            .context()
+            .append_file(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &body.path,
+                content.as_slice(),
+                body.working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to append to file"))?;
+        Ok(HttpResponseOk(WriteFileResponse { success: true }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct EditFileRequest {
+    path: String,
+    working_dir: Option<String>,
+    // Applied in order, e.g. `[{"type": "lines", "start": 3, "end": 4, "content": "..."}]`
+    edits: Vec<FileEdit>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/edit_file",
+}]
+async fn edit_file(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<EditFileRequest>,
+) -> Result<HttpResponseOk<WriteFileResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        rqctx
+            .context()
+            .edit_file(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &body.path,
+                &body.edits,
+                body.working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| {
+                if let Some(not_found) = e.downcast_ref::<FileNotFound>() {
+                    return HttpError::for_not_found(None, not_found.to_string());
+                }
+                tracing::error!("Failed to edit file: {:?}", e);
+                // Edits that do not apply are reported back, so they can be corrected
+                HttpError::for_bad_request(None, format!("Failed to edit file: {:#}", e))
+            })?;
+        Ok(HttpResponseOk(WriteFileResponse { success: true }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ReadFileRequest {
+    path: String,
+    working_dir: Option<String>,
+}
+
+#[derive()]
+struct ReadFileResponse {
+    content: Vec<u8>,
+    // Set when the content is compressed with an encoding the client accepts
+    encoding: Option<Compression>,
+}
+
+// Smaller files are not worth compressing
+const MIN_COMPRESSED_SIZE: usize = 1024;
+
+impl HttpResponse for ReadFileResponse {
+    fn to_result(self) -> Result<Response<Body>, HttpError> {
+        let mut response = Response::builder()
             .header("Content-Type", "application/octet-stream")
+            .header("Vary", "Accept-Encoding");
+        if let Some(encoding) = self.encoding {
+            response = response.header("Content-Encoding", encoding.encoding());
+        }
+        response
             .body(Body::from(self.content))
             .map_err(|e| HttpError::for_internal_error(e.to_string()))
     }
@@ -339,24 +1529,1185 @@ impl HttpResponse for ReadFileResponse {
     path = "/workspaces/{id}/read_file"
 }]
 async fn read_file(
-    rqctx: RequestContext<Mutex<Server>>,
+    rqctx: RequestContext<Arc<Server>>,
     path: Path<SinglePathIdParam>,
     body: TypedBody<ReadFileRequest>,
 ) -> Result<ReadFileResponse, HttpError> {
-    let body = body.into_inner();
-    let content = rqctx
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let body = body.into_inner();
+        let content = rqctx
+            .context()
+            .read_file(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &body.path,
+                body.working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to read file"))?;
+
+        let encoding = rqctx
+            .request
+            .headers()
+            .get(http::header::ACCEPT_ENCODING)
+            .and_then(|header| header.to_str().ok())
+            .and_then(Compression::negotiate)
+            .filter(|_| content.len() >= MIN_COMPRESSED_SIZE);
+        let content = match encoding {
+            Some(encoding) => encoding.compress(&content).map_err(|e| {
+                tracing::error!("Failed to compress file: {:?}", e);
+                HttpError::for_internal_error("Failed to compress file".to_string())
+            })?,
+            None => content,
+        };
+        Ok(ReadFileResponse { content, encoding })
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct OutputPathParams {
+    id: String,
+    cmd_id: String,
+}
+
+// A spilled output, or the part of it that was requested with a Range header
+struct OutputResponse {
+    content: Vec<u8>,
+    // First and last byte and the total size, for partial responses
+    range: Option<(u64, u64, u64)>,
+}
+
+impl HttpResponse for OutputResponse {
+    fn to_result(self) -> Result<Response<Body>, HttpError> {
+        let mut response = Response::builder()
+            .status(self.status_code())
+            .header("Content-Type", "application/octet-stream")
+            .header("Accept-Ranges", "bytes");
+        if let Some((start, end, size)) = self.range {
+            response =
+                response.header("Content-Range", format!("bytes {}-{}/{}", start, end, size));
+        }
+        response
+            .body(Body::from(self.content))
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))
+    }
+    fn response_metadata() -> ApiEndpointResponse {
+        ApiEndpointResponse {
+            schema: None,
+            headers: vec![],
+            success: Some(StatusCode::OK),
+            description: None,
+        }
+    }
+    fn status_code(&self) -> StatusCode {
+        if self.range.is_some() {
+            StatusCode::PARTIAL_CONTENT
+        } else {
+            StatusCode::OK
+        }
+    }
+}
+
+// Returns the output of a command that was spilled to the workspace, supports a single byte range
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/outputs/{cmd_id}",
+}]
+async fn read_output(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<OutputPathParams>,
+) -> Result<OutputResponse, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let OutputPathParams { id, cmd_id } = path.into_inner();
+        let server = rqctx.context();
+        let id = server.workspace_id(&id).await;
+        let size = server
+            .output_size(&id, &cmd_id)
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+
+        let range = match rqctx.request.headers().get(http::header::RANGE) {
+            Some(header) => {
+                let range = header
+                    .to_str()
+                    .ok()
+                    .and_then(|header| parse_range(header, size))
+                    .ok_or_else(|| {
+                        HttpError::for_client_error(
+                            None,
+                            dropshot::ClientErrorStatusCode::RANGE_NOT_SATISFIABLE,
+                            format!("Output has {} bytes", size),
+                        )
+                    })?;
+                Some(range)
+            }
+            None => None,
+        };
+        let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+
+        let content = if size == 0 {
+            Vec::new()
+        } else {
+            server
+                .read_output(&id, &cmd_id, start, end - start + 1)
+                .await
+                .map_err(|e| {
+                    tracing::error!("Failed to read output: {:?}", e);
+                    HttpError::for_internal_error("Failed to read output".to_string())
+                })?
+        };
+        Ok(OutputResponse {
+            content,
+            range: range.map(|(start, end)| (start, end, size)),
+        })
+    })
+    .await
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/usage",
+}]
+async fn disk_usage(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<DiskUsage>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let usage = rqctx
+            .context()
+            .disk_usage(&rqctx.context().workspace_id(&path.into_inner().id).await)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get disk usage: {:?}", e);
+                HttpError::for_internal_error("Failed to get disk usage".to_string())
+            })?;
+        Ok(HttpResponseOk(usage))
+    })
+    .await
+}
+
+#[derive(Serialize, JsonSchema)]
+struct EgressRequestsResponse {
+    requests: Vec<EgressRequest>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/egress",
+}]
+async fn egress_requests(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<EgressRequestsResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let requests = rqctx
+            .context()
+            .egress_requests(&rqctx.context().workspace_id(&path.into_inner().id).await)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get egress requests: {:?}", e);
+                HttpError::for_internal_error("Failed to get egress requests".to_string())
+            })?;
+        Ok(HttpResponseOk(EgressRequestsResponse { requests }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct FileTreeQuery {
+    // Directory to list, relative to the working directory. Defaults to the working directory.
+    path: Option<String>,
+    // Levels of the tree, 1 only lists the entries of the directory
+    depth: Option<usize>,
+    // Only files that match, like `*.rs` or `src/**/*.rs`
+    glob: Option<String>,
+    // Leaves out what git ignores
+    #[serde(default)]
+    gitignore: bool,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/tree",
+}]
+async fn file_tree(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<FileTreeQuery>,
+) -> Result<HttpResponseOk<FileTree>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let query = query.into_inner();
+        if query.depth == Some(0) {
+            return Err(HttpError::for_bad_request(
+                None,
+                "Depth must be at least 1".to_string(),
+            ));
+        }
+        let options = TreeOptions {
+            depth: query.depth,
+            glob: query.glob,
+            gitignore: query.gitignore,
+        };
+        let tree = rqctx
+            .context()
+            .file_tree(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                query.path.as_deref(),
+                &options,
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to list files"))?;
+        Ok(HttpResponseOk(tree))
+    })
+    .await
+}
+
+// Replaces a regex or text in the files matching a glob, see `Replacement`. With `dry_run` the
+// files are left alone and only the diff is returned.
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/replace",
+}]
+async fn replace(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<Replacement>,
+) -> Result<HttpResponseOk<ReplaceResult>, HttpError> {
+    traced(&rqctx, async {
+        let replacement = body.into_inner();
+        let role = if replacement.dry_run {
+            Role::Viewer
+        } else {
+            Role::Operator
+        };
+        authorize(&rqctx, role)?;
+        replacement
+            .regex()
+            .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))?;
+        let result = rqctx
+            .context()
+            .replace(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &replacement,
+            )
+            .await
+            .map_err(|e| match e.downcast_ref::<TooManyFiles>() {
+                Some(too_many) => HttpError::for_bad_request(None, too_many.to_string()),
+                None => file_error(e, "Failed to replace"),
+            })?;
+        Ok(HttpResponseOk(result))
+    })
+    .await
+}
+
+// Symbols returned when the query does not set a limit, and the most it can set
+const DEFAULT_SYMBOL_LIMIT: usize = 50;
+const MAX_SYMBOL_LIMIT: usize = 1000;
+
+#[derive(Deserialize, JsonSchema)]
+struct SymbolsQuery {
+    // Part of the name, ignoring case
+    query: String,
+    // Only symbols of this kind, like `function` or `struct`
+    kind: Option<String>,
+    limit: Option<usize>,
+    // Rebuilds the index first, to pick up changes made since it was built
+    #[serde(default)]
+    refresh: bool,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct SymbolsResponse {
+    symbols: Vec<Symbol>,
+}
+
+// Looks up symbols like functions and types by name, best matches first. Needs universal-ctags in
+// the workspace.
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/symbols",
+}]
+async fn symbols(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<SymbolsQuery>,
+) -> Result<HttpResponseOk<SymbolsResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let query = query.into_inner();
+        if query.query.is_empty() {
+            return Err(HttpError::for_bad_request(
+                None,
+                "Query must not be empty".to_string(),
+            ));
+        }
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_SYMBOL_LIMIT)
+            .min(MAX_SYMBOL_LIMIT);
+        let symbols = rqctx
+            .context()
+            .symbols(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &query.query,
+                query.kind.as_deref(),
+                limit,
+                query.refresh,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to look up symbols: {:?}", e);
+                HttpError::for_internal_error("Failed to look up symbols".to_string())
+            })?;
+        Ok(HttpResponseOk(SymbolsResponse { symbols }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ChunksQuery {
+    // Lines per chunk, 80 by default
+    max_lines: Option<usize>,
+    // Lines a chunk repeats of the one before it
+    #[serde(default)]
+    overlap: usize,
+    // Only files of this language, like `rust`
+    language: Option<SupportedLanguages>,
+    // The `next` of the previous page
+    after: Option<String>,
+}
+
+// Returns the source files of the repositories in chunks of lines, with their path, language and
+// line range, for indexing pipelines. Large codebases are returned in pages of files.
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/chunks",
+}]
+async fn chunks(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<ChunksQuery>,
+) -> Result<HttpResponseOk<ChunkExport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let query = query.into_inner();
+        let defaults = ChunkOptions::default();
+        let options = ChunkOptions {
+            max_lines: query.max_lines.unwrap_or(defaults.max_lines),
+            overlap: query.overlap,
+            language: query.language,
+            after: query.after,
+        };
+        options
+            .validate()
+            .map_err(|e| HttpError::for_bad_request(None, format!("{:#}", e)))?;
+        let export = rqctx
+            .context()
+            .chunks(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                &options,
+            )
+            .await
+            .map_err(|e| file_error(e, "Failed to export chunks"))?;
+        Ok(HttpResponseOk(export))
+    })
+    .await
+}
+
+// Parses the Cargo, npm, Go and pip manifests of the repositories into one list of dependencies
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/dependencies",
+}]
+async fn dependencies(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+) -> Result<HttpResponseOk<DependencyReport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let report = rqctx
+            .context()
+            .dependencies(&rqctx.context().workspace_id(&path.into_inner().id).await)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to parse dependencies: {:?}", e);
+                HttpError::for_internal_error("Failed to parse dependencies".to_string())
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct GitStatusQuery {
+    // Repository to report on, defaults to the working directory of the workspace
+    working_dir: Option<String>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/git/status",
+}]
+async fn git_status(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<GitStatusQuery>,
+) -> Result<HttpResponseOk<GitStatus>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Viewer)?;
+        let status = rqctx
+            .context()
+            .git_status(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                query.into_inner().working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get git status: {:?}", e);
+                HttpError::for_internal_error("Failed to get git status".to_string())
+            })?;
+        Ok(HttpResponseOk(status))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct GitSyncRequest {
+    // Branch, tag or commit every repository is fast-forwarded to. By default the reference of
+    // the repository in the context, or the default branch of its remote.
+    reference: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct GitSyncResponse {
+    repositories: Vec<RepositorySync>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/git/sync",
+}]
+async fn git_sync(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<GitSyncRequest>,
+) -> Result<HttpResponseOk<GitSyncResponse>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let server = rqctx.context();
+        let id = server.workspace_id(&path.into_inner().id).await;
+        let repositories = server
+            .sync_repositories(&id, body.into_inner().reference.as_deref())
+            .await
+            .map_err(|e| HttpError::for_not_found(None, format!("{:#}", e)))?;
+        Ok(HttpResponseOk(GitSyncResponse { repositories }))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct EnvInfoQuery {
+    // Directory the probe runs in, which matters for toolchains selected per directory
+    working_dir: Option<String>,
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/env_info",
+}]
+async fn env_info(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<EnvInfoQuery>,
+) -> Result<HttpResponseOk<EnvInfo>, HttpError> {
+    traced(&rqctx, async {
+        // The probe runs commands in the workspace
+        authorize(&rqctx, Role::Operator)?;
+        let info = rqctx
+            .context()
+            .env_info(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                query.into_inner().working_dir.as_deref(),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to get environment info: {:?}", e);
+                HttpError::for_internal_error("Failed to get environment info".to_string())
+            })?;
+        Ok(HttpResponseOk(info))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct RunTestsRequest {
+    // Project to test, defaults to the working directory of the workspace
+    working_dir: Option<String>,
+    // Detected from the files in the working directory when not given
+    framework: Option<TestFramework>,
+    // Seconds after which the test run is killed
+    timeout: Option<u64>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/run_tests",
+}]
+async fn run_tests(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<RunTestsRequest>,
+) -> Result<HttpResponseOk<TestReport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let report = rqctx
+            .context()
+            .run_tests(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                body.working_dir.as_deref(),
+                body.framework,
+                body.timeout.map(Duration::from_secs),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to run tests: {:?}", e);
+                HttpError::for_internal_error(format!("Failed to run tests: {}", e))
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct CoverageRequest {
+    working_dir: Option<String>,
+    // Overrides the coverage command of the context, `report` is then required as well
+    command: Option<String>,
+    report: Option<String>,
+    // Seconds after which the coverage command is killed
+    timeout: Option<u64>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/coverage",
+}]
+async fn coverage(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<CoverageRequest>,
+) -> Result<HttpResponseOk<CoverageReport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let config = match (body.command, body.report) {
+            (Some(command), Some(report)) => Some(CoverageConfig { command, report }),
+            (None, None) => None,
+            _ => {
+                return Err(HttpError::for_bad_request(
+                    None,
+                    "command and report must be given together".to_string(),
+                ))
+            }
+        };
+        let report = rqctx
+            .context()
+            .coverage(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                config,
+                body.working_dir.as_deref(),
+                body.timeout.map(Duration::from_secs),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to collect coverage: {:?}", e);
+                HttpError::for_internal_error(format!("Failed to collect coverage: {}", e))
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct LintRequest {
+    working_dir: Option<String>,
+    // Defaults to the linters of the context, or the ones configured in the working directory
+    #[serde(default)]
+    linters: Vec<Linter>,
+    // Seconds after which each linter is killed
+    timeout: Option<u64>,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/lint",
+}]
+async fn lint(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    body: TypedBody<LintRequest>,
+) -> Result<HttpResponseOk<LintReport>, HttpError> {
+    traced(&rqctx, async {
+        authorize(&rqctx, Role::Operator)?;
+        let body = body.into_inner();
+        let report = rqctx
+            .context()
+            .lint(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                body.linters,
+                body.working_dir.as_deref(),
+                body.timeout.map(Duration::from_secs),
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to lint: {:?}", e);
+                HttpError::for_internal_error(format!("Failed to lint: {}", e))
+            })?;
+        Ok(HttpResponseOk(report))
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ArtifactsQuery {
+    // Directory the artifact paths are relative to, defaults to the working directory
+    working_dir: Option<String>,
+    // Compression of the tarball, gzip by default
+    compression: Option<Compression>,
+}
+
+struct ArtifactsResponse {
+    tarball: Vec<u8>,
+    compression: Compression,
+}
+
+impl HttpResponse for ArtifactsResponse {
+    fn to_result(self) -> Result<Response<Body>, HttpError> {
+        let (content_type, extension) = self.compression.tarball();
+        Response::builder()
+            .header("Content-Type", content_type)
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"artifacts.{}\"", extension),
+            )
+            .body(Body::from(self.tarball))
+            .map_err(|e| HttpError::for_internal_error(e.to_string()))
+    }
+    fn response_metadata() -> ApiEndpointResponse {
+        ApiEndpointResponse {
+            schema: None,
+            headers: vec![],
+            success: Some(StatusCode::OK),
+            description: None,
+        }
+    }
+    fn status_code(&self) -> StatusCode {
+        StatusCode::OK
+    }
+}
+
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/artifacts",
+}]
+async fn artifacts(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<ArtifactsQuery>,
+) -> Result<ArtifactsResponse, HttpError> {
+    traced(&rqctx, async {
+        // Collecting the artifacts runs commands in the workspace
+        authorize(&rqctx, Role::Operator)?;
+        let query = query.into_inner();
+        let compression = query.compression.unwrap_or(Compression::Gzip);
+        let tarball = rqctx
+            .context()
+            .artifacts(
+                &rqctx.context().workspace_id(&path.into_inner().id).await,
+                query.working_dir.as_deref(),
+                compression,
+            )
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to collect artifacts: {:?}", e);
+                HttpError::for_internal_error(format!("Failed to collect artifacts: {}", e))
+            })?;
+        Ok(ArtifactsResponse {
+            tarball,
+            compression,
+        })
+    })
+    .await
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ShellQuery {
+    // Size of the terminal of the client
+    cols: Option<u16>,
+    rows: Option<u16>,
+}
+
+// Binary messages carry the terminal input and output, the socket closes when the shell exits
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/workspaces/{id}/shell",
+}]
+async fn shell(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<ShellQuery>,
+    conn: WebsocketConnection,
+) -> WebsocketChannelResult {
+    // The socket is already open, a refused client sees it close
+    rqctx
         .context()
-        .lock()
-        .await
-        .read_file(
-            &path.into_inner().id,
-            &body.path,
-            body.working_dir.as_deref(),
+        .authorize(browser_token(&rqctx).as_deref(), Role::Operator)?;
+    let query = query.into_inner();
+    let session = rqctx
+        .context()
+        .shell(
+            &rqctx.context().workspace_id(&path.into_inner().id).await,
+            query.cols.unwrap_or(80),
+            query.rows.unwrap_or(24),
+        )
+        .instrument(request_span(&rqctx))
+        .await?;
+    let (mut sink, mut stream) =
+        WebSocketStream::from_raw_socket(conn.into_inner(), protocol::Role::Server, None)
+            .await
+            .split();
+    let mut input = session.input;
+    let mut output = session.output;
+
+    let to_shell = async {
+        while let Some(message) = stream.next().await {
+            match message? {
+                Message::Binary(data) => input.write_all(&data).await?,
+                Message::Text(text) => input.write_all(text.as_bytes()).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+            input.flush().await?;
+        }
+        input.shutdown().await?;
+        anyhow::Ok(())
+    };
+    let from_shell = async {
+        while let Some(chunk) = output.next().await {
+            sink.send(Message::Binary(chunk?.into())).await?;
+        }
+        sink.send(Message::Close(None)).await?;
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = to_shell => result?,
+        result = from_shell => result?,
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct LogsQuery {
+    // Keeps the socket open for new output, true by default
+    follow: Option<bool>,
+    // Lines from the end of the logs to start with, all of them by default
+    tail: Option<usize>,
+}
+
+// Binary messages carry the logs as the container wrote them, stdout and stderr interleaved
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/workspaces/{id}/logs",
+}]
+async fn logs(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<LogsQuery>,
+    conn: WebsocketConnection,
+) -> WebsocketChannelResult {
+    rqctx
+        .context()
+        .authorize(browser_token(&rqctx).as_deref(), Role::Viewer)?;
+    let query = query.into_inner();
+    let mut logs = rqctx
+        .context()
+        .logs(
+            &rqctx.context().workspace_id(&path.into_inner().id).await,
+            query.follow.unwrap_or(true),
+            query.tail,
+        )
+        .instrument(request_span(&rqctx))
+        .await?;
+    let (mut sink, mut stream) =
+        WebSocketStream::from_raw_socket(conn.into_inner(), protocol::Role::Server, None)
+            .await
+            .split();
+
+    loop {
+        tokio::select! {
+            chunk = logs.next() => match chunk {
+                Some(chunk) => sink.send(Message::Binary(chunk?.into())).await?,
+                None => break,
+            },
+            message = stream.next() => match message {
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+    sink.send(Message::Close(None)).await?;
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct PreviewPath {
+    id: String,
+    port: u16,
+    path: Vec<String>,
+}
+
+// Shows what a workspace serves on a port, like the dev server of an app, without exposing the
+// port. Browsers can open it with `?access_token=`, which is then kept in a cookie for the
+// requests of the page.
+#[endpoint {
+    method = GET,
+    path = "/workspaces/{id}/preview/{port}/{path:.*}",
+    unpublished = true,
+}]
+async fn preview_get(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<PreviewPath>,
+) -> Result<Response<Body>, HttpError> {
+    proxy_preview(&rqctx, path.into_inner(), Vec::new()).await
+}
+
+#[endpoint {
+    method = POST,
+    path = "/workspaces/{id}/preview/{port}/{path:.*}",
+    unpublished = true,
+}]
+async fn preview_post(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<PreviewPath>,
+    body: UntypedBody,
+) -> Result<Response<Body>, HttpError> {
+    proxy_preview(&rqctx, path.into_inner(), body.as_bytes().to_vec()).await
+}
+
+async fn proxy_preview(
+    rqctx: &RequestContext<Arc<Server>>,
+    path: PreviewPath,
+    body: Vec<u8>,
+) -> Result<Response<Body>, HttpError> {
+    traced(rqctx, async {
+        let request = &rqctx.request;
+        let query_token = browser_token(rqctx);
+        let token = query_token
+            .clone()
+            .or_else(|| preview::token_cookie(request.headers()));
+        rqctx
+            .context()
+            .authorize(token.as_deref(), preview::required_role(request.method()))
+            .map_err(|e| {
+                tracing::warn!("Refused request: {}", e);
+                let status = match e {
+                    Unauthorized::Forbidden { .. } => dropshot::ClientErrorStatusCode::FORBIDDEN,
+                    _ => dropshot::ClientErrorStatusCode::UNAUTHORIZED,
+                };
+                HttpError::for_client_error(None, status, e.to_string())
+            })?;
+
+        let server = rqctx.context();
+        let id = server.workspace_id(&path.id).await;
+        let address = server.network_address(&id, path.port).await.map_err(|e| {
+            tracing::error!("Failed to look up workspace address: {:?}", e);
+            HttpError::for_not_found(None, format!("{:#}", e))
+        })?;
+        let mut response = preview::forward(
+            &address,
+            path.port,
+            request.method().clone(),
+            &format!("/{}", path.path.join("/")),
+            request.uri().query(),
+            request.headers(),
+            body,
         )
         .await
         .map_err(|e| {
-            tracing::error!("Failed to read file: {:?}", e);
-            HttpError::for_internal_error("Failed to read file".to_string())
+            tracing::warn!("Failed to proxy preview: {:?}", e);
+            HttpError::for_unavail(None, format!("{:#}", e))
+        })?;
+
+        // The sandboxed pages have an origin of their own, so the cookie has to be sent to the
+        // server from another site
+        let query = request.uri().query();
+        if let Some(token) = query_token.filter(|token| token_in_query(query, token)) {
+            let cookie = format!(
+                "{}={}; Path=/workspaces/{}/preview/{}; HttpOnly; Secure; SameSite=None",
+                preview::TOKEN_COOKIE,
+                token,
+                path.id,
+                path.port
+            );
+            if let Ok(cookie) = cookie.parse() {
+                response
+                    .headers_mut()
+                    .append(http::header::SET_COOKIE, cookie);
+            }
+        }
+        Ok(response)
+    })
+    .await
+}
+
+// Whether the token came from the query rather than the Authorization header
+fn token_in_query(query: Option<&str>, token: &str) -> bool {
+    query.is_some_and(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "access_token" && value == token)
+    })
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct TunnelPath {
+    id: String,
+    port: u16,
+}
+
+// Binary messages carry the bytes of a TCP connection to the port of the workspace, each socket is
+// one connection. Closing either end closes the other.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/workspaces/{id}/tunnel/{port}",
+}]
+async fn tunnel(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<TunnelPath>,
+    conn: WebsocketConnection,
+) -> WebsocketChannelResult {
+    rqctx
+        .context()
+        .authorize(browser_token(&rqctx).as_deref(), Role::Operator)?;
+    let path = path.into_inner();
+    let connection = rqctx
+        .context()
+        .tunnel(&rqctx.context().workspace_id(&path.id).await, path.port)
+        .instrument(request_span(&rqctx))
+        .await?;
+    let (mut sink, mut stream) =
+        WebSocketStream::from_raw_socket(conn.into_inner(), protocol::Role::Server, None)
+            .await
+            .split();
+    let (mut reader, mut writer) = connection.into_split();
+
+    let to_port = async {
+        while let Some(message) = stream.next().await {
+            match message? {
+                Message::Binary(data) => writer.write_all(&data).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        writer.shutdown().await?;
+        anyhow::Ok(())
+    };
+    let from_port = async {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            sink.send(Message::Binary(buf[..read].to_vec().into()))
+                .await?;
+        }
+        sink.send(Message::Close(None)).await?;
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = to_port => result?,
+        result = from_port => result?,
+    }
+    Ok(())
+}
+
+// Every event the server publishes is sent as a JSON text message, starting from when the socket
+// is opened. Events a slow client missed are skipped.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/events",
+}]
+async fn events(
+    rqctx: RequestContext<Arc<Server>>,
+    conn: WebsocketConnection,
+) -> WebsocketChannelResult {
+    rqctx
+        .context()
+        .authorize(browser_token(&rqctx).as_deref(), Role::Viewer)?;
+    let mut events = rqctx.context().subscribe();
+    let (mut sink, mut stream) =
+        WebSocketStream::from_raw_socket(conn.into_inner(), protocol::Role::Server, None)
+            .await
+            .split();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    sink.send(Message::Text(serde_json::to_string(&event)?))
+                        .await?;
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(skipped, "Events client is too slow, skipping events");
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            },
+            message = stream.next() => match message {
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+    Ok(())
+}
+
+// A page to look after the workspaces from the browser, it uses the API like any other client
+#[cfg(feature = "ui")]
+#[endpoint {
+    method = GET,
+    path = "/ui",
+    unpublished = true,
+}]
+async fn ui(_rqctx: RequestContext<Arc<Server>>) -> Result<Response<Body>, HttpError> {
+    Response::builder()
+        .header("Content-Type", "text/html; charset=utf-8")
+        .body(Body::from(include_str!("../assets/ui/index.html")))
+        .map_err(|e| HttpError::for_internal_error(e.to_string()))
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct LspQuery {
+    // Language server of the context, can be left out when there is only one
+    name: Option<String>,
+}
+
+// Every websocket message is a single JSON-RPC message, the Content-Length headers used on stdio
+// are added and removed by the proxy. Each connection starts its own language server.
+#[channel {
+    protocol = WEBSOCKETS,
+    path = "/workspaces/{id}/lsp",
+}]
+async fn lsp(
+    rqctx: RequestContext<Arc<Server>>,
+    path: Path<SinglePathIdParam>,
+    query: Query<LspQuery>,
+    conn: WebsocketConnection,
+) -> WebsocketChannelResult {
+    rqctx
+        .context()
+        .authorize(browser_token(&rqctx).as_deref(), Role::Operator)?;
+    let session = rqctx
+        .context()
+        .language_server(
+            &rqctx.context().workspace_id(&path.into_inner().id).await,
+            query.into_inner().name.as_deref(),
+        )
+        .instrument(request_span(&rqctx))
+        .await?;
+    let (mut sink, mut stream) =
+        WebSocketStream::from_raw_socket(conn.into_inner(), protocol::Role::Server, None)
+            .await
+            .split();
+    let mut input = session.input;
+    let mut output = session.output;
+
+    let to_server = async {
+        while let Some(message) = stream.next().await {
+            match message? {
+                Message::Text(text) => input.write_all(&frame(text.as_bytes())).await?,
+                Message::Binary(data) => input.write_all(&frame(&data)).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+            input.flush().await?;
+        }
+        input.shutdown().await?;
+        anyhow::Ok(())
+    };
+    let from_server = async {
+        let mut reader = MessageReader::default();
+        while let Some(chunk) = output.next().await {
+            for message in reader.push(&chunk?)? {
+                sink.send(Message::Text(String::from_utf8(message)?))
+                    .await?;
+            }
+        }
+        sink.send(Message::Close(None)).await?;
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = to_server => result?,
+        result = from_server => result?,
+    }
+    Ok(())
+}
+
+// Only available when the server has a webhook secret, so that deliveries can be verified
+#[endpoint {
+    method = POST,
+    path = "/webhooks/github",
+}]
+async fn github_webhook(
+    rqctx: RequestContext<Arc<Server>>,
+    body: UntypedBody,
+) -> Result<HttpResponseOk<()>, HttpError> {
+    traced(&rqctx, async {
+        let server = rqctx.context();
+        let secret = server.github_webhook_secret().ok_or_else(|| {
+            HttpError::for_not_found(None, "Webhooks are not enabled".to_string())
         })?;
-    Ok(ReadFileResponse { content })
+
+        let header = |name: &str| {
+            rqctx
+                .request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| HttpError::for_bad_request(None, format!("Missing {} header", name)))
+        };
+
+        webhooks::verify_signature(secret, body.as_bytes(), header("x-hub-signature-256")?)
+            .map_err(|e| {
+                tracing::warn!("Rejected Github webhook: {:?}", e);
+                HttpError::for_client_error(
+                    None,
+                    dropshot::ClientErrorStatusCode::UNAUTHORIZED,
+                    "Invalid signature".to_string(),
+                )
+            })?;
+
+        let event =
+            webhooks::parse_event(header("x-github-event")?, body.as_bytes()).map_err(|e| {
+                tracing::error!("Failed to parse Github webhook: {:?}", e);
+                HttpError::for_bad_request(None, "Failed to parse webhook payload".to_string())
+            })?;
+
+        if let Some(event) = event {
+            tracing::info!(kind = ?event.kind, action = ?event.action, "Received Github webhook");
+            server.publish(Event::Github(event));
+        }
+        Ok(HttpResponseOk(()))
+    })
+    .await
 }
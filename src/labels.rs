@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+// Labels are matched with selectors like `job_id=123,stage=test`, so keys are restricted and
+// values cannot contain a comma
+pub(crate) fn validate_labels(labels: &HashMap<String, String>) -> Result<()> {
+    for (key, value) in labels {
+        let valid_key = !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c));
+        if !valid_key {
+            anyhow::bail!("Invalid label key: {:?}", key);
+        }
+        if value.contains(',') {
+            anyhow::bail!("Label {} contains a comma: {:?}", key, value);
+        }
+    }
+    Ok(())
+}
+
+// Selects the workspaces that have all of the given labels
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelSelector(Vec<(String, String)>);
+
+impl LabelSelector {
+    // Parses comma separated `key=value` pairs, e.g. `job_id=123,stage=test`
+    pub fn parse(selector: &str) -> Result<Self> {
+        selector
+            .split(',')
+            .filter(|pair| !pair.trim().is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').with_context(|| {
+                    format!("Expected key=value in label selector, got {}", pair)
+                })?;
+                Ok((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.0
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_selector_matches_all_labels() {
+        let selector = LabelSelector::parse("job_id=123, stage=test").unwrap();
+        assert!(selector.matches(&labels(&[
+            ("job_id", "123"),
+            ("stage", "test"),
+            ("pipeline", "main")
+        ])));
+        assert!(!selector.matches(&labels(&[("job_id", "123")])));
+        assert!(!selector.matches(&labels(&[("job_id", "124"), ("stage", "test")])));
+
+        assert!(LabelSelector::parse("").unwrap().is_empty());
+        assert!(LabelSelector::parse("job_id").is_err());
+    }
+
+    #[test]
+    fn test_validate_labels() {
+        assert!(validate_labels(&labels(&[("bosun.ai/job-id", "a=b")])).is_ok());
+        assert!(validate_labels(&labels(&[("job id", "123")])).is_err());
+        assert!(validate_labels(&labels(&[("", "123")])).is_err());
+        assert!(validate_labels(&labels(&[("jobs", "1,2")])).is_err());
+    }
+}
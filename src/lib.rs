@@ -1,23 +1,123 @@
+mod accounting;
+mod artifacts;
+mod auth;
+mod builder;
+pub mod client;
+mod cloud_credentials;
+mod codebase;
+mod command_cache;
+mod command_history;
+mod compression;
 mod config;
+mod conflicts;
+mod coverage;
+mod credential_providers;
+mod credentials;
+mod daemons;
+mod dependencies;
 mod docker;
+mod dotfiles;
+mod egress_proxy;
+mod env_info;
+pub mod events;
+mod file_tree;
+mod git_status;
+mod gitea;
 mod github;
 pub mod http_server;
+mod labels;
 // mod messaging;
+mod lint;
+mod lsp;
+mod matrix;
+mod mirror;
+mod names;
+pub mod nats_server;
+mod network;
+mod notifications;
+mod outputs;
+mod preview;
+mod provenance;
+mod provisioning_queue;
+mod push;
+mod remote_nats;
+mod replace;
 mod repository;
+mod repository_sync;
+mod scheduler;
+mod secrets;
 pub mod server;
+mod symbols;
+mod test_runner;
+mod tls;
 // pub mod service;
 pub mod traits;
+mod usage;
+pub mod webhooks;
 mod workspace;
 pub mod workspace_controllers;
 mod workspace_providers;
 
+pub use accounting::{GroupUsage, QuotaExceeded, UsageQuota, UsageReport};
+pub use auth::{ApiToken, Role, Unauthorized};
+pub use builder::DerrickBuilder;
+pub use cloud_credentials::CloudCredentials;
+pub use codebase::{ChunkExport, ChunkOptions, CodeChunk, SupportedLanguages};
+pub use command_history::{CommandNotFound, CommandRecord, CommandStatus};
+pub use compression::Compression;
+pub use config::Config;
+pub use conflicts::{Conflict, MergeResult};
+pub use coverage::{CoverageConfig, CoverageReport, FileCoverage};
+pub use credential_providers::{
+    credential_provider, CachedCredential, Credential, CredentialProvider, CredentialSource,
+    EnvCredential, GithubAppCredential, StaticCredential,
+};
+pub use credentials::{GithubAppSession, RepositoryCredentials};
+pub use daemons::Daemon;
+pub use dependencies::{Dependency, DependencyKind, DependencyReport, Ecosystem, SkippedManifest};
+pub use egress_proxy::{EgressProxyConfig, EgressRequest, InjectedCredential};
+pub use env_info::{EnvInfo, Toolchain};
+pub use file_tree::{EntryKind, FileTree, TreeEntry, TreeOptions};
+pub use git_status::{FileChange, GitStatus, StatusEntry};
+pub use gitea::{GiteaPullRequest, GiteaSession};
+pub use github::{GithubSession, IssueFilter, ReviewComment, ReviewEvent};
+pub use labels::LabelSelector;
+pub use lint::{Diagnostic, LintReport, Linter, LinterRun, Severity, SuggestedFix, TextEdit};
+pub use lsp::LanguageServer;
+pub use matrix::MatrixWorkspace;
+pub use names::NameTaken;
+pub use network::NetworkPolicy;
+pub use notifications::{NatsNotifications, Notifications};
+pub use outputs::SpilledOutput;
+pub use provenance::Provenance;
+pub use provisioning_queue::QueueTimeout;
+pub use push::{PushOptions, PushRejected, PushRejectionReason};
+pub use replace::{FileReplacements, ReplaceResult, Replacement, TooManyFiles};
 pub use repository::Repository;
-pub use workspace::Workspace;
-pub use workspace_controllers::WorkspaceController;
-pub use workspace_providers::get_provider;
-pub use workspace_providers::{WorkspaceContext, WorkspaceProvider};
+pub use repository_sync::RepositorySync;
+pub use scheduler::{Schedule, ScheduleRun, ScheduleStatus};
+pub use secrets::WorkspaceSecret;
+pub use symbols::Symbol;
+pub use test_runner::{TestCase, TestFramework, TestReport, TestStatus};
+pub use tls::TlsConfig;
+pub use usage::{DiskQuota, DiskUsage, QuotaAction};
+pub use workspace::{CherryPick, Workspace};
+pub use workspace_controllers::{flush_cleanups, Health, WorkspaceController};
+pub use workspace_providers::{context_schema, get_provider, validate_context};
+pub use workspace_providers::{
+    DockerProvider, FleetBackend, FleetProvider, LocalTempSyncProvider, RegistryCredentials,
+    RemoteHttpProvider, RemoteNatsProvider,
+};
+pub use workspace_providers::{
+    Grype, ImageScan, ImageScanConfig, ImageScanner, ScannerKind, Trivy, Vulnerability,
+    VulnerabilitySeverity,
+};
+pub use workspace_providers::{
+    ProgressReporter, ProvisioningPhase, ProvisioningStatus, RestartPolicy, SetupStep,
+    WorkspaceContext, WorkspaceProvider,
+};
 
-// Loads the global config async
+// The config of the process, loaded from the environment on first use
 pub fn config() -> &'static config::Config {
     config::Config::from_env()
 }
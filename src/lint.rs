@@ -0,0 +1,518 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shell_escape::escape;
+
+use crate::test_runner::{run_to_completion, tail};
+use crate::workspace_controllers::git::try_output;
+use crate::WorkspaceController;
+
+// Output of a linter that is not diagnostics, like a missing tool, is kept up to this size
+const MAX_LINTER_OUTPUT: usize = 4 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Linter {
+    Clippy,
+    Eslint,
+    Ruff,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+}
+
+// Replaces the text between the start and end (exclusive), lines and columns start at 1
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct TextEdit {
+    pub start_line: u32,
+    pub start_column: u32,
+    pub end_line: u32,
+    pub end_column: u32,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct SuggestedFix {
+    pub message: Option<String>,
+    pub edits: Vec<TextEdit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Diagnostic {
+    pub linter: Linter,
+    // As reported by the linter, relative to the working directory or absolute
+    pub path: String,
+    pub line: u32,
+    pub column: u32,
+    pub severity: Severity,
+    // Lint or rule, e.g. `clippy::needless_return` or `no-unused-vars`
+    pub code: Option<String>,
+    pub message: String,
+    pub fix: Option<SuggestedFix>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct LinterRun {
+    pub linter: Linter,
+    pub exit_code: i32,
+    // End of what the linter printed besides the diagnostics
+    pub output: String,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct LintReport {
+    pub diagnostics: Vec<Diagnostic>,
+    pub runs: Vec<LinterRun>,
+}
+
+impl Linter {
+    // Prints diagnostics as JSON on stdout
+    fn cmd(&self) -> &'static str {
+        match self {
+            Linter::Clippy => "cargo clippy --all-targets --message-format=json --quiet",
+            Linter::Eslint => "npx eslint --format json .",
+            Linter::Ruff => "ruff check --output-format json .",
+        }
+    }
+}
+
+// Picks the linters configured in the files of the working directory
+pub(crate) async fn detect_linters(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+) -> Result<Vec<Linter>> {
+    let listing = controller
+        .cmd_with_output("ls -A", working_dir, HashMap::new(), None)
+        .await?
+        .output;
+    let files = listing.lines().collect::<HashSet<_>>();
+
+    let mut linters = Vec::new();
+    if files.contains("Cargo.toml") {
+        linters.push(Linter::Clippy);
+    }
+    let eslint_config = files
+        .iter()
+        .any(|file| file.starts_with("eslint.config.") || file.starts_with(".eslintrc"));
+    if eslint_config
+        || (files.contains("package.json")
+            && file_contains(controller, working_dir, "package.json", "eslint").await)
+    {
+        linters.push(Linter::Eslint);
+    }
+    if files.contains("ruff.toml")
+        || files.contains(".ruff.toml")
+        || (files.contains("pyproject.toml")
+            && file_contains(controller, working_dir, "pyproject.toml", "[tool.ruff").await)
+    {
+        linters.push(Linter::Ruff);
+    }
+
+    if linters.is_empty() {
+        anyhow::bail!("Could not detect any linters, specify them explicitly");
+    }
+    Ok(linters)
+}
+
+async fn file_contains(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+    file: &str,
+    needle: &str,
+) -> bool {
+    controller
+        .cmd_with_output(&format!("cat {}", file), working_dir, HashMap::new(), None)
+        .await
+        .is_ok_and(|output| output.output.contains(needle))
+}
+
+// Runs the linters in the working directory and collects their diagnostics. A linter that fails
+// to run is reported in the runs instead of failing the whole report.
+pub(crate) async fn lint(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+    linters: &[Linter],
+    timeout: Option<Duration>,
+) -> Result<LintReport> {
+    let mut report = LintReport {
+        diagnostics: Vec::new(),
+        runs: Vec::new(),
+    };
+
+    for linter in linters {
+        // Diagnostics go to a file, so they are not mixed up with what the linter prints on stderr
+        let results = format!("/tmp/derrick-lint-{}.json", uuid::Uuid::new_v4());
+        let cmd = format!("{} > {}", linter.cmd(), results);
+        let (output, exit_code) = run_to_completion(controller, &cmd, working_dir, timeout).await?;
+        let diagnostics = try_output(controller, &format!("cat {0}; rm -f {0}", results))
+            .await
+            .unwrap_or_default();
+
+        let diagnostics = match linter {
+            Linter::Clippy => parse_clippy(&diagnostics),
+            Linter::Eslint => {
+                let mut diagnostics = parse_eslint(&diagnostics);
+                resolve_eslint_fixes(controller, &mut diagnostics).await;
+                diagnostics
+                    .into_iter()
+                    .map(|(diagnostic, _)| diagnostic)
+                    .collect()
+            }
+            Linter::Ruff => parse_ruff(&diagnostics),
+        };
+        report.diagnostics.extend(diagnostics);
+        report.runs.push(LinterRun {
+            linter: *linter,
+            exit_code,
+            output: tail(&output, MAX_LINTER_OUTPUT).to_string(),
+        });
+    }
+    Ok(report)
+}
+
+// Compiler messages of cargo. The same message is reported for every target that includes the
+// code, so duplicates are dropped.
+fn parse_clippy(output: &str) -> Vec<Diagnostic> {
+    let mut seen = HashSet::new();
+    output
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["reason"] == "compiler-message")
+        .filter_map(|event| {
+            let message = &event["message"];
+            let severity = match message["level"].as_str()? {
+                "error" | "error: internal compiler error" => Severity::Error,
+                "warning" => Severity::Warning,
+                "note" | "help" => Severity::Info,
+                _ => return None,
+            };
+            // Summaries like "aborting due to previous error" have no location
+            let span = message["spans"]
+                .as_array()?
+                .iter()
+                .find(|span| span["is_primary"] == true)?;
+
+            let edits = message["children"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .flat_map(|child| child["spans"].as_array().into_iter().flatten())
+                .filter_map(|span| {
+                    Some(TextEdit {
+                        start_line: span["line_start"].as_u64()? as u32,
+                        start_column: span["column_start"].as_u64()? as u32,
+                        end_line: span["line_end"].as_u64()? as u32,
+                        end_column: span["column_end"].as_u64()? as u32,
+                        replacement: span["suggested_replacement"].as_str()?.to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            let fix_message = message["children"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .find(|child| {
+                    let mut spans = child["spans"].as_array().into_iter().flatten();
+                    spans.any(|span| span["suggested_replacement"].is_string())
+                })
+                .and_then(|child| child["message"].as_str())
+                .map(str::to_string);
+
+            Some(Diagnostic {
+                linter: Linter::Clippy,
+                path: span["file_name"].as_str()?.to_string(),
+                line: span["line_start"].as_u64()? as u32,
+                column: span["column_start"].as_u64()? as u32,
+                severity,
+                code: message["code"]["code"].as_str().map(str::to_string),
+                message: message["message"].as_str()?.to_string(),
+                fix: (!edits.is_empty()).then_some(SuggestedFix {
+                    message: fix_message,
+                    edits,
+                }),
+            })
+        })
+        .filter(|diagnostic| {
+            seen.insert((
+                diagnostic.path.clone(),
+                diagnostic.line,
+                diagnostic.column,
+                diagnostic.message.clone(),
+            ))
+        })
+        .collect()
+}
+
+// The fix of an eslint message, as a range of UTF-16 offsets in the file
+type EslintFix = Option<(usize, usize, String)>;
+
+// Fixes are resolved into edits with `resolve_eslint_fixes`, as they are offsets in the file
+fn parse_eslint(output: &str) -> Vec<(Diagnostic, EslintFix)> {
+    let Ok(Value::Array(files)) = serde_json::from_str::<Value>(output.trim()) else {
+        return Vec::new();
+    };
+
+    let mut diagnostics = Vec::new();
+    for file in &files {
+        let Some(path) = file["filePath"].as_str() else {
+            continue;
+        };
+        for message in file["messages"].as_array().into_iter().flatten() {
+            let fix = message["fix"]["range"].as_array().and_then(|range| {
+                Some((
+                    range.first()?.as_u64()? as usize,
+                    range.get(1)?.as_u64()? as usize,
+                    message["fix"]["text"].as_str()?.to_string(),
+                ))
+            });
+            diagnostics.push((
+                Diagnostic {
+                    linter: Linter::Eslint,
+                    path: path.to_string(),
+                    line: message["line"].as_u64().unwrap_or(1) as u32,
+                    column: message["column"].as_u64().unwrap_or(1) as u32,
+                    severity: match message["severity"].as_u64() {
+                        Some(2) => Severity::Error,
+                        _ => Severity::Warning,
+                    },
+                    code: message["ruleId"].as_str().map(str::to_string),
+                    message: message["message"].as_str().unwrap_or_default().to_string(),
+                    fix: None,
+                },
+                fix,
+            ));
+        }
+    }
+    diagnostics
+}
+
+async fn resolve_eslint_fixes(
+    controller: &dyn WorkspaceController,
+    diagnostics: &mut [(Diagnostic, EslintFix)],
+) {
+    let mut sources: HashMap<String, Option<String>> = HashMap::new();
+    for (diagnostic, fix) in diagnostics.iter_mut() {
+        let Some((start, end, replacement)) = fix.take() else {
+            continue;
+        };
+        if !sources.contains_key(&diagnostic.path) {
+            let source = try_output(
+                controller,
+                &format!("cat {}", escape(diagnostic.path.as_str().into())),
+            )
+            .await;
+            sources.insert(diagnostic.path.clone(), source);
+        }
+        let Some(Some(source)) = sources.get(&diagnostic.path) else {
+            continue;
+        };
+        if let (Some(start), Some(end)) = (position(source, start), position(source, end)) {
+            diagnostic.fix = Some(SuggestedFix {
+                message: None,
+                edits: vec![TextEdit {
+                    start_line: start.0,
+                    start_column: start.1,
+                    end_line: end.0,
+                    end_column: end.1,
+                    replacement,
+                }],
+            });
+        }
+    }
+}
+
+// Line and column (both starting at 1, in characters) of a UTF-16 offset, as used by javascript
+fn position(source: &str, offset: usize) -> Option<(u32, u32)> {
+    let (mut line, mut column, mut current) = (1, 1, 0);
+    for c in source.chars() {
+        if current >= offset {
+            break;
+        }
+        current += c.len_utf16();
+        if c == '\n' {
+            line += 1;
+            column = 1;
+        } else {
+            column += 1;
+        }
+    }
+    (current == offset).then_some((line, column))
+}
+
+fn parse_ruff(output: &str) -> Vec<Diagnostic> {
+    let Ok(Value::Array(messages)) = serde_json::from_str::<Value>(output.trim()) else {
+        return Vec::new();
+    };
+
+    let location = |value: &Value| {
+        Some((
+            value["row"].as_u64()? as u32,
+            value["column"].as_u64()? as u32,
+        ))
+    };
+    messages
+        .iter()
+        .filter_map(|message| {
+            let (line, column) = location(&message["location"])?;
+            let fix = &message["fix"];
+            let edits = fix["edits"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|edit| {
+                    let start = location(&edit["location"])?;
+                    let end = location(&edit["end_location"])?;
+                    Some(TextEdit {
+                        start_line: start.0,
+                        start_column: start.1,
+                        end_line: end.0,
+                        end_column: end.1,
+                        replacement: edit["content"].as_str().unwrap_or_default().to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let code = message["code"].as_str().map(str::to_string);
+            Some(Diagnostic {
+                linter: Linter::Ruff,
+                path: message["filename"].as_str()?.to_string(),
+                line,
+                column,
+                // Ruff has no severities, messages without a rule are syntax errors
+                severity: if code.is_some() {
+                    Severity::Warning
+                } else {
+                    Severity::Error
+                },
+                code,
+                message: message["message"].as_str()?.to_string(),
+                fix: (!edits.is_empty()).then(|| SuggestedFix {
+                    message: fix["message"].as_str().map(str::to_string),
+                    edits,
+                }),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_parse_clippy() {
+        let message = r#"{"reason":"compiler-message","message":{"level":"warning","message":"unneeded `return` statement","code":{"code":"clippy::needless_return"},"spans":[{"file_name":"src/lib.rs","line_start":2,"line_end":2,"column_start":5,"column_end":14,"is_primary":true,"suggested_replacement":null}],"children":[{"message":"remove `return`","spans":[{"file_name":"src/lib.rs","line_start":2,"line_end":2,"column_start":5,"column_end":14,"is_primary":true,"suggested_replacement":"1"}]}]}}"#;
+        let summary = r#"{"reason":"compiler-message","message":{"level":"warning","message":"1 warning emitted","code":null,"spans":[],"children":[]}}"#;
+        // Reported for both the library and its tests
+        let output = [message, message, summary, r#"{"reason":"build-finished"}"#].join("\n");
+
+        assert_eq!(
+            parse_clippy(&output),
+            vec![Diagnostic {
+                linter: Linter::Clippy,
+                path: "src/lib.rs".to_string(),
+                line: 2,
+                column: 5,
+                severity: Severity::Warning,
+                code: Some("clippy::needless_return".to_string()),
+                message: "unneeded `return` statement".to_string(),
+                fix: Some(SuggestedFix {
+                    message: Some("remove `return`".to_string()),
+                    edits: vec![TextEdit {
+                        start_line: 2,
+                        start_column: 5,
+                        end_line: 2,
+                        end_column: 14,
+                        replacement: "1".to_string(),
+                    }],
+                }),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_ruff() {
+        let output = r#"[
+  {
+    "code": "F401",
+    "message": "`os` imported but unused",
+    "filename": "/work/app.py",
+    "location": {"row": 1, "column": 8},
+    "end_location": {"row": 1, "column": 10},
+    "fix": {
+      "applicability": "safe",
+      "message": "Remove unused import: `os`",
+      "edits": [{"content": "", "location": {"row": 1, "column": 1}, "end_location": {"row": 2, "column": 1}}]
+    }
+  },
+  {
+    "code": null,
+    "message": "SyntaxError: Expected an expression",
+    "filename": "/work/broken.py",
+    "location": {"row": 3, "column": 4},
+    "end_location": {"row": 3, "column": 5},
+    "fix": null
+  }
+]"#;
+
+        let diagnostics = parse_ruff(output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].code.as_deref(), Some("F401"));
+        assert_eq!(diagnostics[0].fix.as_ref().unwrap().edits[0].end_line, 2);
+        assert_eq!(diagnostics[1].severity, Severity::Error);
+        assert_eq!(diagnostics[1].fix, None);
+    }
+
+    #[test]
+    fn test_position() {
+        let source = "let a = 1;\nconst é = \"x\";\n";
+        assert_eq!(position(source, 0), Some((1, 1)));
+        assert_eq!(position(source, 11), Some((2, 1)));
+        assert_eq!(position(source, 19), Some((2, 9)));
+        assert_eq!(position(source, 1000), None);
+    }
+
+    #[tokio::test]
+    async fn test_eslint_fixes_are_resolved_to_edits() {
+        let controller = TestingController::new("lint-eslint");
+        controller
+            .write_file("index.js", b"var a = 1;\nvar b = 2;\n", None)
+            .await
+            .unwrap();
+        let path = controller
+            .cmd_with_output("realpath index.js", None, HashMap::new(), None)
+            .await
+            .unwrap()
+            .output;
+
+        let output = format!(
+            r#"[{{"filePath":"{}","messages":[{{"ruleId":"no-var","severity":2,"message":"Unexpected var, use let or const instead.","line":2,"column":1,"fix":{{"range":[11,21],"text":"let b = 2;"}}}}]}}]"#,
+            path.trim()
+        );
+        let mut diagnostics = parse_eslint(&output);
+        resolve_eslint_fixes(&controller, &mut diagnostics).await;
+
+        let (diagnostic, _) = &diagnostics[0];
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert_eq!(
+            diagnostic.fix.as_ref().unwrap().edits,
+            vec![TextEdit {
+                start_line: 2,
+                start_column: 1,
+                end_line: 2,
+                end_column: 11,
+                replacement: "let b = 2;".to_string(),
+            }]
+        );
+    }
+}
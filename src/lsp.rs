@@ -0,0 +1,142 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+// A language server that can be started in workspaces, which talks LSP over stdio
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct LanguageServer {
+    pub name: String,
+    // e.g. `rust-analyzer` or `typescript-language-server --stdio`
+    pub command: String,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+}
+
+// Picks the server by name, or the only one when no name is given
+pub(crate) fn select_language_server<'a>(
+    servers: &'a [LanguageServer],
+    name: Option<&str>,
+) -> Result<&'a LanguageServer> {
+    match (name, servers) {
+        (Some(name), _) => servers
+            .iter()
+            .find(|server| server.name == name)
+            .with_context(|| format!("No language server named {}", name)),
+        (None, [server]) => Ok(server),
+        (None, []) => anyhow::bail!("No language servers configured for the workspace"),
+        (None, servers) => anyhow::bail!(
+            "Several language servers are configured, pick one of: {}",
+            servers
+                .iter()
+                .map(|server| server.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+// Adds the header that separates messages on stdio. Over the websocket every message is a frame
+// of its own, without header.
+pub(crate) fn frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = format!("Content-Length: {}\r\n\r\n", message.len()).into_bytes();
+    framed.extend_from_slice(message);
+    framed
+}
+
+// Splits the output of a language server into messages
+#[derive(Debug, Default)]
+pub(crate) struct MessageReader {
+    buffer: Vec<u8>,
+}
+
+impl MessageReader {
+    // Returns the messages completed by the chunk
+    pub(crate) fn push(&mut self, chunk: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.buffer.extend_from_slice(chunk);
+
+        let mut messages = Vec::new();
+        while let Some(header_len) = self
+            .buffer
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+        {
+            let headers = std::str::from_utf8(&self.buffer[..header_len])
+                .context("Invalid LSP message header")?;
+            let content_length: usize = headers
+                .split("\r\n")
+                .filter_map(|header| header.split_once(':'))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+                .context("LSP message without Content-Length")?
+                .1
+                .trim()
+                .parse()
+                .context("Invalid Content-Length")?;
+
+            let start = header_len + HEADER_END.len();
+            if self.buffer.len() < start + content_length {
+                break;
+            }
+            messages.push(self.buffer[start..start + content_length].to_vec());
+            self.buffer.drain(..start + content_length);
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_messages_split_over_chunks() {
+        let first = br#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let second = br#"{"jsonrpc":"2.0","method":"initialized"}"#;
+        let mut output = frame(first);
+        output.extend(
+            format!(
+                "Content-Type: application/vscode-jsonrpc; charset=utf-8\r\ncontent-length: {}\r\n\r\n",
+                second.len()
+            )
+            .into_bytes(),
+        );
+        output.extend_from_slice(second);
+
+        let mut reader = MessageReader::default();
+        let (start, rest) = output.split_at(10);
+        assert!(reader.push(start).unwrap().is_empty());
+        let (middle, end) = rest.split_at(frame(first).len() - start.len());
+        assert_eq!(reader.push(middle).unwrap(), vec![first.to_vec()]);
+        assert_eq!(reader.push(end).unwrap(), vec![second.to_vec()]);
+        assert!(reader.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_message_without_length() {
+        let mut reader = MessageReader::default();
+        assert!(reader.push(b"Content-Type: json\r\n\r\n{}").is_err());
+    }
+
+    #[test]
+    fn test_select_language_server() {
+        let server = |name: &str| LanguageServer {
+            name: name.to_string(),
+            command: name.to_string(),
+            working_dir: None,
+        };
+        let one = vec![server("rust-analyzer")];
+        let two = vec![server("rust-analyzer"), server("pyright")];
+
+        assert_eq!(
+            select_language_server(&one, None).unwrap().name,
+            "rust-analyzer"
+        );
+        assert!(select_language_server(&two, None).is_err());
+        assert_eq!(
+            select_language_server(&two, Some("pyright")).unwrap().name,
+            "pyright"
+        );
+        assert!(select_language_server(&[], None).is_err());
+    }
+}
@@ -1,24 +1,363 @@
-use anyhow::Result;
-use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use derrick::{http_server, server};
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use crossterm::terminal;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::Message;
+
+use derrick::client::{self, Client};
+use derrick::{http_server, nats_server, server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let opts: Opts = Opts::parse();
-    let provider = derrick::get_provider(opts.provisioning_mode).await?;
+    let client = match &opts.token {
+        Some(token) => Client::new(&opts.url).with_token(token)?,
+        None => Client::new(&opts.url),
+    };
+
+    match opts.command {
+        Command::Serve(serve_opts) => serve(serve_opts).await,
+        Command::Create {
+            env,
+            label,
+            name,
+            profile,
+            var,
+            run_async,
+        } => {
+            let id = client
+                .create_workspace(
+                    env.into_iter().collect(),
+                    label.into_iter().collect(),
+                    name.as_deref(),
+                    profile.as_deref(),
+                    var.into_iter().collect(),
+                    run_async,
+                )
+                .await?;
+            println!("{}", id);
+            Ok(())
+        }
+        Command::Adopt {
+            container,
+            label,
+            name,
+        } => {
+            let id = client
+                .adopt_workspace(&container, label.into_iter().collect(), name.as_deref())
+                .await?;
+            println!("{}", id);
+            Ok(())
+        }
+        Command::Exec {
+            id,
+            working_dir,
+            env,
+            cmd,
+        } => {
+            let output = client
+                .cmd_with_output(
+                    &id,
+                    &shell_join(&cmd),
+                    working_dir.as_deref(),
+                    env.into_iter().collect(),
+                )
+                .await?;
+            print!("{}", output.output);
+            std::process::exit(output.exit_code)
+        }
+        Command::Cp {
+            source,
+            destination,
+        } => match (parse_location(&source), parse_location(&destination)) {
+            (Location::Local(source), Location::Workspace { id, path }) => {
+                let content =
+                    std::fs::read(&source).with_context(|| format!("Could not read {}", source))?;
+                client.write_file(&id, &path, &content).await
+            }
+            (Location::Workspace { id, path }, Location::Local(destination)) => {
+                let content = client.read_file(&id, &path).await?;
+                std::fs::write(&destination, content)
+                    .with_context(|| format!("Could not write {}", destination))
+            }
+            _ => anyhow::bail!("Exactly one of source and destination has to be <id>:<path>"),
+        },
+        Command::Shell { id } => {
+            let result = shell(&client, &id).await;
+            // Reading from stdin blocks the runtime from shutting down
+            if let Err(e) = result {
+                eprintln!("{:?}", e);
+                std::process::exit(1)
+            }
+            std::process::exit(0)
+        }
+        Command::Tunnel {
+            id,
+            port,
+            local_port,
+        } => tunnel(&client, &id, port, local_port.unwrap_or(port)).await,
+        Command::Ls { label } => {
+            for workspace in client.list_workspaces(label.as_deref()).await? {
+                let mut labels: Vec<_> = workspace
+                    .labels
+                    .iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                labels.sort();
+                println!(
+                    "{}\t{}\t{}",
+                    workspace.id,
+                    workspace.name.unwrap_or_default(),
+                    labels.join(",")
+                );
+            }
+            Ok(())
+        }
+        Command::Validate { path, schema } => {
+            if schema {
+                let schema = derrick::context_schema();
+                println!("{}", serde_json::to_string_pretty(&schema)?);
+                return Ok(());
+            }
+            let Some(path) = path else {
+                anyhow::bail!("Expected the path of a context file");
+            };
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("Could not read {}", path))?;
+            let problems = derrick::validate_context(&content);
+            if problems.is_empty() {
+                println!("{} is valid", path);
+                return Ok(());
+            }
+            for problem in problems {
+                eprintln!("{}: {}", path, problem);
+            }
+            std::process::exit(1)
+        }
+        Command::Rm { ids, label } => {
+            if let Some(label) = label {
+                for id in client.destroy_workspaces(&label).await? {
+                    println!("{}", id);
+                }
+            }
+            for id in ids {
+                if !client.destroy_workspace(&id).await? {
+                    eprintln!("Workspace not found: {}", id);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// Every connection to the local port gets its own tunnel, until the command is interrupted
+async fn tunnel(client: &Client, id: &str, port: u16, local_port: u16) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", local_port))
+        .await
+        .with_context(|| format!("Could not listen on port {}", local_port))?;
+    eprintln!(
+        "Forwarding 127.0.0.1:{} to port {} of {}",
+        listener.local_addr()?.port(),
+        port,
+        id
+    );
+    loop {
+        let (connection, _) = listener.accept().await?;
+        let client = client.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = forward(&client, &id, port, connection).await {
+                eprintln!("{:#}", e);
+            }
+        });
+    }
+}
+
+async fn forward(
+    client: &Client,
+    id: &str,
+    port: u16,
+    connection: tokio::net::TcpStream,
+) -> Result<()> {
+    let (mut sink, mut stream) = client.tunnel(id, port).await?.split();
+    let (mut reader, mut writer) = connection.into_split();
+
+    let to_workspace = async {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            sink.send(Message::Binary(buf[..read].to_vec().into()))
+                .await?;
+        }
+        sink.send(Message::Close(None)).await?;
+        anyhow::Ok(())
+    };
+    let from_workspace = async {
+        while let Some(message) = stream.next().await {
+            match message? {
+                Message::Binary(data) => writer.write_all(&data).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        writer.shutdown().await?;
+        anyhow::Ok(())
+    };
+
+    tokio::select! {
+        result = to_workspace => result,
+        result = from_workspace => result,
+    }
+}
+
+async fn shell(client: &Client, id: &str) -> Result<()> {
+    let (cols, rows) = terminal::size().unwrap_or((80, 24));
+    let (mut sink, mut stream) = client.shell(id, cols, rows).await?.split();
+
+    // Keys are sent as they are typed, the remote terminal echoes them
+    terminal::enable_raw_mode()?;
+    let to_shell = async {
+        let mut stdin = tokio::io::stdin();
+        let mut buf = vec![0; 1024];
+        loop {
+            let read = stdin.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            sink.send(Message::Binary(buf[..read].to_vec().into()))
+                .await?;
+        }
+        sink.send(Message::Close(None)).await?;
+        anyhow::Ok(())
+    };
+    let from_shell = async {
+        let mut stdout = tokio::io::stdout();
+        while let Some(message) = stream.next().await {
+            match message? {
+                Message::Binary(data) => stdout.write_all(&data).await?,
+                Message::Text(text) => stdout.write_all(text.as_bytes()).await?,
+                Message::Close(_) => break,
+                _ => {}
+            }
+            stdout.flush().await?;
+        }
+        anyhow::Ok(())
+    };
+
+    let result = tokio::select! {
+        result = to_shell => result,
+        result = from_shell => result,
+    };
+    terminal::disable_raw_mode()?;
+    result
+}
+
+async fn serve(opts: ServeOpts) -> Result<()> {
+    let provider = derrick::get_provider(opts.provisioning_mode.clone()).await?;
     let workspace_config_path = opts.workspace_config_path;
 
-    let context = derrick::WorkspaceContext::from_file(workspace_config_path)?;
-    let server = server::Server::create_server(context, provider)?;
+    let context = derrick::WorkspaceContext::from_file(workspace_config_path.clone())?;
+    let api_tokens = match &opts.api_tokens {
+        Some(path) => derrick::ApiToken::from_file(path)?,
+        None => Vec::new(),
+    };
+    let schedules = match &opts.schedules {
+        Some(path) => derrick::Schedule::from_file(path)?,
+        None => Vec::new(),
+    };
+    let usage_quotas = match &opts.usage_quotas {
+        Some(path) => derrick::UsageQuota::from_file(path)?,
+        None => Vec::new(),
+    };
+    let mut server = server::Server::create_server(context.clone(), provider)?
+        .with_provisioning_mode(opts.provisioning_mode.clone());
+    // The context and its profiles can ask for other providers than the default one
+    for mode in context.provisioning_modes() {
+        if mode != opts.provisioning_mode {
+            let provider = derrick::get_provider(mode.clone()).await?;
+            server = server.with_provider(mode, provider);
+        }
+    }
+    let server = Arc::new(
+        server
+            .with_context_path(Some(workspace_config_path))
+            .with_github_webhook_secret(std::env::var("GITHUB_WEBHOOK_SECRET").ok())
+            .with_api_tokens(api_tokens)
+            .with_provisioning_limits(
+                opts.max_concurrent_provisions,
+                Duration::from_secs(opts.queue_timeout),
+            )
+            .with_max_inline_output(opts.max_inline_output)
+            .with_size_limits(server::SizeLimits {
+                request_body: opts.max_request_body,
+                write_file: opts.max_write_file,
+                read_file: opts.max_read_file,
+            })
+            .with_idempotency_key_ttl(Duration::from_secs(opts.idempotency_key_ttl))
+            .with_command_history_dir(opts.command_history_dir)
+            .with_usage_label(opts.usage_label)
+            .with_usage_quotas(usage_quotas),
+    );
+    if opts.watch {
+        server.watch_context(Duration::from_secs(2));
+    }
+    server.enforce_disk_quotas(Duration::from_secs(60));
+    server.watch_health(Duration::from_secs(10));
+    server.record_usage(Duration::from_secs(60));
+    for schedule in schedules {
+        server.add_schedule(schedule).await?;
+    }
+    if opts.prepare_images {
+        server.prepare_contexts(opts.prepare_env.into_iter().collect());
+    }
 
     match opts.server_mode.as_str() {
         "nats" => {
-            todo!()
+            let url = opts
+                .nats_url
+                .context("The nats server mode needs --nats-url or NATS_ENDPOINT")?;
+            let config = nats_server::NatsConfig {
+                url,
+                subject: opts.nats_subject,
+            };
+            let result = tokio::select! {
+                result = nats_server::serve_nats(server.clone(), config) => result,
+                result = shutdown_signal() => result,
+            };
+            shutdown(&server).await;
+            result
+        }
+        "http" => {
+            let tls = match (opts.tls_cert, opts.tls_key) {
+                (Some(cert_file), Some(key_file)) => Some(derrick::TlsConfig {
+                    cert_file,
+                    key_file,
+                    client_ca_file: opts.tls_client_ca,
+                }),
+                _ => None,
+            };
+            let config = http_server::HttpConfig {
+                bind_address: opts.bind,
+                tls,
+            };
+            let result = tokio::select! {
+                result = http_server::serve_http_with_config(server.clone(), config) => result,
+                result = shutdown_signal() => result,
+            };
+            shutdown(&server).await;
+            result
         }
-        "http" => http_server::serve_http(server).await,
         _ => {
             return Err(anyhow::anyhow!(
                 "Unsupported server mode: {}",
@@ -28,10 +367,140 @@ async fn main() -> Result<()> {
     }
 }
 
+// Destroys the workspaces, and removes the containers of workspaces that were just dropped,
+// before the runtime stops
+async fn shutdown(server: &server::Server) {
+    if tokio::time::timeout(Duration::from_secs(30), server.shutdown())
+        .await
+        .is_err()
+    {
+        tracing::warn!("Timed out destroying workspaces");
+    }
+    derrick::flush_cleanups(Duration::from_secs(30)).await;
+}
+
+// Ctrl-C, or SIGTERM from docker or kubernetes
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    tracing::info!("Shutting down");
+    Ok(())
+}
+
+// Ctrl-C, other platforms have no SIGTERM
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    tracing::info!("Shutting down");
+    Ok(())
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Opts {
-    /// The provisioning mode to use (local, docker, remote_nats)
+    /// The url of the derrick server the client commands talk to
+    #[arg(long, env = "DERRICK_URL", default_value = client::DEFAULT_URL, global = true)]
+    url: String,
+    /// Token sent to the derrick server, when it requires one
+    #[arg(long, env = "DERRICK_TOKEN", global = true, hide_env_values = true)]
+    token: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs the workspace server
+    Serve(ServeOpts),
+    /// Creates a workspace and prints its id
+    Create {
+        /// Environment variable for the setup script, as KEY=VALUE
+        #[arg(short, long, value_parser = parse_key_value)]
+        env: Vec<(String, String)>,
+        /// Label to find the workspace by, as KEY=VALUE
+        #[arg(short, long, value_parser = parse_key_value)]
+        label: Vec<(String, String)>,
+        /// Unique name to use in place of the id in other commands
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Profile of the context to create the workspace with, e.g. safe
+        #[arg(long)]
+        profile: Option<String>,
+        /// Value of a variable of the context, as KEY=VALUE
+        #[arg(long, value_parser = parse_key_value)]
+        var: Vec<(String, String)>,
+        /// Return immediately instead of waiting until the workspace is provisioned
+        #[arg(long = "async")]
+        run_async: bool,
+    },
+    /// Registers a running container as a workspace and prints its id
+    Adopt {
+        /// Id or name of the container, or a KEY=VALUE label of it
+        container: String,
+        /// Label to find the workspace by, as KEY=VALUE
+        #[arg(short, long, value_parser = parse_key_value)]
+        label: Vec<(String, String)>,
+        /// Unique name to use in place of the id in other commands
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Runs a command in a workspace and exits with its exit code
+    Exec {
+        id: String,
+        /// The directory to run the command in
+        #[arg(short, long)]
+        working_dir: Option<String>,
+        /// Environment variable for the command, as KEY=VALUE
+        #[arg(short, long, value_parser = parse_key_value)]
+        env: Vec<(String, String)>,
+        #[arg(last = true, required = true)]
+        cmd: Vec<String>,
+    },
+    /// Copies a file into or out of a workspace, e.g. `derrick cp notes.txt <id>:/tmp/notes.txt`
+    Cp { source: String, destination: String },
+    /// Opens an interactive shell in a workspace
+    Shell { id: String },
+    /// Forwards a local port to a port of a workspace, e.g. for a database client
+    Tunnel {
+        id: String,
+        /// The port in the workspace
+        port: u16,
+        /// The port to listen on at 127.0.0.1, the same as the port in the workspace by default
+        #[arg(short, long)]
+        local_port: Option<u16>,
+    },
+    /// Lists the workspaces with their labels
+    Ls {
+        /// Only lists the workspaces with these labels, as KEY=VALUE,...
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+    /// Checks a context file, exiting with 1 when it has problems like misspelled fields
+    Validate {
+        #[arg(required_unless_present = "schema")]
+        path: Option<String>,
+        /// Prints the JSON Schema of context files instead
+        #[arg(long)]
+        schema: bool,
+    },
+    /// Destroys workspaces
+    Rm {
+        #[arg(required_unless_present = "label")]
+        ids: Vec<String>,
+        /// Destroys the workspaces with these labels, as KEY=VALUE,...
+        #[arg(short, long)]
+        label: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeOpts {
+    /// The provisioning mode to use (local, docker, remote_http, remote_nats, fleet), for contexts
+    /// and profiles that do not set their own
     #[arg(short, long)]
     provisioning_mode: String,
     /// The path to the workspace configuration file
@@ -40,4 +509,147 @@ struct Opts {
     /// The server mode to use (nats, http)
     #[arg(short, long)]
     server_mode: String,
+    /// NATS server the nats server mode takes requests from, with the credentials in NATS_CREDS
+    #[arg(long, env = "NATS_ENDPOINT")]
+    nats_url: Option<String>,
+    /// Subject the nats server mode takes create requests on, servers on the same subject take
+    /// turns
+    #[arg(long, env = "DERRICK_NATS_SUBJECT", default_value = "derrick")]
+    nats_subject: String,
+    /// Reload the workspace configuration for new workspaces when the file changes
+    #[arg(long)]
+    watch: bool,
+    /// Build the images of the context and its profiles on startup, `/ready` returns 503 until
+    /// they are built
+    #[arg(long)]
+    prepare_images: bool,
+    /// Environment variable for the setup steps of the images built on startup, as KEY=VALUE.
+    /// Only workspaces created with the same environment use them.
+    #[arg(long, value_parser = parse_key_value, requires = "prepare_images")]
+    prepare_env: Vec<(String, String)>,
+    /// How many workspaces are provisioned at the same time, further workspaces are queued
+    #[arg(long, default_value_t = 4)]
+    max_concurrent_provisions: usize,
+    /// Seconds a workspace waits in the provisioning queue before creating it fails
+    #[arg(long, default_value_t = 600)]
+    queue_timeout: u64,
+    /// Bytes of command output returned inline, the whole output is kept in a file in the
+    /// workspace. By default the output is returned whole.
+    #[arg(long)]
+    max_inline_output: Option<usize>,
+    /// Bytes of a request body, files are base64 encoded in them and take a third more
+    #[arg(long, default_value_t = 100 * 1024 * 1024)]
+    max_request_body: usize,
+    /// Bytes of a file written or appended through the API
+    #[arg(long)]
+    max_write_file: Option<usize>,
+    /// Bytes of a file read through the API
+    #[arg(long)]
+    max_read_file: Option<usize>,
+    /// Seconds a create request with an idempotency key returns the workspace it created
+    #[arg(long, default_value_t = 86400)]
+    idempotency_key_ttl: u64,
+    /// Directory to also write the command history of every workspace to, so that it can be read
+    /// after the workspace is destroyed. The environment of the commands is left out.
+    #[arg(long)]
+    command_history_dir: Option<PathBuf>,
+    /// JSON file with the tokens requests have to carry and their roles, like
+    /// `[{"name": "ci", "token": "...", "role": "operator"}]`. Without it, requests need no token.
+    #[arg(long)]
+    api_tokens: Option<String>,
+    /// JSON file with commands to run in a fresh workspace at an interval, like
+    /// `[{"name": "nightly", "cmd": "cargo test", "interval": 86400}]`
+    #[arg(long)]
+    schedules: Option<String>,
+    /// Label the usage of workspaces is reported and quotas are enforced by, like a team
+    #[arg(long, default_value = "tenant")]
+    usage_label: String,
+    /// JSON file with monthly quotas per value of the usage label, like
+    /// `[{"value": "team-a", "workspace_hours": 500, "cpu_hours": 100}]`
+    #[arg(long)]
+    usage_quotas: Option<String>,
+    /// Address the HTTP server listens on. Anyone who can reach it can run commands in the
+    /// workspaces, use a client CA when it is not on loopback.
+    #[arg(long, default_value = "127.0.0.1:50080")]
+    bind: SocketAddr,
+    /// PEM certificate chain to serve HTTPS with
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// PEM certificates of the CAs client certificates have to be signed by, clients without one
+    /// are refused
+    #[arg(long, requires = "tls_cert")]
+    tls_client_ca: Option<PathBuf>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .with_context(|| format!("Expected KEY=VALUE, got {}", s))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+// A single argument is run as is so that shell syntax works, e.g. `derrick exec <id> -- 'ls | wc'`
+fn shell_join(args: &[String]) -> String {
+    if let [cmd] = args {
+        return cmd.clone();
+    }
+    args.iter()
+        .map(|arg| shell_escape::escape(arg.into()).to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, PartialEq)]
+enum Location {
+    Local(String),
+    Workspace { id: String, path: String },
+}
+
+// `<id>:<path>` refers to a file in a workspace, anything else to a local file
+fn parse_location(location: &str) -> Location {
+    match location.split_once(':') {
+        Some((id, path)) if !id.is_empty() && !id.contains('/') && !path.is_empty() => {
+            Location::Workspace {
+                id: id.to_string(),
+                path: path.to_string(),
+            }
+        }
+        _ => Location::Local(location.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_location() {
+        assert_eq!(
+            parse_location("3f0c:/tmp/notes.txt"),
+            Location::Workspace {
+                id: "3f0c".to_string(),
+                path: "/tmp/notes.txt".to_string()
+            }
+        );
+        assert_eq!(
+            parse_location("./notes.txt"),
+            Location::Local("./notes.txt".to_string())
+        );
+        assert_eq!(
+            parse_location("./dir:with/colon"),
+            Location::Local("./dir:with/colon".to_string())
+        );
+    }
+
+    #[test]
+    fn test_shell_join() {
+        assert_eq!(shell_join(&["ls | wc -l".to_string()]), "ls | wc -l");
+        assert_eq!(
+            shell_join(&["echo".to_string(), "hello world".to_string()]),
+            "echo 'hello world'"
+        );
+    }
 }
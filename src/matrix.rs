@@ -0,0 +1,95 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// More workspaces than this from one request is more likely a mistake in the matrix
+const MAX_COMBINATIONS: usize = 64;
+
+// A workspace created for a combination of a matrix
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MatrixWorkspace {
+    // The values of the combination
+    pub variables: HashMap<String, String>,
+    // Not set when the workspace could not be created, the error says why
+    pub id: Option<String>,
+    pub error: Option<String>,
+}
+
+// Every combination of the values of the variables. The last variable by name changes fastest.
+pub(crate) fn combinations(
+    matrix: &BTreeMap<String, Vec<String>>,
+) -> Result<Vec<HashMap<String, String>>> {
+    if let Some((name, _)) = matrix.iter().find(|(_, values)| values.is_empty()) {
+        anyhow::bail!("Variable {} of the matrix has no values", name);
+    }
+    let count = matrix
+        .values()
+        .try_fold(1usize, |count, values| count.checked_mul(values.len()));
+    if count.map_or(true, |count| count > MAX_COMBINATIONS) {
+        anyhow::bail!("The matrix has more than {} combinations", MAX_COMBINATIONS);
+    }
+
+    let mut combinations = vec![HashMap::new()];
+    for (name, values) in matrix {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    Ok(combinations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matrix(variables: &[(&str, &[&str])]) -> BTreeMap<String, Vec<String>> {
+        variables
+            .iter()
+            .map(|(name, values)| {
+                let values = values.iter().map(|value| value.to_string()).collect();
+                (name.to_string(), values)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_combinations() {
+        let combinations = combinations(&matrix(&[
+            ("toolchain", &["1.74", "1.78"]),
+            ("features", &["default", "all", "none"]),
+        ]))
+        .unwrap();
+        assert_eq!(combinations.len(), 6);
+        assert_eq!(combinations[0]["features"], "default");
+        assert_eq!(combinations[0]["toolchain"], "1.74");
+        assert_eq!(combinations[1]["toolchain"], "1.78");
+        assert_eq!(combinations[5]["features"], "none");
+        assert_eq!(combinations[5]["toolchain"], "1.78");
+    }
+
+    #[test]
+    fn test_invalid_matrices() {
+        let error = combinations(&matrix(&[("toolchain", &[])])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Variable toolchain of the matrix has no values"
+        );
+
+        let values = (0..9).map(|i| i.to_string()).collect::<Vec<_>>();
+        let values = values.iter().map(String::as_str).collect::<Vec<_>>();
+        let error = combinations(&matrix(&[("a", &values), ("b", &values)])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The matrix has more than 64 combinations"
+        );
+    }
+}
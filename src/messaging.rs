@@ -2,13 +2,15 @@ use anyhow::Result;
 pub use async_nats::Subscriber;
 use base64::Engine;
 
-pub async fn establish_connection() -> Result<async_nats::client::Client> {
-    let nats_creds_b64 = crate::config()
+use crate::config::Config;
+
+pub async fn establish_connection(config: &Config) -> Result<async_nats::client::Client> {
+    let nats_creds_b64 = config
         .nats_creds
         .clone()
         .ok_or_else(|| anyhow::anyhow!("NATS_CREDS not set"))?;
 
-    let nats_endpoint = crate::config()
+    let nats_endpoint = config
         .nats_endpoint
         .clone()
         .ok_or_else(|| anyhow::anyhow!("NATS_ENDPOINT not set"))?;
@@ -59,6 +61,7 @@ impl Channel {
     // and a channel topic that is used to indicate what the channel is about. The channel instance subject is a unique
     // subject that is used to communicate with the channel.
     pub async fn establish_and_announce(
+        config: &Config,
         announcement_subject: String,
         channel_topic: String,
         initial_message: String,
@@ -66,7 +69,7 @@ impl Channel {
         let channel_instance_subject = format!("{}.{}", channel_topic, random_hex(8));
 
         // TODO clients could be reused, no reason to establish every time
-        let client = establish_connection().await?;
+        let client = establish_connection(config).await?;
 
         let subscriber = client.subscribe(channel_instance_subject.clone()).await?;
 
@@ -93,10 +96,10 @@ impl Channel {
         ))
     }
 
-    pub async fn establish(topic: String) -> Result<Self> {
+    pub async fn establish(config: &Config, topic: String) -> Result<Self> {
         let channel_instance_subject = format!("{}.{}", topic, random_hex(8));
 
-        let client = establish_connection().await?;
+        let client = establish_connection(config).await?;
 
         Ok(Self {
             channel_topic: topic,
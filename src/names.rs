@@ -0,0 +1,54 @@
+use std::fmt;
+
+use anyhow::Result;
+
+const MAX_NAME_LENGTH: usize = 63;
+
+// Another workspace already has the name
+#[derive(Debug)]
+pub struct NameTaken {
+    pub name: String,
+}
+
+impl fmt::Display for NameTaken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A workspace named {} already exists", self.name)
+    }
+}
+
+impl std::error::Error for NameTaken {}
+
+// Names are used in urls in place of ids, so they are restricted to what docker allows in
+// container names and cannot be mistaken for an id
+pub(crate) fn validate_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+        && name.len() <= MAX_NAME_LENGTH;
+    if !valid {
+        anyhow::bail!(
+            "Invalid workspace name {:?}, expected up to {} letters, digits, `_`, `.` or `-`",
+            name,
+            MAX_NAME_LENGTH
+        );
+    }
+    if uuid::Uuid::parse_str(name).is_ok() {
+        anyhow::bail!("Workspace names cannot be uuids: {}", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("fix-login-bug.attempt_2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("-leading-dash").is_err());
+        assert!(validate_name("with/slash").is_err());
+        assert!(validate_name(&"a".repeat(64)).is_err());
+        assert!(validate_name(&uuid::Uuid::new_v4().to_string()).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use futures_util::StreamExt;
+use serde::Serialize;
+
+use crate::remote_nats::{self, Created, RemoteOutput, Request, Response, DEFAULT_SUBJECT};
+use crate::server::{Server, WorkspaceOptions};
+use crate::workspace_controllers::CommandLimits;
+
+// Servers on the same subject are in this queue group, so each create request is answered once
+const QUEUE_GROUP: &str = "derrick";
+
+// The NATS server and the subject create requests are taken on, with the credentials in
+// NATS_CREDS
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatsConfig {
+    pub url: String,
+    pub subject: String,
+}
+
+impl NatsConfig {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            subject: DEFAULT_SUBJECT.to_string(),
+        }
+    }
+}
+
+// Serves the workspaces of the server to other derricks in the remote_nats provisioning mode.
+// Create requests are taken on the subject of the config, and the requests for the workspaces it
+// created on a subject of its own that it tells the creator.
+pub async fn serve_nats(server: impl Into<Arc<Server>>, config: NatsConfig) -> Result<()> {
+    let server = server.into();
+    let client = remote_nats::connect(&config.url).await?;
+    let own_subject = format!("{}.{}", config.subject, uuid::Uuid::new_v4());
+    let mut creates = client
+        .queue_subscribe(config.subject.clone(), QUEUE_GROUP.to_string())
+        .await?;
+    let mut requests = client.subscribe(own_subject.clone()).await?;
+    tracing::info!(subject = config.subject, "Serving workspaces over NATS");
+
+    loop {
+        let message = tokio::select! {
+            Some(message) = creates.next() => message,
+            Some(message) = requests.next() => message,
+            else => break,
+        };
+        let Some(reply) = message.reply else {
+            tracing::warn!(subject = %message.subject, "Ignoring request without a reply subject");
+            continue;
+        };
+        let server = Arc::clone(&server);
+        let client = client.clone();
+        let own_subject = own_subject.clone();
+        // Commands can run for a long time, so every request is handled on its own
+        tokio::spawn(async move {
+            let result = match serde_json::from_slice::<Request>(&message.payload) {
+                Ok(request) => handle(&server, &own_subject, request).await,
+                Err(e) => Err(anyhow::Error::from(e).context("Invalid request")),
+            };
+            if let Err(e) = &result {
+                tracing::warn!("NATS request failed: {:#}", e);
+            }
+            let response = match serde_json::to_vec(&Response::from_result(result)) {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::error!("Could not serialize response: {:?}", e);
+                    return;
+                }
+            };
+            if let Err(e) = client.publish(reply, response.into()).await {
+                tracing::error!("Could not answer NATS request: {:?}", e);
+            }
+        });
+    }
+    anyhow::bail!("NATS subscriptions closed")
+}
+
+async fn handle(server: &Server, own_subject: &str, request: Request) -> Result<serde_json::Value> {
+    match request {
+        Request::Create { env } => {
+            let id = server
+                .create_workspace(WorkspaceOptions {
+                    env,
+                    ..Default::default()
+                })
+                .await?;
+            to_value(Created {
+                id,
+                subject: own_subject.to_string(),
+            })
+        }
+        Request::Destroy { id } => to_value(server.destroy_workspace(&id).await?),
+        Request::Cmd { id, cmd } => {
+            server
+                .cmd(
+                    &id,
+                    &cmd.cmd,
+                    cmd.working_dir.as_deref(),
+                    cmd.env.clone(),
+                    cmd.timeout(),
+                )
+                .await?;
+            to_value(())
+        }
+        Request::CmdWithOutput { id, cmd } => {
+            let output = server
+                .cmd_with_output(
+                    &id,
+                    &cmd.cmd,
+                    cmd.working_dir.as_deref(),
+                    cmd.env.clone(),
+                    cmd.timeout(),
+                )
+                .await?;
+            to_value(RemoteOutput::from(output))
+        }
+        Request::CmdWithLimits {
+            id,
+            cmd,
+            cpu_time_ms,
+            max_output_bytes,
+        } => {
+            let limits = CommandLimits {
+                cpu_time: cpu_time_ms.map(std::time::Duration::from_millis),
+                max_output_bytes,
+            };
+            let output = server
+                .cmd_with_limits(
+                    &id,
+                    &cmd.cmd,
+                    cmd.working_dir.as_deref(),
+                    cmd.env.clone(),
+                    cmd.timeout(),
+                    &limits,
+                )
+                .await?;
+            to_value(RemoteOutput::from(output))
+        }
+        Request::WriteFile {
+            id,
+            path,
+            content,
+            working_dir,
+            mode,
+        } => {
+            let content = decode(&content)?;
+            server
+                .write_file(&id, &path, &content, working_dir.as_deref(), mode)
+                .await?;
+            to_value(())
+        }
+        Request::AppendFile {
+            id,
+            path,
+            content,
+            working_dir,
+        } => {
+            let content = decode(&content)?;
+            server
+                .append_file(&id, &path, &content, working_dir.as_deref())
+                .await?;
+            to_value(())
+        }
+        Request::ReadFile {
+            id,
+            path,
+            working_dir,
+        } => {
+            let content = server.read_file(&id, &path, working_dir.as_deref()).await?;
+            to_value(base64::prelude::BASE64_STANDARD.encode(content))
+        }
+        Request::DiskUsage { id } => to_value(server.disk_usage(&id).await?.bytes),
+        Request::Health { id } => {
+            let status = server
+                .workspace_status(&id)
+                .await
+                .with_context(|| format!("Workspace not found: {}", id))?;
+            to_value(status.health)
+        }
+    }
+}
+
+fn to_value(value: impl Serialize) -> Result<serde_json::Value> {
+    Ok(serde_json::to_value(value)?)
+}
+
+fn decode(content: &str) -> Result<Vec<u8>> {
+    base64::prelude::BASE64_STANDARD
+        .decode(content)
+        .context("Content is not base64")
+}
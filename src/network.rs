@@ -0,0 +1,123 @@
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Port of the egress proxy of workspaces with a host allowlist
+pub(crate) const PROXY_PORT: u16 = 3128;
+// Name of the egress proxy on the network of the workspace
+pub(crate) const PROXY_HOST: &str = "derrick-proxy";
+
+// What workspaces can reach over the network, e.g. `{"mode": "allowlist", "hosts": ["github.com"]}`
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum NetworkPolicy {
+    // No network at all
+    #[serde(rename = "none")]
+    Disabled,
+    // Only HTTP(S) to the hosts, through an egress proxy. `*.example.com` also allows the
+    // subdomains of example.com.
+    Allowlist {
+        hosts: Vec<String>,
+    },
+    #[default]
+    Full,
+}
+
+impl NetworkPolicy {
+    // Hosts end up in the configuration of the proxy
+    pub(crate) fn validate(&self) -> Result<()> {
+        let NetworkPolicy::Allowlist { hosts } = self else {
+            return Ok(());
+        };
+        for host in hosts {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            if !valid {
+                anyhow::bail!("Invalid host in network allowlist: {:?}", host);
+            }
+        }
+        Ok(())
+    }
+
+    // Hosts that can be reached, all of them when not set
+    pub(crate) fn allowed_hosts(&self) -> Option<Vec<String>> {
+        match self {
+            NetworkPolicy::Allowlist { hosts } => Some(hosts.clone()),
+            _ => None,
+        }
+    }
+}
+
+// Environment that sends the HTTP(S) traffic of a workspace through a proxy
+pub(crate) fn proxy_env(proxy: &str) -> Vec<(String, String)> {
+    ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"]
+        .iter()
+        .map(|key| (key.to_string(), proxy.to_string()))
+        .chain(
+            ["NO_PROXY", "no_proxy"]
+                .iter()
+                .map(|key| (key.to_string(), "localhost,127.0.0.1".to_string())),
+        )
+        .collect()
+}
+
+// Squid configuration that only lets requests to the allowed hosts through
+pub(crate) fn proxy_config(hosts: &[String]) -> String {
+    let domains = hosts
+        .iter()
+        .map(|host| match host.strip_prefix("*.") {
+            Some(domain) => format!(".{}", domain),
+            None => host.clone(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    let allow = if hosts.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "acl allowed dstdomain {}\nhttp_access allow allowed\n",
+            domains
+        )
+    };
+
+    format!(
+        "http_port {}\n{}http_access deny all\ncache deny all\npid_filename none\naccess_log stdio:/dev/stdout\n",
+        PROXY_PORT, allow
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_policies() {
+        let policy: NetworkPolicy = serde_json::from_value(serde_json::json!({
+            "mode": "allowlist",
+            "hosts": ["github.com", "*.crates.io"]
+        }))
+        .unwrap();
+        assert!(policy.validate().is_ok());
+        assert_eq!(
+            serde_json::from_value::<NetworkPolicy>(serde_json::json!({ "mode": "none" })).unwrap(),
+            NetworkPolicy::Disabled
+        );
+
+        let invalid = NetworkPolicy::Allowlist {
+            hosts: vec!["github.com\nhttp_access allow all".to_string()],
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_proxy_config_allows_subdomains() {
+        let config = proxy_config(&["github.com".to_string(), "*.crates.io".to_string()]);
+        assert!(config.contains("acl allowed dstdomain github.com .crates.io\n"));
+        assert!(config.find("http_access allow allowed") < config.find("http_access deny all"));
+
+        assert!(!proxy_config(&[]).contains("http_access allow"));
+    }
+}
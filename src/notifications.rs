@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Where the server reports something to, like a failed scheduled run or a workspace that is
+// ready. Either or both can be set.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Notifications {
+    // Url the notifications are posted to as JSON
+    #[serde(default)]
+    pub webhook: Option<String>,
+    // NATS server and subject the notifications are published to, with the credentials in
+    // NATS_CREDS
+    #[serde(default)]
+    pub nats: Option<NatsNotifications>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct NatsNotifications {
+    pub url: String,
+    pub subject: String,
+}
+
+impl Notifications {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(webhook) = &self.webhook {
+            let url = url::Url::parse(webhook).context("Invalid webhook")?;
+            if !matches!(url.scheme(), "http" | "https") {
+                anyhow::bail!("Webhook {} is not an http url", webhook);
+            }
+        }
+        if let Some(nats) = &self.nats {
+            if nats.subject.is_empty() {
+                anyhow::bail!("The NATS subject is empty");
+            }
+        }
+        Ok(())
+    }
+
+    // Delivers the notification to the webhook and the NATS subject, failures are only logged
+    pub(crate) async fn send(&self, notification: &impl Serialize) {
+        if let Some(webhook) = &self.webhook {
+            if let Err(e) = send_webhook(webhook, notification).await {
+                tracing::error!("Could not notify webhook: {:?}", e);
+            }
+        }
+        if let Some(nats) = &self.nats {
+            if let Err(e) = send_nats(nats, notification).await {
+                tracing::error!(subject = nats.subject, "Could not notify NATS: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn send_webhook(webhook: &str, notification: &impl Serialize) -> Result<()> {
+    reqwest::Client::new()
+        .post(webhook)
+        .timeout(Duration::from_secs(30))
+        .json(notification)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn send_nats(nats: &NatsNotifications, notification: &impl Serialize) -> Result<()> {
+    // Notifications are rare enough to connect for each of them
+    let client = crate::remote_nats::connect(&nats.url).await?;
+    client
+        .publish(
+            nats.subject.clone(),
+            serde_json::to_vec(notification)?.into(),
+        )
+        .await?;
+    client.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let notifications = |webhook: &str| Notifications {
+            webhook: Some(webhook.to_string()),
+            nats: None,
+        };
+        assert!(notifications("https://example.com/hooks/derrick")
+            .validate()
+            .is_ok());
+        assert!(notifications("example.com/hooks").validate().is_err());
+        assert_eq!(
+            notifications("file:///etc/passwd")
+                .validate()
+                .unwrap_err()
+                .to_string(),
+            "Webhook file:///etc/passwd is not an http url"
+        );
+        assert!(Notifications::default().validate().is_ok());
+    }
+}
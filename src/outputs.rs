@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use shell_escape::escape;
+
+use crate::workspace_controllers::git::try_output;
+use crate::WorkspaceController;
+
+// Directory in the workspace the output of commands is written to, unless the controller has one
+// of its own
+pub(crate) const OUTPUTS_DIR: &str = "/tmp/derrick-outputs";
+
+// Output of a command that was written to a file in the workspace, of which only the start is
+// returned inline
+#[derive(Debug)]
+pub struct SpilledOutput {
+    // Identifies the output file, see `read_output`
+    pub cmd_id: String,
+    pub output: String,
+    pub exit_code: i32,
+    // Size of the whole output in bytes
+    pub size: u64,
+    // Whether `output` is only the start of the output
+    pub truncated: bool,
+}
+
+// Where the output is kept in the directory of the workspace, see
+// `WorkspaceController::outputs_dir`
+fn output_path(controller: &dyn WorkspaceController, cmd_id: &str) -> Result<String> {
+    // The id ends up in a command, so only ids like the ones we generate are accepted
+    let cmd_id = uuid::Uuid::parse_str(cmd_id).context("Invalid command id")?;
+    let path = format!("{}/{}.log", controller.outputs_dir(), cmd_id);
+    Ok(escape(path.into()).to_string())
+}
+
+// Runs a command with its output (stdout and stderr) written to a file instead of kept in
+// memory, and returns at most `max_inline` bytes of it
+pub(crate) async fn cmd_with_spilled_output(
+    controller: &dyn WorkspaceController,
+    cmd: &str,
+    working_dir: Option<&str>,
+    env: HashMap<String, String>,
+    timeout: Option<Duration>,
+    max_inline: usize,
+) -> Result<SpilledOutput> {
+    let cmd_id = uuid::Uuid::new_v4().to_string();
+    let path = output_path(controller, &cmd_id)?;
+
+    // The wrapper prints the size of the output and the exit code of the command, and succeeds
+    // itself, as controllers differ in how they report failed commands. The subshell keeps an
+    // `exit` in the command from skipping the rest.
+    let wrapped = format!(
+        "mkdir -p {dir} && (\n{cmd}\n) > {path} 2>&1; code=$?; wc -c < {path}; echo $code",
+        dir = escape(controller.outputs_dir().into()),
+        cmd = cmd,
+        path = path
+    );
+    let result = controller
+        .cmd_with_output(&wrapped, working_dir, env, timeout)
+        .await?;
+
+    let mut lines = result.output.lines().map(str::trim);
+    let (size, exit_code) = match (
+        lines.next().and_then(|size| size.parse().ok()),
+        lines.next().and_then(|code| code.parse().ok()),
+    ) {
+        (Some(size), Some(exit_code)) => (size, exit_code),
+        // The wrapper itself was killed, e.g. by the timeout
+        _ => (
+            output_size(controller, &cmd_id).await.unwrap_or(0),
+            result.exit_code,
+        ),
+    };
+
+    let inline = size.min(max_inline as u64);
+    let output = if inline > 0 {
+        read_output(controller, &cmd_id, 0, inline).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(SpilledOutput {
+        cmd_id,
+        output: String::from_utf8_lossy(&output).to_string(),
+        exit_code,
+        size,
+        truncated: size > inline,
+    })
+}
+
+// Size in bytes of the output of a command run with `cmd_with_spilled_output`
+pub(crate) async fn output_size(controller: &dyn WorkspaceController, cmd_id: &str) -> Result<u64> {
+    let path = output_path(controller, cmd_id)?;
+    try_output(controller, &format!("wc -c < {}", path))
+        .await
+        .and_then(|size| size.trim().parse().ok())
+        .with_context(|| format!("Output not found: {}", cmd_id))
+}
+
+// Reads `len` bytes of an output starting at `start`. The bytes are base64 encoded in the
+// workspace, as commands return their output as text.
+pub(crate) async fn read_output(
+    controller: &dyn WorkspaceController,
+    cmd_id: &str,
+    start: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let path = output_path(controller, cmd_id)?;
+    let encoded = try_output(
+        controller,
+        &format!("tail -c +{} {} | head -c {} | base64", start + 1, path, len),
+    )
+    .await
+    .with_context(|| format!("Could not read output {}", cmd_id))?;
+
+    let encoded: String = encoded.split_whitespace().collect();
+    Ok(base64::engine::general_purpose::STANDARD.decode(encoded)?)
+}
+
+// Parses a single range of a `Range: bytes=...` header into the first and last byte (inclusive)
+// of an output of `size` bytes
+pub(crate) fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') || size == 0 {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+
+    let (start, end) = match (start.trim(), end.trim()) {
+        // The last bytes, e.g. `bytes=-500`
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size - 1)
+        }
+        (start, "") => (start.parse().ok()?, size - 1),
+        (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(size - 1)),
+    };
+
+    (start <= end && start < size).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), None);
+        assert_eq!(parse_range("lines=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_rejects_ids_that_are_not_generated() {
+        let controller = TestingController::new("outputs-ids");
+        assert!(output_path(&controller, "../../etc/passwd").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_spills_and_reads_output() {
+        let controller = TestingController::new("outputs");
+
+        let output = cmd_with_spilled_output(
+            &controller,
+            "seq 1 1000; exit 3",
+            None,
+            HashMap::new(),
+            None,
+            10,
+        )
+        .await
+        .unwrap();
+        assert_eq!(output.output, "1\n2\n3\n4\n5\n");
+        assert_eq!(output.exit_code, 3);
+        assert!(output.truncated);
+        assert_eq!(output.size, 3893);
+
+        assert_eq!(
+            output_size(&controller, &output.cmd_id).await.unwrap(),
+            3893
+        );
+        let tail = read_output(&controller, &output.cmd_id, 3889, 4)
+            .await
+            .unwrap();
+        assert_eq!(tail, b"000\n");
+    }
+}
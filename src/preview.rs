@@ -0,0 +1,192 @@
+use anyhow::{Context, Result};
+use dropshot::Body;
+use http::{HeaderMap, HeaderValue, Method, Response};
+
+use crate::auth::Role;
+use crate::egress_proxy::HOP_BY_HOP;
+
+// Cookie the access token of a preview is kept in once it was given as a query parameter, so
+// that the pages and assets of the app are authorized as well
+pub(crate) const TOKEN_COOKIE: &str = "derrick_preview_token";
+// Pages of a preview run with an origin of their own instead of the one of the server, so that
+// they cannot read the token of the dashboard or call the API with it
+const CONTENT_SECURITY_POLICY: &str = "sandbox allow-scripts allow-forms";
+
+// Viewers can look at a preview, but requests that may change something in the workspace (or on
+// the host, for local workspaces) need an operator
+pub(crate) fn required_role(method: &Method) -> Role {
+    if method == Method::GET || method == Method::HEAD {
+        Role::Viewer
+    } else {
+        Role::Operator
+    }
+}
+
+// Forwards a request to the port of a workspace, which the server reaches at `address`. The path
+// is what follows `/preview/{port}` in the url of the request.
+pub(crate) async fn forward(
+    address: &str,
+    port: u16,
+    method: Method,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<Response<Body>> {
+    let url = upstream_url(address, port, path, query)?;
+    // Redirects are for the browser to follow
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let mut forwarded = client.request(method, url).body(body);
+    for (name, value) in headers {
+        if HOP_BY_HOP.contains(&name.as_str()) || name == http::header::AUTHORIZATION {
+            continue;
+        }
+        if name == http::header::COOKIE {
+            if let Some(cookies) = value.to_str().ok().and_then(strip_token_cookie) {
+                forwarded = forwarded.header(name, cookies);
+            }
+            continue;
+        }
+        forwarded = forwarded.header(name, value);
+    }
+    let upstream = forwarded
+        .send()
+        .await
+        .with_context(|| format!("Nothing answers on port {} of the workspace", port))?;
+
+    let mut response = Response::builder().status(upstream.status());
+    if let Some(headers) = response.headers_mut() {
+        *headers = response_headers(upstream.headers());
+    }
+    let body = upstream.bytes().await?;
+    Ok(response.body(Body::from(body.to_vec()))?)
+}
+
+// Cookies of the app would be set for the server, so they are left out
+fn response_headers(upstream: &HeaderMap) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in upstream {
+        if !HOP_BY_HOP.contains(&name.as_str()) && name != http::header::SET_COOKIE {
+            headers.append(name, value.clone());
+        }
+    }
+    headers.append(
+        http::header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CONTENT_SECURITY_POLICY),
+    );
+    headers
+}
+
+// The access token is left out of the query, the app has no use for it
+fn upstream_url(address: &str, port: u16, path: &str, query: Option<&str>) -> Result<url::Url> {
+    let mut url = url::Url::parse(&format!("http://{}:{}", address, port))?;
+    url.set_path(path);
+    if let Some(query) = query {
+        if url::form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == "access_token") {
+            let pairs = url::form_urlencoded::parse(query.as_bytes())
+                .filter(|(key, _)| key != "access_token")
+                .collect::<Vec<_>>();
+            if !pairs.is_empty() {
+                url.query_pairs_mut().extend_pairs(pairs);
+            }
+        } else {
+            url.set_query(Some(query));
+        }
+    }
+    Ok(url)
+}
+
+// The cookies of a request without the one with the access token, None when nothing is left
+fn strip_token_cookie(cookies: &str) -> Option<String> {
+    let kept = cookies
+        .split(';')
+        .map(str::trim)
+        .filter(|cookie| !cookie.is_empty() && cookie.split('=').next() != Some(TOKEN_COOKIE))
+        .collect::<Vec<_>>();
+    (!kept.is_empty()).then(|| kept.join("; "))
+}
+
+// The access token of the cookie, for requests of the app that do not have it in their url
+pub(crate) fn token_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == TOKEN_COOKIE).then(|| value.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upstream_url() {
+        let url = upstream_url("172.17.0.2", 3000, "/assets/app.js", Some("v=1")).unwrap();
+        assert_eq!(url.as_str(), "http://172.17.0.2:3000/assets/app.js?v=1");
+
+        let url =
+            upstream_url("172.17.0.2", 3000, "/", Some("access_token=secret&page=2")).unwrap();
+        assert_eq!(url.as_str(), "http://172.17.0.2:3000/?page=2");
+
+        let url = upstream_url("127.0.0.1", 8080, "/", Some("access_token=secret")).unwrap();
+        assert_eq!(url.as_str(), "http://127.0.0.1:8080/");
+    }
+
+    #[test]
+    fn test_viewers_cannot_post() {
+        let tokens = crate::auth::ApiTokens::new(vec![crate::ApiToken {
+            name: "dashboard".to_string(),
+            token: "secret".to_string(),
+            role: Role::Viewer,
+        }]);
+        assert!(tokens
+            .authorize(Some("secret"), required_role(&Method::GET))
+            .is_ok());
+        assert!(tokens
+            .authorize(Some("secret"), required_role(&Method::POST))
+            .is_err());
+    }
+
+    #[test]
+    fn test_response_headers() {
+        let mut upstream = HeaderMap::new();
+        upstream.insert(http::header::CONTENT_TYPE, "text/html".parse().unwrap());
+        upstream.insert(http::header::SET_COOKIE, "session=abc".parse().unwrap());
+        upstream.insert(http::header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+
+        let headers = response_headers(&upstream);
+        assert_eq!(headers[http::header::CONTENT_TYPE], "text/html");
+        assert!(!headers.contains_key(http::header::SET_COOKIE));
+        assert!(!headers.contains_key(http::header::TRANSFER_ENCODING));
+        assert_eq!(
+            headers[http::header::CONTENT_SECURITY_POLICY],
+            "sandbox allow-scripts allow-forms"
+        );
+    }
+
+    #[test]
+    fn test_token_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::COOKIE,
+            format!("session=abc; {}=secret; theme=dark", TOKEN_COOKIE)
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(token_cookie(&headers).as_deref(), Some("secret"));
+        assert_eq!(
+            strip_token_cookie(headers[http::header::COOKIE].to_str().unwrap()).as_deref(),
+            Some("session=abc; theme=dark")
+        );
+        assert_eq!(
+            strip_token_cookie(&format!("{}=secret", TOKEN_COOKIE)),
+            None
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::command_history::CommandRecord;
+use crate::WorkspaceContext;
+
+// Where a change made in a workspace comes from, so that commits and pull requests made by
+// agents can be traced back to the workspace, what it was built from and the commands run in it.
+// Attached to commits as git trailers and to pull requests as a section of their description.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Provenance {
+    pub workspace_id: String,
+    // Of the repositories and scripts the workspace was built from, `sha256:<hex>`
+    pub context_hash: String,
+    // Of the commands the server recorded for the workspace, in order, `sha256:<hex>`
+    pub command_history_digest: String,
+    // HMAC of the other fields with the provenance key of the server, `hmac-sha256:<hex>`.
+    // Not set when the server has no key.
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+impl Provenance {
+    pub fn new(workspace_id: &str, context: &WorkspaceContext, commands: &[CommandRecord]) -> Self {
+        Self {
+            workspace_id: workspace_id.to_string(),
+            context_hash: context_hash(context),
+            command_history_digest: command_history_digest(commands),
+            signature: None,
+        }
+    }
+
+    pub fn sign(mut self, key: &[u8]) -> Result<Self> {
+        let signature = self.mac(key)?.finalize().into_bytes();
+        self.signature = Some(format!("hmac-sha256:{}", hex::encode(signature)));
+        Ok(self)
+    }
+
+    // Whether the provenance was signed with the key and not changed since
+    pub fn verify(&self, key: &[u8]) -> Result<()> {
+        let signature = self
+            .signature
+            .as_deref()
+            .context("Provenance is not signed")?
+            .strip_prefix("hmac-sha256:")
+            .context("Unsupported signature format")?;
+        let signature = hex::decode(signature).context("Signature is not valid hex")?;
+        self.mac(key)?
+            .verify_slice(&signature)
+            .map_err(|_| anyhow::anyhow!("Signature does not match"))
+    }
+
+    fn mac(&self, key: &[u8]) -> Result<Hmac<Sha256>> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(key).context("Could not create hmac from key")?;
+        mac.update(
+            format!(
+                "{}\n{}\n{}",
+                self.workspace_id, self.context_hash, self.command_history_digest
+            )
+            .as_bytes(),
+        );
+        Ok(mac)
+    }
+
+    // Git trailers, like `Derrick-Workspace: <id>`
+    pub fn trailers(&self) -> Vec<(&'static str, String)> {
+        let mut trailers = vec![
+            ("Derrick-Workspace", self.workspace_id.clone()),
+            ("Derrick-Context", self.context_hash.clone()),
+            ("Derrick-Commands", self.command_history_digest.clone()),
+        ];
+        if let Some(signature) = &self.signature {
+            trailers.push(("Derrick-Signature", signature.clone()));
+        }
+        trailers
+    }
+
+    // The commit message with the trailers. They are added as a paragraph of their own, unless
+    // the message already ends with trailers.
+    pub fn commit_message(&self, message: &str) -> String {
+        let message = message.trim_end();
+        let ends_with_trailers = message.rsplit_once("\n\n").is_some_and(|(_, last)| {
+            last.lines()
+                .all(|line| line.split_once(": ").is_some_and(|(key, _)| is_token(key)))
+        });
+        let separator = if ends_with_trailers { "\n" } else { "\n\n" };
+        let trailers = self
+            .trailers()
+            .into_iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{}{}{}\n", message, separator, trailers)
+    }
+
+    // The pull request description with the trailers in a section at the end
+    pub fn pull_request_description(&self, description: &str) -> String {
+        let trailers = self
+            .trailers()
+            .into_iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!(
+            "{}\n\n<details>\n<summary>Provenance</summary>\n\n```\n{}\n```\n</details>\n",
+            description.trim_end(),
+            trailers
+        )
+    }
+}
+
+// Trailer keys are a single token like `Signed-off-by`
+fn is_token(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+// Hashes what determines the files of a workspace. Settings like limits and the network policy
+// are left out, as is everything that is a map without a stable order.
+fn context_hash(context: &WorkspaceContext) -> String {
+    let mut hasher = Sha256::new();
+    let mut field = |value: &str| {
+        hasher.update((value.len() as u64).to_be_bytes());
+        hasher.update(value);
+    };
+    field(&context.name);
+    field(context.profile.as_deref().unwrap_or_default());
+    field(context.provisioning_mode.as_deref().unwrap_or_default());
+    for repository in &context.repositories {
+        field(&repository.url);
+        field(&repository.path);
+        field(repository.reference.as_deref().unwrap_or_default());
+    }
+    for step in context.steps() {
+        field(&step.name);
+        field(&step.script);
+    }
+    field(context.post_create_script.as_deref().unwrap_or_default());
+    format!("sha256:{}", hex::encode(hasher.finalize()))
+}
+
+fn command_history_digest(commands: &[CommandRecord]) -> String {
+    let mut hasher = Sha256::new();
+    for command in commands {
+        // Records serialize their fields in a fixed order
+        let record = serde_json::to_vec(command).unwrap_or_default();
+        hasher.update((record.len() as u64).to_be_bytes());
+        hasher.update(record);
+    }
+    format!("sha256:{}", hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provenance() -> Provenance {
+        let context = WorkspaceContext {
+            name: "app".to_string(),
+            setup_script: Some("cargo fetch".to_string()),
+            ..Default::default()
+        };
+        Provenance::new("3f0c", &context, &[])
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let provenance = provenance().sign(b"key").unwrap();
+        assert!(provenance.verify(b"key").is_ok());
+        assert!(provenance.verify(b"other").is_err());
+
+        let tampered = Provenance {
+            workspace_id: "a1b2".to_string(),
+            ..provenance.clone()
+        };
+        assert!(tampered.verify(b"key").is_err());
+        assert!(self::provenance().verify(b"key").is_err());
+    }
+
+    #[test]
+    fn test_commit_message() {
+        let provenance = provenance();
+        let message = provenance.commit_message("Fix parser\n\nHandles empty input.\n");
+        let (body, trailers) = message.rsplit_once("\n\n").unwrap();
+        assert_eq!(body, "Fix parser\n\nHandles empty input.");
+        assert_eq!(trailers.lines().next().unwrap(), "Derrick-Workspace: 3f0c");
+        assert_eq!(trailers.lines().count(), 3);
+
+        // Joins trailers that are already there
+        let message =
+            provenance.commit_message("Fix parser\n\nSigned-off-by: Bot <bot@example.com>");
+        assert!(message.contains("bot@example.com>\nDerrick-Workspace: 3f0c\n"));
+    }
+
+    #[test]
+    fn test_context_hash() {
+        let context = WorkspaceContext {
+            name: "app".to_string(),
+            setup_script: Some("cargo fetch".to_string()),
+            ..Default::default()
+        };
+        let changed = WorkspaceContext {
+            setup_script: Some("cargo build".to_string()),
+            ..context.clone()
+        };
+        assert_eq!(context_hash(&context), provenance().context_hash);
+        assert_ne!(context_hash(&context), context_hash(&changed));
+    }
+}
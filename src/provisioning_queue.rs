@@ -0,0 +1,175 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::ProgressReporter;
+
+pub(crate) const DEFAULT_MAX_CONCURRENT_PROVISIONS: usize = 4;
+pub(crate) const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// Limits how many workspaces are provisioned at the same time. Workspaces that have to wait are
+// served in the order they arrived, and their status reports their position in the queue.
+pub(crate) struct ProvisioningQueue {
+    max_concurrent: usize,
+    timeout: Duration,
+    slots: Arc<Semaphore>,
+    waiting: Mutex<VecDeque<(String, ProgressReporter)>>,
+}
+
+// Error returned when a workspace waited longer than the queue timeout for a provisioning slot.
+// Use `error.downcast_ref::<QueueTimeout>()` to tell it apart from a failed provision.
+#[derive(Debug, Clone)]
+pub struct QueueTimeout {
+    pub waited: Duration,
+}
+
+impl fmt::Display for QueueTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Timed out after {}s waiting for a provisioning slot",
+            self.waited.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for QueueTimeout {}
+
+impl Default for ProvisioningQueue {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_CONCURRENT_PROVISIONS, DEFAULT_QUEUE_TIMEOUT)
+    }
+}
+
+impl ProvisioningQueue {
+    pub(crate) fn new(max_concurrent: usize, timeout: Duration) -> Self {
+        // With no slots nothing would ever be provisioned
+        let max_concurrent = max_concurrent.max(1);
+        Self {
+            max_concurrent,
+            timeout,
+            slots: Arc::new(Semaphore::new(max_concurrent)),
+            waiting: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Waits for a provisioning slot, which is released when the permit is dropped
+    pub(crate) async fn acquire(
+        &self,
+        id: &str,
+        progress: &ProgressReporter,
+    ) -> Result<OwnedSemaphorePermit> {
+        if let Ok(permit) = Arc::clone(&self.slots).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        self.waiting
+            .lock()
+            .unwrap()
+            .push_back((id.to_string(), progress.clone()));
+        self.report_positions();
+
+        let result =
+            tokio::time::timeout(self.timeout, Arc::clone(&self.slots).acquire_owned()).await;
+
+        self.waiting
+            .lock()
+            .unwrap()
+            .retain(|(waiting_id, _)| waiting_id != id);
+        self.report_positions();
+
+        match result {
+            Ok(permit) => Ok(permit?),
+            Err(_) => Err(QueueTimeout {
+                waited: self.timeout,
+            }
+            .into()),
+        }
+    }
+
+    fn report_positions(&self) {
+        for (position, (_, progress)) in self.waiting.lock().unwrap().iter().enumerate() {
+            progress.queued(position + 1);
+        }
+    }
+
+    // Number of workspaces waiting for a slot
+    pub(crate) fn depth(&self) -> usize {
+        self.waiting.lock().unwrap().len()
+    }
+
+    // Number of workspaces being provisioned
+    pub(crate) fn in_progress(&self) -> usize {
+        self.max_concurrent - self.slots.available_permits()
+    }
+
+    pub(crate) fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProvisioningPhase;
+
+    #[tokio::test]
+    async fn test_queues_beyond_max_concurrent() {
+        let queue = Arc::new(ProvisioningQueue::new(1, Duration::from_secs(5)));
+        let first = queue
+            .acquire("first", &ProgressReporter::default())
+            .await
+            .unwrap();
+        assert_eq!(queue.in_progress(), 1);
+
+        let second_progress = ProgressReporter::default();
+        let third_progress = ProgressReporter::default();
+        let second = tokio::spawn({
+            let (queue, progress) = (Arc::clone(&queue), second_progress.clone());
+            async move { queue.acquire("second", &progress).await }
+        });
+        while queue.depth() < 1 {
+            tokio::task::yield_now().await;
+        }
+        let third = tokio::spawn({
+            let (queue, progress) = (Arc::clone(&queue), third_progress.clone());
+            async move { queue.acquire("third", &progress).await }
+        });
+        while queue.depth() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(second_progress.status().phase, ProvisioningPhase::Queued);
+        assert_eq!(second_progress.status().queue_position, Some(1));
+        assert_eq!(third_progress.status().queue_position, Some(2));
+
+        drop(first);
+        let second = second.await.unwrap().unwrap();
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(third_progress.status().queue_position, Some(1));
+
+        drop(second);
+        third.await.unwrap().unwrap();
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_times_out_in_queue() {
+        let queue = ProvisioningQueue::new(1, Duration::from_millis(10));
+        let _first = queue
+            .acquire("first", &ProgressReporter::default())
+            .await
+            .unwrap();
+
+        let error = queue
+            .acquire("second", &ProgressReporter::default())
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<QueueTimeout>().is_some());
+        assert_eq!(queue.depth(), 0);
+    }
+}
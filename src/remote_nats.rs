@@ -0,0 +1,316 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::credential_providers::{credential_provider, CredentialSource};
+use crate::workspace_controllers::{CommandOutput, FileNotFound, LimitExceeded};
+
+// Subject a server in the nats server mode takes create requests on, unless it is given another.
+// Requests for a workspace go to a subject of the server that created it, so several servers can
+// share the subject and take turns creating workspaces.
+pub const DEFAULT_SUBJECT: &str = "derrick";
+
+// Commands get this long on top of their own timeout for the answer to arrive
+const ANSWER_MARGIN: Duration = Duration::from_secs(30);
+
+// Where the NATS credentials (base64 encoded) come from: NATS_CREDS, or the credential source in
+// DERRICK_NATS_CREDS_SOURCE as JSON, like `{"type": "vault", "path": "secret/data/nats", "field":
+// "creds"}`. Without either, connections are not authenticated.
+fn credential_source() -> Result<Option<CredentialSource>> {
+    if std::env::var_os("NATS_CREDS").is_some() {
+        return Ok(Some(CredentialSource::Env {
+            env: "NATS_CREDS".to_string(),
+        }));
+    }
+    std::env::var("DERRICK_NATS_CREDS_SOURCE")
+        .ok()
+        .map(|source| serde_json::from_str(&source))
+        .transpose()
+        .context("Invalid DERRICK_NATS_CREDS_SOURCE")
+}
+
+pub(crate) async fn connect(url: &str) -> Result<async_nats::Client> {
+    let options = match credential_source()? {
+        Some(source) => {
+            let creds = credential_provider(&source).credential().await?;
+            let creds = base64::prelude::BASE64_STANDARD
+                .decode(creds.secret)
+                .context("NATS credentials are not base64")?;
+            async_nats::ConnectOptions::with_credentials(&String::from_utf8(creds)?)?
+        }
+        None => async_nats::ConnectOptions::new(),
+    };
+    options
+        .connect(url)
+        .await
+        .with_context(|| format!("Could not connect to NATS at {}", url))
+}
+
+// What one derrick asks of another over NATS, as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Request {
+    // Answered with `Created`, the workspace is provisioned with the context of the remote server
+    Create {
+        env: HashMap<String, String>,
+    },
+    Destroy {
+        id: String,
+    },
+    Cmd {
+        id: String,
+        cmd: RemoteCommand,
+    },
+    CmdWithOutput {
+        id: String,
+        cmd: RemoteCommand,
+    },
+    CmdWithLimits {
+        id: String,
+        cmd: RemoteCommand,
+        cpu_time_ms: Option<u64>,
+        max_output_bytes: Option<usize>,
+    },
+    WriteFile {
+        id: String,
+        path: String,
+        // Base64 encoded
+        content: String,
+        working_dir: Option<String>,
+        mode: Option<u32>,
+    },
+    AppendFile {
+        id: String,
+        path: String,
+        content: String,
+        working_dir: Option<String>,
+    },
+    ReadFile {
+        id: String,
+        path: String,
+        working_dir: Option<String>,
+    },
+    DiskUsage {
+        id: String,
+    },
+    Health {
+        id: String,
+    },
+}
+
+impl Request {
+    // How long to wait for the answer, None for as long as it takes. Creating waits for the
+    // provisioning queue of the remote server, which times out on its own.
+    fn timeout(&self) -> Option<Duration> {
+        match self {
+            Request::Create { .. } => None,
+            Request::Cmd { cmd, .. }
+            | Request::CmdWithOutput { cmd, .. }
+            | Request::CmdWithLimits { cmd, .. } => cmd
+                .timeout_ms
+                .map(|timeout| Duration::from_millis(timeout) + ANSWER_MARGIN),
+            _ => Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RemoteCommand {
+    pub cmd: String,
+    pub working_dir: Option<String>,
+    pub env: HashMap<String, String>,
+    pub timeout_ms: Option<u64>,
+}
+
+impl RemoteCommand {
+    pub fn new(
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            cmd: cmd.to_string(),
+            working_dir: working_dir.map(str::to_string),
+            env,
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+        }
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+}
+
+// The answer to `Request::Create`, later requests for the workspace go to `subject`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Created {
+    pub id: String,
+    pub subject: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RemoteOutput {
+    pub output: String,
+    // Base64 encoded
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: i32,
+    pub limit_exceeded: Option<LimitExceeded>,
+}
+
+impl From<CommandOutput> for RemoteOutput {
+    fn from(output: CommandOutput) -> Self {
+        Self {
+            output: output.output,
+            stdout: base64::prelude::BASE64_STANDARD.encode(output.stdout),
+            stderr: base64::prelude::BASE64_STANDARD.encode(output.stderr),
+            exit_code: output.exit_code,
+            limit_exceeded: output.limit_exceeded,
+        }
+    }
+}
+
+impl TryFrom<RemoteOutput> for CommandOutput {
+    type Error = anyhow::Error;
+
+    fn try_from(output: RemoteOutput) -> Result<Self> {
+        Ok(Self {
+            output: output.output,
+            stdout: base64::prelude::BASE64_STANDARD.decode(output.stdout)?,
+            stderr: base64::prelude::BASE64_STANDARD.decode(output.stderr)?,
+            exit_code: output.exit_code,
+            limit_exceeded: output.limit_exceeded,
+        })
+    }
+}
+
+// The answer to every request, errors keep whether a file was missing so that it is still a 404
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Response {
+    Ok(serde_json::Value),
+    Error {
+        message: String,
+        #[serde(default)]
+        file_not_found: Option<String>,
+    },
+}
+
+impl Response {
+    pub fn from_result(result: Result<serde_json::Value>) -> Self {
+        match result {
+            Ok(value) => Response::Ok(value),
+            Err(e) => Response::Error {
+                message: format!("{:#}", e),
+                file_not_found: e.downcast_ref::<FileNotFound>().map(|e| e.path.clone()),
+            },
+        }
+    }
+
+    fn into_result<T: DeserializeOwned>(self) -> Result<T> {
+        match self {
+            Response::Ok(value) => {
+                serde_json::from_value(value).context("Unexpected answer from the remote server")
+            }
+            Response::Error {
+                file_not_found: Some(path),
+                ..
+            } => Err(FileNotFound { path }.into()),
+            Response::Error { message, .. } => Err(anyhow::anyhow!(message)),
+        }
+    }
+}
+
+// Sends the request to `subject` and waits for the answer of the server
+pub(crate) async fn request<T: DeserializeOwned>(
+    client: &async_nats::Client,
+    subject: &str,
+    request: &Request,
+) -> Result<T> {
+    let payload = serde_json::to_vec(request)?;
+    let message = client
+        .send_request(
+            subject.to_string(),
+            async_nats::client::Request::new()
+                .payload(payload.into())
+                .timeout(request.timeout()),
+        )
+        .await
+        .with_context(|| format!("No answer from a derrick server on {}", subject))?;
+    serde_json::from_slice::<Response>(&message.payload)
+        .context("Invalid answer from the remote server")?
+        .into_result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_format() {
+        let request = Request::CmdWithOutput {
+            id: "3f0c".to_string(),
+            cmd: RemoteCommand::new(
+                "cargo test",
+                Some("/app"),
+                HashMap::new(),
+                Some(Duration::from_secs(5)),
+            ),
+        };
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["op"], "cmd_with_output");
+        assert_eq!(json["cmd"]["timeout_ms"], 5000);
+        assert_eq!(serde_json::from_value::<Request>(json).unwrap(), request);
+        assert_eq!(request.timeout(), Some(Duration::from_secs(35)));
+        assert_eq!(
+            Request::Create {
+                env: HashMap::new()
+            }
+            .timeout(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_response_errors() {
+        let response = Response::from_result(Err(FileNotFound {
+            path: "/tmp/missing".to_string(),
+        }
+        .into()));
+        let json = serde_json::to_string(&response).unwrap();
+        let error = serde_json::from_str::<Response>(&json)
+            .unwrap()
+            .into_result::<()>()
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FileNotFound>().unwrap().path,
+            "/tmp/missing"
+        );
+
+        let response = Response::from_result(Err(anyhow::anyhow!("Workspace not found: 3f0c")));
+        let error = response.into_result::<()>().unwrap_err();
+        assert_eq!(error.to_string(), "Workspace not found: 3f0c");
+    }
+
+    #[test]
+    fn test_output_round_trip() {
+        let output = CommandOutput {
+            output: "ok\n".to_string(),
+            stdout: vec![0xff, b'o', b'k'],
+            stderr: Vec::new(),
+            exit_code: 1,
+            limit_exceeded: Some(LimitExceeded::OutputSize),
+        };
+        let remote: RemoteOutput =
+            serde_json::from_str(&serde_json::to_string(&RemoteOutput::from(output)).unwrap())
+                .unwrap();
+        let output = CommandOutput::try_from(remote).unwrap();
+        assert_eq!(output.stdout, vec![0xff, b'o', b'k']);
+        assert_eq!(output.exit_code, 1);
+        assert_eq!(output.limit_exceeded, Some(LimitExceeded::OutputSize));
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::io::Read;
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+
+use crate::file_tree::{list_entries, TreeOptions};
+use crate::workspace_controllers::FileWrite;
+use crate::WorkspaceController;
+
+// Replacements across more files are refused, they should be narrowed down with a path or glob
+const MAX_REPLACE_FILES: usize = 1000;
+// Lines around the changes in the diff
+const DIFF_CONTEXT: usize = 3;
+
+// Replaces a pattern in the files of a directory, like `{"pattern": "fn (\\w+)_old", "replacement":
+// "fn ${1}_new", "glob": "*.rs"}`
+#[derive(Debug, Clone, Deserialize, JsonSchema)]
+pub struct Replacement {
+    // A regular expression, or text with `literal`
+    pub pattern: String,
+    // `$1` and `${name}` are the groups of the pattern, unless it is literal
+    pub replacement: String,
+    #[serde(default)]
+    pub literal: bool,
+    // Only files that match, like `*.rs` or `src/**/*.rs`. All files by default.
+    #[serde(default)]
+    pub glob: Option<String>,
+    // Directory the files are in, relative to the working directory. Defaults to the working
+    // directory.
+    #[serde(default)]
+    pub path: Option<String>,
+    // Also replaces in files git ignores, which are left alone by default
+    #[serde(default)]
+    pub include_ignored: bool,
+    // Only returns the diff, without changing the files
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct FileReplacements {
+    pub path: String,
+    pub replacements: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct ReplaceResult {
+    // The files with matches, relative to the working directory
+    pub files: Vec<FileReplacements>,
+    pub replacements: usize,
+    // Unified diff of the changes, which were not made with `dry_run`
+    pub diff: String,
+    pub dry_run: bool,
+}
+
+// More files match than are replaced in one request
+#[derive(Debug)]
+pub struct TooManyFiles {
+    pub files: usize,
+}
+
+impl std::fmt::Display for TooManyFiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} files match, narrow them down to at most {} with a path or glob",
+            self.files, MAX_REPLACE_FILES
+        )
+    }
+}
+
+impl std::error::Error for TooManyFiles {}
+
+impl Replacement {
+    pub fn regex(&self) -> Result<Regex> {
+        let pattern = if self.literal {
+            regex::escape(&self.pattern)
+        } else {
+            self.pattern.clone()
+        };
+        Regex::new(&pattern).with_context(|| format!("Invalid pattern {}", self.pattern))
+    }
+}
+
+// Replaces the pattern in the files, skipping binary ones. The files are read in one go, and the
+// changed ones written in another.
+#[tracing::instrument(skip_all, fields(pattern = replacement.pattern))]
+pub(crate) async fn replace(
+    controller: &dyn WorkspaceController,
+    replacement: &Replacement,
+    working_dir: Option<&str>,
+) -> Result<ReplaceResult> {
+    let regex = replacement.regex()?;
+    let dir = replacement.path.as_deref().unwrap_or(".");
+    let options = TreeOptions {
+        depth: None,
+        glob: replacement.glob.clone(),
+        gitignore: !replacement.include_ignored,
+    };
+    let files = list_entries(controller, dir, &options, working_dir)
+        .await?
+        .into_iter()
+        .filter(|(_, is_dir)| !is_dir)
+        .map(|(file, _)| file)
+        .collect::<Vec<_>>();
+    if files.len() > MAX_REPLACE_FILES {
+        return Err(TooManyFiles { files: files.len() }.into());
+    }
+
+    let mut result = ReplaceResult {
+        files: Vec::new(),
+        replacements: 0,
+        diff: String::new(),
+        dry_run: replacement.dry_run,
+    };
+    let mut writes = Vec::new();
+    for (file, content) in read_files(controller, dir, &files, working_dir).await? {
+        let Ok(content) = String::from_utf8(content) else {
+            continue;
+        };
+        let edits = find_edits(&regex, &content, replacement);
+        if edits.is_empty() {
+            continue;
+        }
+        let path = match dir {
+            "." => file,
+            _ => format!("{}/{}", dir.trim_end_matches('/'), file),
+        };
+        result.diff.push_str(&unified_diff(&path, &content, &edits));
+        result.replacements += edits.len();
+        result.files.push(FileReplacements {
+            path: path.clone(),
+            replacements: edits.len(),
+        });
+        writes.push(FileWrite {
+            path,
+            content: apply_edits(&content, &edits).into_bytes(),
+            mode: None,
+        });
+    }
+
+    if !replacement.dry_run && !writes.is_empty() {
+        controller
+            .write_files(&writes, working_dir)
+            .await
+            .context("Could not write replacements")?;
+    }
+    Ok(result)
+}
+
+// Reads the files of the directory as a tarball, which keeps it to a single command
+pub(crate) async fn read_files(
+    controller: &dyn WorkspaceController,
+    dir: &str,
+    files: &[String],
+    working_dir: Option<&str>,
+) -> Result<Vec<(String, Vec<u8>)>> {
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+    // Prefixed, so that files starting with a dash are not taken as options by tar
+    let list = files
+        .iter()
+        .map(|file| format!("./{}", file))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let cmd = format!(
+        "cd {} && tar -cf - --no-recursion -T - <<'DERRICK_FILES' | base64\n{}\nDERRICK_FILES",
+        escape(dir.into()),
+        list
+    );
+    let output = controller
+        .cmd_with_output(&cmd, working_dir, HashMap::new(), None)
+        .await
+        .context("Could not read files")?;
+    if output.exit_code != 0 {
+        anyhow::bail!("Could not read files: {}", output.output.trim());
+    }
+
+    let encoded: String = output.output.split_whitespace().collect();
+    let tarball = base64::engine::general_purpose::STANDARD.decode(encoded)?;
+    let mut archive = tar::Archive::new(tarball.as_slice());
+    let mut contents = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        contents.push((path.trim_start_matches("./").to_string(), content));
+    }
+    Ok(contents)
+}
+
+// Spans of the content that are replaced, with what replaces them. Matches that would stay the
+// same are left out.
+fn find_edits(
+    regex: &Regex,
+    content: &str,
+    replacement: &Replacement,
+) -> Vec<(usize, usize, String)> {
+    regex
+        .captures_iter(content)
+        .filter_map(|captures| {
+            let found = captures.get(0)?;
+            let mut replaced = String::new();
+            if replacement.literal {
+                replaced.push_str(&replacement.replacement);
+            } else {
+                captures.expand(&replacement.replacement, &mut replaced);
+            }
+            (found.as_str() != replaced).then(|| (found.start(), found.end(), replaced))
+        })
+        .collect()
+}
+
+fn apply_edits(content: &str, edits: &[(usize, usize, String)]) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut offset = 0;
+    for (start, end, replaced) in edits {
+        result.push_str(&content[offset..*start]);
+        result.push_str(replaced);
+        offset = *end;
+    }
+    result.push_str(&content[offset..]);
+    result
+}
+
+// Lines of the original that changed, with the lines that replace them
+struct Block {
+    first: usize,
+    // Exclusive
+    last: usize,
+    lines: Vec<String>,
+}
+
+fn unified_diff(path: &str, content: &str, edits: &[(usize, usize, String)]) -> String {
+    let lines = content.split_inclusive('\n').collect::<Vec<_>>();
+    let mut starts = Vec::with_capacity(lines.len() + 1);
+    let mut offset = 0;
+    for line in &lines {
+        starts.push(offset);
+        offset += line.len();
+    }
+    starts.push(offset);
+    let line_of = |offset: usize| {
+        starts
+            .partition_point(|start| *start <= offset)
+            .saturating_sub(1)
+            .min(lines.len())
+    };
+
+    // Edits on the same lines end up in the same block
+    let mut spans: Vec<(usize, usize, Vec<&(usize, usize, String)>)> = Vec::new();
+    for edit in edits {
+        let (start, end, _) = edit;
+        let first = line_of(*start);
+        // A match that ends with a newline does not touch the line after it
+        let last = if *end > *start && content.as_bytes()[end - 1] == b'\n' {
+            line_of(end - 1) + 1
+        } else {
+            (line_of(*end) + 1).min(lines.len())
+        }
+        .max(first);
+        match spans.last_mut() {
+            Some((_, previous_last, block_edits)) if first < *previous_last => {
+                *previous_last = (*previous_last).max(last);
+                block_edits.push(edit);
+            }
+            _ => spans.push((first, last, vec![edit])),
+        }
+    }
+    let blocks = spans
+        .into_iter()
+        .map(|(first, last, block_edits)| {
+            let mut text = String::new();
+            let mut offset = starts[first];
+            for (start, end, replaced) in block_edits {
+                text.push_str(&content[offset..*start]);
+                text.push_str(replaced);
+                offset = *end;
+            }
+            text.push_str(&content[offset..starts[last]]);
+            Block {
+                first,
+                last,
+                lines: text.split_inclusive('\n').map(str::to_string).collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", path, path);
+    // Lines the new file has more than the original before the hunk
+    let mut delta = 0isize;
+    let mut index = 0;
+    while index < blocks.len() {
+        // Blocks whose context overlaps share a hunk
+        let mut end = index + 1;
+        while end < blocks.len() && blocks[end].first <= blocks[end - 1].last + 2 * DIFF_CONTEXT {
+            end += 1;
+        }
+        let hunk = &blocks[index..end];
+        let hunk_start = hunk[0].first.saturating_sub(DIFF_CONTEXT);
+        let hunk_end = (hunk[hunk.len() - 1].last + DIFF_CONTEXT).min(lines.len());
+
+        let mut body = String::new();
+        let mut cursor = hunk_start;
+        let mut new_count = 0;
+        for block in hunk {
+            for line in &lines[cursor..block.first] {
+                push_line(&mut body, ' ', line);
+                new_count += 1;
+            }
+            for line in &lines[block.first..block.last] {
+                push_line(&mut body, '-', line);
+            }
+            for line in &block.lines {
+                push_line(&mut body, '+', line);
+                new_count += 1;
+            }
+            cursor = block.last;
+        }
+        for line in &lines[cursor..hunk_end] {
+            push_line(&mut body, ' ', line);
+            new_count += 1;
+        }
+
+        let old_count = hunk_end - hunk_start;
+        let old_start = hunk_start + (old_count > 0) as usize;
+        let new_start = (hunk_start as isize + delta) as usize + (new_count > 0) as usize;
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_count, new_start, new_count
+        ));
+        diff.push_str(&body);
+        delta += new_count as isize - old_count as isize;
+        index = end;
+    }
+    diff
+}
+
+fn push_line(diff: &mut String, prefix: char, line: &str) {
+    diff.push(prefix);
+    diff.push_str(line);
+    if !line.ends_with('\n') {
+        diff.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    fn replacement(pattern: &str, replacement: &str, literal: bool) -> Replacement {
+        Replacement {
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            literal,
+            glob: None,
+            path: None,
+            include_ignored: false,
+            dry_run: false,
+        }
+    }
+
+    fn diff(pattern: &str, with: &str, content: &str) -> String {
+        let replacement = replacement(pattern, with, false);
+        let edits = find_edits(&replacement.regex().unwrap(), content, &replacement);
+        unified_diff("file", content, &edits)
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let content = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\nk\nl\nm\n";
+        assert_eq!(
+            diff("b|l", "X", content),
+            "--- a/file\n+++ b/file\n@@ -1,5 +1,5 @@\n a\n-b\n+X\n c\n d\n e\n@@ -9,5 +9,5 @@\n i\n j\n k\n-l\n+X\n m\n"
+        );
+        // Close changes share a hunk, and lines can be added
+        assert_eq!(
+            diff("b|f", "$0\n$0", content),
+            "--- a/file\n+++ b/file\n@@ -1,9 +1,11 @@\n a\n-b\n+b\n+b\n c\n d\n e\n-f\n+f\n+f\n g\n h\n i\n"
+        );
+        assert_eq!(
+            diff("b", "X", "a\nb"),
+            "--- a/file\n+++ b/file\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+X\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_find_edits() {
+        let content = "let old_name = old_name();";
+        let regex_replacement = replacement(r"old_(\w+)", "new_$1", false);
+        let edits = find_edits(
+            &regex_replacement.regex().unwrap(),
+            content,
+            &regex_replacement,
+        );
+        assert_eq!(apply_edits(content, &edits), "let new_name = new_name();");
+
+        let literal = replacement("old_name()", "$1", true);
+        let edits = find_edits(&literal.regex().unwrap(), content, &literal);
+        assert_eq!(apply_edits(content, &edits), "let old_name = $1;");
+        assert!(replacement("(", "", false).regex().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replace() {
+        let controller = TestingController::new("replace");
+        controller
+            .cmd(
+                "git init -q . && mkdir -p src target && printf 'fn old() {}\\n' > src/lib.rs && printf 'old\\n' > notes.txt && printf 'fn old() {}\\n' > target/gen.rs && echo target > .gitignore",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut request = replacement("old", "new", true);
+        request.glob = Some("*.rs".to_string());
+        request.dry_run = true;
+        let result = replace(&controller, &request, None).await.unwrap();
+        assert_eq!(
+            result.files,
+            vec![FileReplacements {
+                path: "src/lib.rs".to_string(),
+                replacements: 1,
+            }]
+        );
+        assert!(result.diff.contains("-fn old() {}\n+fn new() {}\n"));
+        assert_eq!(
+            controller.read_file("src/lib.rs", None).await.unwrap(),
+            b"fn old() {}\n"
+        );
+
+        request.dry_run = false;
+        request.path = Some("src".to_string());
+        let result = replace(&controller, &request, None).await.unwrap();
+        assert_eq!(result.replacements, 1);
+        assert_eq!(
+            controller.read_file("src/lib.rs", None).await.unwrap(),
+            b"fn new() {}\n"
+        );
+        assert_eq!(
+            controller.read_file("target/gen.rs", None).await.unwrap(),
+            b"fn old() {}\n"
+        );
+    }
+}
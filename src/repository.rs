@@ -1,8 +1,11 @@
 use anyhow::Result;
 use derive_builder::Builder;
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Deserialize, Default, PartialEq, Builder)]
+use crate::credentials::{GithubAppSession, RepositoryCredentials};
+
+#[derive(Debug, Clone, Deserialize, JsonSchema, Default, PartialEq, Builder)]
 #[serde(rename_all = "camelCase")]
 #[builder(
     derive(Deserialize, Debug),
@@ -17,6 +20,26 @@ pub struct Repository {
     pub path: String,
     #[builder(default)]
     pub reference: Option<String>,
+    #[builder(default)]
+    pub credentials: Option<RepositoryCredentials>,
+    // Run `git lfs pull` after checking out the repository
+    #[builder(default)]
+    #[serde(default)]
+    pub lfs: bool,
+    // Recursively check out submodules, with the same credentials as the repository
+    #[builder(default)]
+    #[serde(default)]
+    pub submodules: bool,
+    // Only check out these directories (cone mode sparse checkout), for large monorepos
+    #[builder(default)]
+    #[serde(default)]
+    pub sparse_paths: Vec<String>,
+    // Set by the server that provisions the repository, see `GithubAppSession`
+    #[builder(default)]
+    #[builder_field_attr(serde(skip))]
+    #[serde(skip)]
+    #[schemars(skip)]
+    pub github_session: GithubAppSession,
 }
 
 impl Repository {
@@ -35,14 +58,25 @@ impl From<&Repository> for Repository {
     }
 }
 
+impl Repository {
+    // Ssh urls, either scp-like (git@github.com:owner/repo.git) or ssh://
+    pub fn is_ssh_url(&self) -> bool {
+        self.url.starts_with("ssh://") || (!self.url.contains("://") && self.url.contains('@'))
+    }
+}
+
 impl RepositoryBuilder {
     fn default_repository_name(&self) -> Result<String> {
         let mut parts = self
             .url
             .as_ref()
             .ok_or(anyhow::anyhow!("Expected url when building repository"))?
-            .split('/');
+            // Also split on ':' for scp-like ssh urls, e.g. git@github.com:owner/repo.git
+            .split(['/', ':']);
         let last_two = parts.by_ref().rev().take(2).collect::<Vec<&str>>();
+        if last_two.len() < 2 {
+            anyhow::bail!("Could not determine repository name from url")
+        }
 
         Ok(format!(
             "{}/{}",
@@ -51,3 +85,36 @@ impl RepositoryBuilder {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_repository_name() {
+        for url in [
+            "https://github.com/bosun-ai/derrick.git",
+            "git@github.com:bosun-ai/derrick.git",
+            "ssh://git@github.com/bosun-ai/derrick",
+        ] {
+            let builder = Repository::from_url(url);
+            assert_eq!(
+                builder.default_repository_name().unwrap(),
+                "bosun-ai/derrick"
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_ssh_url() {
+        let ssh = Repository::from_url("git@github.com:bosun-ai/derrick.git")
+            .build()
+            .unwrap();
+        assert!(ssh.is_ssh_url());
+
+        let https = Repository::from_url("https://github.com/bosun-ai/derrick.git")
+            .build()
+            .unwrap();
+        assert!(!https.is_ssh_url());
+    }
+}
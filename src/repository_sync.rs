@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::Serialize;
+use shell_escape::escape;
+
+use crate::credentials::{scrub, RepositoryAuth};
+use crate::{Repository, WorkspaceController};
+
+// What syncing changed in a repository of a workspace
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct RepositorySync {
+    pub path: String,
+    // The reference that was fetched, the default branch of the remote when it is `HEAD`
+    pub reference: String,
+    // Commits checked out before and after, None before the first commit
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // Commits the repository was fast-forwarded by
+    pub commits: u32,
+    // Files that differ between `from` and `to`
+    pub files: Vec<String>,
+    // Set when the repository could not be synced, like when it has diverged from the remote or
+    // local changes are in the way. It is left as it was then.
+    pub error: Option<String>,
+}
+
+// Fetches the reference of every repository and fast-forwards the checkout to it. The reference
+// is the given one, or else the one the repository was provisioned with, or else the default
+// branch of the remote. A repository that fails does not stop the others.
+pub(crate) async fn sync_repositories(
+    controller: &dyn WorkspaceController,
+    repositories: &[Repository],
+    reference: Option<&str>,
+) -> Vec<RepositorySync> {
+    let mut synced = Vec::new();
+    for repository in repositories {
+        let reference = reference
+            .or(repository.reference.as_deref())
+            .unwrap_or("HEAD");
+        let mut sync = RepositorySync {
+            path: repository.path.clone(),
+            reference: reference.to_string(),
+            ..Default::default()
+        };
+        if let Err(e) = sync_repository(controller, repository, &mut sync).await {
+            // The failed command may have the token in its url
+            let error = scrub(&format!("{:#}", e));
+            tracing::warn!(
+                path = repository.path,
+                "Could not sync repository: {}",
+                error
+            );
+            sync.error = Some(error);
+        }
+        synced.push(sync);
+    }
+    synced
+}
+
+async fn sync_repository(
+    controller: &dyn WorkspaceController,
+    repository: &Repository,
+    sync: &mut RepositorySync,
+) -> Result<()> {
+    let path = repository.path.as_str();
+    sync.from = head(controller, path).await;
+
+    let auth = RepositoryAuth::prepare(controller, repository).await?;
+    let fetched = async {
+        let git = git(&auth);
+        let fetch = fetch_cmd(&git, &auth.url, &sync.reference);
+        run(controller, path, &fetch, &auth.env).await?;
+        run(
+            controller,
+            path,
+            "git merge --ff-only FETCH_HEAD",
+            &HashMap::new(),
+        )
+        .await?;
+        if repository.submodules {
+            let cmd = format!("{} submodule update --init --recursive", git);
+            run(controller, path, &cmd, &auth.env).await?;
+        }
+        if repository.lfs {
+            run(controller, path, &format!("{} lfs pull", git), &auth.env).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    auth.cleanup(controller).await?;
+    fetched?;
+
+    sync.to = head(controller, path).await;
+    if let (Some(from), Some(to)) = (&sync.from, &sync.to) {
+        if from != to {
+            let range = format!("{}..{}", from, to);
+            let count = run(
+                controller,
+                path,
+                &format!("git rev-list --count {}", range),
+                &HashMap::new(),
+            )
+            .await?;
+            sync.commits = count.trim().parse().unwrap_or_default();
+            let files = run(
+                controller,
+                path,
+                &format!("git diff --name-only {}", range),
+                &HashMap::new(),
+            )
+            .await?;
+            sync.files = files.lines().map(str::to_string).collect();
+        }
+    }
+    Ok(())
+}
+
+// Git authenticated like the clone was, including for submodules on the same host
+fn git(auth: &RepositoryAuth) -> String {
+    match auth.url_rewrite_config() {
+        Some(config) => format!("git -c {}", escape(config.into())),
+        None => "git".to_string(),
+    }
+}
+
+// The remote of the workspace may have been removed after cloning, so the url is fetched directly
+fn fetch_cmd(git: &str, url: &str, reference: &str) -> String {
+    format!(
+        "{} fetch {} {}",
+        git,
+        escape(url.into()),
+        escape(reference.into())
+    )
+}
+
+async fn head(controller: &dyn WorkspaceController, path: &str) -> Option<String> {
+    run(controller, path, "git rev-parse HEAD", &HashMap::new())
+        .await
+        .ok()
+        .map(|output| output.trim().to_string())
+}
+
+// Output of a git command in the repository, which fails with its output when it exits non-zero
+async fn run(
+    controller: &dyn WorkspaceController,
+    path: &str,
+    cmd: &str,
+    env: &HashMap<String, String>,
+) -> Result<String> {
+    let output = controller
+        .cmd_with_output(cmd, Some(path), env.clone(), None)
+        .await?;
+    if output.exit_code != 0 {
+        anyhow::bail!("{} failed: {}", cmd, output.output.trim());
+    }
+    Ok(output.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_cmd() {
+        let url = "https://github.com/bosun-ai/derrick";
+        assert_eq!(
+            fetch_cmd("git", url, "feature/sync"),
+            "git fetch https://github.com/bosun-ai/derrick feature/sync"
+        );
+        assert_eq!(
+            fetch_cmd("git", url, "main; rm -rf /"),
+            "git fetch https://github.com/bosun-ai/derrick 'main; rm -rf /'"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::credentials::scrub;
+use crate::names::validate_name;
+use crate::notifications::Notifications;
+
+// Runs of a schedule that are kept, older ones are dropped
+const MAX_RUNS: usize = 20;
+// The output of a run is cut to its end beyond this many bytes
+const MAX_RUN_OUTPUT: usize = 16 * 1024;
+// Shorter intervals are more likely a mistake, like minutes given as seconds
+const MIN_INTERVAL: u64 = 60;
+
+// A command that runs in a fresh workspace every `interval` seconds, which is destroyed after
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Schedule {
+    // Unique, registering a schedule with the same name replaces it
+    pub name: String,
+    pub cmd: String,
+    // Seconds between runs, the first run is one interval after the schedule is registered
+    pub interval: u64,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    // Environment of both the setup steps and the command
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // Seconds the command may run
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    // Profile and variables of the context the workspace is created with
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(default)]
+    pub variables: HashMap<String, String>,
+    // Where failed runs are reported
+    #[serde(default)]
+    pub notify: Notifications,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ScheduleRun {
+    // Unix time in milliseconds
+    pub started_at: u64,
+    pub duration_ms: u64,
+    // Destroyed once the command finished
+    pub workspace_id: Option<String>,
+    // Not set when the workspace could not be created or the command could not be run
+    pub exit_code: Option<i32>,
+    // The end of the output, or the error
+    pub output: String,
+}
+
+impl ScheduleRun {
+    pub(crate) fn new(started_at: SystemTime, duration: Duration) -> Self {
+        Self {
+            started_at: started_at
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis() as u64)
+                .unwrap_or_default(),
+            duration_ms: duration.as_millis() as u64,
+            workspace_id: None,
+            exit_code: None,
+            output: String::new(),
+        }
+    }
+
+    pub(crate) fn with_output(mut self, output: &str) -> Self {
+        let mut start = output.len().saturating_sub(MAX_RUN_OUTPUT);
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        self.output = scrub(&output[start..]);
+        self
+    }
+
+    pub fn failed(&self) -> bool {
+        self.exit_code != Some(0)
+    }
+}
+
+// A schedule with its most recent runs, oldest first
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ScheduleStatus {
+    pub schedule: Schedule,
+    pub runs: Vec<ScheduleRun>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ScheduleRuns(VecDeque<ScheduleRun>);
+
+impl ScheduleRuns {
+    pub(crate) fn push(&mut self, run: ScheduleRun) {
+        self.0.push_back(run);
+        while self.0.len() > MAX_RUNS {
+            self.0.pop_front();
+        }
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<ScheduleRun> {
+        self.0.iter().cloned().collect()
+    }
+}
+
+impl Schedule {
+    // Reads a JSON list of schedules, which are registered on startup
+    pub fn from_file(path: &str) -> Result<Vec<Schedule>> {
+        let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path))?;
+        let schedules: Vec<Schedule> = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Invalid schedules in {}", path))?;
+        for schedule in &schedules {
+            schedule.validate()?;
+        }
+        Ok(schedules)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        validate_name(&self.name).context("Invalid schedule name")?;
+        if self.interval < MIN_INTERVAL {
+            anyhow::bail!(
+                "Schedule {} runs every {} seconds, the minimum is {}",
+                self.name,
+                self.interval,
+                MIN_INTERVAL
+            );
+        }
+        self.notify
+            .validate()
+            .with_context(|| format!("Invalid notifications of schedule {}", self.name))?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    schedule: &'a str,
+    cmd: String,
+    run: &'a ScheduleRun,
+}
+
+// Reports a failed run to the webhook and NATS subject of the schedule
+pub(crate) async fn notify(schedule: &Schedule, run: &ScheduleRun) {
+    let notification = Notification {
+        schedule: &schedule.name,
+        cmd: scrub(&schedule.cmd),
+        run,
+    };
+    schedule.notify.send(&notification).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(value: serde_json::Value) -> Schedule {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let nightly = schedule(serde_json::json!({
+            "name": "nightly-deps",
+            "cmd": "cargo update && cargo test",
+            "interval": 86400,
+            "notify": { "webhook": "https://example.com/hooks/derrick" }
+        }));
+        assert!(nightly.validate().is_ok());
+
+        let too_often = Schedule {
+            interval: 5,
+            ..nightly.clone()
+        };
+        assert_eq!(
+            too_often.validate().unwrap_err().to_string(),
+            "Schedule nightly-deps runs every 5 seconds, the minimum is 60"
+        );
+
+        let invalid_name = Schedule {
+            name: "nightly deps".to_string(),
+            ..nightly
+        };
+        assert!(invalid_name.validate().is_err());
+    }
+
+    #[test]
+    fn test_run_output_is_cut_to_its_end() {
+        let output = format!("{}{}", "a".repeat(10), "b".repeat(MAX_RUN_OUTPUT));
+        let run = ScheduleRun::new(SystemTime::now(), Duration::ZERO).with_output(&output);
+        assert_eq!(run.output, "b".repeat(MAX_RUN_OUTPUT));
+        assert!(run.failed());
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use shell_escape::escape;
+
+use crate::credential_providers::{credential_provider, CredentialSource};
+use crate::egress_proxy::EgressRequest;
+use crate::workspace_controllers::{
+    CommandLimits, CommandOutput, FileEdit, FileWrite, Health, OutputLineCallback,
+    ResourceCounters, ShellSession,
+};
+use crate::WorkspaceController;
+
+// A secret from a secret store that workspaces get once they are provisioned, like
+// `{"source": {"type": "vault", "path": "secret/data/npm", "field": "token"}, "env": "NPM_TOKEN"}`.
+// Setup steps do not get it, so that it does not end up in the images they are cached in.
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct WorkspaceSecret {
+    pub source: CredentialSource,
+    // Environment variable every command in the workspace gets the secret in
+    #[serde(default)]
+    pub env: Option<String>,
+    // File the secret is written to, readable by the workspace user only
+    #[serde(default)]
+    pub file: Option<String>,
+}
+
+impl WorkspaceSecret {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.env.is_none() && self.file.is_none() {
+            anyhow::bail!("Secrets need an env or a file to be injected as")
+        }
+        Ok(())
+    }
+}
+
+// Resolves the secrets and writes those that are files into the workspace. Returns the
+// environment with the others, which commands get through `SecretsController`. Errors never
+// contain the secrets themselves.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn inject_secrets(
+    controller: &dyn WorkspaceController,
+    secrets: &[WorkspaceSecret],
+) -> Result<HashMap<String, String>> {
+    let mut env = HashMap::new();
+    for secret in secrets {
+        secret.validate()?;
+        let credential = credential_provider(&secret.source)
+            .credential()
+            .await
+            .with_context(|| format!("Could not resolve secret from {:?}", secret.source))?;
+        if let Some(path) = &secret.file {
+            if let Some((dir, _)) = path.rsplit_once('/').filter(|(dir, _)| !dir.is_empty()) {
+                controller
+                    .cmd(
+                        &format!("mkdir -p {}", escape(dir.into())),
+                        None,
+                        HashMap::new(),
+                        None,
+                    )
+                    .await?;
+            }
+            controller
+                .write_file_with_mode(path, credential.secret.as_bytes(), None, Some(0o600))
+                .await
+                .with_context(|| format!("Could not write secret to {}", path))?;
+        }
+        if let Some(name) = &secret.env {
+            env.insert(name.clone(), credential.secret);
+        }
+    }
+    Ok(env)
+}
+
+// A workspace with secrets, whose commands get them in their environment. The environment of a
+// command takes precedence over them.
+//
+// Shells and attached processes, like language servers, are the exception and do not get them.
+// `shell` and `attach` take no environment, and putting the secrets on the command line instead
+// would show them to every process in the workspace. Secrets in files are readable by them.
+pub(crate) struct SecretsController {
+    inner: Box<dyn WorkspaceController>,
+    env: HashMap<String, String>,
+    // Forks are refused when secrets were written to files, which they would be committed with
+    has_files: bool,
+}
+
+impl SecretsController {
+    pub(crate) fn wrap(
+        inner: Box<dyn WorkspaceController>,
+        secrets: &[WorkspaceSecret],
+        env: HashMap<String, String>,
+    ) -> Box<dyn WorkspaceController> {
+        let has_files = secrets.iter().any(|secret| secret.file.is_some());
+        if env.is_empty() && !has_files {
+            return inner;
+        }
+        Box::new(Self {
+            inner,
+            env,
+            has_files,
+        })
+    }
+
+    fn env(&self, env: HashMap<String, String>) -> HashMap<String, String> {
+        let mut merged = self.env.clone();
+        merged.extend(env);
+        merged
+    }
+}
+
+// Only the names of the secrets, never their values
+impl std::fmt::Debug for SecretsController {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecretsController")
+            .field("inner", &self.inner)
+            .field("env", &self.env.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl WorkspaceController for SecretsController {
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn stop(&self) -> Result<()> {
+        self.inner.stop().await
+    }
+
+    async fn provision_repositories(
+        &self,
+        repositories: Vec<crate::repository::Repository>,
+    ) -> Result<()> {
+        self.inner.provision_repositories(repositories).await
+    }
+
+    async fn cmd(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.inner
+            .cmd(cmd, working_dir, self.env(env), timeout)
+            .await
+    }
+
+    async fn cmd_with_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_output(cmd, working_dir, self.env(env), timeout)
+            .await
+    }
+
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_limits(cmd, working_dir, self.env(env), timeout, limits)
+            .await
+    }
+
+    async fn cmd_with_streamed_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        on_line: &OutputLineCallback,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_streamed_output(cmd, working_dir, self.env(env), on_line)
+            .await
+    }
+
+    async fn write_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.write_file(path, content, working_dir).await
+    }
+
+    async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
+        self.inner.read_file(path, working_dir).await
+    }
+
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.inner
+            .write_file_with_mode(path, content, working_dir, mode)
+            .await
+    }
+
+    async fn write_files(&self, files: &[FileWrite], working_dir: Option<&str>) -> Result<()> {
+        self.inner.write_files(files, working_dir).await
+    }
+
+    async fn symlink(&self, path: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        self.inner.symlink(path, target, working_dir).await
+    }
+
+    async fn append_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.append_file(path, content, working_dir).await
+    }
+
+    async fn edit_file(
+        &self,
+        path: &str,
+        edits: &[FileEdit],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.edit_file(path, edits, working_dir).await
+    }
+
+    async fn disk_usage(&self) -> Result<u64> {
+        self.inner.disk_usage().await
+    }
+
+    async fn resource_counters(&self) -> Result<Option<ResourceCounters>> {
+        self.inner.resource_counters().await
+    }
+
+    // Without the secrets in the environment, see `SecretsController`
+    async fn shell(&self, cols: u16, rows: u16) -> Result<ShellSession> {
+        self.inner.shell(cols, rows).await
+    }
+
+    // Without the secrets in the environment, see `SecretsController`
+    async fn attach(&self, cmd: &str, working_dir: Option<&str>) -> Result<ShellSession> {
+        self.inner.attach(cmd, working_dir).await
+    }
+
+    async fn fork(&self) -> Result<Box<dyn WorkspaceController>> {
+        if self.has_files {
+            anyhow::bail!("Workspaces with secrets in files cannot be forked")
+        }
+        Ok(Box::new(SecretsController {
+            inner: self.inner.fork().await?,
+            env: self.env.clone(),
+            has_files: false,
+        }))
+    }
+
+    async fn health(&self) -> Result<Option<Health>> {
+        self.inner.health().await
+    }
+
+    async fn is_running(&self) -> Result<bool> {
+        self.inner.is_running().await
+    }
+
+    async fn restart(&self) -> Result<()> {
+        self.inner.restart().await
+    }
+
+    fn logs(
+        &self,
+        follow: bool,
+        tail: Option<usize>,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.inner.logs(follow, tail)
+    }
+
+    async fn network_address(&self, port: u16) -> Result<String> {
+        self.inner.network_address(port).await
+    }
+
+    fn egress_requests(&self) -> Result<Vec<EgressRequest>> {
+        self.inner.egress_requests()
+    }
+
+    fn outputs_dir(&self) -> String {
+        self.inner.outputs_dir()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::LocalTempSyncController;
+
+    fn secret(value: &str, env: Option<&str>, file: Option<&str>) -> WorkspaceSecret {
+        WorkspaceSecret {
+            source: CredentialSource::Exec {
+                command: vec!["echo".to_string(), value.to_string()],
+            },
+            env: env.map(str::to_string),
+            file: file.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_inject_secrets() {
+        let root = std::env::temp_dir().join(format!("derrick-secrets-{}", uuid::Uuid::new_v4()));
+        let controller = LocalTempSyncController::initialize_in(&root, "workspace").await;
+        let secrets = vec![
+            secret("npm-token", Some("NPM_TOKEN"), None),
+            secret("deploy-key", None, Some("keys/deploy")),
+        ];
+
+        let env = inject_secrets(&controller, &secrets).await.unwrap();
+        let controller = SecretsController::wrap(Box::new(controller), &secrets, env);
+
+        let output = controller
+            .cmd_with_output("echo $NPM_TOKEN", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(output.output.trim(), "npm-token");
+        let output = controller
+            .cmd_with_output(
+                "echo $NPM_TOKEN",
+                None,
+                HashMap::from([("NPM_TOKEN".to_string(), "own".to_string())]),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(output.output.trim(), "own");
+        assert_eq!(
+            controller.read_file("keys/deploy", None).await.unwrap(),
+            b"deploy-key"
+        );
+        assert!(!format!("{:?}", controller).contains("npm-token"));
+        assert!(controller.fork().await.is_err());
+
+        controller.stop().await.unwrap();
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(secret("a", Some("A"), None).validate().is_ok());
+        assert!(secret("a", None, None).validate().is_err());
+    }
+}
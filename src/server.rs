@@ -1,14 +1,225 @@
-use std::collections::HashMap;
-use std::time::Duration;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::workspace_controllers::CommandOutput;
-use crate::{WorkspaceContext, WorkspaceController, WorkspaceProvider};
-use anyhow::Result;
+use crate::accounting::{self, Ledger, UsageQuota, UsageReport, DEFAULT_USAGE_LABEL};
+use crate::artifacts::collect_artifacts;
+use crate::auth::{ApiToken, ApiTokens, Role, Unauthorized};
+use crate::cloud_credentials::{self, CloudSession};
+use crate::codebase::{ChunkExport, ChunkOptions, Codebase};
+use crate::command_cache::{self, CommandCache};
+use crate::command_history::{
+    self, CommandHistory, CommandNotFound, CommandRecord, CommandRequest, CommandStatus,
+    RunningCommand,
+};
+use crate::config::Config;
+use crate::coverage::{self, CoverageConfig, CoverageReport};
+use crate::credentials::{GithubAppSession, RepositoryCredentials};
+use crate::daemons;
+use crate::dependencies::{self, DependencyReport};
+use crate::egress_proxy::EgressRequest;
+use crate::env_info::{self, EnvInfo};
+use crate::events::Event;
+use crate::file_tree::{self, FileTree, TreeOptions};
+use crate::git_status::{git_status, GitStatus};
+use crate::github::GithubSession;
+use crate::labels::{validate_labels, LabelSelector};
+use crate::lint::{self, LintReport, Linter};
+use crate::lsp::select_language_server;
+use crate::matrix::{self, MatrixWorkspace};
+use crate::names::{validate_name, NameTaken};
+use crate::notifications::Notifications;
+use crate::outputs::{self, SpilledOutput};
+use crate::provenance::Provenance;
+use crate::provisioning_queue::ProvisioningQueue;
+use crate::replace::{self, ReplaceResult, Replacement};
+use crate::repository_sync::{self, RepositorySync};
+use crate::scheduler::{self, Schedule, ScheduleRun, ScheduleRuns, ScheduleStatus};
+use crate::secrets::{self, SecretsController};
+use crate::symbols::{Symbol, SymbolIndex};
+use crate::test_runner::{self, TestFramework, TestReport};
+use crate::usage::{DiskQuota, DiskUsage, QuotaAction};
+use crate::workspace_controllers::{
+    CommandLimits, CommandOutput, ContainerStopped, FileEdit, FileTooLarge, FileWrite, Health,
+    ShellSession,
+};
+use crate::workspace_providers::run_post_create_script;
+use crate::{
+    Compression, ImageScan, ProgressReporter, ProvisioningPhase, ProvisioningStatus,
+    WorkspaceContext, WorkspaceController, WorkspaceProvider,
+};
+use anyhow::{Context, Result};
+use futures_util::stream::BoxStream;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
+// Events that are not yet received by a slow subscriber are dropped after this many new events
+const EVENT_CAPACITY: usize = 256;
+const DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const DEFAULT_FAILED_STATUS_TTL: Duration = Duration::from_secs(60 * 60);
+// Label of the workspaces created for a run of a schedule, with the name of the schedule
+const SCHEDULE_LABEL: &str = "derrick.schedule";
+
+// Sizes the server accepts and returns, see `Server::with_size_limits`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizeLimits {
+    // Bytes of a request body, files are base64 encoded in them so they take a third more
+    pub request_body: usize,
+    // Bytes of a file that is written or appended
+    pub write_file: Option<usize>,
+    // Bytes of a file that is read
+    pub read_file: Option<usize>,
+}
+
+impl Default for SizeLimits {
+    fn default() -> Self {
+        SizeLimits {
+            request_body: 100 * 1024 * 1024,
+            write_file: None,
+            read_file: None,
+        }
+    }
+}
+
+// Fails for a file over the limit
+fn check_size(path: &str, size: usize, limit: Option<usize>) -> Result<()> {
+    match limit {
+        Some(limit) if size > limit => Err(FileTooLarge {
+            path: path.to_string(),
+            size,
+            limit,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+// Stops a workspace that failed to provision and returns the error it failed with. That it could
+// not be stopped is only logged, the error has to say why provisioning failed.
+async fn stop_failed(controller: &dyn WorkspaceController, e: anyhow::Error) -> anyhow::Error {
+    if let Err(stop_error) = controller.stop().await {
+        tracing::warn!(
+            "Could not stop workspace that failed to provision: {:?}",
+            stop_error
+        );
+    }
+    e
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Metrics {
+    // Provisioned workspaces
+    pub workspaces: usize,
+    pub provisions_in_progress: usize,
+    pub max_concurrent_provisions: usize,
+    // Workspaces waiting for a provisioning slot
+    pub provisioning_queue_depth: usize,
+}
+
+// What a new workspace is created with
+#[derive(Debug, Clone, Default)]
+pub struct WorkspaceOptions {
+    // Environment for provisioning, e.g. tokens the setup script needs
+    pub env: HashMap<String, String>,
+    pub labels: HashMap<String, String>,
+    // Requests with the same key create a single workspace, so that they can be retried safely
+    pub idempotency_key: Option<String>,
+    // Unique name the workspace can be addressed by instead of its id
+    pub name: Option<String>,
+    // Where the workspace reports being ready, failing or destroyed, and commands finishing
+    pub callback: Option<Notifications>,
+    // Profile of the context with the sandbox, network and limits of the workspace
+    pub profile: Option<String>,
+    // Values of the variables of the context, like the branch to check out
+    pub variables: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WorkspaceSummary {
+    pub id: String,
+    pub name: Option<String>,
+    pub labels: HashMap<String, String>,
+    // Of workspaces with a health check
+    pub health: Option<Health>,
+}
+
+// The server synchronizes internally so that long running operations (like provisioning) do not
+// block requests for other workspaces, or status requests for the workspace being provisioned.
 pub struct Server {
-    context: WorkspaceContext,
+    // New workspaces are provisioned with the context at the time they are created, so reloading
+    // it does not affect existing workspaces
+    context: RwLock<Arc<WorkspaceContext>>,
+    context_path: Option<String>,
     provider: Box<dyn WorkspaceProvider>,
-    workspaces: HashMap<String, Box<dyn WorkspaceController>>,
+    // Mode of `provider`, for contexts that ask for it by name
+    provisioning_mode: Option<String>,
+    // Providers of the other provisioning modes contexts can ask for
+    providers: HashMap<String, Box<dyn WorkspaceProvider>>,
+    provisioning_queue: ProvisioningQueue,
+    workspaces: RwLock<HashMap<String, Arc<dyn WorkspaceController>>>,
+    statuses: RwLock<HashMap<String, ProgressReporter>>,
+    // Workspaces destroyed while they were provisioning, which are stopped instead of registered
+    // once they are provisioned
+    cancelled: RwLock<HashSet<String>>,
+    // When the workspaces that failed to provision failed. Their status is forgotten
+    // `failed_status_ttl` later, by `record_usage`.
+    failed_at: RwLock<HashMap<String, Instant>>,
+    failed_status_ttl: Duration,
+    // Labels the workspaces were created with, for grouping them
+    labels: RwLock<HashMap<String, HashMap<String, String>>>,
+    // Workspaces created with an idempotency key, until the key expires
+    idempotency_keys: RwLock<HashMap<String, (String, Instant)>>,
+    idempotency_key_ttl: Duration,
+    // Ids of the workspaces created with a name
+    names: RwLock<HashMap<String, String>>,
+    // Where the lifecycle and command events of a workspace are delivered, when its create request
+    // asked for it. Not async, as events are published from synchronous code.
+    callbacks: std::sync::RwLock<HashMap<String, Notifications>>,
+    // Quotas of the contexts the workspaces were created with
+    disk_quotas: RwLock<HashMap<String, DiskQuota>>,
+    // Contexts the workspaces were created with, for settings that apply after provisioning
+    workspace_contexts: RwLock<HashMap<String, Arc<WorkspaceContext>>>,
+    // Cloud credentials minted for the workspaces, revoked when they are destroyed
+    cloud_sessions: RwLock<HashMap<String, Vec<CloudSession>>>,
+    // Symbols of the repositories of the workspaces, built on provisioning or the first lookup
+    symbol_indexes: RwLock<HashMap<String, Arc<SymbolIndex>>>,
+    command_caches: RwLock<HashMap<String, CommandCache>>,
+    // Recent commands run through the API, per workspace
+    command_histories: RwLock<HashMap<String, CommandHistory>>,
+    // Directory the histories are also written to, where they outlive their workspaces
+    command_history_dir: Option<PathBuf>,
+    events: broadcast::Sender<Event>,
+    github_webhook_secret: Option<String>,
+    api_tokens: ApiTokens,
+    // Output of `cmd_with_output` beyond this is only kept in a file in the workspace
+    max_inline_output: Option<usize>,
+    size_limits: SizeLimits,
+    // Cleared while the contexts are prepared on startup
+    ready: AtomicBool,
+    // Registered schedules, with the token that stops their task
+    schedules: RwLock<HashMap<String, (Schedule, CancellationToken)>>,
+    schedule_runs: RwLock<HashMap<String, ScheduleRuns>>,
+    // Times the container of a workspace was restarted after it exited. Locked while restarting,
+    // so that commands failing at the same time restart it once.
+    restarts: RwLock<HashMap<String, Arc<Mutex<u32>>>>,
+    // Last known health of the workspaces with a health check, kept up to date by `watch_health`
+    health: RwLock<HashMap<String, Health>>,
+    // Workspace time, CPU time and network traffic of the workspaces, kept up to date by
+    // `record_usage`
+    ledger: RwLock<Ledger>,
+    // Label usage is reported and quotas are enforced by, like a tenant or team
+    usage_label: String,
+    usage_quotas: Vec<UsageQuota>,
+    // Github app and NATS settings, the process config is used when none is given
+    config: Option<Arc<Config>>,
+    // Session of the Github app of the config, created for the first repository that needs it
+    github_session: std::sync::Mutex<Option<Arc<GithubSession>>>,
 }
 
 impl Server {
@@ -16,48 +227,1414 @@ impl Server {
         context: WorkspaceContext,
         provider: Box<dyn WorkspaceProvider>,
     ) -> Result<Server> {
-        Ok(Server {
-            context,
+        Ok(Self::new(context, provider))
+    }
+
+    pub(crate) fn new(context: WorkspaceContext, provider: Box<dyn WorkspaceProvider>) -> Server {
+        Server {
+            context: RwLock::new(Arc::new(context)),
+            context_path: None,
             provider,
-            workspaces: HashMap::new(),
+            provisioning_mode: None,
+            providers: HashMap::new(),
+            provisioning_queue: ProvisioningQueue::default(),
+            workspaces: RwLock::new(HashMap::new()),
+            statuses: RwLock::new(HashMap::new()),
+            cancelled: RwLock::new(HashSet::new()),
+            failed_at: RwLock::new(HashMap::new()),
+            failed_status_ttl: DEFAULT_FAILED_STATUS_TTL,
+            labels: RwLock::new(HashMap::new()),
+            idempotency_keys: RwLock::new(HashMap::new()),
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+            names: RwLock::new(HashMap::new()),
+            callbacks: Default::default(),
+            disk_quotas: RwLock::new(HashMap::new()),
+            workspace_contexts: RwLock::new(HashMap::new()),
+            cloud_sessions: RwLock::new(HashMap::new()),
+            symbol_indexes: RwLock::new(HashMap::new()),
+            command_caches: RwLock::new(HashMap::new()),
+            command_histories: RwLock::new(HashMap::new()),
+            command_history_dir: None,
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            github_webhook_secret: None,
+            api_tokens: ApiTokens::default(),
+            max_inline_output: None,
+            size_limits: SizeLimits::default(),
+            ready: AtomicBool::new(true),
+            schedules: RwLock::new(HashMap::new()),
+            schedule_runs: RwLock::new(HashMap::new()),
+            restarts: RwLock::new(HashMap::new()),
+            health: RwLock::new(HashMap::new()),
+            ledger: RwLock::new(Ledger::default()),
+            usage_label: DEFAULT_USAGE_LABEL.to_string(),
+            usage_quotas: Vec::new(),
+            config: None,
+            github_session: Default::default(),
+        }
+    }
+
+    /// Allows reloading the context from the file it was read from.
+    pub fn with_context_path(mut self, path: Option<String>) -> Self {
+        self.context_path = path;
+        self
+    }
+
+    /// Names the provisioning mode of the provider the server was created with, which contexts
+    /// without a mode of their own use.
+    pub fn with_provisioning_mode(mut self, mode: impl Into<String>) -> Self {
+        self.provisioning_mode = Some(mode.into());
+        self
+    }
+
+    /// Provisions the workspaces of contexts and profiles that ask for this mode with the
+    /// provider, e.g. docker for untrusted code next to a local default.
+    pub fn with_provider(
+        mut self,
+        mode: impl Into<String>,
+        provider: Box<dyn WorkspaceProvider>,
+    ) -> Self {
+        self.providers.insert(mode.into(), provider);
+        self
+    }
+
+    // The provider of the provisioning mode of the context
+    pub fn provider(&self, context: &WorkspaceContext) -> Result<&dyn WorkspaceProvider> {
+        self.mode_provider(context.provisioning_mode.as_deref())
+    }
+
+    fn mode_provider(&self, mode: Option<&str>) -> Result<&dyn WorkspaceProvider> {
+        match mode {
+            None => Ok(self.provider.as_ref()),
+            Some(mode) if Some(mode) == self.provisioning_mode.as_deref() => {
+                Ok(self.provider.as_ref())
+            }
+            Some(mode) => self
+                .providers
+                .get(mode)
+                .map(|provider| provider.as_ref())
+                .with_context(|| format!("Provisioning mode {} is not enabled", mode)),
+        }
+    }
+
+    /// Limits how many workspaces are provisioned at the same time. Further workspaces wait in a
+    /// queue and fail when they waited longer than `queue_timeout`.
+    pub fn with_provisioning_limits(
+        mut self,
+        max_concurrent_provisions: usize,
+        queue_timeout: Duration,
+    ) -> Self {
+        self.provisioning_queue = ProvisioningQueue::new(max_concurrent_provisions, queue_timeout);
+        self
+    }
+
+    /// Sets how long an idempotency key returns the workspace it created, 24 hours by default.
+    pub fn with_idempotency_key_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_key_ttl = ttl;
+        self
+    }
+
+    /// Sets how long the status of a workspace that failed to provision can be read, an hour by
+    /// default.
+    pub fn with_failed_status_ttl(mut self, ttl: Duration) -> Self {
+        self.failed_status_ttl = ttl;
+        self
+    }
+
+    /// Groups the usage of workspaces by the value of this label, `tenant` by default.
+    pub fn with_usage_label(mut self, label: impl Into<String>) -> Self {
+        self.usage_label = label.into();
+        self
+    }
+
+    /// Refuses new workspaces whose usage label has a value that used up its monthly quota.
+    pub fn with_usage_quotas(mut self, quotas: Vec<UsageQuota>) -> Self {
+        self.usage_quotas = quotas;
+        self
+    }
+
+    pub async fn context(&self) -> Arc<WorkspaceContext> {
+        Arc::clone(&*self.context.read().await)
+    }
+
+    // Reads the context file again, keeping the current context if it is invalid
+    pub async fn reload_context(&self) -> Result<()> {
+        let Some(path) = &self.context_path else {
+            anyhow::bail!("The context was not loaded from a file");
+        };
+        let context = WorkspaceContext::from_file(path.clone())
+            .with_context(|| format!("Could not load context from {}", path))?;
+        // The providers are set up when the server starts
+        for mode in context.provisioning_modes() {
+            self.mode_provider(Some(&mode))?;
+        }
+        *self.context.write().await = Arc::new(context);
+        tracing::info!(path, "Reloaded workspace context");
+        Ok(())
+    }
+
+    // Reloads the context whenever the modification time of the context file changes
+    pub fn watch_context(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            let Some(path) = server.context_path.clone() else {
+                return;
+            };
+            let modified = || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+            let mut last_modified = modified();
+            loop {
+                tokio::time::sleep(interval).await;
+                let current = modified();
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+                if let Err(e) = server.reload_context().await {
+                    tracing::error!("Failed to reload workspace context: {:?}", e);
+                }
+            }
+        })
+    }
+
+    // Builds the images (or pristine copies) of the context and of each of its profiles in the
+    // background, with the given environment for the setup steps. The server is not ready until
+    // it is done, whether or not that succeeded.
+    pub fn prepare_contexts(self: &Arc<Self>, env: HashMap<String, String>) -> JoinHandle<()> {
+        self.ready.store(false, Ordering::SeqCst);
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            let context = server.context().await;
+            let mut profiles = context.profiles.keys().cloned().collect::<Vec<_>>();
+            profiles.sort();
+            for profile in std::iter::once(None).chain(profiles.into_iter().map(Some)) {
+                let profile = profile.as_deref();
+                tracing::info!(profile, "Preparing context {}", context.name);
+                let result: Result<()> = async {
+                    // With the defaults of the variables, which fails for variables without one
+                    let context = server.resolve_context(profile, &HashMap::new()).await?;
+                    server
+                        .provider(&context)?
+                        .prepare(&context, env.clone(), &ProgressReporter::default())
+                        .await
+                }
+                .await;
+                if let Err(e) = result {
+                    tracing::error!(profile, "Failed to prepare context: {:?}", e);
+                }
+            }
+            server.ready.store(true, Ordering::SeqCst);
+            tracing::info!("Prepared contexts, the server is ready");
         })
     }
 
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    /// Also writes the command history of every workspace to a file in the directory, where it
+    /// can be read after the workspace is destroyed.
+    pub fn with_command_history_dir(mut self, dir: Option<PathBuf>) -> Self {
+        self.command_history_dir = dir;
+        self
+    }
+
+    /// Accepts Github webhook deliveries signed with the given secret.
+    pub fn with_github_webhook_secret(mut self, secret: Option<String>) -> Self {
+        self.github_webhook_secret = secret;
+        self
+    }
+
+    /// Requires requests to carry one of the tokens, with a role that allows what they do.
+    /// Without tokens, every request is allowed.
+    pub fn with_api_tokens(mut self, tokens: Vec<ApiToken>) -> Self {
+        self.api_tokens = ApiTokens::new(tokens);
+        self
+    }
+
+    pub fn authorize(&self, token: Option<&str>, required: Role) -> Result<(), Unauthorized> {
+        self.api_tokens.authorize(token, required)
+    }
+
+    /// Spills the output of commands to a file in the workspace and returns only the first
+    /// `max_bytes` inline, unless a request sets its own maximum.
+    pub fn with_max_inline_output(mut self, max_bytes: Option<usize>) -> Self {
+        self.max_inline_output = max_bytes;
+        self
+    }
+
+    pub fn max_inline_output(&self) -> Option<usize> {
+        self.max_inline_output
+    }
+
+    /// Limits the size of request bodies, 100MB by default, and of the files written and read
+    /// through the API, which are not limited by default.
+    pub fn with_size_limits(mut self, size_limits: SizeLimits) -> Self {
+        self.size_limits = size_limits;
+        self
+    }
+
+    pub fn size_limits(&self) -> SizeLimits {
+        self.size_limits
+    }
+
+    pub fn github_webhook_secret(&self) -> Option<&str> {
+        self.github_webhook_secret.as_deref()
+    }
+
+    /// Uses the Github app and NATS settings of the config instead of the ones of the process,
+    /// so that servers in the same process can each have their own.
+    pub fn with_config(mut self, config: Arc<Config>) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    pub fn config(&self) -> &Config {
+        self.config.as_deref().unwrap_or_else(crate::config)
+    }
+
+    // Shared by the workspaces, so installation tokens are reused until they are about to expire
+    fn github_session(&self) -> Result<Arc<GithubSession>> {
+        let mut session = self.github_session.lock().unwrap();
+        if let Some(session) = &*session {
+            return Ok(Arc::clone(session));
+        }
+        let created = Arc::new(GithubSession::from_config(self.config())?);
+        Ok(Arc::clone(session.insert(created)))
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        let callback = event
+            .callback_workspace()
+            .and_then(|id| self.callbacks.read().unwrap().get(id).cloned());
+        if let Some(callback) = callback {
+            let event = event.clone();
+            tokio::spawn(async move { callback.send(&event).await }.in_current_span());
+        }
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
     // HTTP Server endpoints:
     // POST /workspaces                                 creates a new workspace
+    //                                                  (?async=true returns before it is provisioned)
+    //                                                  (idempotency_key returns the workspace of a retried request)
+    // POST /workspaces/:workspace_id/clone            creates a new workspace from the state of one
+    // POST /adopt                                      registers a running container as a workspace
     // DELETE /workspaces/:workspace_id                 destroys a workspace
     // GET /workspaces                                  lists existing workspaces
+    //                                                  (?label=key=value,... filters them by label)
+    // DELETE /workspaces?label=key=value,...           destroys the workspaces with the labels
+    // GET /workspaces/:workspace_id/status             returns the provisioning status of a workspace
+    // GET /metrics                                     returns workspace and provisioning queue counts
+    // GET /usage                                       returns workspace hours, CPU time and traffic per label value
+    //                                                  (?group_by=label&from=..&to=.. in Unix ms or RFC 3339)
+    // GET /images/scans                                returns the vulnerability scans of the prepared images
+    // GET /schemas/context                             returns the JSON Schema of context files
+    // POST /contexts/reload                            reloads the context for new workspaces
+    // POST /webhooks/github                            receives Github webhooks and publishes them as events
+    // GET /events                                      streams the published events over a websocket
+    // GET /ui                                          serves the dashboard (with the `ui` feature)
     //
-    // Workspace actions
+    // Workspace actions, which accept the name of a workspace in place of its id
     // POST /workspaces/:workspace_id/cmd               runs a command in the workspace
     // POST /workspaces/:workspace_id/cmd_with_output   runs a command in the workspace and returns the output
     // POST /workspaces/:workspace_id/write_file        writes a file in the workspace
+    // POST /workspaces/:workspace_id/write_files       writes several files in one go
+    // POST /workspaces/:workspace_id/append_file       appends to a file in the workspace
+    // POST /workspaces/:workspace_id/edit_file         replaces ranges of a file or applies a diff to it
     // POST /workspaces/:workspace_id/read_file         reads a file in the workspace
+    // GET /workspaces/:workspace_id/outputs/:cmd_id   returns the spilled output of a command (supports Range)
+    // GET /workspaces/:workspace_id/usage              returns the disk usage of a workspace
+    // GET /workspaces/:workspace_id/provenance         returns the provenance for commits made in it
+    // GET /workspaces/:workspace_id/tree               returns a directory of the workspace as a tree
+    // POST /workspaces/:workspace_id/replace           replaces a pattern across files (or returns the diff)
+    // GET /workspaces/:workspace_id/symbols            looks up symbols of the repositories by name
+    // GET /workspaces/:workspace_id/chunks             returns the source files split into chunks for embedding
+    // GET /workspaces/:workspace_id/dependencies       returns the dependencies parsed from the manifests
+    // GET /workspaces/:workspace_id/git/status         returns the parsed git status of a repository
+    // POST /workspaces/:workspace_id/git/sync          fast-forwards the repositories and returns what changed
+    // GET /workspaces/:workspace_id/shell              opens an interactive shell over a websocket
+    // GET /workspaces/:workspace_id/tunnel/:port       forwards TCP to a port of the workspace over a websocket
+
+    pub async fn create_workspace(&self, options: WorkspaceOptions) -> Result<String> {
+        self.validate_options(&options).await?;
+        let (id, progress, created) = self.register_workspace_once(&options).await?;
+        if !created {
+            // A retry of a request that is still provisioning waits for it like the first did
+            let status = progress.finished().await;
+            if status.phase == ProvisioningPhase::Failed {
+                anyhow::bail!(
+                    "Workspace {} failed to provision: {}",
+                    id,
+                    status.message.unwrap_or_default()
+                );
+            }
+            return Ok(id);
+        }
+        self.provision_workspace(
+            &id,
+            options.env,
+            options.profile.as_deref(),
+            &options.variables,
+            &progress,
+        )
+        .await?;
+        Ok(id)
+    }
+
+    // Registers the workspace and provisions it in a background task. Its progress (and
+    // eventual failure) can be followed through `workspace_status`.
+    pub async fn create_workspace_async(
+        self: &Arc<Self>,
+        options: WorkspaceOptions,
+    ) -> Result<String> {
+        self.validate_options(&options).await?;
+        let (id, progress, created) = self.register_workspace_once(&options).await?;
+        if !created {
+            return Ok(id);
+        }
+
+        let server = Arc::clone(self);
+        let workspace_id = id.clone();
+        let env = options.env;
+        let profile = options.profile;
+        let variables = options.variables;
+        tokio::spawn(
+            async move {
+                if let Err(e) = server
+                    .provision_workspace(
+                        &workspace_id,
+                        env,
+                        profile.as_deref(),
+                        &variables,
+                        &progress,
+                    )
+                    .await
+                {
+                    tracing::error!(workspace_id, "Failed to provision workspace: {:?}", e);
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(id)
+    }
+
+    // Creates a workspace for every combination of the values in the matrix, which are added to
+    // the variables of the options. Every combination is validated before any workspace is
+    // created, after that a workspace that fails does not fail the others. The workspaces are
+    // provisioned concurrently, within the provisioning limits.
+    pub async fn create_matrix(
+        self: &Arc<Self>,
+        options: WorkspaceOptions,
+        matrix: &BTreeMap<String, Vec<String>>,
+        run_async: bool,
+    ) -> Result<Vec<MatrixWorkspace>> {
+        if options.name.is_some() {
+            anyhow::bail!("Workspaces of a matrix can not have a name");
+        }
+        let mut combinations = Vec::new();
+        for (index, combination) in matrix::combinations(matrix)?.into_iter().enumerate() {
+            let mut variables = options.variables.clone();
+            variables.extend(combination.clone());
+            let options = WorkspaceOptions {
+                variables,
+                idempotency_key: options
+                    .idempotency_key
+                    .as_ref()
+                    .map(|key| format!("{}/{}", key, index)),
+                ..options.clone()
+            };
+            self.validate_options(&options).await?;
+            combinations.push((combination, options));
+        }
+
+        let workspaces = combinations
+            .into_iter()
+            .map(|(variables, options)| async move {
+                let result = if run_async {
+                    self.create_workspace_async(options).await
+                } else {
+                    self.create_workspace(options).await
+                };
+                match result {
+                    Ok(id) => MatrixWorkspace {
+                        variables,
+                        id: Some(id),
+                        error: None,
+                    },
+                    Err(e) => {
+                        tracing::error!("Failed to create workspace of matrix: {:?}", e);
+                        MatrixWorkspace {
+                            variables,
+                            id: None,
+                            error: Some(format!("{:#}", e)),
+                        }
+                    }
+                }
+            });
+        Ok(futures_util::future::join_all(workspaces).await)
+    }
+
+    async fn validate_options(&self, options: &WorkspaceOptions) -> Result<()> {
+        validate_labels(&options.labels)?;
+        if let Some(name) = &options.name {
+            validate_name(name)?;
+        }
+        if let Some(callback) = &options.callback {
+            callback.validate().context("Invalid callback")?;
+        }
+        self.ledger.read().await.check_quota(
+            &self.usage_label,
+            &options.labels,
+            &self.usage_quotas,
+            accounting::now_ms(),
+        )?;
+        let context = self
+            .resolve_context(options.profile.as_deref(), &options.variables)
+            .await?;
+        self.provider(&context)?;
+        Ok(())
+    }
+
+    // The context a new workspace is provisioned with, with the profile and variable values of
+    // its create request
+    pub async fn resolve_context(
+        &self,
+        profile: Option<&str>,
+        variables: &HashMap<String, String>,
+    ) -> Result<WorkspaceContext> {
+        let context = self.context().await;
+        let context = match profile {
+            Some(profile) => context.with_profile(profile)?,
+            None => context.as_ref().clone(),
+        };
+        let mut context = context.with_variables(variables)?;
+        // Cloned with the Github app of the config of the server
+        for repository in &mut context.repositories {
+            if repository.credentials == Some(RepositoryCredentials::GithubApp) {
+                repository.github_session = GithubAppSession::new(self.github_session()?);
+            }
+        }
+        Ok(context)
+    }
+
+    // Returns the workspace registered earlier with the idempotency key, if it still exists and
+    // did not fail, and registers a new workspace otherwise. The returned flag tells whether the
+    // workspace is new.
+    async fn register_workspace_once(
+        &self,
+        options: &WorkspaceOptions,
+    ) -> Result<(String, ProgressReporter, bool)> {
+        let labels = options.labels.clone();
+        let name = options.name.as_deref();
+        let callback = options.callback.clone();
+        let Some(key) = options.idempotency_key.as_deref() else {
+            let (id, progress) = self.register_workspace(labels, name, callback).await?;
+            return Ok((id, progress, true));
+        };
+
+        // Held until the workspace is registered, so concurrent retries do not both create one
+        let mut keys = self.idempotency_keys.write().await;
+        let now = Instant::now();
+        keys.retain(|_, (_, expires_at)| *expires_at > now);
+
+        if let Some((id, _)) = keys.get(key) {
+            let progress = self.statuses.read().await.get(id).cloned();
+            if let Some(progress) = progress {
+                if progress.status().phase != ProvisioningPhase::Failed {
+                    tracing::info!(workspace_id = id, "Reusing workspace for idempotency key");
+                    return Ok((id.clone(), progress, false));
+                }
+            }
+        }
+
+        let (id, progress) = self.register_workspace(labels, name, callback).await?;
+        keys.insert(
+            key.to_string(),
+            (id.clone(), now + self.idempotency_key_ttl),
+        );
+        Ok((id, progress, true))
+    }
 
-    pub async fn create_workspace(&mut self, env: HashMap<String, String>) -> Result<String> {
-        let controller = self.provider.provision(&self.context, env).await?;
+    async fn register_workspace(
+        &self,
+        labels: HashMap<String, String>,
+        name: Option<&str>,
+        callback: Option<Notifications>,
+    ) -> Result<(String, ProgressReporter)> {
         let id: String = uuid::Uuid::new_v4().to_string();
+        if let Some(name) = name {
+            let mut names = self.names.write().await;
+            if names.contains_key(name) {
+                return Err(NameTaken {
+                    name: name.to_string(),
+                }
+                .into());
+            }
+            names.insert(name.to_string(), id.clone());
+        }
+        let progress = ProgressReporter::for_workspace(&id, self.events.clone());
+        self.statuses
+            .write()
+            .await
+            .insert(id.clone(), progress.clone());
+        self.ledger
+            .write()
+            .await
+            .start(&id, labels.clone(), accounting::now_ms());
+        self.labels.write().await.insert(id.clone(), labels);
+        if let Some(callback) = callback {
+            self.callbacks.write().unwrap().insert(id.clone(), callback);
+        }
+        Ok((id, progress))
+    }
+
+    // Marks a workspace that failed to provision, it stays listed with its status but frees
+    // its name
+    async fn provisioning_failed(&self, id: &str, progress: &ProgressReporter, e: &anyhow::Error) {
+        progress.failed(e);
+        self.failed_at
+            .write()
+            .await
+            .insert(id.to_string(), Instant::now());
+        self.cancelled.write().await.remove(id);
+        self.release_name(id).await;
+        self.ledger.write().await.finish(id, accounting::now_ms());
+        self.publish(Event::workspace_failed(id, e));
+        self.callbacks.write().unwrap().remove(id);
+    }
+
+    // Frees the name of a workspace that is destroyed or failed to provision
+    async fn release_name(&self, id: &str) {
+        self.names
+            .write()
+            .await
+            .retain(|_, workspace_id| workspace_id != id);
+    }
+
+    // Looks up the id of the workspace with the name, anything else is taken to be an id already
+    pub async fn workspace_id(&self, id_or_name: &str) -> String {
+        self.names
+            .read()
+            .await
+            .get(id_or_name)
+            .cloned()
+            .unwrap_or_else(|| id_or_name.to_string())
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    async fn provision_workspace(
+        &self,
+        id: &str,
+        env: HashMap<String, String>,
+        profile: Option<&str>,
+        variables: &HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        let _slot = match self.provisioning_queue.acquire(id, progress).await {
+            Ok(slot) => slot,
+            Err(e) => {
+                self.provisioning_failed(id, progress, &e).await;
+                return Err(e);
+            }
+        };
+        if self.cancelled.write().await.remove(id) {
+            return Err(self.provisioning_cancelled(id, None).await);
+        }
+        // The profile or variables may be gone when the context was reloaded while the workspace
+        // was queued
+        let context = self
+            .resolve_context(profile, variables)
+            .await
+            .and_then(|context| context.with_output_dir(id));
+        let context = match context {
+            Ok(context) => Arc::new(context),
+            Err(e) => {
+                self.provisioning_failed(id, progress, &e).await;
+                return Err(e);
+            }
+        };
+        match self.provision(id, &context, env, progress).await {
+            Ok(controller) => {
+                progress.phase(ProvisioningPhase::Ready, "Workspace is ready");
+                if let Some(quota) = &context.disk_quota {
+                    self.disk_quotas
+                        .write()
+                        .await
+                        .insert(id.to_string(), quota.clone());
+                }
+                self.workspace_contexts
+                    .write()
+                    .await
+                    .insert(id.to_string(), Arc::clone(&context));
+                // Checked under the lock `destroy_workspace` cancels with, so that the workspace
+                // is either registered before it is destroyed or stopped here
+                let cancelled = {
+                    let mut workspaces = self.workspaces.write().await;
+                    let cancelled = self.cancelled.write().await.remove(id);
+                    if !cancelled {
+                        workspaces.insert(id.to_string(), Arc::clone(&controller));
+                    }
+                    cancelled
+                };
+                if cancelled {
+                    self.disk_quotas.write().await.remove(id);
+                    self.workspace_contexts.write().await.remove(id);
+                    return Err(self.provisioning_cancelled(id, Some(controller)).await);
+                }
+                self.publish(Event::WorkspaceReady {
+                    workspace_id: id.to_string(),
+                });
+                Ok(())
+            }
+            Err(e) => {
+                self.revoke_cloud_credentials(id).await;
+                self.provisioning_failed(id, progress, &e).await;
+                Err(e)
+            }
+        }
+    }
+
+    // Stops a workspace that was destroyed while it was provisioning, `destroy_workspace` already
+    // removed the rest of it
+    async fn provisioning_cancelled(
+        &self,
+        id: &str,
+        controller: Option<Arc<dyn WorkspaceController>>,
+    ) -> anyhow::Error {
+        if let Some(controller) = controller {
+            if let Err(e) = controller.stop().await {
+                tracing::warn!("Failed to stop workspace: {:?}", e);
+            }
+        }
+        self.revoke_cloud_credentials(id).await;
+        self.ledger.write().await.finish(id, accounting::now_ms());
+        self.publish(Event::WorkspaceDestroyed {
+            workspace_id: id.to_string(),
+        });
+        self.callbacks.write().unwrap().remove(id);
+        anyhow::anyhow!("Workspace {} was destroyed while it was provisioning", id)
+    }
+
+    async fn provision(
+        &self,
+        id: &str,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<Arc<dyn WorkspaceController>> {
+        let controller = self
+            .provider(context)?
+            .provision(context, env.clone(), progress)
+            .await?;
         controller.init().await?;
-        self.workspaces.insert(id.clone(), controller);
+
+        // Injected after the images were built, so that they never contain the secrets
+        let mut secret_env = HashMap::new();
+        if !context.secrets.is_empty() {
+            progress.phase(ProvisioningPhase::StartingWorkspace, "Injecting secrets");
+            match secrets::inject_secrets(controller.as_ref(), &context.secrets).await {
+                Ok(env) => secret_env = env,
+                Err(e) => return Err(stop_failed(controller.as_ref(), e).await),
+            }
+        }
+        if !context.cloud_credentials.is_empty() {
+            progress.phase(
+                ProvisioningPhase::StartingWorkspace,
+                "Minting cloud credentials",
+            );
+            match cloud_credentials::mint(id, &context.cloud_credentials).await {
+                Ok((env, sessions)) => {
+                    secret_env.extend(env);
+                    self.cloud_sessions
+                        .write()
+                        .await
+                        .insert(id.to_string(), sessions);
+                }
+                Err(e) => return Err(stop_failed(controller.as_ref(), e).await),
+            }
+        }
+        let controller = SecretsController::wrap(controller, &context.secrets, secret_env);
+
+        if !context.daemons.is_empty() {
+            progress.phase(ProvisioningPhase::StartingWorkspace, "Starting daemons");
+            if let Err(e) = daemons::start_daemons(controller.as_ref(), &context.daemons, env).await
+            {
+                return Err(stop_failed(controller.as_ref(), e).await);
+            }
+        }
+
+        // The index is only a head start for lookups, so the workspace is usable without it
+        if context.index_symbols {
+            progress.phase(ProvisioningPhase::StartingWorkspace, "Indexing symbols");
+            match SymbolIndex::build(controller.as_ref(), &Codebase::from_context(context)).await {
+                Ok(index) => {
+                    self.symbol_indexes
+                        .write()
+                        .await
+                        .insert(id.to_string(), Arc::new(index));
+                }
+                Err(e) => tracing::warn!(workspace_id = id, "Could not index symbols: {:#}", e),
+            }
+        }
+        Ok(Arc::from(controller))
+    }
+
+    // Registers an environment created by other tooling, like a running container, as a
+    // workspace. Nothing is provisioned, and destroying the workspace leaves the environment
+    // running.
+    #[tracing::instrument(skip(self, labels))]
+    pub async fn adopt_workspace(
+        &self,
+        target: &str,
+        labels: HashMap<String, String>,
+        name: Option<&str>,
+    ) -> Result<String> {
+        validate_labels(&labels)?;
+        if let Some(name) = name {
+            validate_name(name)?;
+        }
+        let (id, progress) = self.register_workspace(labels, name, None).await?;
+
+        let controller = match self.provider.adopt(target).await {
+            Ok(controller) => controller,
+            Err(e) => {
+                progress.failed(&e);
+                self.failed_at
+                    .write()
+                    .await
+                    .insert(id.clone(), Instant::now());
+                self.release_name(&id).await;
+                self.ledger.write().await.finish(&id, accounting::now_ms());
+                return Err(e);
+            }
+        };
+        progress.phase(ProvisioningPhase::Ready, "Workspace is ready");
+        self.workspace_contexts
+            .write()
+            .await
+            .insert(id.clone(), self.context().await);
+        self.workspaces
+            .write()
+            .await
+            .insert(id.clone(), Arc::from(controller));
         Ok(id)
     }
 
-    pub async fn destroy_workspace(&mut self, id: &str) -> Result<bool> {
-        match self.workspaces.get(id) {
-            Some(controller) => {
-                controller.stop().await?;
-                self.workspaces.remove(id);
-                Ok(true)
+    // Creates a workspace from the current state of an existing one. Only the files are carried
+    // over, so the daemons of the workspace are started again in the clone.
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn clone_workspace(&self, id: &str, env: HashMap<String, String>) -> Result<String> {
+        let context = self.workspace_context(id).await?;
+        let controller =
+            Arc::<dyn WorkspaceController>::from(self.controller(id).await?.fork().await?);
+
+        if let Err(e) = daemons::start_daemons(controller.as_ref(), &context.daemons, env).await {
+            controller.stop().await?;
+            return Err(e);
+        }
+
+        let labels = self
+            .labels
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .unwrap_or_default();
+        let (clone_id, progress) = self.register_workspace(labels, None, None).await?;
+        progress.phase(ProvisioningPhase::Ready, "Workspace is ready");
+        if let Some(quota) = self.disk_quotas.read().await.get(id).cloned() {
+            self.disk_quotas
+                .write()
+                .await
+                .insert(clone_id.clone(), quota);
+        }
+        self.workspace_contexts
+            .write()
+            .await
+            .insert(clone_id.clone(), context);
+        self.workspaces
+            .write()
+            .await
+            .insert(clone_id.clone(), controller);
+        Ok(clone_id)
+    }
+
+    pub async fn workspace_status(&self, id: &str) -> Option<ProvisioningStatus> {
+        let mut status = self
+            .statuses
+            .read()
+            .await
+            .get(id)
+            .map(ProgressReporter::status)?;
+        status.health = self.health.read().await.get(id).copied();
+        Some(status)
+    }
+
+    // Checks the health of the workspaces every interval, publishing an event when it changed
+    pub fn watch_health(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let workspaces = server.workspaces.read().await.clone();
+                for (id, controller) in workspaces {
+                    match controller.health().await {
+                        Ok(health) => server.update_health(&id, health).await,
+                        // Like when the workspace was destroyed in the meantime
+                        Err(e) => {
+                            tracing::debug!(workspace_id = id, "Could not check health: {:?}", e)
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    // Samples the CPU time and network traffic of the workspaces every interval, and forgets the
+    // usage of workspaces destroyed long ago and the status of workspaces that failed to provision
+    pub fn record_usage(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let workspaces = server.workspaces.read().await.clone();
+                for (id, controller) in workspaces {
+                    server.record_counters(&id, controller.as_ref()).await;
+                }
+                server.ledger.write().await.prune(accounting::now_ms());
+                server.forget_failed_workspaces().await;
+            }
+        })
+    }
+
+    // Forgets the status and labels of the workspaces that failed to provision longer than
+    // `failed_status_ttl` ago
+    async fn forget_failed_workspaces(&self) {
+        let mut expired = Vec::new();
+        self.failed_at.write().await.retain(|id, failed_at| {
+            let keep = failed_at.elapsed() < self.failed_status_ttl;
+            if !keep {
+                expired.push(id.clone());
+            }
+            keep
+        });
+        for id in expired {
+            self.statuses.write().await.remove(&id);
+            self.labels.write().await.remove(&id);
+        }
+    }
+
+    async fn record_counters(&self, id: &str, controller: &dyn WorkspaceController) {
+        match controller.resource_counters().await {
+            Ok(Some(counters)) => {
+                self.ledger
+                    .write()
+                    .await
+                    .record(id, counters, accounting::now_ms());
+            }
+            Ok(None) => {}
+            // Like when the container is not running
+            Err(e) => tracing::debug!(workspace_id = id, "Could not read usage: {:?}", e),
+        }
+    }
+
+    // Vulnerability scans of the images the providers prepared, for contexts with an image scan
+    pub fn image_scans(&self) -> Vec<ImageScan> {
+        std::iter::once(&self.provider)
+            .chain(self.providers.values())
+            .flat_map(|provider| provider.image_scans())
+            .collect()
+    }
+
+    // Usage of the workspaces between `from` and `to` (Unix time in milliseconds) grouped by a
+    // label, by default the usage label from the start of the month until now
+    pub async fn usage_report(
+        &self,
+        group_by: Option<&str>,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> Result<UsageReport> {
+        let now = accounting::now_ms();
+        let from = from.unwrap_or_else(|| accounting::month_start_ms(now));
+        let to = to.unwrap_or(now);
+        if from >= to {
+            anyhow::bail!("The start of the range must be before its end");
+        }
+        let group_by = group_by.unwrap_or(&self.usage_label);
+        Ok(self.ledger.read().await.report(group_by, from, to, now))
+    }
+
+    async fn update_health(&self, id: &str, health: Option<Health>) {
+        let mut healths = self.health.write().await;
+        let previous = match health {
+            Some(health) => healths.insert(id.to_string(), health),
+            None => healths.remove(id),
+        };
+        if let (Some(health), true) = (health, previous != health) {
+            tracing::info!(workspace_id = id, ?health, "Workspace health changed");
+            self.publish(Event::WorkspaceHealthChanged {
+                workspace_id: id.to_string(),
+                health,
+            });
+        }
+    }
+
+    // Runs the command of the schedule in a fresh workspace every interval, until the schedule is
+    // removed. Replaces the schedule with the same name, after the run in progress.
+    pub async fn add_schedule(self: &Arc<Self>, schedule: Schedule) -> Result<()> {
+        schedule.validate()?;
+        // Checked now rather than at the first run, which may be a day away
+        self.resolve_context(schedule.profile.as_deref(), &schedule.variables)
+            .await?;
+
+        let token = CancellationToken::new();
+        let server = Arc::clone(self);
+        let task_schedule = schedule.clone();
+        let task_token = token.clone();
+        let period = Duration::from_secs(schedule.interval);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            loop {
+                tokio::select! {
+                    _ = task_token.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                server.run_schedule(&task_schedule).await;
+            }
+        });
+
+        let name = schedule.name.clone();
+        tracing::info!(schedule = %name, "Registered schedule");
+        if let Some((_, previous)) = self
+            .schedules
+            .write()
+            .await
+            .insert(name.clone(), (schedule, token))
+        {
+            previous.cancel();
+        }
+        self.schedule_runs.write().await.entry(name).or_default();
+        Ok(())
+    }
+
+    // Stops the schedule, a run in progress still finishes
+    pub async fn remove_schedule(&self, name: &str) -> bool {
+        let Some((_, token)) = self.schedules.write().await.remove(name) else {
+            return false;
+        };
+        token.cancel();
+        self.schedule_runs.write().await.remove(name);
+        true
+    }
+
+    // The schedules by name, with their recent runs
+    pub async fn schedules(&self) -> Vec<ScheduleStatus> {
+        let runs = self.schedule_runs.read().await;
+        let mut schedules = self
+            .schedules
+            .read()
+            .await
+            .values()
+            .map(|(schedule, _)| ScheduleStatus {
+                schedule: schedule.clone(),
+                runs: runs
+                    .get(&schedule.name)
+                    .map(ScheduleRuns::to_vec)
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+        schedules.sort_by(|a, b| a.schedule.name.cmp(&b.schedule.name));
+        schedules
+    }
+
+    // Creates a workspace for a run of the schedule, runs the command in it and destroys it.
+    // Failed runs are reported to the notifications of the schedule.
+    #[tracing::instrument(skip_all, fields(schedule = %schedule.name))]
+    async fn run_schedule(&self, schedule: &Schedule) {
+        let started_at = SystemTime::now();
+        let started = Instant::now();
+        let options = WorkspaceOptions {
+            env: schedule.env.clone(),
+            labels: HashMap::from([(SCHEDULE_LABEL.to_string(), schedule.name.clone())]),
+            profile: schedule.profile.clone(),
+            variables: schedule.variables.clone(),
+            ..Default::default()
+        };
+
+        let mut workspace_id = None;
+        let result = async {
+            let id = self.create_workspace(options).await?;
+            workspace_id = Some(id.clone());
+            let timeout = schedule.timeout.map(Duration::from_secs);
+            let (timeout, limits) = self.command_limits(&id, timeout, None).await;
+            let working_dir = schedule.working_dir.as_deref();
+            let env = schedule.env.clone();
+            match limits {
+                Some(limits) => {
+                    self.cmd_with_limits(&id, &schedule.cmd, working_dir, env, timeout, &limits)
+                        .await
+                }
+                None => {
+                    self.cmd_with_output(&id, &schedule.cmd, working_dir, env, timeout)
+                        .await
+                }
+            }
+        }
+        .await;
+        if let Some(id) = &workspace_id {
+            if let Err(e) = self.destroy_workspace(id).await {
+                tracing::error!(workspace_id = id, "Failed to destroy workspace: {:?}", e);
+            }
+        }
+
+        let mut run = ScheduleRun::new(started_at, started.elapsed());
+        run.workspace_id = workspace_id;
+        let run = match result {
+            Ok(output) => {
+                run.exit_code = Some(output.exit_code);
+                run.with_output(&output.output)
+            }
+            Err(e) => run.with_output(&format!("{:#}", e)),
+        };
+        if run.failed() {
+            tracing::warn!(exit_code = run.exit_code, "Scheduled run failed");
+            scheduler::notify(schedule, &run).await;
+        }
+        self.schedule_runs
+            .write()
+            .await
+            .entry(schedule.name.clone())
+            .or_default()
+            .push(run);
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn destroy_workspace(&self, id: &str) -> Result<bool> {
+        let controller = {
+            let mut workspaces = self.workspaces.write().await;
+            let controller = workspaces.remove(id);
+            if controller.is_none() && !self.cancel_provisioning(id).await {
+                return Ok(false);
+            }
+            controller
+        };
+        let Some(controller) = controller else {
+            // Still provisioning, `provision_workspace` stops it once it is provisioned
+            self.statuses.write().await.remove(id);
+            self.labels.write().await.remove(id);
+            self.release_name(id).await;
+            return Ok(true);
+        };
+        self.statuses.write().await.remove(id);
+        self.labels.write().await.remove(id);
+        self.release_name(id).await;
+        self.disk_quotas.write().await.remove(id);
+        if let Some(context) = self.workspace_contexts.write().await.remove(id) {
+            // The workspace is stopped either way, this only gives daemons a chance to shut down
+            if let Err(e) = daemons::stop_daemons(controller.as_ref(), &context.daemons).await {
+                tracing::warn!("Failed to stop daemons: {:?}", e);
+            }
+        }
+        self.command_caches.write().await.remove(id);
+        self.command_histories.write().await.remove(id);
+        self.restarts.write().await.remove(id);
+        self.health.write().await.remove(id);
+        // What the workspace used since it was last sampled
+        self.record_counters(id, controller.as_ref()).await;
+        self.ledger.write().await.finish(id, accounting::now_ms());
+        // The workspace is gone from the server even when stopping it fails
+        let stopped = controller.stop().await;
+        self.revoke_cloud_credentials(id).await;
+        self.symbol_indexes.write().await.remove(id);
+        self.publish(Event::WorkspaceDestroyed {
+            workspace_id: id.to_string(),
+        });
+        self.callbacks.write().unwrap().remove(id);
+        stopped?;
+        Ok(true)
+    }
+
+    // Marks a workspace that is still provisioning to be stopped by `provision_workspace` once it
+    // is provisioned. Called with the workspaces locked.
+    async fn cancel_provisioning(&self, id: &str) -> bool {
+        let provisioning = self
+            .statuses
+            .read()
+            .await
+            .get(id)
+            .is_some_and(|progress| progress.status().phase != ProvisioningPhase::Failed);
+        if provisioning {
+            self.cancelled.write().await.insert(id.to_string());
+        }
+        provisioning
+    }
+
+    // Revokes the cloud credentials minted for the workspace, if it has any
+    async fn revoke_cloud_credentials(&self, id: &str) {
+        if let Some(sessions) = self.cloud_sessions.write().await.remove(id) {
+            cloud_credentials::revoke(sessions).await;
+        }
+    }
+
+    pub async fn egress_requests(&self, id: &str) -> Result<Vec<EgressRequest>> {
+        self.controller(id).await?.egress_requests()
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn disk_usage(&self, id: &str) -> Result<DiskUsage> {
+        let bytes = self.controller(id).await?.disk_usage().await?;
+        Ok(DiskUsage::new(bytes, self.disk_quotas.read().await.get(id)))
+    }
+
+    // Checks the disk usage of every workspace with a quota, publishing an event for (and
+    // depending on the quota destroying) the ones over it
+    pub fn enforce_disk_quotas(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let server = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let quotas = server.disk_quotas.read().await.clone();
+                for (id, quota) in quotas {
+                    if let Err(e) = server.enforce_disk_quota(&id, &quota).await {
+                        tracing::error!(workspace_id = id, "Failed to enforce disk quota: {:?}", e);
+                    }
+                }
+            }
+        })
+    }
+
+    async fn enforce_disk_quota(&self, id: &str, quota: &DiskQuota) -> Result<()> {
+        let usage = self.disk_usage(id).await?;
+        if !usage.exceeded {
+            return Ok(());
+        }
+
+        tracing::warn!(
+            workspace_id = id,
+            bytes = usage.bytes,
+            max_bytes = quota.max_bytes,
+            "Workspace exceeds its disk quota"
+        );
+        self.publish(Event::DiskQuotaExceeded {
+            workspace_id: id.to_string(),
+            usage,
+            action: quota.action,
+        });
+        if quota.action == QuotaAction::Stop {
+            self.destroy_workspace(id).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn metrics(&self) -> Metrics {
+        Metrics {
+            workspaces: self.workspaces.read().await.len(),
+            provisions_in_progress: self.provisioning_queue.in_progress(),
+            max_concurrent_provisions: self.provisioning_queue.max_concurrent(),
+            provisioning_queue_depth: self.provisioning_queue.depth(),
+        }
+    }
+
+    pub async fn list_workspaces(&self, selector: &LabelSelector) -> Result<Vec<WorkspaceSummary>> {
+        let labels = self.labels.read().await;
+        let health = self.health.read().await;
+        let names: HashMap<_, _> = self
+            .names
+            .read()
+            .await
+            .iter()
+            .map(|(name, id)| (id.clone(), name.clone()))
+            .collect();
+        Ok(self
+            .workspaces
+            .read()
+            .await
+            .keys()
+            .map(|id| WorkspaceSummary {
+                id: id.clone(),
+                name: names.get(id).cloned(),
+                labels: labels.get(id).cloned().unwrap_or_default(),
+                health: health.get(id).copied(),
+            })
+            .filter(|workspace| selector.matches(&workspace.labels))
+            .collect())
+    }
+
+    // Destroys every workspace with the labels of the selector, returning their ids. An empty
+    // selector is refused rather than destroying everything.
+    pub async fn destroy_workspaces(&self, selector: &LabelSelector) -> Result<Vec<String>> {
+        if selector.is_empty() {
+            anyhow::bail!("Destroying workspaces requires a label selector");
+        }
+
+        let mut destroyed = Vec::new();
+        let mut failures = Vec::new();
+        for workspace in self.list_workspaces(selector).await? {
+            match self.destroy_workspace(&workspace.id).await {
+                Ok(true) => destroyed.push(workspace.id),
+                Ok(false) => {}
+                Err(e) => failures.push(format!("{}: {:?}", workspace.id, e)),
+            }
+        }
+        if !failures.is_empty() {
+            anyhow::bail!(
+                "Could not destroy all workspaces (destroyed {}): {}",
+                destroyed.join(", "),
+                failures.join("; ")
+            );
+        }
+        Ok(destroyed)
+    }
+
+    // Destroys every workspace before the server stops. Its background tasks hold on to the
+    // server, so the workspaces would not be dropped and cleaned up otherwise.
+    pub async fn shutdown(&self) {
+        let ids = self
+            .workspaces
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        futures_util::future::join_all(ids.iter().map(|id| async move {
+            if let Err(e) = self.destroy_workspace(id).await {
+                tracing::warn!(workspace_id = id, "Failed to destroy workspace: {:?}", e);
+            }
+        }))
+        .await;
+    }
+
+    async fn controller(&self, id: &str) -> Result<Arc<dyn WorkspaceController>> {
+        self.workspaces
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Workspace not found: {}", id))
+    }
+
+    // Commands fail when the container of the workspace exited. The workspace is then marked as
+    // stopped in its status, and restarted when its context has a restart policy. The command is
+    // not run again, as it may have done part of its work before the container exited.
+    async fn check_stopped<T>(&self, id: &str, result: Result<T>) -> Result<T> {
+        let stopped = match &result {
+            Err(e) => e
+                .downcast_ref::<ContainerStopped>()
+                .map(ToString::to_string),
+            Ok(_) => None,
+        };
+        let Some(stopped) = stopped else {
+            return result;
+        };
+        match self.restart_workspace(id, &stopped).await {
+            Ok(true) => result.context("The workspace was restarted, the command can be run again"),
+            Ok(false) => result,
+            Err(e) => {
+                tracing::error!(workspace_id = id, "Failed to restart workspace: {:?}", e);
+                if let Some(progress) = self.statuses.read().await.get(id) {
+                    progress.phase(
+                        ProvisioningPhase::Stopped,
+                        format!("{}, restarting it failed: {:#}", stopped, e),
+                    );
+                }
+                result
             }
-            None => Ok(false),
         }
     }
 
-    // TODO implement showable workspace type
-    pub async fn list_workspaces(&self) -> Result<Vec<String>> {
-        Ok(self.workspaces.keys().cloned().collect())
+    // Returns whether the workspace is running again
+    #[tracing::instrument(skip(self), fields(workspace_id = id))]
+    async fn restart_workspace(&self, id: &str, stopped: &str) -> Result<bool> {
+        let restarts = Arc::clone(
+            self.restarts
+                .write()
+                .await
+                .entry(id.to_string())
+                .or_default(),
+        );
+        let mut restarts = restarts.lock().await;
+        let controller = self.controller(id).await?;
+        // Another command may have restarted it in the meantime
+        if controller.is_running().await? {
+            return Ok(true);
+        }
+        let Some(progress) = self.statuses.read().await.get(id).cloned() else {
+            return Ok(false);
+        };
+        progress.phase(ProvisioningPhase::Stopped, stopped);
+
+        let context = self.workspace_context(id).await?;
+        let Some(policy) = &context.restart else {
+            return Ok(false);
+        };
+        if *restarts >= policy.max_restarts {
+            progress.phase(
+                ProvisioningPhase::Stopped,
+                format!("{}, it was restarted {} times already", stopped, *restarts),
+            );
+            return Ok(false);
+        }
+
+        tracing::warn!("Restarting workspace: {}", stopped);
+        progress.phase(ProvisioningPhase::Restarting, stopped);
+        *restarts += 1;
+        controller.restart().await?;
+        run_post_create_script(controller.as_ref(), &context, HashMap::new(), &progress).await?;
+        if !context.daemons.is_empty() {
+            daemons::start_daemons(controller.as_ref(), &context.daemons, HashMap::new()).await?;
+        }
+        progress.phase(
+            ProvisioningPhase::Ready,
+            format!("Workspace was restarted after: {}", stopped),
+        );
+        Ok(true)
+    }
+
+    async fn workspace_context(&self, id: &str) -> Result<Arc<WorkspaceContext>> {
+        self.workspace_contexts
+            .read()
+            .await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Workspace not found: {}", id))
     }
 
+    // The timeout and limits of a command, capped by the ones of the profile the workspace was
+    // created with
+    pub async fn command_limits(
+        &self,
+        id: &str,
+        timeout: Option<Duration>,
+        limits: Option<CommandLimits>,
+    ) -> (Option<Duration>, Option<CommandLimits>) {
+        let contexts = self.workspace_contexts.read().await;
+        let Some(profile) = contexts
+            .get(id)
+            .and_then(|context| context.execution_profile())
+        else {
+            return (timeout, limits);
+        };
+        let limits = limits.unwrap_or_default();
+        let limits = CommandLimits {
+            cpu_time: lowest(limits.cpu_time, profile.cpu_time.map(Duration::from_secs)),
+            max_output_bytes: lowest(limits.max_output_bytes, profile.max_output_bytes),
+        };
+        let timeout = lowest(timeout, profile.timeout.map(Duration::from_secs));
+        if limits == CommandLimits::default() {
+            return (timeout, None);
+        }
+        (timeout, Some(limits))
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn cmd(
         &self,
         id: &str,
@@ -66,12 +1643,12 @@ impl Server {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<()> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.cmd(cmd, working_dir, env, timeout).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
-        }
+        let controller = self.controller(id).await?;
+        let result = controller.cmd(cmd, working_dir, env, timeout).await;
+        self.check_stopped(id, result).await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn cmd_with_output(
         &self,
         id: &str,
@@ -80,41 +1657,600 @@ impl Server {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<CommandOutput> {
-        match self.workspaces.get(id) {
-            Some(controller) => {
-                controller
-                    .cmd_with_output(cmd, working_dir, env, timeout)
-                    .await
+        let controller = self.controller(id).await?;
+        let result = controller
+            .cmd_with_output(cmd, working_dir, env, timeout)
+            .await;
+        self.check_stopped(id, result).await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn cmd_with_spilled_output(
+        &self,
+        id: &str,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        max_inline: usize,
+    ) -> Result<SpilledOutput> {
+        let controller = self.controller(id).await?;
+        let result = outputs::cmd_with_spilled_output(
+            controller.as_ref(),
+            cmd,
+            working_dir,
+            env,
+            timeout,
+            max_inline,
+        )
+        .await;
+        self.check_stopped(id, result).await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn output_size(&self, id: &str, cmd_id: &str) -> Result<u64> {
+        let controller = self.controller(id).await?;
+        outputs::output_size(controller.as_ref(), cmd_id).await
+    }
+
+    // Reads `len` bytes from `start` of the output of a command run with
+    // `cmd_with_spilled_output`
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn read_output(
+        &self,
+        id: &str,
+        cmd_id: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<u8>> {
+        let controller = self.controller(id).await?;
+        outputs::read_output(controller.as_ref(), cmd_id, start, len).await
+    }
+
+    // Runs an idempotent command, or returns its earlier output when the command, its environment
+    // and the files it depends on did not change. By default those are the files in the git
+    // repository of the working directory, except the ignored ones. Also returns whether the output came from the cache.
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn cmd_with_cached_output(
+        &self,
+        id: &str,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        files: &[String],
+    ) -> Result<(CommandOutput, bool)> {
+        let controller = self.controller(id).await?;
+        let key =
+            command_cache::cache_key(controller.as_ref(), cmd, working_dir, &env, files).await;
+        if let Some(key) = &key {
+            let cached = self
+                .command_caches
+                .read()
+                .await
+                .get(id)
+                .and_then(|cache| cache.get(key));
+            if let Some(output) = cached {
+                tracing::debug!("Returning cached command output");
+                return Ok((output, true));
+            }
+        }
+
+        let output = controller
+            .cmd_with_output(cmd, working_dir, env, timeout)
+            .await;
+        let output = self.check_stopped(id, output).await?;
+        if let (Some(key), 0) = (key, output.exit_code) {
+            self.command_caches
+                .write()
+                .await
+                .entry(id.to_string())
+                .or_default()
+                .insert(key, output.clone());
+        }
+        Ok((output, false))
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn cmd_with_limits(
+        &self,
+        id: &str,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        let controller = self.controller(id).await?;
+        let result = controller
+            .cmd_with_limits(cmd, working_dir, env, timeout, limits)
+            .await;
+        self.check_stopped(id, result).await
+    }
+
+    pub async fn record_command(&self, id: &str, record: CommandRecord) {
+        if let Some(dir) = &self.command_history_dir {
+            if let Err(e) = command_history::persist(dir, id, &record).await {
+                tracing::warn!(workspace_id = id, "Could not persist command: {:?}", e);
+            }
+        }
+        self.command_histories
+            .write()
+            .await
+            .entry(id.to_string())
+            .or_default()
+            .push(record);
+    }
+
+    // The recent commands of a workspace, oldest first. Destroyed workspaces only have a history
+    // when it was written to disk.
+    pub async fn command_history(&self, id: &str) -> Result<Vec<CommandRecord>> {
+        if let Some(history) = self.command_histories.read().await.get(id) {
+            return Ok(history.records());
+        }
+        if let Some(dir) = &self.command_history_dir {
+            if let Some(records) = command_history::load(dir, id).await? {
+                return Ok(records);
+            }
+        }
+        self.controller(id).await?;
+        Ok(Vec::new())
+    }
+
+    // Where the changes made in the workspace so far come from, signed with the provenance key of
+    // the config when it has one. Commits and pull requests made with `Workspace` get it with
+    // `Workspace::set_provenance`.
+    pub async fn provenance(&self, id: &str) -> Result<Provenance> {
+        let context = self.workspace_context(id).await?;
+        let commands = self.command_history(id).await?;
+        let provenance = Provenance::new(id, &context, &commands);
+        match &self.config().provenance_key {
+            Some(key) => provenance.sign(key.as_bytes()),
+            None => Ok(provenance),
+        }
+    }
+
+    // Runs a command of the history again, with the same working directory, environment and
+    // limits, and records it as a replay of the original. Publishes events like commands run
+    // through the API.
+    #[tracing::instrument(skip_all, fields(workspace_id = id, command_id))]
+    pub async fn replay_command(&self, id: &str, command_id: &str) -> Result<CommandRecord> {
+        let request = self
+            .command_histories
+            .read()
+            .await
+            .get(id)
+            .and_then(|history| history.get(command_id))
+            .and_then(|record| record.request.clone())
+            .ok_or_else(|| CommandNotFound {
+                id: command_id.to_string(),
+            })?;
+        self.run_command(
+            id,
+            RunningCommand::start(request, Some(command_id.to_string())),
+        )
+        .await
+    }
+
+    // Runs the command in a background task and returns its id right away, so that long builds
+    // do not hold a request open. Its status, and once it finished its record, are returned by
+    // `command_status`.
+    pub(crate) async fn start_command(
+        self: &Arc<Self>,
+        id: &str,
+        request: CommandRequest,
+    ) -> Result<String> {
+        self.controller(id).await?;
+        let running = RunningCommand::start(request, None);
+        let command_id = running.id().to_string();
+        self.command_histories
+            .write()
+            .await
+            .entry(id.to_string())
+            .or_default()
+            .start(&running);
+
+        let server = Arc::clone(self);
+        let workspace_id = id.to_string();
+        tokio::spawn(
+            async move {
+                if let Err(e) = server.run_command(&workspace_id, running).await {
+                    tracing::error!(workspace_id, "Failed to run command: {:?}", e);
+                }
             }
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
+            .in_current_span(),
+        );
+        Ok(command_id)
+    }
+
+    // A command started with `start_command`, until it is dropped from the history
+    pub async fn command_status(&self, id: &str, command_id: &str) -> Result<CommandStatus> {
+        let status = self
+            .command_histories
+            .read()
+            .await
+            .get(id)
+            .and_then(|history| history.status(command_id));
+        if let Some(status) = status {
+            return Ok(status);
         }
+        // The workspace may be destroyed, with its history on disk
+        self.command_history(id)
+            .await?
+            .into_iter()
+            .find(|record| record.id == command_id)
+            .map(CommandStatus::Finished)
+            .ok_or_else(|| {
+                CommandNotFound {
+                    id: command_id.to_string(),
+                }
+                .into()
+            })
+    }
+
+    // Runs a command with the limits of its request, publishing events and recording it like
+    // commands run through the API. The record is returned when the command could be run.
+    async fn run_command(&self, id: &str, running: RunningCommand) -> Result<CommandRecord> {
+        let request = running.request().clone();
+        let limits = CommandLimits {
+            cpu_time: request.cpu_time.map(Duration::from_secs),
+            max_output_bytes: request.max_output_bytes,
+        };
+        let limits = (limits != CommandLimits::default()).then_some(limits);
+        let (timeout, limits) = self
+            .command_limits(id, request.timeout.map(Duration::from_secs), limits)
+            .await;
+        self.publish(Event::command_started(id, &request.cmd));
+        let working_dir = request.working_dir.as_deref();
+        let output = match limits {
+            Some(limits) => {
+                self.cmd_with_limits(id, &request.cmd, working_dir, request.env, timeout, &limits)
+                    .await
+            }
+            None => {
+                self.cmd_with_output(id, &request.cmd, working_dir, request.env, timeout)
+                    .await
+            }
+        };
+        let record = match &output {
+            Ok(output) => running.finish(
+                Some(output.exit_code),
+                &output.output,
+                output.limit_exceeded,
+                None,
+            ),
+            Err(e) => running.finish(None, &format!("{:#}", e), None, None),
+        };
+        self.publish(Event::command_finished(
+            id,
+            &request.cmd,
+            record.exit_code,
+            &record.output,
+        ));
+        self.record_command(id, record.clone()).await;
+        output?;
+        Ok(record)
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn write_file(
         &self,
         id: &str,
         path: &str,
         content: &[u8],
         working_dir: Option<&str>,
+        mode: Option<u32>,
     ) -> Result<()> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.write_file(path, content, working_dir).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
+        check_size(path, content.len(), self.size_limits.write_file)?;
+        self.controller(id)
+            .await?
+            .write_file_with_mode(path, content, working_dir, mode)
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id, files = files.len()))]
+    pub async fn write_files(
+        &self,
+        id: &str,
+        files: &[FileWrite],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        for file in files {
+            check_size(&file.path, file.content.len(), self.size_limits.write_file)?;
         }
+        self.controller(id)
+            .await?
+            .write_files(files, working_dir)
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn append_file(
+        &self,
+        id: &str,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        check_size(path, content.len(), self.size_limits.write_file)?;
+        self.controller(id)
+            .await?
+            .append_file(path, content, working_dir)
+            .await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn edit_file(
+        &self,
+        id: &str,
+        path: &str,
+        edits: &[FileEdit],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.controller(id)
+            .await?
+            .edit_file(path, edits, working_dir)
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn symlink(
+        &self,
+        id: &str,
+        path: &str,
+        target: &str,
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.controller(id)
+            .await?
+            .symlink(path, target, working_dir)
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn read_file(
         &self,
         id: &str,
         path: &str,
         working_dir: Option<&str>,
     ) -> Result<Vec<u8>> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.read_file(path, working_dir).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
+        let content = self
+            .controller(id)
+            .await?
+            .read_file(path, working_dir)
+            .await?;
+        check_size(path, content.len(), self.size_limits.read_file)?;
+        Ok(content)
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn shell(&self, id: &str, cols: u16, rows: u16) -> Result<ShellSession> {
+        self.controller(id).await?.shell(cols, rows).await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn logs(
+        &self,
+        id: &str,
+        follow: bool,
+        tail: Option<usize>,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.controller(id).await?.logs(follow, tail)
+    }
+
+    // Address the server reaches a port of a workspace at
+    #[tracing::instrument(skip_all, fields(workspace_id = id, port))]
+    pub async fn network_address(&self, id: &str, port: u16) -> Result<String> {
+        self.controller(id).await?.network_address(port).await
+    }
+
+    // Opens a connection to a port of the workspace, for clients that speak something other than
+    // HTTP to it, like database clients
+    #[tracing::instrument(skip_all, fields(workspace_id = id, port))]
+    pub async fn tunnel(&self, id: &str, port: u16) -> Result<tokio::net::TcpStream> {
+        let address = self.network_address(id, port).await?;
+        tokio::net::TcpStream::connect((address.as_str(), port))
+            .await
+            .with_context(|| format!("Nothing listens on port {} of the workspace", port))
+    }
+
+    // Starts a language server of the context the workspace was created with, `name` can be left
+    // out when there is only one
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn language_server(&self, id: &str, name: Option<&str>) -> Result<ShellSession> {
+        let controller = self.controller(id).await?;
+        let context = self.workspace_context(id).await?;
+        let server = select_language_server(&context.language_servers, name)?;
+        controller
+            .attach(&server.command, server.working_dir.as_deref())
+            .await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn git_status(&self, id: &str, working_dir: Option<&str>) -> Result<GitStatus> {
+        git_status(self.controller(id).await?.as_ref(), working_dir).await
+    }
+
+    // Lists a directory of the workspace as a tree, relative to its working directory
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn file_tree(
+        &self,
+        id: &str,
+        path: Option<&str>,
+        options: &TreeOptions,
+    ) -> Result<FileTree> {
+        file_tree::file_tree(
+            self.controller(id).await?.as_ref(),
+            path.unwrap_or("."),
+            options,
+            None,
+        )
+        .await
+    }
+
+    // Looks up symbols of the repositories of the workspace by name. The index is built when the
+    // workspace has none yet, and rebuilt with `refresh` to pick up changes since.
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn symbols(
+        &self,
+        id: &str,
+        query: &str,
+        kind: Option<&str>,
+        limit: usize,
+        refresh: bool,
+    ) -> Result<Vec<Symbol>> {
+        let index = match self.symbol_indexes.read().await.get(id) {
+            Some(index) if !refresh => Some(Arc::clone(index)),
+            _ => None,
+        };
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let controller = self.controller(id).await?;
+                let context = self.workspace_context(id).await?;
+                let codebase = Codebase::from_context(&context);
+                let index = Arc::new(SymbolIndex::build(controller.as_ref(), &codebase).await?);
+                self.symbol_indexes
+                    .write()
+                    .await
+                    .insert(id.to_string(), Arc::clone(&index));
+                index
+            }
+        };
+        Ok(index.search(query, kind, limit))
+    }
+
+    // A page of the source files of the repositories of the workspace, split into chunks
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn chunks(&self, id: &str, options: &ChunkOptions) -> Result<ChunkExport> {
+        let controller = self.controller(id).await?;
+        let context = self.workspace_context(id).await?;
+        Codebase::from_context(&context)
+            .chunks(controller.as_ref(), options, None)
+            .await
+    }
+
+    // The dependencies declared in the manifests of the repositories of the workspace
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn dependencies(&self, id: &str) -> Result<DependencyReport> {
+        let controller = self.controller(id).await?;
+        let context = self.workspace_context(id).await?;
+        dependencies::dependencies(controller.as_ref(), &Codebase::from_context(&context), None)
+            .await
+    }
+
+    // Replaces a pattern across the files of the workspace, or only returns the diff of doing so
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn replace(&self, id: &str, replacement: &Replacement) -> Result<ReplaceResult> {
+        replace::replace(self.controller(id).await?.as_ref(), replacement, None).await
+    }
+
+    // Fetches the repositories of a workspace that is reused for another task and fast-forwards
+    // them, see `RepositorySync` for what is reported per repository
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn sync_repositories(
+        &self,
+        id: &str,
+        reference: Option<&str>,
+    ) -> Result<Vec<RepositorySync>> {
+        let controller = self.controller(id).await?;
+        let context = self.workspace_context(id).await?;
+        Ok(repository_sync::sync_repositories(
+            controller.as_ref(),
+            &context.repositories,
+            reference,
+        )
+        .await)
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn env_info(&self, id: &str, working_dir: Option<&str>) -> Result<EnvInfo> {
+        env_info::env_info(self.controller(id).await?.as_ref(), working_dir).await
+    }
+
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn run_tests(
+        &self,
+        id: &str,
+        working_dir: Option<&str>,
+        framework: Option<TestFramework>,
+        timeout: Option<Duration>,
+    ) -> Result<TestReport> {
+        let (timeout, _) = self.command_limits(id, timeout, None).await;
+        test_runner::run_tests(
+            self.controller(id).await?.as_ref(),
+            working_dir,
+            framework,
+            timeout,
+        )
+        .await
+    }
+
+    // Collects coverage with the given config, or else the one of the context the workspace was
+    // created with
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn coverage(
+        &self,
+        id: &str,
+        config: Option<CoverageConfig>,
+        working_dir: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<CoverageReport> {
+        let (timeout, _) = self.command_limits(id, timeout, None).await;
+        let controller = self.controller(id).await?;
+        let config = match config {
+            Some(config) => config,
+            None => self
+                .workspace_context(id)
+                .await?
+                .coverage
+                .clone()
+                .context("No coverage command configured for the workspace")?,
+        };
+        coverage::collect_coverage(controller.as_ref(), &config, working_dir, timeout).await
+    }
+
+    // Runs the given linters, or else the ones of the context the workspace was created with
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn lint(
+        &self,
+        id: &str,
+        linters: Vec<Linter>,
+        working_dir: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<LintReport> {
+        let (timeout, _) = self.command_limits(id, timeout, None).await;
+        let controller = self.controller(id).await?;
+        let mut linters = linters;
+        if linters.is_empty() {
+            linters = self.workspace_context(id).await?.linters.clone();
+        }
+        if linters.is_empty() {
+            linters = lint::detect_linters(controller.as_ref(), working_dir).await?;
         }
+        lint::lint(controller.as_ref(), working_dir, &linters, timeout).await
+    }
+
+    // Gzipped tarball of the artifacts of the context the workspace was created with
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
+    pub async fn artifacts(
+        &self,
+        id: &str,
+        working_dir: Option<&str>,
+        compression: Compression,
+    ) -> Result<Vec<u8>> {
+        let controller = self.controller(id).await?;
+        let context = self.workspace_context(id).await?;
+        collect_artifacts(
+            controller.as_ref(),
+            &context.artifacts,
+            working_dir,
+            compression,
+        )
+        .await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn workspace_cmd(
         &self,
         id: &str,
@@ -123,12 +2259,13 @@ impl Server {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<()> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.cmd(cmd, working_dir, env, timeout).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
-        }
+        self.controller(id)
+            .await?
+            .cmd(cmd, working_dir, env, timeout)
+            .await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn workspace_cmd_with_output(
         &self,
         id: &str,
@@ -137,16 +2274,13 @@ impl Server {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<CommandOutput> {
-        match self.workspaces.get(id) {
-            Some(controller) => {
-                controller
-                    .cmd_with_output(cmd, working_dir, env, timeout)
-                    .await
-            }
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
-        }
+        self.controller(id)
+            .await?
+            .cmd_with_output(cmd, working_dir, env, timeout)
+            .await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn workspace_write_file(
         &self,
         id: &str,
@@ -154,21 +2288,30 @@ impl Server {
         content: &[u8],
         working_dir: Option<&str>,
     ) -> Result<()> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.write_file(path, content, working_dir).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
-        }
+        self.controller(id)
+            .await?
+            .write_file(path, content, working_dir)
+            .await
     }
 
+    #[tracing::instrument(skip_all, fields(workspace_id = id))]
     pub async fn workspace_read_file(
         &self,
         id: &str,
         path: &str,
         working_dir: Option<&str>,
     ) -> Result<Vec<u8>> {
-        match self.workspaces.get(id) {
-            Some(controller) => controller.read_file(path, working_dir).await,
-            None => Err(anyhow::anyhow!("Workspace not found: {}", id)),
-        }
+        self.controller(id)
+            .await?
+            .read_file(path, working_dir)
+            .await
+    }
+}
+
+// The lower of two limits, where a missing one is no limit
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
     }
 }
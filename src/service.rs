@@ -1,3 +1,4 @@
+use crate::config::Config;
 use crate::messaging;
 use anyhow::Result;
 use async_nats::Subscriber;
@@ -41,8 +42,8 @@ struct WorkspaceServiceContext {
 }
 
 impl WorkspaceService {
-    pub async fn start(workspace: Workspace) -> Result<Self> {
-        let channel = messaging::Channel::establish("workspace".to_string()).await?;
+    pub async fn start(workspace: Workspace, config: &Config) -> Result<Self> {
+        let channel = messaging::Channel::establish(config, "workspace".to_string()).await?;
         let subject = channel.channel_instance_subject.clone();
         let subscriber = channel.subscribe().await?;
         let controller = WorkspaceServiceContext::run(channel, subscriber, workspace);
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::Serialize;
+use shell_escape::escape;
+
+use crate::codebase::{Codebase, SupportedLanguages};
+use crate::WorkspaceController;
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Symbol {
+    pub name: String,
+    // Like `function`, `struct` or `method`, as ctags names them
+    pub kind: String,
+    pub path: String,
+    pub line: u32,
+    // The symbol it is defined in, like `struct:Server`
+    pub scope: Option<String>,
+    pub language: Option<SupportedLanguages>,
+}
+
+// The symbols of the repositories of a workspace at the time it was built. Lookups are in memory,
+// so they do not run anything in the workspace.
+#[derive(Debug, Default)]
+pub(crate) struct SymbolIndex {
+    symbols: Vec<Symbol>,
+    // Lowercase names of the symbols, for case insensitive lookups
+    names: Vec<String>,
+}
+
+impl SymbolIndex {
+    // Runs universal-ctags over the codebase, which needs to be installed in the workspace
+    #[tracing::instrument(skip(controller))]
+    pub(crate) async fn build(
+        controller: &dyn WorkspaceController,
+        codebase: &Codebase,
+    ) -> Result<Self> {
+        let languages = SupportedLanguages::ALL
+            .iter()
+            .map(|language| language.ctags_name())
+            .collect::<Vec<_>>()
+            .join(",");
+        let paths = codebase
+            .paths()
+            .iter()
+            .map(|path| escape(path.into()).to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        // Line numbers instead of search patterns keep tabs in the source out of the output
+        let cmd = format!(
+            "command -v ctags >/dev/null || exit 127\nctags --recurse --excmd=number --fields=+KlZ --languages={} -f - {}",
+            escape(languages.into()),
+            paths
+        );
+        let output = controller
+            .cmd_with_output(&cmd, None, HashMap::new(), None)
+            .await
+            .context("Could not index symbols")?;
+        match output.exit_code {
+            0 => Ok(Self::from_tags(&output.output)),
+            127 => anyhow::bail!("Indexing symbols needs universal-ctags in the workspace"),
+            _ => anyhow::bail!("Could not index symbols: {}", output.output.trim()),
+        }
+    }
+
+    fn from_tags(tags: &str) -> Self {
+        let symbols = tags.lines().filter_map(parse_tag).collect::<Vec<_>>();
+        let names = symbols
+            .iter()
+            .map(|symbol| symbol.name.to_lowercase())
+            .collect();
+        Self { symbols, names }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    // Symbols whose name contains the query, ignoring case. Exact matches come first, then the
+    // ones that start with it, shorter names before longer ones.
+    pub(crate) fn search(&self, query: &str, kind: Option<&str>, limit: usize) -> Vec<Symbol> {
+        let lowercase = query.to_lowercase();
+        let mut matches = self
+            .symbols
+            .iter()
+            .zip(&self.names)
+            .filter(|(symbol, _)| kind.is_none_or(|kind| symbol.kind == kind))
+            .filter_map(|(symbol, name)| {
+                let rank = if symbol.name == query {
+                    0
+                } else if *name == lowercase {
+                    1
+                } else if name.starts_with(&lowercase) {
+                    2
+                } else if name.contains(&lowercase) {
+                    3
+                } else {
+                    return None;
+                };
+                Some((rank, symbol))
+            })
+            .collect::<Vec<_>>();
+        matches.sort_by(|(rank, symbol), (other_rank, other)| {
+            (rank, symbol.name.len(), &symbol.path, symbol.line).cmp(&(
+                other_rank,
+                other.name.len(),
+                &other.path,
+                other.line,
+            ))
+        });
+        matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, symbol)| symbol.clone())
+            .collect()
+    }
+}
+
+// A line of ctags output, `name<TAB>path<TAB>line;"<TAB>key:value...`
+fn parse_tag(line: &str) -> Option<Symbol> {
+    if line.starts_with("!_") {
+        return None;
+    }
+    let mut fields = line.split('\t');
+    let name = fields.next()?;
+    let path = fields.next()?;
+    let line = fields.next()?.strip_suffix(";\"")?.parse().ok()?;
+    let mut symbol = Symbol {
+        name: name.to_string(),
+        kind: String::new(),
+        path: path.strip_prefix("./").unwrap_or(path).to_string(),
+        line,
+        scope: None,
+        language: None,
+    };
+    for field in fields {
+        match field.split_once(':') {
+            Some(("kind", kind)) => symbol.kind = kind.to_string(),
+            Some(("language", language)) => {
+                symbol.language = SupportedLanguages::from_ctags_name(language)
+            }
+            Some(("scope", scope)) => symbol.scope = Some(scope.to_string()),
+            _ => {}
+        }
+    }
+    Some(symbol)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TAGS: &str = "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+        Server\tsrc/server.rs\t120;\"\tkind:struct\tlanguage:Rust\n\
+        server\tsrc/main.rs\t12;\"\tkind:function\tlanguage:Rust\n\
+        create_server\t./src/server.rs\t180;\"\tkind:method\tlanguage:Rust\tscope:implementation:Server\n\
+        ServerConfig\tweb/config.ts\t3;\"\tkind:interface\tlanguage:TypeScript\n";
+
+    #[test]
+    fn test_parse_tag() {
+        let index = SymbolIndex::from_tags(TAGS);
+        assert_eq!(index.len(), 4);
+        assert_eq!(
+            index.symbols[2],
+            Symbol {
+                name: "create_server".to_string(),
+                kind: "method".to_string(),
+                path: "src/server.rs".to_string(),
+                line: 180,
+                scope: Some("implementation:Server".to_string()),
+                language: Some(SupportedLanguages::Rust),
+            }
+        );
+        assert_eq!(
+            index.symbols[3].language,
+            Some(SupportedLanguages::Typescript)
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let index = SymbolIndex::from_tags(TAGS);
+        let names = |symbols: Vec<Symbol>| {
+            symbols
+                .into_iter()
+                .map(|symbol| symbol.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            names(index.search("Server", None, 10)),
+            vec!["Server", "server", "ServerConfig", "create_server"]
+        );
+        assert_eq!(
+            names(index.search("server", None, 2)),
+            vec!["server", "Server"]
+        );
+        assert_eq!(
+            names(index.search("server", Some("method"), 10)),
+            vec!["create_server"]
+        );
+        assert!(index.search("client", None, 10).is_empty());
+    }
+}
@@ -0,0 +1,458 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::WorkspaceController;
+
+// Printed after the output of a command, followed by its exit code
+const EXIT_CODE_MARKER: &str = "__derrick_exit_code=";
+// Only the end of the output is kept in the report
+pub(crate) const MAX_REPORT_OUTPUT: usize = 16 * 1024;
+const JEST_RESULTS: &str = "/tmp/derrick-jest-results.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TestFramework {
+    Cargo,
+    Jest,
+    Pytest,
+    Go,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct TestCase {
+    pub name: String,
+    pub status: TestStatus,
+    // Why the test failed or was skipped, when the framework reports it
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TestReport {
+    pub framework: TestFramework,
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub tests: Vec<TestCase>,
+    pub exit_code: i32,
+    // End of the output of the run, e.g. to see why a build failed before any test ran
+    pub output: String,
+}
+
+impl TestReport {
+    pub fn success(&self) -> bool {
+        self.exit_code == 0 && self.failed == 0
+    }
+}
+
+impl TestFramework {
+    // Prints results in a format that can be parsed, on stdout
+    fn cmd(&self) -> String {
+        match self {
+            // The JSON format of libtest is unstable, RUSTC_BOOTSTRAP allows it on stable
+            TestFramework::Cargo => {
+                "RUSTC_BOOTSTRAP=1 cargo test --no-fail-fast -- -Z unstable-options --format json"
+                    .to_string()
+            }
+            TestFramework::Jest => format!(
+                "npx jest --ci --json --outputFile={results} >&2; code=$?; cat {results}; rm -f {results}; exit $code",
+                results = JEST_RESULTS
+            ),
+            TestFramework::Pytest => "python -m pytest -rA --color=no".to_string(),
+            TestFramework::Go => "go test -json ./...".to_string(),
+        }
+    }
+
+    fn parse(&self, output: &str) -> Vec<TestCase> {
+        match self {
+            TestFramework::Cargo => parse_cargo(output),
+            TestFramework::Jest => parse_jest(output),
+            TestFramework::Pytest => parse_pytest(output),
+            TestFramework::Go => parse_go(output),
+        }
+    }
+}
+
+// Guesses the framework from the files in the working directory
+pub(crate) async fn detect_framework(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+) -> Result<TestFramework> {
+    let listing = controller
+        .cmd_with_output("ls -A", working_dir, HashMap::new(), None)
+        .await?
+        .output;
+    let files = listing.lines().collect::<HashSet<_>>();
+
+    if files.contains("Cargo.toml") {
+        return Ok(TestFramework::Cargo);
+    }
+    if files.contains("go.mod") {
+        return Ok(TestFramework::Go);
+    }
+    if files.contains("package.json") {
+        let package = controller
+            .cmd_with_output("cat package.json", working_dir, HashMap::new(), None)
+            .await?
+            .output;
+        if package.contains("jest") {
+            return Ok(TestFramework::Jest);
+        }
+    }
+    let python_files = [
+        "pytest.ini",
+        "pyproject.toml",
+        "setup.py",
+        "setup.cfg",
+        "tox.ini",
+        "conftest.py",
+    ];
+    if python_files.iter().any(|file| files.contains(file)) {
+        return Ok(TestFramework::Pytest);
+    }
+    anyhow::bail!("Could not detect the test framework, specify it explicitly")
+}
+
+// Runs the tests of the project in the working directory, detecting the framework if not given
+pub(crate) async fn run_tests(
+    controller: &dyn WorkspaceController,
+    working_dir: Option<&str>,
+    framework: Option<TestFramework>,
+    timeout: Option<Duration>,
+) -> Result<TestReport> {
+    let framework = match framework {
+        Some(framework) => framework,
+        None => detect_framework(controller, working_dir).await?,
+    };
+
+    let (output, exit_code) =
+        run_to_completion(controller, &framework.cmd(), working_dir, timeout).await?;
+
+    let tests = framework.parse(&output);
+    let count = |status| tests.iter().filter(|test| test.status == status).count();
+    Ok(TestReport {
+        framework,
+        passed: count(TestStatus::Passed),
+        failed: count(TestStatus::Failed),
+        skipped: count(TestStatus::Skipped),
+        tests,
+        exit_code,
+        output: tail(&output, MAX_REPORT_OUTPUT).to_string(),
+    })
+}
+
+// Runs a command that is expected to fail at times, like a test run, returning its output (stdout
+// and stderr) and exit code. Failed commands are reported differently by controllers, so the
+// wrapper always succeeds and prints the exit code instead.
+pub(crate) async fn run_to_completion(
+    controller: &dyn WorkspaceController,
+    cmd: &str,
+    working_dir: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<(String, i32)> {
+    let wrapped = format!(
+        "(\n{}\n) 2>&1; printf '\\n{}%s\\n' \"$?\"",
+        cmd, EXIT_CODE_MARKER
+    );
+    let output = controller
+        .cmd_with_output(&wrapped, working_dir, HashMap::new(), timeout)
+        .await?;
+
+    match output.output.rsplit_once(EXIT_CODE_MARKER) {
+        Some((output, exit_code)) => Ok((
+            output.to_string(),
+            exit_code
+                .trim()
+                .parse()
+                .context("Could not parse exit code")?,
+        )),
+        // Killed before it finished, e.g. by the timeout
+        None => Ok((output.output, output.exit_code)),
+    }
+}
+
+pub(crate) fn tail(output: &str, max_len: usize) -> &str {
+    let mut start = output.len().saturating_sub(max_len);
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    &output[start..]
+}
+
+// Parses the JSON events of libtest, falling back to its human readable output
+fn parse_cargo(output: &str) -> Vec<TestCase> {
+    let json_tests = output
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|event| event["type"] == "test")
+        .filter_map(|event| {
+            let status = match event["event"].as_str()? {
+                "ok" => TestStatus::Passed,
+                "failed" | "timeout" => TestStatus::Failed,
+                "ignored" => TestStatus::Skipped,
+                _ => return None,
+            };
+            Some(TestCase {
+                name: event["name"].as_str()?.to_string(),
+                status,
+                message: event["stdout"]
+                    .as_str()
+                    .or(event["message"].as_str())
+                    .map(str::to_string),
+            })
+        })
+        .collect::<Vec<_>>();
+    if !json_tests.is_empty() {
+        return json_tests;
+    }
+
+    let line = regex::Regex::new(r"^test (\S+) \.\.\. (ok|FAILED|ignored)").unwrap();
+    output
+        .lines()
+        .filter_map(|l| line.captures(l))
+        .map(|captures| TestCase {
+            name: captures[1].to_string(),
+            status: match &captures[2] {
+                "ok" => TestStatus::Passed,
+                "FAILED" => TestStatus::Failed,
+                _ => TestStatus::Skipped,
+            },
+            message: None,
+        })
+        .collect()
+}
+
+// Parses the report jest writes with --json, a single line printed after its other output
+fn parse_jest(output: &str) -> Vec<TestCase> {
+    let Some(report) = output
+        .lines()
+        .rev()
+        .find(|line| line.starts_with('{'))
+        .and_then(|line| serde_json::from_str::<Value>(line).ok())
+    else {
+        return Vec::new();
+    };
+
+    let mut tests = Vec::new();
+    for file in report["testResults"].as_array().into_iter().flatten() {
+        for assertion in file["assertionResults"].as_array().into_iter().flatten() {
+            let status = match assertion["status"].as_str() {
+                Some("passed") => TestStatus::Passed,
+                Some("failed") => TestStatus::Failed,
+                _ => TestStatus::Skipped,
+            };
+            let messages = assertion["failureMessages"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>();
+            tests.push(TestCase {
+                name: assertion["fullName"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                status,
+                message: (!messages.is_empty()).then(|| messages.join("\n")),
+            });
+        }
+    }
+    tests
+}
+
+// Parses the short test summary pytest prints with -rA
+fn parse_pytest(output: &str) -> Vec<TestCase> {
+    let Some((_, summary)) = output.split_once("short test summary info") else {
+        return Vec::new();
+    };
+
+    summary
+        .lines()
+        .filter_map(|line| {
+            let (outcome, rest) = line.split_once(' ')?;
+            let status = match outcome {
+                "PASSED" | "XPASS" => TestStatus::Passed,
+                "FAILED" | "ERROR" => TestStatus::Failed,
+                "SKIPPED" | "XFAIL" => TestStatus::Skipped,
+                _ => return None,
+            };
+            // Skips are reported by location, e.g. `SKIPPED [1] tests/test_a.py:8: reason`
+            let (name, message) = if outcome == "SKIPPED" {
+                let rest = rest.split_once("] ").map_or(rest, |(_, rest)| rest);
+                rest.split_once(": ")
+                    .map_or((rest, None), |(name, reason)| (name, Some(reason)))
+            } else {
+                rest.split_once(" - ")
+                    .map_or((rest, None), |(name, message)| (name, Some(message)))
+            };
+            Some(TestCase {
+                name: name.trim().to_string(),
+                status,
+                message: message.map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+// Parses the events of `go test -json`. The output of a test is its message when it fails.
+fn parse_go(output: &str) -> Vec<TestCase> {
+    let mut outputs: HashMap<String, String> = HashMap::new();
+    let mut tests = Vec::new();
+
+    for event in output
+        .lines()
+        .filter(|line| line.starts_with('{'))
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+    {
+        let (Some(package), Some(test)) = (event["Package"].as_str(), event["Test"].as_str())
+        else {
+            continue;
+        };
+        let name = format!("{}.{}", package, test);
+        let status = match event["Action"].as_str() {
+            Some("output") => {
+                let line = event["Output"].as_str().unwrap_or_default();
+                outputs.entry(name).or_default().push_str(line);
+                continue;
+            }
+            Some("pass") => TestStatus::Passed,
+            Some("fail") => TestStatus::Failed,
+            Some("skip") => TestStatus::Skipped,
+            _ => continue,
+        };
+        let output = outputs.remove(&name);
+        tests.push(TestCase {
+            name,
+            status,
+            message: output.filter(|_| status != TestStatus::Passed),
+        });
+    }
+    tests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_parse_cargo_json() {
+        let output = r#"   Compiling derrick v0.2.2
+{ "type": "suite", "event": "started", "test_count": 3 }
+{ "type": "test", "event": "started", "name": "tests::a" }
+{ "type": "test", "name": "tests::a", "event": "ok" }
+{ "type": "test", "name": "tests::b", "event": "failed", "stdout": "assertion failed" }
+{ "type": "test", "name": "tests::c", "event": "ignored" }
+{ "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 1 }"#;
+
+        let tests = parse_cargo(output);
+        assert_eq!(tests.len(), 3);
+        assert_eq!(tests[0].status, TestStatus::Passed);
+        assert_eq!(tests[1].status, TestStatus::Failed);
+        assert_eq!(tests[1].message.as_deref(), Some("assertion failed"));
+        assert_eq!(tests[2].status, TestStatus::Skipped);
+    }
+
+    #[test]
+    fn test_parse_cargo_text() {
+        let output = "running 2 tests\ntest tests::a ... ok\ntest tests::b ... FAILED\n";
+        let tests = parse_cargo(output);
+        assert_eq!(tests[0].name, "tests::a");
+        assert_eq!(tests[1].status, TestStatus::Failed);
+    }
+
+    #[test]
+    fn test_parse_jest() {
+        let output = r#"PASS src/a.test.js
+{"numFailedTests":1,"testResults":[{"name":"src/a.test.js","assertionResults":[{"fullName":"adds","status":"passed","failureMessages":[]},{"fullName":"subtracts","status":"failed","failureMessages":["Expected 1"]},{"fullName":"later","status":"todo","failureMessages":[]}]}]}"#;
+
+        let tests = parse_jest(output);
+        assert_eq!(tests.len(), 3);
+        assert_eq!(tests[1].name, "subtracts");
+        assert_eq!(tests[1].message.as_deref(), Some("Expected 1"));
+        assert_eq!(tests[2].status, TestStatus::Skipped);
+    }
+
+    #[test]
+    fn test_parse_pytest() {
+        let output = "tests/test_a.py .Fs\n=========================== short test summary info ============================
+PASSED tests/test_a.py::test_ok
+FAILED tests/test_a.py::test_bad - assert 1 == 2
+SKIPPED [1] tests/test_a.py:8: unconditional skip
+==================== 1 failed, 1 passed, 1 skipped in 0.01s ====================";
+
+        assert_eq!(
+            parse_pytest(output),
+            vec![
+                TestCase {
+                    name: "tests/test_a.py::test_ok".to_string(),
+                    status: TestStatus::Passed,
+                    message: None,
+                },
+                TestCase {
+                    name: "tests/test_a.py::test_bad".to_string(),
+                    status: TestStatus::Failed,
+                    message: Some("assert 1 == 2".to_string()),
+                },
+                TestCase {
+                    name: "tests/test_a.py:8".to_string(),
+                    status: TestStatus::Skipped,
+                    message: Some("unconditional skip".to_string()),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_go() {
+        let output = r#"{"Action":"run","Package":"example.com/calc","Test":"TestAdd"}
+{"Action":"output","Package":"example.com/calc","Test":"TestAdd","Output":"=== RUN   TestAdd\n"}
+{"Action":"pass","Package":"example.com/calc","Test":"TestAdd","Elapsed":0}
+{"Action":"run","Package":"example.com/calc","Test":"TestSub"}
+{"Action":"output","Package":"example.com/calc","Test":"TestSub","Output":"    calc_test.go:12: got 2\n"}
+{"Action":"fail","Package":"example.com/calc","Test":"TestSub","Elapsed":0}
+{"Action":"fail","Package":"example.com/calc","Elapsed":0.1}"#;
+
+        let tests = parse_go(output);
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].name, "example.com/calc.TestAdd");
+        assert_eq!(tests[0].message, None);
+        assert_eq!(tests[1].status, TestStatus::Failed);
+        assert_eq!(
+            tests[1].message.as_deref(),
+            Some("    calc_test.go:12: got 2\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_framework() {
+        let controller = TestingController::new("test-runner");
+        assert!(detect_framework(&controller, None).await.is_err());
+
+        controller
+            .write_file(
+                "package.json",
+                br#"{"devDependencies": {"jest": "^29"}}"#,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            detect_framework(&controller, None).await.unwrap(),
+            TestFramework::Jest
+        );
+    }
+}
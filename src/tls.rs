@@ -0,0 +1,92 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+
+// Certificate of the server, and optionally the CA that client certificates have to be signed by
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+    // Clients without a certificate signed by one of these CAs are refused. When not set, clients
+    // need no certificate.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+impl TlsConfig {
+    // The configuration dropshot terminates TLS with
+    pub(crate) fn server_config(&self) -> Result<ServerConfig> {
+        let builder = ServerConfig::builder();
+        let builder = match &self.client_ca_file {
+            Some(client_ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca_file)? {
+                    roots.add(cert)?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .context("Invalid client CA")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(read_certs(&self.cert_file)?, read_key(&self.key_file)?)
+            .context("Invalid server certificate or key")?;
+        // Like dropshot does with the certificates of its own config
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path).with_context(|| format!("Could not read {}", path.display()))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .with_context(|| format!("Invalid private key in {}", path.display()))?
+        .with_context(|| format!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_reports_unusable_files() {
+        let dir = std::env::temp_dir().join(format!("derrick-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let empty = dir.join("empty.pem");
+        std::fs::write(&empty, "").unwrap();
+
+        let config = TlsConfig {
+            cert_file: dir.join("missing.pem"),
+            key_file: empty.clone(),
+            client_ca_file: None,
+        };
+        let error = config.server_config().unwrap_err();
+        assert!(format!("{:#}", error).contains("Could not read"));
+
+        let config = TlsConfig {
+            cert_file: empty.clone(),
+            key_file: empty,
+            client_ca_file: None,
+        };
+        let error = config.server_config().unwrap_err();
+        assert!(format!("{:#}", error).contains("No certificates found"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use anyhow::Result;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Maximum disk space a workspace may use, checked periodically by the server
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct DiskQuota {
+    pub max_bytes: u64,
+    #[serde(default)]
+    pub action: QuotaAction,
+}
+
+// What to do with a workspace that uses more than its quota
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaAction {
+    // Log a warning and publish an event
+    #[default]
+    Warn,
+    // Also destroy the workspace
+    Stop,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema, PartialEq)]
+pub struct DiskUsage {
+    // Bytes written in the workspace, for docker workspaces only what was added to the image
+    pub bytes: u64,
+    pub max_bytes: Option<u64>,
+    pub exceeded: bool,
+}
+
+impl DiskUsage {
+    pub fn new(bytes: u64, quota: Option<&DiskQuota>) -> Self {
+        let max_bytes = quota.map(|quota| quota.max_bytes);
+        Self {
+            bytes,
+            max_bytes,
+            exceeded: max_bytes.is_some_and(|max_bytes| bytes > max_bytes),
+        }
+    }
+}
+
+// Total size of the files in a directory, like `du -sb`. Symlinks are not followed.
+pub(crate) fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += directory_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_size() {
+        let dir = std::env::temp_dir().join(format!("derrick-usage-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.txt"), [0; 100]).unwrap();
+        std::fs::write(dir.join("nested/b.txt"), [0; 50]).unwrap();
+
+        assert_eq!(directory_size(&dir).unwrap(), 150);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_usage_exceeded() {
+        let quota = DiskQuota {
+            max_bytes: 100,
+            action: QuotaAction::Stop,
+        };
+        assert!(DiskUsage::new(101, Some(&quota)).exceeded);
+        assert!(!DiskUsage::new(100, Some(&quota)).exceeded);
+        assert!(!DiskUsage::new(u64::MAX, None).exceeded);
+    }
+
+    #[test]
+    fn test_deserialize_quota() {
+        let quota: DiskQuota = serde_json::from_str(r#"{"max_bytes": 1024}"#).unwrap();
+        assert_eq!(quota.action, QuotaAction::Warn);
+    }
+}
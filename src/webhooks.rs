@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GithubEventKind {
+    Issue,
+    PullRequest,
+    Push,
+}
+
+// The parts of a Github webhook delivery that are needed to act on it, with the full payload
+// for anything else
+#[derive(Debug, Clone, Serialize)]
+pub struct GithubEvent {
+    pub kind: GithubEventKind,
+    pub action: Option<String>,
+    pub repository_url: String,
+    // Issue or pull request number
+    pub number: Option<u64>,
+    // Pushed ref, e.g. refs/heads/main
+    pub reference: Option<String>,
+    pub payload: serde_json::Value,
+}
+
+// Verifies the X-Hub-Signature-256 header, which is `sha256=` followed by the hex encoded HMAC of
+// the body with the webhook secret as key
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> Result<()> {
+    let signature = signature
+        .strip_prefix("sha256=")
+        .context("Unsupported signature format")?;
+    let signature = hex::decode(signature).context("Signature is not valid hex")?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .context("Could not create hmac from secret")?;
+    mac.update(body);
+    mac.verify_slice(&signature)
+        .map_err(|_| anyhow::anyhow!("Signature does not match"))
+}
+
+// Converts a webhook delivery into an event, returns None for event types that are not handled
+pub fn parse_event(event_type: &str, body: &[u8]) -> Result<Option<GithubEvent>> {
+    let kind = match event_type {
+        "issues" => GithubEventKind::Issue,
+        "pull_request" => GithubEventKind::PullRequest,
+        "push" => GithubEventKind::Push,
+        _ => return Ok(None),
+    };
+
+    let payload: serde_json::Value =
+        serde_json::from_slice(body).context("Could not parse webhook payload")?;
+
+    let repository_url = payload["repository"]["clone_url"]
+        .as_str()
+        .context("Webhook payload has no repository")?
+        .to_string();
+    let number = match kind {
+        GithubEventKind::Issue => payload["issue"]["number"].as_u64(),
+        GithubEventKind::PullRequest => payload["pull_request"]["number"].as_u64(),
+        GithubEventKind::Push => None,
+    };
+
+    Ok(Some(GithubEvent {
+        kind,
+        action: payload["action"].as_str().map(str::to_string),
+        repository_url,
+        number,
+        reference: payload["ref"].as_str().map(str::to_string),
+        payload,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_signature() {
+        // Example from the Github documentation on validating webhook deliveries
+        let secret = "It's a Secret to Everybody";
+        let body = b"Hello, World!";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert!(verify_signature(secret, body, signature).is_ok());
+        assert!(verify_signature(secret, b"Hello, Mars!", signature).is_err());
+        assert!(verify_signature("wrong", body, signature).is_err());
+        assert!(verify_signature(secret, body, "sha1=abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_event() {
+        let body = serde_json::json!({
+            "action": "labeled",
+            "issue": { "number": 42 },
+            "repository": { "clone_url": "https://github.com/bosun-ai/derrick.git" }
+        })
+        .to_string();
+
+        let event = parse_event("issues", body.as_bytes()).unwrap().unwrap();
+        assert_eq!(event.kind, GithubEventKind::Issue);
+        assert_eq!(event.action.as_deref(), Some("labeled"));
+        assert_eq!(event.number, Some(42));
+        assert_eq!(
+            event.repository_url,
+            "https://github.com/bosun-ai/derrick.git"
+        );
+
+        assert!(parse_event("ping", b"{}").unwrap().is_none());
+    }
+}
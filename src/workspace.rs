@@ -1,15 +1,26 @@
+use crate::config::Config;
+use crate::conflicts::{self, MergeResult};
+use crate::credentials::scrub;
+use crate::git_status::{git_status, GitStatus};
+use crate::gitea::{GiteaPullRequest, GiteaSession};
+use crate::github::{GithubSession, ReviewComment, ReviewEvent};
+use crate::provenance::Provenance;
+use crate::push::{PushOptions, PushRejected};
 use crate::repository::Repository;
+use crate::test_runner::{self, TestFramework, TestReport};
 use crate::traits::{self, CodeCommands, Command, FileCommands, GitCommands, GithubCommands};
-use crate::workspace_controllers::{CommandOutput, WorkspaceController};
+use crate::workspace_controllers::{git, CommandOutput, WorkspaceController};
 use anyhow::Result;
 use async_trait::async_trait;
-use octocrab::models::pulls::PullRequest;
+use octocrab::models::pulls::{Comment, PullRequest, Review};
+use octocrab::models::repos::DiffEntry;
 use shell_escape::escape as escape_cow;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tracing::info;
 
 #[derive(Debug, Clone)]
@@ -19,21 +30,51 @@ pub struct Workspace(Arc<Mutex<WorkspaceInner>>);
 pub struct WorkspaceInner {
     adapter: Box<dyn WorkspaceController>,
     pub repository: Repository,
+    // Reused so that installation tokens are cached for the lifetime of the workspace
+    github_session: Option<Arc<GithubSession>>,
+    // Config of the Github app, the one of the process when not given
+    config: Option<Arc<Config>>,
+    // Attached to the commits and pull requests the workspace creates
+    provenance: Option<Provenance>,
+    // Keeps the token of the origin remote fresh, started by `Workspace::init`
+    credentials_refresh: Option<JoinHandle<()>>,
+}
+
+// How often the credentials are refreshed in the background. Well below the margin with which
+// `GithubSession` renews installation tokens, so the remote never has an expired one.
+const CREDENTIALS_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Outcome of `Workspace::cherry_pick`. The pull request is only opened when there were no
+// conflicts, otherwise push the branch and open it after resolving them.
+#[derive(Debug)]
+pub struct CherryPick {
+    pub result: MergeResult,
+    pub pull_request: Option<PullRequest>,
 }
 
 fn escape(s: &str) -> String {
     escape_cow(std::borrow::Cow::Borrowed(s)).to_string()
 }
 
-static MAIN_BRANCH_CMD: &str =
-    "git symbolic-ref refs/remotes/origin/HEAD | sed 's@^refs/remotes/origin/@@'";
-
 impl Workspace {
-    #[tracing::instrument(skip_all)]
     pub fn new(adapter: Box<dyn WorkspaceController>, repository: &Repository) -> Self {
+        Self::new_with_config(adapter, repository, None)
+    }
+
+    // Authenticates with the Github app of the config, or the one of the process without one
+    #[tracing::instrument(skip_all)]
+    pub fn new_with_config(
+        adapter: Box<dyn WorkspaceController>,
+        repository: &Repository,
+        config: Option<Arc<Config>>,
+    ) -> Self {
         let inner = WorkspaceInner {
             adapter,
             repository: repository.to_owned(),
+            github_session: None,
+            config,
+            provenance: None,
+            credentials_refresh: None,
         };
 
         Self(Arc::new(Mutex::new(inner)))
@@ -44,6 +85,7 @@ impl Workspace {
         info!("Initializing workspace");
 
         self.authenticate_with_repository_if_possible().await?;
+        self.refresh_credentials_in_background().await;
         self.0.lock().await.adapter.init().await?;
 
         if self.repository_exists().await {
@@ -150,18 +192,17 @@ impl Workspace {
     async fn clean_repository(&self) -> Result<()> {
         let inner = self.0.lock().await;
 
-        let checkout_cmd = format!("git checkout $({MAIN_BRANCH_CMD})");
-        let cmds = vec![
-            "git reset --hard",
-            "git clean -fd",
-            "git fetch origin",
-            &checkout_cmd,
-        ];
-
+        let cmds = vec!["git reset --hard", "git clean -fd", "git fetch origin"];
         for cmd in cmds {
             inner.adapter.cmd(cmd, None, HashMap::new(), None).await?;
         }
-        Ok(())
+
+        let main_branch = git::default_branch(inner.adapter.as_ref()).await?;
+        let checkout_cmd = format!("git checkout {}", escape(&main_branch));
+        inner
+            .adapter
+            .cmd(&checkout_cmd, None, HashMap::new(), None)
+            .await
     }
 
     #[tracing::instrument(skip_all, fields(bosun.tracing=true), name = "workspace.configure_git")]
@@ -170,8 +211,9 @@ impl Workspace {
             return Ok(());
         }
 
+        let github_session = self.github_session().await;
         let inner = self.0.lock().await;
-        match crate::github::GithubSession::try_new() {
+        match github_session {
             Ok(github_session) => {
                 // https://github.com/orgs/community/discussions/24664
                 let user = github_session.user().await?;
@@ -228,7 +270,20 @@ impl Workspace {
             return Ok(());
         }
 
-        match crate::github::GithubSession::try_new() {
+        // Ssh urls authenticate through the credentials of the repository instead of a token
+        if self.0.lock().await.repository.is_ssh_url() {
+            return Ok(());
+        }
+
+        // Repositories on the Gitea server of the config use its token, the rest the Github app
+        let repo_url = self.0.lock().await.repository.url.clone();
+        if let Some(gitea_session) = self.gitea_session(&repo_url).await {
+            let gitea_url = gitea_session.add_token_to_url(&repo_url)?;
+            self.0.lock().await.repository.url = gitea_url;
+            return Ok(());
+        }
+
+        match self.github_session().await {
             Ok(github_session) => {
                 // Locks should never go over awaits
                 let mut codebase_url: String = String::new();
@@ -250,6 +305,93 @@ impl Workspace {
         Ok(())
     }
 
+    async fn github_session(&self) -> Result<Arc<GithubSession>> {
+        let mut inner = self.0.lock().await;
+        if let Some(github_session) = &inner.github_session {
+            return Ok(Arc::clone(github_session));
+        }
+
+        let github_session = Arc::new(match &inner.config {
+            Some(config) => GithubSession::from_config(config)?,
+            None => GithubSession::try_new()?,
+        });
+        inner.github_session = Some(Arc::clone(&github_session));
+        Ok(github_session)
+    }
+
+    // The Gitea server of the config, when the repository is on it
+    async fn gitea_session(&self, repo_url: &str) -> Option<GiteaSession> {
+        let inner = self.0.lock().await;
+        let config = inner.config.as_deref().unwrap_or_else(crate::config);
+        config.gitea_url.as_ref()?;
+        match GiteaSession::from_config(config) {
+            Ok(session) if session.serves(repo_url) => Some(session),
+            Ok(_) => None,
+            Err(e) => {
+                tracing::warn!(error = ?e, "Could not set up the Gitea session");
+                None
+            }
+        }
+    }
+
+    /// Makes sure the origin remote has a valid token, updating it when the token was rotated.
+    /// Returns whether the remote was updated.
+    ///
+    /// Initialized workspaces already call this every few minutes in the background, and
+    /// [`Workspace::push`] before pushing.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn refresh_credentials(&self) -> Result<bool> {
+        let previous_url = self.0.lock().await.repository.url.clone();
+        self.authenticate_with_repository_if_possible().await?;
+
+        if self.0.lock().await.repository.url == previous_url {
+            return Ok(false);
+        }
+
+        self.update_remote().await?;
+        Ok(true)
+    }
+
+    // Refreshes the credentials every `CREDENTIALS_REFRESH_INTERVAL` until the workspace is
+    // dropped, so that the remote gets a new token before the old one expires
+    async fn refresh_credentials_in_background(&self) {
+        let mut inner = self.0.lock().await;
+        if inner
+            .credentials_refresh
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return;
+        }
+
+        let workspace = Arc::downgrade(&self.0);
+        inner.credentials_refresh = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(CREDENTIALS_REFRESH_INTERVAL).await;
+                let Some(inner) = workspace.upgrade() else {
+                    break;
+                };
+                if let Err(e) = Workspace(inner).refresh_credentials().await {
+                    tracing::warn!("Could not refresh credentials: {:?}", e);
+                }
+            }
+        }));
+    }
+
+    /// Adds the provenance as trailers to the commits and as a section to the descriptions of
+    /// the pull requests the workspace creates from now on, see `Server::provenance`.
+    pub async fn set_provenance(&self, provenance: Option<Provenance>) {
+        self.0.lock().await.provenance = provenance;
+    }
+
+    // The description of a pull request, with the provenance when there is one
+    async fn pull_request_description(&self, description: &str) -> String {
+        match &self.0.lock().await.provenance {
+            Some(provenance) => provenance.pull_request_description(description),
+            None => description.to_string(),
+        }
+    }
+
     #[tracing::instrument(skip_all, err)]
     pub async fn create_branch(&self, maybe_name: Option<&str>) -> Result<String> {
         let inner = self.0.lock().await;
@@ -266,6 +408,10 @@ impl Workspace {
     #[tracing::instrument(skip_all, err)]
     pub async fn commit(&self, message: &str, files: Option<Vec<String>>) -> Result<()> {
         let inner = self.0.lock().await;
+        let message = match &inner.provenance {
+            Some(provenance) => provenance.commit_message(message),
+            None => message.to_string(),
+        };
 
         if let Some(files) = files {
             // first add all the files, making sure to surround them with quotes
@@ -283,7 +429,7 @@ impl Workspace {
                 .cmd(&add_cmd, None, HashMap::new(), None)
                 .await?;
 
-            let cmd = format!("git commit -m {}", escape(message));
+            let cmd = format!("git commit -m {}", escape(&message));
             inner.adapter.cmd(&cmd, None, HashMap::new(), None).await
         } else {
             let add_cmd = "git add .";
@@ -291,17 +437,130 @@ impl Workspace {
                 .adapter
                 .cmd(add_cmd, None, HashMap::new(), None)
                 .await?;
-            let cmd = format!("git commit -m {}", escape(message));
+            let cmd = format!("git commit -m {}", escape(&message));
             inner.adapter.cmd(&cmd, None, HashMap::new(), None).await
         }
     }
 
     #[tracing::instrument(skip_all, err)]
-    pub async fn push(&self, target_branch: &str) -> Result<()> {
+    pub async fn status(&self) -> Result<GitStatus> {
         let inner = self.0.lock().await;
+        git_status(inner.adapter.as_ref(), None).await
+    }
 
-        let cmd = format!("git push origin HEAD:{}", escape(target_branch));
-        inner.adapter.cmd(&cmd, None, HashMap::new(), None).await
+    /// Runs the tests of the project and parses the results. The framework is detected from the
+    /// files in the working directory when not given.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn run_tests(&self, framework: Option<TestFramework>) -> Result<TestReport> {
+        let inner = self.0.lock().await;
+        test_runner::run_tests(inner.adapter.as_ref(), None, framework, None).await
+    }
+
+    /// Rebases the current branch onto `onto`. When it stops on conflicts, resolve them with
+    /// [`Workspace::resolve_and_continue`].
+    #[tracing::instrument(skip(self), err)]
+    pub async fn rebase(&self, onto: &str) -> Result<MergeResult> {
+        let inner = self.0.lock().await;
+        conflicts::rebase(inner.adapter.as_ref(), onto).await
+    }
+
+    /// Merges `branch` into the current branch. When it stops on conflicts, resolve them with
+    /// [`Workspace::resolve_and_continue`].
+    #[tracing::instrument(skip(self), err)]
+    pub async fn merge(&self, branch: &str) -> Result<MergeResult> {
+        let inner = self.0.lock().await;
+        conflicts::merge(inner.adapter.as_ref(), branch).await
+    }
+
+    /// Creates `target_branch` from `base` on origin and cherry-picks `commits` onto it, e.g. to
+    /// backport fixes to a release branch. When all commits apply cleanly and a pull request title
+    /// is given, the branch is pushed and a pull request against `base` is opened.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn cherry_pick(
+        &self,
+        commits: &[&str],
+        base: &str,
+        target_branch: &str,
+        pull_request_title: Option<&str>,
+    ) -> Result<CherryPick> {
+        let result = {
+            let inner = self.0.lock().await;
+            conflicts::cherry_pick(inner.adapter.as_ref(), commits, base, target_branch).await?
+        };
+
+        let pull_request = match (&result, pull_request_title) {
+            (MergeResult::Completed, Some(title)) => {
+                self.push(
+                    target_branch,
+                    &PushOptions {
+                        set_upstream: true,
+                        ..Default::default()
+                    },
+                )
+                .await?;
+
+                let description = format!(
+                    "Cherry-picked onto `{}`:\n\n{}",
+                    base,
+                    commits
+                        .iter()
+                        .map(|commit| format!("- {}", commit))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                );
+                let description = self.pull_request_description(&description).await;
+                let repo_url = self.0.lock().await.repository.url.clone();
+                let pull_request = self
+                    .github_session()
+                    .await?
+                    .create_merge_request(&repo_url, target_branch, base, title, &description)
+                    .await?;
+                tracing::info!("Created merge request: {}", pull_request.url);
+                Some(pull_request)
+            }
+            _ => None,
+        };
+
+        Ok(CherryPick {
+            result,
+            pull_request,
+        })
+    }
+
+    /// Writes the resolved content for each conflicted path and continues the rebase or merge
+    #[tracing::instrument(skip_all, err)]
+    pub async fn resolve_and_continue(
+        &self,
+        resolutions: &HashMap<String, String>,
+    ) -> Result<MergeResult> {
+        let inner = self.0.lock().await;
+        conflicts::resolve_and_continue(inner.adapter.as_ref(), resolutions).await
+    }
+
+    /// Pushes HEAD to `target_branch` on origin. When the remote rejects the push, the error is
+    /// a [`PushRejected`] describing why.
+    #[tracing::instrument(skip(self), err)]
+    pub async fn push(&self, target_branch: &str, options: &PushOptions) -> Result<()> {
+        // Installation tokens expire after an hour, long lived workspaces need a fresh one
+        self.refresh_credentials().await?;
+        let inner = self.0.lock().await;
+
+        let cmd = options.cmd(&escape(target_branch));
+        // Depending on the controller a failed command is an error or a non-zero exit code
+        let output = match inner
+            .adapter
+            .cmd_with_output(&cmd, None, HashMap::new(), None)
+            .await
+        {
+            Ok(output) if output.exit_code == 0 => return Ok(()),
+            Ok(output) => output.output,
+            Err(e) => format!("{:#}", e),
+        };
+
+        match PushRejected::from_output(&output) {
+            Some(rejected) => Err(rejected.into()),
+            None => anyhow::bail!("Failed to push: {}", scrub(&output)),
+        }
     }
 
     #[tracing::instrument(skip_all, err)]
@@ -311,23 +570,96 @@ impl Workspace {
         description: &str,
         branch_name: &str,
     ) -> Result<PullRequest> {
-        let github_session = crate::github::GithubSession::try_new()?;
+        let github_session = self.github_session().await?;
         let repo_url = self.0.lock().await.repository.url.clone();
-        let main_branch = self
-            .cmd_with_output(MAIN_BRANCH_CMD, HashMap::new(), None)
-            .await?
-            .output
-            .trim()
-            .to_owned();
+        let main_branch = {
+            let inner = self.0.lock().await;
+            git::default_branch(inner.adapter.as_ref()).await?
+        };
+        let description = self.pull_request_description(description).await;
 
         let mr = github_session
-            .create_merge_request(&repo_url, branch_name, &main_branch, title, description)
+            .create_merge_request(&repo_url, branch_name, &main_branch, title, &description)
             .await?;
 
         tracing::info!("Created merge request: {}", mr.url);
 
         Ok(mr)
     }
+
+    /// Opens a pull request on the Gitea server of the config, for repositories hosted there.
+    #[tracing::instrument(skip_all, err)]
+    pub async fn create_gitea_merge_request(
+        &self,
+        title: &str,
+        description: &str,
+        branch_name: &str,
+    ) -> Result<GiteaPullRequest> {
+        let repo_url = self.0.lock().await.repository.url.clone();
+        let gitea_session = self
+            .gitea_session(&repo_url)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Repository is not on the configured Gitea server"))?;
+        let main_branch = {
+            let inner = self.0.lock().await;
+            git::default_branch(inner.adapter.as_ref()).await?
+        };
+        let description = self.pull_request_description(description).await;
+
+        let pr = gitea_session
+            .create_merge_request(&repo_url, branch_name, &main_branch, title, &description)
+            .await?;
+
+        tracing::info!("Created merge request: {}", pr.html_url);
+
+        Ok(pr)
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn merge_request_files(&self, merge_request_number: u64) -> Result<Vec<DiffEntry>> {
+        let repo_url = self.0.lock().await.repository.url.clone();
+        self.github_session()
+            .await?
+            .get_merge_request_files(&repo_url, merge_request_number)
+            .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn merge_request_diff(&self, merge_request_number: u64) -> Result<String> {
+        let repo_url = self.0.lock().await.repository.url.clone();
+        self.github_session()
+            .await?
+            .get_merge_request_diff(&repo_url, merge_request_number)
+            .await
+    }
+
+    #[tracing::instrument(skip(self), err)]
+    pub async fn add_review_comment(
+        &self,
+        merge_request_number: u64,
+        comment: &ReviewComment,
+    ) -> Result<Comment> {
+        let repo_url = self.0.lock().await.repository.url.clone();
+        self.github_session()
+            .await?
+            .add_review_comment(&repo_url, merge_request_number, comment)
+            .await
+    }
+
+    #[tracing::instrument(skip(self, body, comments), err)]
+    pub async fn submit_review(
+        &self,
+        merge_request_number: u64,
+        event: ReviewEvent,
+        body: &str,
+        comments: &[ReviewComment],
+    ) -> Result<Review> {
+        let repo_url = self.0.lock().await.repository.url.clone();
+        self.github_session()
+            .await?
+            .submit_review(&repo_url, merge_request_number, event, body, comments)
+            .await
+    }
 }
 
 // command_to_string is a helper function that converts a Command enum to a string
@@ -356,6 +688,10 @@ fn command_to_shell_string(cmd: &traits::Command) -> String {
 #[async_trait]
 impl traits::Workspace for Workspace {
     async fn exec_cmd(&self, cmd: &traits::Command) -> Result<traits::CommandOutput> {
+        if let Command::Code(CodeCommands::RunTests) = cmd {
+            let report = self.run_tests(None).await?;
+            return Ok(serde_json::to_string(&report)?);
+        }
         self.cmd_with_output(&command_to_shell_string(cmd), HashMap::new(), None)
             .await
             .map(|output| output.output)
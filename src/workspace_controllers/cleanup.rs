@@ -0,0 +1,94 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+// Cleanups of workspaces that were dropped without being stopped, like removing their containers.
+// Drop can not wait for them, so they run in the background, and the server waits for the ones
+// that are still running before it exits.
+static PENDING: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+// Runs the cleanup on the runtime of the caller, which includes the threads of `spawn_blocking`.
+// Without a runtime, for instance when the controller outlived it, the cleanup blocks on a runtime
+// of its own, in a thread of its own so that it never runs within another runtime.
+pub(crate) fn spawn(cleanup: impl Future<Output = ()> + Send + 'static) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            let task = handle.spawn(cleanup);
+            let mut pending = PENDING.lock().unwrap();
+            pending.retain(|task| !task.is_finished());
+            pending.push(task);
+        }
+        Err(_) => {
+            let result = std::thread::spawn(move || {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .map(|runtime| runtime.block_on(cleanup))
+            })
+            .join();
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!("Could not start a runtime for cleanup: {:?}", e),
+                Err(_) => tracing::warn!("Cleanup panicked"),
+            }
+        }
+    }
+}
+
+/// Waits up to the timeout for the cleanups of dropped workspaces that are still running. Call it
+/// before the runtime shuts down, which would cancel them and leave their containers behind.
+pub async fn flush_cleanups(timeout: Duration) {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let pending = pending
+        .into_iter()
+        .filter(|task| !task.is_finished())
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        return;
+    }
+
+    tracing::info!("Waiting for {} workspace cleanups", pending.len());
+    if tokio::time::timeout(timeout, futures_util::future::join_all(pending))
+        .await
+        .is_err()
+    {
+        tracing::warn!("Workspace cleanups did not finish in time");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_cleanup_runs_outside_of_a_runtime() {
+        let done = Arc::new(AtomicBool::new(false));
+        let cleaned = Arc::clone(&done);
+        spawn(async move {
+            tokio::task::yield_now().await;
+            cleaned.store(true, Ordering::SeqCst);
+        });
+        assert!(done.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_flush_waits_for_cleanups() {
+        let done = Arc::new(AtomicBool::new(false));
+        let cleaned = Arc::clone(&done);
+        tokio::task::spawn_blocking(move || {
+            spawn(async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                cleaned.store(true, Ordering::SeqCst);
+            })
+        })
+        .await
+        .unwrap();
+
+        flush_cleanups(Duration::from_secs(5)).await;
+        assert!(done.load(Ordering::SeqCst));
+    }
+}
@@ -1,81 +1,499 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{BoxStream, StreamExt};
 use futures_util::TryStreamExt;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tracing::debug;
 
 use bollard::container::{
-    Config, CreateContainerOptions, DownloadFromContainerOptions, RemoveContainerOptions,
-    UploadToContainerOptions,
+    Config, CreateContainerOptions, DownloadFromContainerOptions, InspectContainerOptions,
+    LogOutput, LogsOptions, RemoveContainerOptions, StatsOptions, UploadToContainerOptions,
 };
-use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::exec::{CreateExecOptions, ResizeExecOptions, StartExecResults};
+use bollard::image::{CommitContainerOptions, RemoveImageOptions};
+use bollard::models::{EndpointSettings, HealthConfig, HealthStatusEnum, HostConfig};
+use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions};
 use bollard::Docker;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tar::{Archive, Builder as TarBuilder, Header as TarHeader};
+use tokio::sync::OnceCell;
 
-use crate::workspace_controllers::{CommandOutput, WorkspaceController};
+use crate::credentials::{scrub, RepositoryAuth};
+use crate::egress_proxy::{EgressProxy, EgressRequest};
+use crate::network::{proxy_config, proxy_env, NetworkPolicy, PROXY_HOST, PROXY_PORT};
+use crate::workspace_controllers::limits::{kill_tree_cmd, LimitedOutput, OutputStream};
+use crate::workspace_controllers::{
+    cleanup, git, shell_command, CommandLimits, CommandOutput, ContainerStopped, FileNotFound,
+    FileWrite, Health, OutputLineCallback, ResourceCounters, ShellSession, WorkspaceController,
+    FALLBACK_SHELLS,
+};
 
 pub static BASE_IMAGE: &str = "bosunai/build-baseimage";
+// Squid, which runs the egress proxy of workspaces with a host allowlist
+pub static EGRESS_PROXY_IMAGE: &str = "ubuntu/squid";
+// Name of the host in containers
+static DOCKER_HOST: &str = "host.docker.internal";
 
 #[derive(Debug)]
 pub struct DockerController {
     docker: Docker,
     pub container_id: String,
+    shell: OnceCell<Vec<String>>,
+    // Directory in the container with git mirrors to clone from
+    mirror_dir: Option<String>,
+    // Containers adopted from other tooling are left running when the workspace is stopped
+    owned: bool,
+    // Set once `stop` removed the container, so that dropping the controller does not remove it again
+    stopped: AtomicBool,
+    // Network and proxy of a workspace with a host allowlist, shared with its forks
+    egress: Option<Arc<Egress>>,
+    // Proxy of derrick that records the requests of the workspace, shared with its forks
+    egress_proxy: Option<Arc<EgressProxy>>,
+    // Commands run as root when not set
+    user: Option<WorkspaceUser>,
+    // Uid and gid of the user, which own the files that are written
+    owner: OnceCell<(u64, u64)>,
 }
 
-impl DockerController {
-    pub async fn start(docker: &Docker, base_image: &str, name: &str) -> Result<Self> {
-        let name = format!("{}-{}", name, uuid::Uuid::new_v4());
+// User that commands run as in docker workspaces, instead of root. Identified by name, by uid, or
+// both.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct WorkspaceUser {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    // The primary group of the user when not set
+    #[serde(default)]
+    pub gid: Option<u32>,
+    // Adds the user to the image and gives it the repositories, before the setup steps run
+    #[serde(default)]
+    pub create: bool,
+}
 
-        let container_config = Config {
-            image: Some(base_image),
-            tty: Some(true),
-            ..Default::default()
+impl WorkspaceUser {
+    // The name ends up in commands
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(name) = &self.name {
+            let mut chars = name.chars();
+            let valid = chars
+                .next()
+                .is_some_and(|c| c.is_ascii_alphanumeric() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+            if !valid {
+                anyhow::bail!("Invalid user name: {:?}", name);
+            }
+        }
+        if self.name.is_none() && self.uid.is_none() {
+            anyhow::bail!("The user needs a name or a uid");
+        }
+        if self.create && self.name.is_none() {
+            anyhow::bail!("Creating the user needs a name");
+        }
+        Ok(())
+    }
+
+    // As docker expects it for exec, e.g. `agent`, `1000` or `1000:1000`
+    fn exec_user(&self) -> String {
+        let user = match (&self.uid, &self.name) {
+            (Some(uid), _) => uid.to_string(),
+            (None, Some(name)) => name.clone(),
+            (None, None) => "root".to_string(),
         };
+        match self.gid {
+            Some(gid) => format!("{}:{}", user, gid),
+            None => user,
+        }
+    }
 
-        let container_options = Some(CreateContainerOptions {
-            name: name.as_str(),
-            platform: None,
-        });
+    // Adds the user, with useradd or the adduser of busybox, unless it exists already, and makes
+    // it the owner of the paths
+    pub(crate) fn create_script(&self, paths: &[&str]) -> Option<String> {
+        let name = self.name.as_deref().filter(|_| self.create)?;
+        let uid = self
+            .uid
+            .map(|uid| format!(" -u {}", uid))
+            .unwrap_or_default();
+        let (groupadd, group, addgroup, busybox_group) = match self.gid {
+            Some(gid) => (
+                format!("getent group {gid} > /dev/null || groupadd -g {gid} {name}\n    "),
+                format!(" -g {}", gid),
+                format!(
+                    "grep -q '^[^:]*:[^:]*:{gid}:' /etc/group || addgroup -g {gid} {name}\n    "
+                ),
+                format!(" -G \"$(awk -F: '$3 == {gid} {{ print $1 }}' /etc/group)\""),
+            ),
+            None => Default::default(),
+        };
 
-        let id = docker
-            .create_container::<&str, &str>(container_options, container_config)
-            .await?
-            .id;
+        let mut script = format!(
+            r#"set -e
+if ! id -u {name} > /dev/null 2>&1; then
+  if command -v useradd > /dev/null 2>&1; then
+    {groupadd}useradd -m{uid}{group} {name}
+  else
+    {addgroup}adduser -D{uid}{busybox_group} {name}
+  fi
+fi
+"#
+        );
+        if !paths.is_empty() {
+            script.push_str(&format!(
+                "chown -R \"$(id -u {name}):$(id -g {name})\" {}\n",
+                paths.join(" ")
+            ));
+        }
+        Some(script)
+    }
+}
 
-        debug!("Starting container with name: {} and id {}", name, id);
+// Health check of the workspace container, in place of the HEALTHCHECK of its image. Times are in
+// seconds.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Healthcheck {
+    // Shell command that exits with 0 while the workspace is healthy
+    pub command: String,
+    #[serde(default = "default_healthcheck_interval")]
+    pub interval: u64,
+    #[serde(default = "default_healthcheck_timeout")]
+    pub timeout: u64,
+    // Failures in a row after which the workspace is unhealthy
+    #[serde(default = "default_healthcheck_retries")]
+    pub retries: i64,
+    // Failures during this time after the container started do not count
+    #[serde(default)]
+    pub start_period: u64,
+}
 
-        docker.start_container::<String>(&id, None).await?;
+fn default_healthcheck_interval() -> u64 {
+    30
+}
 
-        Ok(Self {
-            docker: docker.clone(),
-            container_id: id,
+fn default_healthcheck_timeout() -> u64 {
+    30
+}
+
+fn default_healthcheck_retries() -> i64 {
+    3
+}
+
+impl Healthcheck {
+    // Docker takes the times in nanoseconds
+    fn config(&self) -> HealthConfig {
+        let nanos = |secs: u64| Some(Duration::from_secs(secs).as_nanos() as i64);
+        HealthConfig {
+            test: Some(vec!["CMD-SHELL".to_string(), self.command.clone()]),
+            interval: nanos(self.interval),
+            timeout: nanos(self.timeout),
+            retries: Some(self.retries),
+            start_period: nanos(self.start_period),
+            ..Default::default()
+        }
+    }
+}
+
+// Settings of the context for workspace containers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContainerOptions {
+    pub healthcheck: Option<Healthcheck>,
+    // Size of /dev/shm in bytes, docker gives 64MB when not set
+    pub shm_size: Option<u64>,
+    // Paths mounted as tmpfs, with their mount options like `size=1g`
+    pub tmpfs: HashMap<String, String>,
+    pub mounts: Vec<Mount>,
+}
+
+// A directory or file of the host mounted into workspaces
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Mount {
+    // Path on the host, which has to exist
+    pub source: String,
+    // Path in the workspace
+    pub target: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl Mount {
+    fn validate(&self) -> Result<()> {
+        // Docker separates the paths and options of a bind with colons and commas
+        let valid = |path: &str| path.starts_with('/') && !path.contains([':', ',']);
+        if !valid(&self.source) {
+            anyhow::bail!("Invalid mount source: {:?}", self.source);
+        }
+        if !valid(&self.target) || self.target == "/" {
+            anyhow::bail!("Invalid mount target: {:?}", self.target);
+        }
+        if !Path::new(&self.source).exists() {
+            anyhow::bail!("Mount source {} does not exist", self.source);
+        }
+        Ok(())
+    }
+
+    fn bind(&self) -> String {
+        let mode = if self.read_only { ":ro" } else { "" };
+        format!("{}:{}{}", self.source, self.target, mode)
+    }
+}
+
+impl ContainerOptions {
+    pub(crate) fn validate(&self) -> Result<()> {
+        for path in self.tmpfs.keys() {
+            if !path.starts_with('/') || path == "/" {
+                anyhow::bail!("Invalid tmpfs path: {:?}", path);
+            }
+        }
+        for mount in &self.mounts {
+            mount.validate()?;
+        }
+        Ok(())
+    }
+
+    fn host_config(&self, host_config: Option<HostConfig>) -> Option<HostConfig> {
+        if self.shm_size.is_none() && self.tmpfs.is_empty() && self.mounts.is_empty() {
+            return host_config;
+        }
+        let host_config = host_config.unwrap_or_default();
+        let mut binds = host_config.binds.clone().unwrap_or_default();
+        binds.extend(self.mounts.iter().map(Mount::bind));
+        Some(HostConfig {
+            shm_size: self.shm_size.map(|size| size as i64),
+            tmpfs: (!self.tmpfs.is_empty()).then(|| self.tmpfs.clone()),
+            binds: (!binds.is_empty()).then_some(binds),
+            ..host_config
         })
     }
+}
+
+// An internal network, without a way out but the egress proxy that is also attached to it. The
+// proxy only lets requests to the allowed hosts through.
+#[derive(Debug)]
+struct Egress {
+    docker: Docker,
+    network: String,
+    proxy_id: String,
+}
+
+impl Egress {
+    async fn start(docker: &Docker, hosts: &[String]) -> Result<Self> {
+        let network = format!("derrick-egress-{}", uuid::Uuid::new_v4());
+        docker
+            .create_network(CreateNetworkOptions {
+                name: network.as_str(),
+                internal: true,
+                ..Default::default()
+            })
+            .await?;
+
+        let config = Config {
+            image: Some(EGRESS_PROXY_IMAGE.to_string()),
+            entrypoint: Some(
+                [
+                    "sh",
+                    "-c",
+                    r#"printf '%s' "$PROXY_CONFIG" > /tmp/squid.conf && exec squid -N -f /tmp/squid.conf"#,
+                ]
+                .map(String::from)
+                .to_vec(),
+            ),
+            env: Some(vec![format!("PROXY_CONFIG={}", proxy_config(hosts))]),
+            ..Default::default()
+        };
+        let proxy = docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: format!("{}-proxy", network),
+                    platform: None,
+                }),
+                config,
+            )
+            .await;
+        let proxy_id = match proxy {
+            Ok(proxy) => proxy.id,
+            Err(e) => {
+                let _ = docker.remove_network(&network).await;
+                return Err(e.into());
+            }
+        };
+
+        // Reachable by name from the workspace, and through the default network of the proxy
+        // from the outside world
+        let egress = Self {
+            docker: docker.clone(),
+            network,
+            proxy_id,
+        };
+        let started = async {
+            docker
+                .connect_network(
+                    &egress.network,
+                    ConnectNetworkOptions {
+                        container: egress.proxy_id.as_str(),
+                        endpoint_config: EndpointSettings {
+                            aliases: Some(vec![PROXY_HOST.to_string()]),
+                            ..Default::default()
+                        },
+                    },
+                )
+                .await?;
+            docker
+                .start_container::<String>(&egress.proxy_id, None)
+                .await
+        };
+        if let Err(e) = started.await {
+            egress.remove().await;
+            return Err(e.into());
+        }
+        debug!("Started egress proxy on network {}", egress.network);
+        Ok(egress)
+    }
+
+    async fn remove(&self) {
+        if let Err(e) = stop_container(&self.docker, &self.proxy_id).await {
+            tracing::warn!("Could not remove egress proxy {}: {:?}", self.proxy_id, e);
+        }
+        if let Err(e) = self.docker.remove_network(&self.network).await {
+            tracing::warn!("Could not remove network {}: {:?}", self.network, e);
+        }
+    }
+}
+
+impl DockerController {
+    pub async fn start(docker: &Docker, base_image: &str, name: &str) -> Result<Self> {
+        Self::run(
+            docker,
+            base_image,
+            name,
+            None,
+            Vec::new(),
+            &ContainerOptions::default(),
+        )
+        .await
+    }
+
+    // Starts the container with the network it is allowed by the policy
+    pub async fn start_with_network(
+        docker: &Docker,
+        base_image: &str,
+        name: &str,
+        policy: &NetworkPolicy,
+        options: &ContainerOptions,
+    ) -> Result<Self> {
+        match policy {
+            NetworkPolicy::Full => {
+                Self::run(docker, base_image, name, None, Vec::new(), options).await
+            }
+            NetworkPolicy::Disabled => {
+                let host_config = HostConfig {
+                    network_mode: Some("none".to_string()),
+                    ..Default::default()
+                };
+                Self::run(
+                    docker,
+                    base_image,
+                    name,
+                    Some(host_config),
+                    Vec::new(),
+                    options,
+                )
+                .await
+            }
+            NetworkPolicy::Allowlist { hosts } => {
+                let egress = Egress::start(docker, hosts).await?;
+                let host_config = HostConfig {
+                    network_mode: Some(egress.network.clone()),
+                    ..Default::default()
+                };
+                let env = proxy_env(&format!("http://{}:{}", PROXY_HOST, PROXY_PORT))
+                    .into_iter()
+                    .map(|(key, value)| format!("{}={}", key, value))
+                    .collect();
+                match Self::run(docker, base_image, name, Some(host_config), env, options).await {
+                    Ok(mut controller) => {
+                        controller.egress = Some(Arc::new(egress));
+                        Ok(controller)
+                    }
+                    Err(e) => {
+                        egress.remove().await;
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    // Starts the container with its traffic going through the egress proxy of derrick, which it
+    // reaches on the host
+    pub async fn start_with_egress_proxy(
+        docker: &Docker,
+        base_image: &str,
+        name: &str,
+        proxy: Arc<EgressProxy>,
+        options: &ContainerOptions,
+    ) -> Result<Self> {
+        let host_config = HostConfig {
+            extra_hosts: Some(vec![format!("{}:host-gateway", DOCKER_HOST)]),
+            ..Default::default()
+        };
+        let env = proxy
+            .env(DOCKER_HOST)
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let mut controller =
+            Self::run(docker, base_image, name, Some(host_config), env, options).await?;
+        controller.egress_proxy = Some(proxy);
+        Ok(controller)
+    }
 
     pub async fn start_with_mounts(
         docker: &Docker,
         base_image: &str,
         name: &str,
         mounts: Vec<(&str, &str)>,
+    ) -> Result<Self> {
+        let host_config = HostConfig {
+            binds: Some(
+                mounts
+                    .iter()
+                    .map(|(host, container)| format!("{}:{}", host, container))
+                    .collect(),
+            ),
+            ..Default::default()
+        };
+        Self::run(
+            docker,
+            base_image,
+            name,
+            Some(host_config),
+            Vec::new(),
+            &ContainerOptions::default(),
+        )
+        .await
+    }
+
+    async fn run(
+        docker: &Docker,
+        image: &str,
+        name: &str,
+        host_config: Option<HostConfig>,
+        env: Vec<String>,
+        options: &ContainerOptions,
     ) -> Result<Self> {
         let name = format!("{}-{}", name, uuid::Uuid::new_v4());
 
         let container_config = Config {
-            image: Some(base_image),
+            image: Some(image),
             tty: Some(true),
-            host_config: Some(bollard::models::HostConfig {
-                binds: Some(
-                    mounts
-                        .iter()
-                        .map(|(host, container)| format!("{}:{}", host, container))
-                        .collect(),
-                ),
-                ..Default::default()
-            }),
+            host_config: options.host_config(host_config),
+            env: (!env.is_empty()).then(|| env.iter().map(String::as_str).collect()),
+            healthcheck: options.healthcheck.as_ref().map(Healthcheck::config),
             ..Default::default()
         };
 
@@ -96,46 +514,164 @@ impl DockerController {
         Ok(Self {
             docker: docker.clone(),
             container_id: id,
+            shell: OnceCell::new(),
+            mirror_dir: None,
+            owned: true,
+            stopped: AtomicBool::new(false),
+            egress: None,
+            egress_proxy: None,
+            user: None,
+            owner: OnceCell::new(),
         })
     }
-}
 
-async fn stop_container(docker: &Docker, container_id: &str) -> Result<()> {
-    docker
-        .remove_container(
-            container_id,
-            Some(RemoveContainerOptions {
-                force: true,
-                ..Default::default()
-            }),
-        )
-        .await?;
-    Ok(())
-}
+    // Exec fails when the container is not running, which is told apart as `ContainerStopped`
+    async fn exec_error(&self, error: bollard::errors::Error) -> anyhow::Error {
+        let state = self
+            .docker
+            .inspect_container(&self.container_id, None::<InspectContainerOptions>)
+            .await
+            .ok()
+            .and_then(|inspect| inspect.state);
+        match state {
+            Some(state) if state.running == Some(false) => ContainerStopped {
+                container_id: self.container_id.clone(),
+                exit_code: state.exit_code,
+                oom_killed: state.oom_killed.unwrap_or(false),
+            }
+            .into(),
+            _ => error.into(),
+        }
+    }
 
-#[async_trait]
-impl WorkspaceController for DockerController {
-    async fn init(&self) -> Result<()> {
-        // Can also connect over http or tls
-        Ok(())
+    // Uses a running container that was created by something else, by id or name
+    pub async fn adopt(docker: &Docker, container: &str) -> Result<Self> {
+        let inspect = docker
+            .inspect_container(container, None::<InspectContainerOptions>)
+            .await?;
+        let running = inspect
+            .state
+            .and_then(|state| state.running)
+            .unwrap_or(false);
+        if !running {
+            anyhow::bail!("Container {} is not running", container);
+        }
+        let id = inspect
+            .id
+            .ok_or_else(|| anyhow::anyhow!("Container {} has no id", container))?;
+
+        debug!("Adopting container {} with id {}", container, id);
+        Ok(Self {
+            docker: docker.clone(),
+            container_id: id,
+            shell: OnceCell::new(),
+            mirror_dir: None,
+            owned: false,
+            stopped: AtomicBool::new(false),
+            egress: None,
+            egress_proxy: None,
+            user: None,
+            owner: OnceCell::new(),
+        })
     }
 
-    async fn stop(&self) -> Result<()> {
-        stop_container(&self.docker, &self.container_id).await
+    /// Uses the given shell for running commands instead of detecting one in the container.
+    pub fn with_shell(self, shell: Option<Vec<String>>) -> Self {
+        if let Some(shell) = shell {
+            let _ = self.shell.set(shell);
+        }
+        self
     }
 
-    async fn cmd_with_output(
+    /// Runs the commands as the given user instead of root, and gives it the files that are
+    /// written.
+    pub fn with_user(mut self, user: Option<WorkspaceUser>) -> Self {
+        self.user = user;
+        self
+    }
+
+    // Permissions of the file, if it exists
+    async fn existing_mode(&self, path: &Path) -> Option<u32> {
+        let output = self
+            .cmd_with_output(
+                &format!(
+                    "stat -c %a {}",
+                    shell_escape::escape(path.to_string_lossy())
+                ),
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .ok()?;
+        if output.exit_code != 0 {
+            return None;
+        }
+        u32::from_str_radix(output.output.trim(), 8).ok()
+    }
+
+    // Uid and gid of the user, looked up in the container when they are not configured
+    async fn owner(&self) -> Result<Option<(u64, u64)>> {
+        let Some(user) = &self.user else {
+            return Ok(None);
+        };
+        let owner = self
+            .owner
+            .get_or_try_init(|| async {
+                if let (Some(uid), Some(gid)) = (user.uid, user.gid) {
+                    return anyhow::Ok((uid as u64, gid as u64));
+                }
+                let output = self
+                    .cmd_with_output("id -u; id -g", None, HashMap::new(), None)
+                    .await?;
+                let mut ids = output.output.lines().map(|id| id.trim().parse::<u64>());
+                match (ids.next(), ids.next()) {
+                    (Some(Ok(uid)), Some(Ok(gid))) => Ok((uid, gid)),
+                    _ => anyhow::bail!("Could not look up user: {}", output.output),
+                }
+            })
+            .await?;
+        Ok(Some(*owner))
+    }
+
+    /// Clones repositories with the git mirrors in the given container directory as reference.
+    pub fn with_mirror_dir(mut self, mirror_dir: Option<String>) -> Self {
+        self.mirror_dir = mirror_dir;
+        self
+    }
+
+    // The exec id ties the command to the docker daemon logs
+    #[tracing::instrument(
+        skip_all,
+        fields(container_id = %self.container_id, exec_id, cmd = scrub(cmd))
+    )]
+    async fn exec(
         &self,
         cmd: &str,
-        _working_dir: Option<&str>,
         env: HashMap<String, String>,
         timeout: Option<Duration>,
+        limits: &CommandLimits,
+        on_line: Option<&OutputLineCallback>,
     ) -> Result<CommandOutput> {
         let env_strings: Vec<String> = env
             .into_iter()
             .map(|(k, v)| format!("{}={}", k, v))
             .collect();
 
+        // Docker cannot kill an exec, so the shell records its pid for when the command has to be
+        // killed for writing too much output
+        let pid_file = limits
+            .max_output_bytes
+            .map(|_| format!("/tmp/derrick-cmd-{}.pid", uuid::Uuid::new_v4()));
+        let mut cmd = limits.apply_cpu_limit(cmd);
+        if let Some(pid_file) = &pid_file {
+            cmd = format!(
+                "echo $$ > {pid_file}; trap 'rm -f {pid_file}' EXIT\n{cmd}",
+                pid_file = pid_file,
+                cmd = cmd
+            );
+        }
+
         let timeout_str: String;
         let mut cmd_vec = Vec::with_capacity(5);
 
@@ -144,12 +680,11 @@ impl WorkspaceController for DockerController {
             cmd_vec.push("timeout");
             cmd_vec.push(timeout_str.as_str());
         }
-        cmd_vec.push("bash");
-        cmd_vec.push("-c");
-        cmd_vec.push(cmd);
+        cmd_vec.extend(shell_command(self.shell().await?, &cmd));
 
         // TODO: Working dir
-        let exec = self
+        let user = self.user.as_ref().map(WorkspaceUser::exec_user);
+        let exec = match self
             .docker
             .create_exec(
                 &self.container_id,
@@ -158,30 +693,172 @@ impl WorkspaceController for DockerController {
                     attach_stderr: Some(true),
                     cmd: Some(cmd_vec),
                     env: Some(env_strings.iter().map(|s| s.as_str()).collect()),
+                    user: user.as_deref(),
                     ..Default::default()
                 },
             )
-            .await?;
+            .await
+        {
+            Ok(exec) => exec,
+            Err(e) => return Err(self.exec_error(e).await),
+        };
+        tracing::Span::current().record("exec_id", exec.id.as_str());
 
-        let mut response = String::new();
+        let mut output = LimitedOutput::new(limits).with_lines(on_line);
 
-        if let StartExecResults::Attached { mut output, .. } =
-            self.docker.start_exec(&exec.id, None).await?
+        if let StartExecResults::Attached {
+            output: mut stream, ..
+        } = self.docker.start_exec(&exec.id, None).await?
         {
-            while let Some(Ok(msg)) = output.next().await {
-                response.push_str(&msg.to_string());
+            while let Some(msg) = stream.next().await {
+                let pushed = match msg? {
+                    LogOutput::StdOut { message } | LogOutput::Console { message } => {
+                        output.push(OutputStream::Stdout, &message)
+                    }
+                    LogOutput::StdErr { message } => output.push(OutputStream::Stderr, &message),
+                    LogOutput::StdIn { .. } => true,
+                };
+                if !pushed {
+                    break;
+                }
             }
         } else {
             todo!();
         }
 
+        if let (true, Some(pid_file)) = (output.exceeded(), &pid_file) {
+            tracing::warn!("Command exceeded its output limit, killing it");
+            self.cmd_with_output(&kill_tree_cmd(pid_file), None, HashMap::new(), None)
+                .await?;
+        }
+
         let exec_inspect = self.docker.inspect_exec(&exec.id).await?;
         let exit_code = exec_inspect.exit_code.unwrap_or(0) as i32;
 
-        Ok(CommandOutput {
-            output: response,
-            exit_code,
-        })
+        Ok(output.finish(exit_code, limits, timeout))
+    }
+
+    async fn shell(&self) -> Result<&[String]> {
+        self.shell
+            .get_or_try_init(|| detect_shell(&self.docker, &self.container_id))
+            .await
+            .map(Vec::as_slice)
+    }
+}
+
+// Picks the first of the fallback shells that is available in the container
+// Files are uploaded and downloaded by absolute path, relative paths start at the working dir or
+// otherwise at the root of the container
+fn resolve_path(path: &str, working_dir: Option<&str>) -> PathBuf {
+    Path::new("/")
+        .join(working_dir.unwrap_or_default())
+        .join(path)
+}
+
+// Docker answers with a 404 both for missing files and for missing containers
+fn read_error(path: &str, error: bollard::errors::Error) -> anyhow::Error {
+    match error {
+        bollard::errors::Error::DockerResponseServerError {
+            status_code: 404,
+            message,
+        } if !message.starts_with("No such container") => FileNotFound {
+            path: path.to_string(),
+        }
+        .into(),
+        error => anyhow::Error::new(error).context(format!("Could not download {}", path)),
+    }
+}
+
+async fn detect_shell(docker: &Docker, container_id: &str) -> Result<Vec<String>> {
+    for candidate in FALLBACK_SHELLS {
+        let exec = docker
+            .create_exec(
+                container_id,
+                CreateExecOptions {
+                    cmd: Some(vec![candidate[0], candidate[1], "true"]),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        if let StartExecResults::Attached { mut output, .. } =
+            docker.start_exec(&exec.id, None).await?
+        {
+            while output.next().await.is_some() {}
+        }
+        if docker.inspect_exec(&exec.id).await?.exit_code == Some(0) {
+            debug!(
+                "Detected shell {:?} in container {}",
+                candidate, container_id
+            );
+            return Ok(candidate.iter().map(|s| s.to_string()).collect());
+        }
+    }
+    Err(anyhow::anyhow!(
+        "No usable shell found in container {}",
+        container_id
+    ))
+}
+
+async fn stop_container(docker: &Docker, container_id: &str) -> Result<()> {
+    docker
+        .remove_container(
+            container_id,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+    Ok(())
+}
+
+#[async_trait]
+impl WorkspaceController for DockerController {
+    async fn init(&self) -> Result<()> {
+        // Can also connect over http or tls
+        Ok(())
+    }
+
+    async fn stop(&self) -> Result<()> {
+        if !self.owned {
+            return Ok(());
+        }
+        stop_container(&self.docker, &self.container_id).await?;
+        self.stopped.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn cmd_with_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        self.cmd_with_limits(cmd, working_dir, env, timeout, &CommandLimits::default())
+            .await
+    }
+
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        _working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        self.exec(cmd, env, timeout, limits, None).await
+    }
+
+    async fn cmd_with_streamed_output(
+        &self,
+        cmd: &str,
+        _working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        on_line: &OutputLineCallback,
+    ) -> Result<CommandOutput> {
+        self.exec(cmd, env, None, &CommandLimits::default(), Some(on_line))
+            .await
     }
 
     async fn cmd(
@@ -209,12 +886,20 @@ impl WorkspaceController for DockerController {
         content: &[u8],
         working_dir: Option<&str>,
     ) -> Result<()> {
-        let mut path = Path::new(path).to_path_buf();
-
-        if let Some(working_dir) = working_dir {
-            path = Path::new(working_dir).join(path);
-        }
+        self.write_file_with_mode(path, content, working_dir, None)
+            .await
+    }
 
+    // The upload replaces the file with the mode in the tar header, so without a mode the one of
+    // the existing file is looked up
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        let path = resolve_path(path, working_dir);
         let directory = if let Some(directory) = path.parent() {
             directory.to_string_lossy().to_string()
         } else {
@@ -233,7 +918,15 @@ impl WorkspaceController for DockerController {
         let mut header = TarHeader::new_gnu();
         header.set_path(file_name)?;
         header.set_size(content.len() as u64);
-        header.set_mode(0o644);
+        let mode = match mode {
+            Some(mode) => mode,
+            None => self.existing_mode(&path).await.unwrap_or(0o644),
+        };
+        header.set_mode(mode);
+        if let Some((uid, gid)) = self.owner().await? {
+            header.set_uid(uid);
+            header.set_gid(gid);
+        }
         header.set_cksum();
 
         let mut archive = TarBuilder::new(Vec::new());
@@ -247,26 +940,430 @@ impl WorkspaceController for DockerController {
         Ok(())
     }
 
+    // All files are sent in a single upload. Their directories are created by a command first, so
+    // that they are owned by the user rather than by root.
+    async fn write_files(&self, files: &[FileWrite], working_dir: Option<&str>) -> Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let paths = files
+            .iter()
+            .map(|file| resolve_path(&file.path, working_dir))
+            .collect::<Vec<PathBuf>>();
+        let directories = paths
+            .iter()
+            .filter_map(|path| path.parent())
+            .map(|directory| shell_escape::escape(directory.to_string_lossy()))
+            .collect::<BTreeSet<_>>();
+        let escaped_paths = paths
+            .iter()
+            .map(|path| shell_escape::escape(path.to_string_lossy()))
+            .collect::<Vec<_>>();
+
+        // Existing files keep their permissions when no mode is given
+        let output = self
+            .cmd_with_output(
+                &format!(
+                    "mkdir -p -- {} || exit 1; stat -c '%a %n' -- {} 2>/dev/null; exit 0",
+                    directories.into_iter().collect::<Vec<_>>().join(" "),
+                    escaped_paths.join(" ")
+                ),
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await?;
+        if output.exit_code != 0 {
+            anyhow::bail!("Could not create directories: {}", output.output);
+        }
+        let existing_modes = output
+            .output
+            .lines()
+            .filter_map(|line| {
+                let (mode, path) = line.split_once(' ')?;
+                Some((path, u32::from_str_radix(mode, 8).ok()?))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let owner = self.owner().await?;
+        let mut archive = TarBuilder::new(Vec::new());
+        for (file, path) in files.iter().zip(&paths) {
+            let mode = file
+                .mode
+                .or_else(|| existing_modes.get(path.to_string_lossy().as_ref()).copied())
+                .unwrap_or(0o644);
+            let mut header = TarHeader::new_gnu();
+            header.set_size(file.content.len() as u64);
+            header.set_mode(mode);
+            if let Some((uid, gid)) = owner {
+                header.set_uid(uid);
+                header.set_gid(gid);
+            }
+            archive.append_data(
+                &mut header,
+                path.strip_prefix("/").unwrap_or(path),
+                file.content.as_slice(),
+            )?;
+        }
+        let tar_bytes = archive.into_inner()?;
+
+        self.docker
+            .upload_to_container(
+                &self.container_id,
+                Some(UploadToContainerOptions {
+                    path: "/".to_string(),
+                    ..Default::default()
+                }),
+                tar_bytes.into(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // The content is uploaded next to the file and appended by a command, so the file itself does
+    // not have to be downloaded
+    async fn append_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        let path = resolve_path(path, working_dir);
+        let file_name = path
+            .file_name()
+            .ok_or(anyhow::anyhow!("No file name specified in path"))?
+            .to_string_lossy();
+        let upload = path.with_file_name(format!(
+            ".{}.derrick-append-{}",
+            file_name,
+            uuid::Uuid::new_v4()
+        ));
+        self.write_file_with_mode(&upload.to_string_lossy(), content, None, Some(0o600))
+            .await?;
+        self.cmd(
+            &format!(
+                "cat {upload} >> {path}; status=$?; rm -f {upload}; exit $status",
+                upload = shell_escape::escape(upload.to_string_lossy()),
+                path = shell_escape::escape(path.to_string_lossy())
+            ),
+            None,
+            HashMap::new(),
+            None,
+        )
+        .await
+    }
+
+    // Commands do not run in the working directory, so relative paths are resolved here
+    async fn symlink(&self, path: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        let path = resolve_path(path, working_dir);
+        self.cmd(
+            &format!(
+                "ln -sfn {} {}",
+                shell_escape::escape(target.into()),
+                shell_escape::escape(path.to_string_lossy())
+            ),
+            None,
+            HashMap::new(),
+            None,
+        )
+        .await
+    }
+
     async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
         let tar_bytes_results_stream = self.docker.download_from_container(
             &self.container_id,
             Some(DownloadFromContainerOptions {
-                path: path.to_string(),
+                path: resolve_path(path, working_dir)
+                    .to_string_lossy()
+                    .to_string(),
                 ..Default::default()
             }),
         );
-        let tar_bytes = tar_bytes_results_stream.try_collect::<Vec<_>>().await?;
+        let tar_bytes = tar_bytes_results_stream
+            .try_collect::<Vec<_>>()
+            .await
+            .map_err(|e| read_error(path, e))?;
         let concatenated = tar_bytes.concat();
         let mut archive = Archive::new(std::io::Cursor::new(concatenated));
         let mut entry = archive
             .entries()?
             .next()
             .ok_or(anyhow::anyhow!("No file found in archive"))??;
+        if entry.header().entry_type().is_dir() {
+            anyhow::bail!("{} is a directory", path);
+        }
         let mut buf = Vec::new();
         entry.read_to_end(&mut buf)?;
         Ok(buf.into())
     }
 
+    // Size of the writable layer of the container, which excludes the image
+    async fn disk_usage(&self) -> Result<u64> {
+        let container = self
+            .docker
+            .inspect_container(
+                &self.container_id,
+                Some(InspectContainerOptions { size: true }),
+            )
+            .await?;
+        Ok(container.size_rw.unwrap_or(0).max(0) as u64)
+    }
+
+    // Docker counts from when the container was last started
+    async fn resource_counters(&self) -> Result<Option<ResourceCounters>> {
+        let Some(stats) = self
+            .docker
+            .stats(
+                &self.container_id,
+                Some(StatsOptions {
+                    stream: false,
+                    one_shot: true,
+                }),
+            )
+            .next()
+            .await
+            .transpose()?
+        else {
+            return Ok(None);
+        };
+        let network_bytes = stats
+            .networks
+            .into_iter()
+            .flat_map(|networks| networks.into_values())
+            .map(|network| network.rx_bytes + network.tx_bytes)
+            .sum();
+        Ok(Some(ResourceCounters {
+            cpu_time: Duration::from_nanos(stats.cpu_stats.cpu_usage.total_usage),
+            network_bytes,
+        }))
+    }
+
+    #[tracing::instrument(skip(self), fields(container_id = %self.container_id, exec_id))]
+    async fn shell(&self, cols: u16, rows: u16) -> Result<ShellSession> {
+        let shell = self.shell().await?[0].clone();
+        let user = self.user.as_ref().map(WorkspaceUser::exec_user);
+        let exec = self
+            .docker
+            .create_exec(
+                &self.container_id,
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    tty: Some(true),
+                    cmd: Some(vec![shell.as_str()]),
+                    env: Some(vec!["TERM=xterm-256color"]),
+                    user: user.as_deref(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        tracing::Span::current().record("exec_id", exec.id.as_str());
+
+        let StartExecResults::Attached { output, input } =
+            self.docker.start_exec(&exec.id, None).await?
+        else {
+            anyhow::bail!("Could not attach to shell");
+        };
+        self.docker
+            .resize_exec(
+                &exec.id,
+                ResizeExecOptions {
+                    height: rows,
+                    width: cols,
+                },
+            )
+            .await?;
+
+        Ok(ShellSession {
+            input,
+            output: output.map(|chunk| Ok(chunk?.into_bytes().to_vec())).boxed(),
+        })
+    }
+
+    #[tracing::instrument(skip(self), fields(container_id = %self.container_id, exec_id))]
+    async fn attach(&self, cmd: &str, working_dir: Option<&str>) -> Result<ShellSession> {
+        let user = self.user.as_ref().map(WorkspaceUser::exec_user);
+        let exec = self
+            .docker
+            .create_exec(
+                &self.container_id,
+                CreateExecOptions {
+                    attach_stdin: Some(true),
+                    attach_stdout: Some(true),
+                    attach_stderr: Some(true),
+                    cmd: Some(shell_command(self.shell().await?, cmd)),
+                    working_dir,
+                    user: user.as_deref(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        tracing::Span::current().record("exec_id", exec.id.as_str());
+
+        let StartExecResults::Attached { output, input } =
+            self.docker.start_exec(&exec.id, None).await?
+        else {
+            anyhow::bail!("Could not attach to process");
+        };
+
+        // Without a terminal docker keeps stdout and stderr apart
+        let output = output.filter_map(|chunk| async move {
+            match chunk {
+                Ok(LogOutput::StdOut { message }) => Some(Ok(message.to_vec())),
+                Ok(LogOutput::StdErr { message }) => {
+                    debug!("stderr: {}", String::from_utf8_lossy(&message));
+                    None
+                }
+                Ok(_) => None,
+                Err(e) => Some(Err(e.into())),
+            }
+        });
+        Ok(ShellSession {
+            input,
+            output: output.boxed(),
+        })
+    }
+
+    async fn is_running(&self) -> Result<bool> {
+        let state = self
+            .docker
+            .inspect_container(&self.container_id, None::<InspectContainerOptions>)
+            .await?
+            .state;
+        Ok(state.and_then(|state| state.running).unwrap_or(false))
+    }
+
+    async fn health(&self) -> Result<Option<Health>> {
+        let status = self
+            .docker
+            .inspect_container(&self.container_id, None::<InspectContainerOptions>)
+            .await?
+            .state
+            .and_then(|state| state.health)
+            .and_then(|health| health.status);
+        Ok(match status {
+            Some(HealthStatusEnum::STARTING) => Some(Health::Starting),
+            Some(HealthStatusEnum::HEALTHY) => Some(Health::Healthy),
+            Some(HealthStatusEnum::UNHEALTHY) => Some(Health::Unhealthy),
+            // Without a health check
+            _ => None,
+        })
+    }
+
+    // Address of the container on its network, which is reachable from the host on Linux. Every
+    // port of the container is its own.
+    async fn network_address(&self, _port: u16) -> Result<String> {
+        self.docker
+            .inspect_container(&self.container_id, None::<InspectContainerOptions>)
+            .await?
+            .network_settings
+            .and_then(|settings| settings.networks)
+            .into_iter()
+            .flat_map(|networks| networks.into_values())
+            .filter_map(|network| network.ip_address)
+            .find(|address| !address.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("The workspace has no network"))
+    }
+
+    // The container keeps its filesystem when it exits, and starting it runs its command again
+    #[tracing::instrument(skip(self), fields(container_id = %self.container_id))]
+    async fn restart(&self) -> Result<()> {
+        self.docker
+            .start_container::<String>(&self.container_id, None)
+            .await?;
+        Ok(())
+    }
+
+    // The console of the container, which is where setup scripts and daemons end up that write to
+    // /proc/1/fd/1
+    fn logs(
+        &self,
+        follow: bool,
+        tail: Option<usize>,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        let options = LogsOptions::<String> {
+            follow,
+            stdout: true,
+            stderr: true,
+            tail: tail.map_or_else(|| "all".to_string(), |lines| lines.to_string()),
+            ..Default::default()
+        };
+        Ok(self
+            .docker
+            .logs(&self.container_id, Some(options))
+            .map(|chunk| Ok(chunk?.into_bytes().to_vec()))
+            .boxed())
+    }
+
+    fn egress_requests(&self) -> Result<Vec<EgressRequest>> {
+        match &self.egress_proxy {
+            Some(proxy) => Ok(proxy.requests()),
+            None => anyhow::bail!("The workspace does not use the egress proxy"),
+        }
+    }
+
+    // Commits the container to an image and starts a container from it with the same host
+    // config. The image is only needed to create the container, its layers stay around as long as
+    // the container does.
+    #[tracing::instrument(skip(self), fields(container_id = %self.container_id))]
+    async fn fork(&self) -> Result<Box<dyn WorkspaceController>> {
+        let host_config = self
+            .docker
+            .inspect_container(&self.container_id, None::<InspectContainerOptions>)
+            .await?
+            .host_config;
+
+        let tag = uuid::Uuid::new_v4().to_string();
+        self.docker
+            .commit_container(
+                CommitContainerOptions {
+                    container: self.container_id.as_str(),
+                    repo: "derrick-fork",
+                    tag: tag.as_str(),
+                    pause: true,
+                    ..Default::default()
+                },
+                Config::<String>::default(),
+            )
+            .await?;
+
+        let image = format!("derrick-fork:{}", tag);
+        // The environment and health check are part of the image, and the host config puts the
+        // fork on the same network
+        let fork = Self::run(
+            &self.docker,
+            &image,
+            "derrick-fork",
+            host_config,
+            Vec::new(),
+            &ContainerOptions::default(),
+        )
+        .await;
+        if let Err(e) = self
+            .docker
+            .remove_image(
+                &image,
+                Some(RemoveImageOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+                None,
+            )
+            .await
+        {
+            tracing::warn!("Could not remove fork image {}: {:?}", image, e);
+        }
+
+        let mut fork = fork?
+            .with_shell(self.shell.get().cloned())
+            .with_mirror_dir(self.mirror_dir.clone());
+        fork.egress = self.egress.clone();
+        fork.egress_proxy = self.egress_proxy.clone();
+        fork.user = self.user.clone();
+        Ok(Box::new(fork))
+    }
+
     async fn provision_repositories(
         &self,
         repositories: Vec<crate::repository::Repository>,
@@ -287,43 +1384,58 @@ impl WorkspaceController for DockerController {
                 "Has repository: {}, {:?}",
                 has_repository, repository_listing
             );
-            if !has_repository {
-                debug!("Cloning repository: {}", repository.url);
-                self.cmd(
-                    &format!("mkdir -p {}", repository.path),
-                    None,
-                    HashMap::new(),
-                    None,
-                )
-                .await?;
-                self.cmd(
-                    &format!("git clone {} {}", repository.url, repository.path),
-                    None,
-                    HashMap::new(),
-                    None,
-                )
-                .await?;
-            } else {
-                debug!("Pulling latest changes for repository: {}", repository.url);
-                // if the repository exists, we pull the latest changes, but first we add back the remote origin
-                self.cmd(
-                    &format!(
-                        "cd {} && git remote add origin {}",
-                        repository.path, repository.url
-                    ),
-                    None,
-                    HashMap::new(),
-                    None,
-                )
-                .await?;
-                self.cmd(
-                    &format!("cd {} && git pull origin master", repository.path),
-                    None,
-                    HashMap::new(),
-                    None,
-                )
-                .await?;
+            let auth = RepositoryAuth::prepare(self, &repository).await?;
+            // The credentials are removed again whether or not provisioning succeeded
+            let provisioned = async {
+                if !has_repository {
+                    debug!("Cloning repository: {}", repository.url);
+                    self.cmd(
+                        &format!("mkdir -p {}", repository.path),
+                        None,
+                        HashMap::new(),
+                        None,
+                    )
+                    .await?;
+                    self.cmd(
+                        &git::clone_cmd(
+                            &repository,
+                            &auth.url,
+                            &repository.path,
+                            self.mirror_dir.as_deref(),
+                        ),
+                        None,
+                        auth.env.clone(),
+                        None,
+                    )
+                    .await?;
+                } else {
+                    debug!("Pulling latest changes for repository: {}", repository.url);
+                    // if the repository exists, we pull the latest changes, but first we add back the remote origin
+                    self.cmd(
+                        &format!(
+                            "cd {} && git remote add origin {}",
+                            repository.path, auth.url
+                        ),
+                        None,
+                        HashMap::new(),
+                        None,
+                    )
+                    .await?;
+                    self.cmd(
+                        &format!("cd {} && git pull origin master", repository.path),
+                        None,
+                        auth.env.clone(),
+                        None,
+                    )
+                    .await?;
+                }
+                git::sparse_checkout(self, &repository, &repository.path, &auth).await?;
+                git::update_submodules_and_lfs(self, &repository, &repository.path, &auth).await?;
+                Ok::<_, anyhow::Error>(())
             }
+            .await;
+            auth.cleanup(self).await?;
+            provisioned?;
             // remove the remote origin so that we don't leak the access token
             self.cmd(
                 &format!("cd {} && git remote remove origin", repository.path),
@@ -337,11 +1449,150 @@ impl WorkspaceController for DockerController {
     }
 }
 
+// Removes the container when it was not stopped, and the egress network and proxy with the last
+// workspace that uses them, after its container is gone
 impl Drop for DockerController {
     fn drop(&mut self) {
-        let handle = tokio::runtime::Handle::current();
+        if !self.owned {
+            return;
+        }
+        let container_id =
+            (!self.stopped.load(Ordering::SeqCst)).then(|| self.container_id.clone());
+        let egress = self.egress.take().and_then(Arc::into_inner);
+        if container_id.is_none() && egress.is_none() {
+            return;
+        }
         let docker = self.docker.clone();
-        let container_id = self.container_id.clone();
-        handle.spawn(async move { stop_container(&docker, &container_id).await });
+        cleanup::spawn(async move {
+            if let Some(container_id) = container_id {
+                if let Err(e) = stop_container(&docker, &container_id).await {
+                    tracing::warn!("Could not remove container {}: {:?}", container_id, e);
+                }
+            }
+            if let Some(egress) = egress {
+                egress.remove().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_path() {
+        assert_eq!(
+            resolve_path("src/main.rs", Some("/app")),
+            Path::new("/app/src/main.rs")
+        );
+        assert_eq!(
+            resolve_path("src/main.rs", Some("app")),
+            Path::new("/app/src/main.rs")
+        );
+        assert_eq!(resolve_path("src/main.rs", None), Path::new("/src/main.rs"));
+        assert_eq!(
+            resolve_path("/etc/hosts", Some("/app")),
+            Path::new("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn test_healthcheck_config() {
+        let healthcheck: Healthcheck = serde_json::from_value(serde_json::json!({
+            "command": "curl -sf localhost:8080/health",
+            "interval": 10
+        }))
+        .unwrap();
+        let config = healthcheck.config();
+        assert_eq!(
+            config.test,
+            Some(vec![
+                "CMD-SHELL".to_string(),
+                "curl -sf localhost:8080/health".to_string()
+            ])
+        );
+        assert_eq!(config.interval, Some(10_000_000_000));
+        assert_eq!(config.retries, Some(3));
+        assert_eq!(config.start_period, Some(0));
+    }
+
+    #[test]
+    fn test_container_options() {
+        let options = ContainerOptions {
+            shm_size: Some(2 * 1024 * 1024 * 1024),
+            tmpfs: HashMap::from([("/var/lib/postgresql".to_string(), "size=1g".to_string())]),
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+        let network = HostConfig {
+            network_mode: Some("none".to_string()),
+            ..Default::default()
+        };
+        let host_config = options.host_config(Some(network)).unwrap();
+        assert_eq!(host_config.network_mode.as_deref(), Some("none"));
+        assert_eq!(host_config.shm_size, Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(host_config.tmpfs, Some(options.tmpfs.clone()));
+
+        assert_eq!(ContainerOptions::default().host_config(None), None);
+        let relative = ContainerOptions {
+            tmpfs: HashMap::from([("tmp".to_string(), String::new())]),
+            ..Default::default()
+        };
+        assert!(relative.validate().is_err());
+    }
+
+    #[test]
+    fn test_mounts() {
+        let source = std::env::temp_dir().to_string_lossy().to_string();
+        let mount = |target: &str| Mount {
+            source: source.clone(),
+            target: target.to_string(),
+            read_only: true,
+        };
+        let options = ContainerOptions {
+            mounts: vec![mount("/src")],
+            ..Default::default()
+        };
+        assert!(options.validate().is_ok());
+        assert_eq!(
+            options.host_config(None).unwrap().binds,
+            Some(vec![format!("{}:/src:ro", source)])
+        );
+
+        assert!(mount("/").validate().is_err());
+        assert!(mount("/src:rw").validate().is_err());
+        let missing = Mount {
+            source: "/does/not/exist".to_string(),
+            ..mount("/src")
+        };
+        assert_eq!(
+            missing.validate().unwrap_err().to_string(),
+            "Mount source /does/not/exist does not exist"
+        );
+    }
+
+    #[test]
+    fn test_read_error_tells_missing_files_apart() {
+        let error = read_error(
+            "missing.txt",
+            bollard::errors::Error::DockerResponseServerError {
+                status_code: 404,
+                message: "Could not find the file /missing.txt in container abc".to_string(),
+            },
+        );
+        assert_eq!(
+            error.downcast_ref::<FileNotFound>().unwrap().path,
+            "missing.txt"
+        );
+
+        let error = read_error(
+            "missing.txt",
+            bollard::errors::Error::DockerResponseServerError {
+                status_code: 404,
+                message: "No such container: abc".to_string(),
+            },
+        );
+        assert!(error.downcast_ref::<FileNotFound>().is_none());
     }
 }
@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// A change to part of a file. Edits are applied in order, each to the result of the ones before
+// it.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FileEdit {
+    // Replaces the bytes from `start` up to, not including, `end`
+    Bytes {
+        start: usize,
+        end: usize,
+        content: String,
+    },
+    // Replaces the lines from `start` through `end`, counted from 1. With `end` set to
+    // `start - 1` the content is inserted before line `start`. The content is given a trailing
+    // newline when it has none.
+    Lines {
+        start: usize,
+        end: usize,
+        content: String,
+    },
+    // The hunks of a unified diff, like the output of `git diff`. A hunk whose lines moved is
+    // applied where its old lines are found closest to the line it names.
+    Patch {
+        diff: String,
+    },
+}
+
+pub(crate) fn apply_edits(content: &[u8], edits: &[FileEdit]) -> Result<Vec<u8>> {
+    let mut content = content.to_vec();
+    for (i, edit) in edits.iter().enumerate() {
+        content = apply_edit(content, edit).with_context(|| format!("Edit {} failed", i + 1))?;
+    }
+    Ok(content)
+}
+
+fn apply_edit(mut content: Vec<u8>, edit: &FileEdit) -> Result<Vec<u8>> {
+    match edit {
+        FileEdit::Bytes {
+            start,
+            end,
+            content: replacement,
+        } => {
+            if start > end || *end > content.len() {
+                anyhow::bail!(
+                    "Byte range {}..{} is outside the file of {} bytes",
+                    start,
+                    end,
+                    content.len()
+                );
+            }
+            content.splice(*start..*end, replacement.bytes());
+            Ok(content)
+        }
+        FileEdit::Lines {
+            start,
+            end,
+            content: replacement,
+        } => {
+            let mut lines = split_lines(&content);
+            if *start == 0 || *end + 1 < *start || *end > lines.len() {
+                anyhow::bail!(
+                    "Line range {}..={} is outside the file of {} lines",
+                    start,
+                    end,
+                    lines.len()
+                );
+            }
+            let mut replacement = replacement.as_bytes().to_vec();
+            // The last line keeps going without a newline when it had none
+            let at_end_without_newline =
+                *end == lines.len() && lines.last().is_some_and(|line| !line.ends_with(b"\n"));
+            if !replacement.is_empty() && !replacement.ends_with(b"\n") && !at_end_without_newline {
+                replacement.push(b'\n');
+            }
+            lines.splice(start - 1..*end, [replacement.as_slice()]);
+            Ok(lines.concat())
+        }
+        FileEdit::Patch { diff } => apply_patch(&content, diff),
+    }
+}
+
+// Lines with their line endings
+fn split_lines(content: &[u8]) -> Vec<&[u8]> {
+    content
+        .split_inclusive(|byte| *byte == b'\n')
+        .collect::<Vec<_>>()
+}
+
+fn trim_newline(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\n").unwrap_or(line)
+}
+
+#[derive(Debug, Default)]
+struct Hunk {
+    // Line the hunk starts at in the original file, counted from 0
+    start: usize,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+// Start and line count of a range in a hunk header, like `-12,3`
+fn parse_range(range: Option<&str>, sign: char) -> Option<(usize, usize)> {
+    let range = range?.strip_prefix(sign)?;
+    match range.split_once(',') {
+        Some((start, count)) => Some((start.parse().ok()?, count.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+fn parse_hunks(diff: &str) -> Result<Vec<Hunk>> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    // Old and new lines left in the current hunk, anything after them is not part of it
+    let mut remaining = (0, 0);
+    for line in diff.lines() {
+        if remaining == (0, 0) {
+            // File headers like `diff --git` and `--- a/file` are skipped
+            if let Some(header) = line.strip_prefix("@@ ") {
+                let mut ranges = header.split_whitespace();
+                let (Some((start, old_count)), Some((_, new_count))) = (
+                    parse_range(ranges.next(), '-'),
+                    parse_range(ranges.next(), '+'),
+                ) else {
+                    anyhow::bail!("Invalid hunk header: {}", line);
+                };
+                hunks.push(Hunk {
+                    start: start.saturating_sub(1),
+                    ..Default::default()
+                });
+                remaining = (old_count, new_count);
+            }
+            continue;
+        }
+        let Some(hunk) = hunks.last_mut() else {
+            continue;
+        };
+        match line.chars().next() {
+            // Some tools strip the space of empty context lines
+            Some(' ') | None => {
+                let line = line.get(1..).unwrap_or_default().to_string();
+                hunk.old.push(line.clone());
+                hunk.new.push(line);
+                remaining = (remaining.0.saturating_sub(1), remaining.1.saturating_sub(1));
+            }
+            Some('-') => {
+                hunk.old.push(line[1..].to_string());
+                remaining.0 = remaining.0.saturating_sub(1);
+            }
+            Some('+') => {
+                hunk.new.push(line[1..].to_string());
+                remaining.1 = remaining.1.saturating_sub(1);
+            }
+            // `\ No newline at end of file`
+            Some('\\') => {}
+            _ => anyhow::bail!("Invalid line in hunk {}: {}", hunks.len(), line),
+        }
+    }
+    if hunks.is_empty() {
+        anyhow::bail!("The diff has no hunks");
+    }
+    Ok(hunks)
+}
+
+fn apply_patch(content: &[u8], diff: &str) -> Result<Vec<u8>> {
+    let lines = split_lines(content);
+    let mut result: Vec<Vec<u8>> = Vec::new();
+    // Lines of the original file up to here are in the result
+    let mut position = 0;
+
+    for (i, hunk) in parse_hunks(diff)?.iter().enumerate() {
+        let matches = |at: usize| {
+            at + hunk.old.len() <= lines.len()
+                && hunk
+                    .old
+                    .iter()
+                    .zip(&lines[at..])
+                    .all(|(old, line)| old.as_bytes() == trim_newline(line))
+        };
+        let expected = hunk.start.clamp(position, lines.len());
+        let found = (0..=lines.len())
+            .flat_map(|distance| {
+                [
+                    expected.checked_add(distance),
+                    expected.checked_sub(distance),
+                ]
+            })
+            .flatten()
+            .filter(|at| *at >= position && *at <= lines.len())
+            .find(|at| matches(*at))
+            .with_context(|| {
+                format!(
+                    "Hunk {} does not apply, its lines were not found in the file",
+                    i + 1
+                )
+            })?;
+
+        result.extend(lines[position..found].iter().map(|line| line.to_vec()));
+        let end = found + hunk.old.len();
+        // Replacing the last line of a file without a trailing newline keeps it that way
+        let keep_missing_newline =
+            end == lines.len() && lines.last().is_some_and(|line| !line.ends_with(b"\n"));
+        for (j, line) in hunk.new.iter().enumerate() {
+            let mut line = line.as_bytes().to_vec();
+            if !(keep_missing_newline && j + 1 == hunk.new.len()) {
+                line.push(b'\n');
+            }
+            result.push(line);
+        }
+        position = end;
+    }
+    result.extend(lines[position..].iter().map(|line| line.to_vec()));
+    Ok(result.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(content: &str, edits: &[FileEdit]) -> Result<String> {
+        apply_edits(content.as_bytes(), edits).map(|content| String::from_utf8(content).unwrap())
+    }
+
+    #[test]
+    fn test_byte_and_line_edits() {
+        let edited = apply(
+            "one\ntwo\nthree\n",
+            &[
+                FileEdit::Bytes {
+                    start: 0,
+                    end: 3,
+                    content: "ONE".to_string(),
+                },
+                FileEdit::Lines {
+                    start: 2,
+                    end: 2,
+                    content: "2a\n2b".to_string(),
+                },
+                // Inserts before the last line
+                FileEdit::Lines {
+                    start: 4,
+                    end: 3,
+                    content: "3-".to_string(),
+                },
+            ],
+        )
+        .unwrap();
+        assert_eq!(edited, "ONE\n2a\n2b\n3-\nthree\n");
+
+        assert!(apply(
+            "one\n",
+            &[FileEdit::Lines {
+                start: 1,
+                end: 2,
+                content: String::new()
+            }]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_patch_applies_moved_hunks() {
+        let diff = "--- a/file\n+++ b/file\n@@ -2,3 +2,3 @@\n b\n-c\n+C\n d\n@@ -8,2 +8,3 @@\n g\n h\n+i\n";
+        // A line was added at the start since the diff was made
+        let edited = apply(
+            "new\na\nb\nc\nd\ne\nf\ng\nh",
+            &[FileEdit::Patch {
+                diff: diff.to_string(),
+            }],
+        )
+        .unwrap();
+        assert_eq!(edited, "new\na\nb\nC\nd\ne\nf\ng\nh\ni");
+
+        let error = apply(
+            "a\nb\nx\nd\n",
+            &[FileEdit::Patch {
+                diff: diff.to_string(),
+            }],
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("Hunk 1 does not apply"));
+    }
+}
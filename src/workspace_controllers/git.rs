@@ -0,0 +1,288 @@
+// Git helpers shared by the controllers when provisioning repositories
+use std::collections::HashMap;
+
+use anyhow::Result;
+use shell_escape::escape as escape_cow;
+
+use crate::credentials::RepositoryAuth;
+use crate::mirror::mirror_name;
+use crate::repository::Repository;
+use crate::WorkspaceController;
+
+fn escape(s: &str) -> String {
+    escape_cow(std::borrow::Cow::Borrowed(s)).to_string()
+}
+
+// Git invocation for a repository checked out at `path`, authenticated the same way as its origin
+fn git(path: &str, auth: &RepositoryAuth) -> String {
+    match auth.url_rewrite_config() {
+        Some(config) => format!("cd {} && git -c {}", path, escape(&config)),
+        None => format!("cd {} && git", path),
+    }
+}
+
+// Sparse repositories are cloned without blobs or a full checkout; `sparse_checkout` then only
+// materializes the configured paths. With a mirror directory, objects are copied from the mirror
+// of the repository if there is one, and only the remainder is fetched from the remote.
+pub(crate) fn clone_cmd(
+    repository: &Repository,
+    url: &str,
+    path: &str,
+    mirror_dir: Option<&str>,
+) -> String {
+    let mut cmd = "git clone".to_string();
+    if let Some(mirror_dir) = mirror_dir {
+        cmd.push_str(&format!(
+            " --reference-if-able {}/{} --dissociate",
+            mirror_dir,
+            mirror_name(&repository.url)
+        ));
+    }
+    if !repository.sparse_paths.is_empty() {
+        cmd.push_str(" --filter=blob:none --sparse");
+    }
+    format!("{} {} {}", cmd, url, path)
+}
+
+pub(crate) async fn sparse_checkout(
+    controller: &dyn WorkspaceController,
+    repository: &Repository,
+    path: &str,
+    auth: &RepositoryAuth,
+) -> Result<()> {
+    if repository.sparse_paths.is_empty() {
+        return Ok(());
+    }
+
+    let paths = repository
+        .sparse_paths
+        .iter()
+        .map(|p| escape(p))
+        .collect::<Vec<_>>()
+        .join(" ");
+    controller
+        .cmd(
+            &format!("{} sparse-checkout set --cone {}", git(path, auth), paths),
+            None,
+            auth.env.clone(),
+            None,
+        )
+        .await
+}
+
+// Fetches submodules and LFS objects when the repository asks for them. Has to run while the
+// origin remote still carries its credentials.
+pub(crate) async fn update_submodules_and_lfs(
+    controller: &dyn WorkspaceController,
+    repository: &Repository,
+    path: &str,
+    auth: &RepositoryAuth,
+) -> Result<()> {
+    let git = git(path, auth);
+
+    if repository.submodules {
+        controller
+            .cmd(
+                &format!("{} submodule sync --recursive", git),
+                None,
+                auth.env.clone(),
+                None,
+            )
+            .await?;
+        controller
+            .cmd(
+                &format!("{} submodule update --init --recursive", git),
+                None,
+                auth.env.clone(),
+                None,
+            )
+            .await?;
+    }
+
+    if repository.lfs {
+        controller
+            .cmd(
+                &format!("{} lfs install --local", git),
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await?;
+        controller
+            .cmd(&format!("{} lfs pull", git), None, auth.env.clone(), None)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// Branches assumed to be the default when the remote can not tell us
+const FALLBACK_DEFAULT_BRANCHES: &[&str] = &["main", "master"];
+
+// Detects the default branch of the origin remote of the repository in the working directory.
+// `refs/remotes/origin/HEAD` is only set by a regular clone, so repositories cloned from mirrors
+// or set up with a plain fetch ask the remote instead, and fall back to `main` or `master` when
+// the remote is unreachable.
+pub(crate) async fn default_branch(controller: &dyn WorkspaceController) -> Result<String> {
+    let attempts: &[(&str, fn(&str) -> Option<String>)] = &[
+        (
+            "git symbolic-ref --short refs/remotes/origin/HEAD",
+            parse_symbolic_ref,
+        ),
+        ("git remote show origin", parse_remote_show),
+        ("git ls-remote --symref origin HEAD", parse_ls_remote_symref),
+    ];
+
+    for (cmd, parse) in attempts {
+        if let Some(branch) = try_output(controller, cmd).await.as_deref().and_then(parse) {
+            return Ok(branch);
+        }
+        tracing::debug!(cmd, "Could not detect default branch");
+    }
+
+    for branch in FALLBACK_DEFAULT_BRANCHES {
+        let cmd = format!(
+            "git rev-parse --verify --quiet refs/remotes/origin/{branch} || git rev-parse --verify --quiet refs/heads/{branch}"
+        );
+        if try_output(controller, &cmd).await.is_some() {
+            tracing::warn!(branch, "Falling back to default branch");
+            return Ok(branch.to_string());
+        }
+    }
+
+    anyhow::bail!("Could not detect the default branch of the repository")
+}
+
+// Output of a command if it succeeded, controllers differ in whether a failing command is an error
+pub(crate) async fn try_output(controller: &dyn WorkspaceController, cmd: &str) -> Option<String> {
+    match controller
+        .cmd_with_output(
+            &format!("({}) 2>/dev/null", cmd),
+            None,
+            HashMap::new(),
+            None,
+        )
+        .await
+    {
+        Ok(output) if output.exit_code == 0 => Some(output.output),
+        _ => None,
+    }
+}
+
+// `origin/main`
+fn parse_symbolic_ref(output: &str) -> Option<String> {
+    non_empty(output.trim().strip_prefix("origin/")?)
+}
+
+// `  HEAD branch: main`, which is `(unknown)` when the remote has no default branch
+fn parse_remote_show(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("HEAD branch:"))
+        .map(str::trim)
+        .filter(|branch| *branch != "(unknown)")
+        .and_then(non_empty)
+}
+
+// `ref: refs/heads/main\tHEAD`
+fn parse_ls_remote_symref(output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let (reference, name) = line.strip_prefix("ref:")?.trim().split_once('\t')?;
+        if name.trim() != "HEAD" {
+            return None;
+        }
+        non_empty(reference.strip_prefix("refs/heads/")?)
+    })
+}
+
+fn non_empty(branch: &str) -> Option<String> {
+    (!branch.is_empty()).then(|| branch.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::testing::TestingController;
+
+    #[test]
+    fn test_clone_cmd() {
+        let mut repository = Repository::from_url("https://github.com/bosun-ai/derrick")
+            .build()
+            .unwrap();
+        assert_eq!(
+            clone_cmd(&repository, &repository.url, "/code", None),
+            "git clone https://github.com/bosun-ai/derrick /code"
+        );
+
+        repository.sparse_paths = vec!["services/api".to_string()];
+        assert_eq!(
+            clone_cmd(&repository, &repository.url, "/code", None),
+            "git clone --filter=blob:none --sparse https://github.com/bosun-ai/derrick /code"
+        );
+
+        repository.sparse_paths = vec![];
+        assert_eq!(
+            clone_cmd(&repository, &repository.url, "/code", Some("/mirrors")),
+            format!(
+                "git clone --reference-if-able /mirrors/{} --dissociate https://github.com/bosun-ai/derrick /code",
+                mirror_name(&repository.url)
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_symbolic_ref() {
+        assert_eq!(
+            parse_symbolic_ref("origin/main\n"),
+            Some("main".to_string())
+        );
+        assert_eq!(parse_symbolic_ref(""), None);
+    }
+
+    #[test]
+    fn test_parse_remote_show() {
+        let output = "* remote origin\n  Fetch URL: https://github.com/bosun-ai/derrick\n  HEAD branch: develop\n  Remote branches:\n";
+        assert_eq!(parse_remote_show(output), Some("develop".to_string()));
+        assert_eq!(parse_remote_show("  HEAD branch: (unknown)\n"), None);
+    }
+
+    #[test]
+    fn test_parse_ls_remote_symref() {
+        let output = "ref: refs/heads/trunk\tHEAD\n2a4f3c1e\tHEAD\n";
+        assert_eq!(parse_ls_remote_symref(output), Some("trunk".to_string()));
+        assert_eq!(parse_ls_remote_symref("2a4f3c1e\tHEAD\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_default_branch_without_origin_head() {
+        let controller = TestingController::new("default-branch");
+        // A plain fetch, unlike a clone, does not set refs/remotes/origin/HEAD
+        controller
+            .cmd(
+                "git init -q -b trunk upstream && git -C upstream commit -q --allow-empty -m init && git init -q -b work . && git remote add origin ./upstream && git fetch -q origin",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(default_branch(&controller).await.unwrap(), "trunk");
+    }
+
+    #[tokio::test]
+    async fn test_default_branch_falls_back_to_main() {
+        let controller = TestingController::new("default-branch-fallback");
+        controller
+            .cmd(
+                "git init -q -b main . && git commit -q --allow-empty -m init",
+                None,
+                HashMap::new(),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(default_branch(&controller).await.unwrap(), "main");
+    }
+}
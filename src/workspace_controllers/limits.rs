@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{CommandOutput, OutputLineCallback};
+
+// Exit code of a shell whose command was killed by SIGXCPU, which is sent when the soft CPU limit
+// is reached
+const CPU_LIMIT_EXIT_CODE: i32 = 128 + 24;
+// Exit code of `timeout` when the command took too long
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+// Resource limits for a single command, on top of its timeout. The limits are set by the shell
+// running the command, so they need a POSIX shell like bash or sh.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandLimits {
+    // CPU time the command (and each process it starts) may use
+    pub cpu_time: Option<Duration>,
+    // Output after which the command is killed, the output up to the limit is returned
+    pub max_output_bytes: Option<usize>,
+}
+
+// The limit a command was killed for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitExceeded {
+    CpuTime,
+    WallTime,
+    OutputSize,
+}
+
+impl CommandLimits {
+    // Prefixes the command with setting the CPU limit. The hard limit is a second higher than the
+    // soft limit, so the command first gets SIGXCPU, which tells it apart from other kills.
+    pub(crate) fn apply_cpu_limit(&self, cmd: &str) -> String {
+        match self.cpu_time {
+            Some(cpu_time) => {
+                let secs = cpu_time.as_secs().max(1);
+                format!(
+                    "ulimit -t {} && ulimit -S -t {} || exit 126\n{}",
+                    secs + 1,
+                    secs,
+                    cmd
+                )
+            }
+            None => cmd.to_string(),
+        }
+    }
+}
+
+// Kills the process with the pid in the file and everything it started. Processes are stopped
+// before their children are looked up, so they cannot start new ones in the meantime.
+pub(crate) fn kill_tree_cmd(pid_file: &str) -> String {
+    format!(
+        r#"kill_tree() {{ kill -STOP "$1" 2>/dev/null; for stat in /proc/[0-9]*/stat; do read -r pid _ _ ppid _ < "$stat" 2>/dev/null && [ "$ppid" = "$1" ] && kill_tree "$pid"; done; kill -9 "$1" 2>/dev/null; }}; kill_tree "$(cat {pid_file})"; rm -f {pid_file}"#,
+        pid_file = pid_file
+    )
+}
+
+// The stream of a command a chunk of output was written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+// Collects the output of a command up to the output limit, which counts both streams
+pub(crate) struct LimitedOutput<'a> {
+    bytes: Vec<u8>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    max_bytes: Option<usize>,
+    exceeded: bool,
+    // Gets the output line by line as it comes in, with the unfinished line of each stream
+    on_line: Option<&'a OutputLineCallback>,
+    partial_lines: [Vec<u8>; 2],
+}
+
+impl<'a> LimitedOutput<'a> {
+    pub(crate) fn new(limits: &CommandLimits) -> Self {
+        Self {
+            bytes: Vec::new(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            max_bytes: limits.max_output_bytes,
+            exceeded: false,
+            on_line: None,
+            partial_lines: Default::default(),
+        }
+    }
+
+    pub(crate) fn with_lines(mut self, on_line: Option<&'a OutputLineCallback>) -> Self {
+        self.on_line = on_line;
+        self
+    }
+
+    // Returns false once the output exceeds the limit, the command should then be killed
+    pub(crate) fn push(&mut self, stream: OutputStream, chunk: &[u8]) -> bool {
+        let mut kept = chunk;
+        if let Some(max_bytes) = self.max_bytes {
+            let remaining = max_bytes.saturating_sub(self.bytes.len());
+            if chunk.len() > remaining {
+                kept = &chunk[..remaining];
+                self.exceeded = true;
+            }
+        }
+        self.bytes.extend_from_slice(kept);
+        match stream {
+            OutputStream::Stdout => self.stdout.extend_from_slice(kept),
+            OutputStream::Stderr => self.stderr.extend_from_slice(kept),
+        }
+        if let Some(on_line) = self.on_line {
+            let partial = &mut self.partial_lines[stream as usize];
+            partial.extend_from_slice(kept);
+            while let Some(end) = partial.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = partial.drain(..=end).collect();
+                on_line(String::from_utf8_lossy(&line[..end]).trim_end_matches('\r'));
+            }
+        }
+        !self.exceeded
+    }
+
+    pub(crate) fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+
+    pub(crate) fn finish(
+        self,
+        exit_code: i32,
+        limits: &CommandLimits,
+        timeout: Option<Duration>,
+    ) -> CommandOutput {
+        if let Some(on_line) = self.on_line {
+            for partial in self.partial_lines.iter().filter(|line| !line.is_empty()) {
+                on_line(&String::from_utf8_lossy(partial));
+            }
+        }
+        let limit_exceeded = if self.exceeded {
+            Some(LimitExceeded::OutputSize)
+        } else if limits.cpu_time.is_some() && exit_code == CPU_LIMIT_EXIT_CODE {
+            Some(LimitExceeded::CpuTime)
+        } else if timeout.is_some() && exit_code == TIMEOUT_EXIT_CODE {
+            Some(LimitExceeded::WallTime)
+        } else {
+            None
+        };
+
+        CommandOutput {
+            output: String::from_utf8_lossy(&self.bytes).to_string(),
+            stdout: self.stdout,
+            stderr: self.stderr,
+            exit_code,
+            limit_exceeded,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limited_output() {
+        let limits = CommandLimits {
+            max_output_bytes: Some(5),
+            ..Default::default()
+        };
+        let mut output = LimitedOutput::new(&limits);
+        assert!(output.push(OutputStream::Stdout, b"abc"));
+        assert!(!output.push(OutputStream::Stdout, b"defg"));
+
+        let output = output.finish(137, &limits, None);
+        assert_eq!(output.output, "abcde");
+        assert_eq!(output.limit_exceeded, Some(LimitExceeded::OutputSize));
+    }
+
+    #[test]
+    fn test_reports_lines_as_they_come_in() {
+        let lines = std::sync::Mutex::new(Vec::new());
+        let on_line = |line: &str| lines.lock().unwrap().push(line.to_string());
+        let limits = CommandLimits::default();
+        let mut output = LimitedOutput::new(&limits).with_lines(Some(&on_line));
+        output.push(OutputStream::Stdout, b"Compiling a\r\nCompi");
+        output.push(OutputStream::Stderr, b"warning: unused\n");
+        output.push(OutputStream::Stdout, b"ling b\ndone");
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["Compiling a", "warning: unused", "Compiling b"]
+        );
+
+        output.finish(0, &limits, None);
+        assert_eq!(lines.lock().unwrap().last().unwrap(), "done");
+    }
+
+    #[test]
+    fn test_keeps_streams_apart_and_binary() {
+        let limits = CommandLimits::default();
+        let mut output = LimitedOutput::new(&limits);
+        assert!(output.push(OutputStream::Stdout, &[0xff, 0x00, b'a']));
+        assert!(output.push(OutputStream::Stderr, b"warning\n"));
+        assert!(output.push(OutputStream::Stdout, b"b"));
+
+        let output = output.finish(0, &limits, None);
+        assert_eq!(output.stdout, vec![0xff, 0x00, b'a', b'b']);
+        assert_eq!(output.stderr, b"warning\n");
+        assert_eq!(output.output, "\u{fffd}\0awarning\nb");
+    }
+
+    #[test]
+    fn test_detects_cpu_limit_from_exit_code() {
+        let limits = CommandLimits {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let output = LimitedOutput::new(&limits).finish(152, &limits, None);
+        assert_eq!(output.limit_exceeded, Some(LimitExceeded::CpuTime));
+
+        // Without a CPU limit the command could have exited with 152 itself
+        let output = LimitedOutput::new(&CommandLimits::default()).finish(
+            152,
+            &CommandLimits::default(),
+            None,
+        );
+        assert_eq!(output.limit_exceeded, None);
+    }
+
+    #[test]
+    fn test_apply_cpu_limit() {
+        let limits = CommandLimits {
+            cpu_time: Some(Duration::from_millis(500)),
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.apply_cpu_limit("make"),
+            "ulimit -t 2 && ulimit -S -t 1 || exit 126\nmake"
+        );
+        assert_eq!(CommandLimits::default().apply_cpu_limit("make"), "make");
+    }
+}
@@ -1,12 +1,24 @@
-use crate::workspace_controllers::CommandOutput;
+use crate::credentials::{scrub, RepositoryAuth};
+use crate::egress_proxy::{EgressProxy, EgressRequest};
+use crate::usage::directory_size;
+use crate::workspace_controllers::limits::{LimitedOutput, OutputStream};
 use crate::workspace_controllers::WorkspaceController;
+use crate::workspace_controllers::{
+    git, shell_command, CommandLimits, CommandOutput, FileNotFound, FileWrite, OutputLineCallback,
+    Sandbox, SandboxNamespaces, ShellSession, FALLBACK_SHELLS,
+};
 use anyhow::{Context, Result};
 use async_trait::async_trait;
-use regex;
-use std::process::Command;
+use futures_util::StreamExt;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+};
 use tokio::sync::RwLock;
+use tokio_util::io::ReaderStream;
 use tracing::{debug, info, warn};
 
 const ALLOWED_ENV: &[&str] = &["PATH", "CARGO_HOME", "RUST_HOME", "RUST_VERSION"];
@@ -15,24 +27,38 @@ const ALLOWED_ENV: &[&str] = &["PATH", "CARGO_HOME", "RUST_HOME", "RUST_VERSION"
 // Useful for debugging, testing and experimentation
 //
 // NOTE:
-//  - might be useful to drop the directory after out of scope
+//  - the directory is removed when the workspace is stopped, not when it is dropped
 //  - haven't decided what to do with stdout/stderr
 #[derive(Debug)]
 pub struct LocalTempSyncController {
     path: String,
     whitelisted_env: RwLock<HashMap<String, String>>,
-}
-
-// scrub removes x-access-token:<token> from a string like x-access-token:1234@github.com
-fn scrub(output: &str) -> String {
-    let re = regex::Regex::new(r"x-access-token:[^@]+@").unwrap();
-    re.replace_all(output, "x-access-token:***@").to_string()
+    shell: Vec<String>,
+    mirror_dir: Option<String>,
+    // Upper and work directory when the directory is an overlay mount, which is unmounted when
+    // the workspace is stopped
+    overlay: Option<PathBuf>,
+    // Runs the commands with bubblewrap or nsjail, with a /tmp of their own next to the directory
+    sandbox: Option<Sandbox>,
+    // The namespaces the sandboxed commands share, started with the first one
+    sandbox_namespaces: Mutex<Option<SandboxNamespaces>>,
+    // Proxy of derrick that records the requests of the workspace, shared with its forks
+    egress_proxy: Option<Arc<EgressProxy>>,
+    // Ports previews and tunnels can reach
+    ports: Vec<u16>,
 }
 
 impl LocalTempSyncController {
+    // Creates the directory under the default root, `$TMPDIR/derrick`
     #[tracing::instrument]
     pub async fn initialize(name: &str) -> Self {
-        let path = init_path(name)
+        Self::initialize_in(&default_root(), name).await
+    }
+
+    // Creates a directory of its own under the root, also when another workspace has the name
+    #[tracing::instrument]
+    pub async fn initialize_in(root: &Path, name: &str) -> Self {
+        let path = init_path(root, name)
             .context("Could not create local temp directory")
             .unwrap();
 
@@ -46,7 +72,106 @@ impl LocalTempSyncController {
         Self {
             path,
             whitelisted_env: RwLock::new(whitelisted_env),
+            shell: detect_shell(),
+            mirror_dir: None,
+            overlay: None,
+            sandbox: None,
+            sandbox_namespaces: Mutex::new(None),
+            egress_proxy: None,
+            ports: Vec::new(),
+        }
+    }
+
+    /// Uses the given shell for running commands instead of the detected one.
+    pub fn with_shell(mut self, shell: Option<Vec<String>>) -> Self {
+        if let Some(shell) = shell {
+            self.shell = shell;
+        }
+        self
+    }
+
+    /// Clones repositories with the git mirrors in the given directory as reference.
+    pub fn with_mirror_dir(mut self, mirror_dir: Option<String>) -> Self {
+        self.mirror_dir = mirror_dir;
+        self
+    }
+
+    // Marks the directory as an overlay mount with its upper and work directory in `overlay`
+    pub(crate) fn with_overlay(mut self, overlay: Option<PathBuf>) -> Self {
+        self.overlay = overlay;
+        self
+    }
+
+    /// Runs the commands in the given sandbox, see `Sandbox::check` for whether it is installed.
+    pub fn with_sandbox(mut self, mut sandbox: Option<Sandbox>) -> Self {
+        if let Some(sandbox) = &mut sandbox {
+            // Cloned repositories refer to the objects in the mirrors
+            sandbox.read_only_paths.extend(self.mirror_dir.clone());
         }
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Sends the HTTP(S) traffic of the commands through the egress proxy.
+    pub fn with_egress_proxy(mut self, proxy: Option<Arc<EgressProxy>>) -> Self {
+        if let Some(proxy) = &proxy {
+            self.whitelisted_env
+                .get_mut()
+                .extend(proxy.env("127.0.0.1"));
+        }
+        self.egress_proxy = proxy;
+        self
+    }
+
+    /// Lets previews and tunnels reach the given ports, which are ports of the host.
+    pub fn with_ports(mut self, ports: Vec<u16>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    fn sandbox_tmp(&self) -> PathBuf {
+        PathBuf::from(format!("{}.tmp", self.path))
+    }
+
+    // Next to the directory of the workspace, so that sandboxed commands cannot change it
+    fn sandbox_seccomp(&self) -> PathBuf {
+        PathBuf::from(format!("{}.seccomp", self.path))
+    }
+
+    // The program and arguments that run the command, in the sandbox when there is one. Its temp
+    // directory, seccomp program and namespaces are created on first use, and again when they
+    // went away.
+    fn command_args(&self, args: &[&str], working_dir: &Path) -> Result<Vec<String>> {
+        let Some(sandbox) = &self.sandbox else {
+            return Ok(args.iter().map(|arg| arg.to_string()).collect());
+        };
+        let tmp = self.sandbox_tmp();
+        std::fs::create_dir_all(&tmp).context("Could not create sandbox temp directory")?;
+        let seccomp = self.sandbox_seccomp();
+        if !seccomp.exists() {
+            if let Some(program) = sandbox.default_seccomp_program()? {
+                std::fs::write(&seccomp, program).context("Could not write seccomp program")?;
+            }
+        }
+        let mut namespaces = self.sandbox_namespaces.lock().unwrap();
+        if !namespaces
+            .as_mut()
+            .is_some_and(SandboxNamespaces::is_running)
+        {
+            *namespaces = sandbox.namespaces()?;
+        }
+        Ok(sandbox.wrap(
+            args,
+            self.directory(),
+            &tmp,
+            &seccomp,
+            working_dir,
+            namespaces.as_ref(),
+        ))
+    }
+
+    pub(crate) fn directory(&self) -> &Path {
+        Path::new(&self.path)
     }
 
     fn spawn_cmd(
@@ -55,24 +180,24 @@ impl LocalTempSyncController {
         working_dir: Option<&str>,
         envs: &HashMap<String, String>,
     ) -> Result<std::process::Output> {
+        let path = self.path(working_dir)?;
         debug!(
             cmd = scrub(cmd),
-            path = self
-                .path(working_dir)
-                .to_str()
-                .context("Could not convert path to string")?,
+            path = path.to_str().context("Could not convert path to string")?,
             "Running command"
         );
-        Command::new("bash")
-            .args(["-c", cmd])
+        let args = self.command_args(&shell_command(&self.shell, cmd), &path)?;
+        Command::new(&args[0])
+            .args(&args[1..])
             .env_clear()
             .envs(envs)
-            .current_dir(self.path(working_dir))
+            .current_dir(path)
             .output()
             .context("Could not run command")
     }
 
-    fn path(&self, working_dir: Option<&str>) -> PathBuf {
+    // Directory in the workspace, which has to stay inside of it like the files
+    fn path(&self, working_dir: Option<&str>) -> Result<PathBuf> {
         let mut base_path = std::path::PathBuf::from(self.path.clone());
 
         let mut working_dir = std::path::PathBuf::from(working_dir.unwrap_or(""));
@@ -85,19 +210,171 @@ impl LocalTempSyncController {
         }
 
         base_path.push(working_dir);
-        base_path
+        contained_path(Path::new(&self.path), &base_path)
+    }
+
+    // Path of a file in the workspace, which has to stay inside of it
+    fn file_path(&self, working_dir: Option<&str>, file: &str) -> Result<PathBuf> {
+        contained_path(Path::new(&self.path), &self.path(working_dir)?.join(file))
     }
+
+    // Runs in its own process group, so that everything the command started can be killed
+    #[tracing::instrument(skip(self, env, limits, on_line), fields(cmd = scrub(cmd)))]
+    async fn run_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+        on_line: Option<&OutputLineCallback>,
+    ) -> Result<CommandOutput> {
+        let mut envs = self.whitelisted_env.read().await.clone();
+        envs.extend(env);
+
+        let cmd = limits.apply_cpu_limit(cmd);
+        let path = self.path(working_dir)?;
+        let args = self.command_args(&shell_command(&self.shell, &cmd), &path)?;
+        let mut command = tokio::process::Command::new(&args[0]);
+        command
+            .args(&args[1..])
+            .env_clear()
+            .envs(&envs)
+            .current_dir(path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command.spawn().context("Could not run command")?;
+
+        let stdout = ReaderStream::new(child.stdout.take().context("Could not read output")?)
+            .map(|chunk| (OutputStream::Stdout, chunk));
+        let stderr = ReaderStream::new(child.stderr.take().context("Could not read output")?)
+            .map(|chunk| (OutputStream::Stderr, chunk));
+        let mut chunks = futures_util::stream::select(stdout, stderr);
+
+        // Finishes without an exit status when the command has to be killed
+        let mut output = LimitedOutput::new(limits).with_lines(on_line);
+        let run = async {
+            while let Some((stream, chunk)) = chunks.next().await {
+                if !output.push(stream, &chunk?) {
+                    return anyhow::Ok(None);
+                }
+            }
+            Ok(Some(child.wait().await?))
+        };
+        let status = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or(Ok(None))?,
+            None => run.await?,
+        };
+
+        let exit_code = match status {
+            Some(status) => shell_exit_code(status),
+            None => {
+                warn!("Command exceeded its limits, killing it");
+                kill_process_group(&mut child).await?;
+                let status = child.wait().await.context("Could not run command")?;
+                if output.exceeded() {
+                    shell_exit_code(status)
+                } else {
+                    // Timed out, reported like the `timeout` command does
+                    124
+                }
+            }
+        };
+        Ok(output.finish(exit_code, limits, timeout))
+    }
+}
+
+// Resolves `..` without following symlinks and fails when the path ends up outside of the root,
+// e.g. for `../../etc/passwd` or a symlink to /etc. The returned path has its symlinks resolved,
+// so it stays inside the root when it is used.
+fn contained_path(root: &Path, path: &Path) -> Result<PathBuf> {
+    let root = root
+        .canonicalize()
+        .context("Could not resolve workspace directory")?;
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+
+    // Symlinks can only be resolved in the part of the path that exists
+    let mut existing = normalized.as_path();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => {
+                let rest = normalized.strip_prefix(existing)?;
+                break if rest.as_os_str().is_empty() {
+                    resolved
+                } else {
+                    resolved.join(rest)
+                };
+            }
+            // A broken symlink could point anywhere once its target is created
+            Err(_)
+                if existing
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.file_type().is_symlink()) =>
+            {
+                anyhow::bail!("{} is a broken symlink", existing.display())
+            }
+            Err(_) => {
+                existing = existing
+                    .parent()
+                    .with_context(|| format!("Could not resolve {}", path.display()))?
+            }
+        }
+    };
+    if !resolved.starts_with(&root) {
+        anyhow::bail!("{} is outside of the workspace", path.display());
+    }
+    Ok(resolved)
 }
 
-fn init_path(name: &str) -> Result<String> {
-    let mut current_dir = std::env::current_dir().expect("Could not get current directory");
-    current_dir.push("tmp");
-    current_dir.push(format!("{}-{}", name, std::process::id()));
+// Picks the first fallback shell found on the PATH, or `cmd /C` on Windows hosts
+fn detect_shell() -> Vec<String> {
+    let found = std::env::var_os("PATH").and_then(|paths| {
+        FALLBACK_SHELLS.iter().find(|candidate| {
+            std::env::split_paths(&paths).any(|dir| dir.join(candidate[0]).is_file())
+        })
+    });
 
-    if !current_dir.exists() {
-        std::fs::create_dir_all(&current_dir).context("Could not create local temp directory")?;
+    match found {
+        Some(shell) => shell.iter().map(|s| s.to_string()).collect(),
+        None if cfg!(target_os = "windows") => vec!["cmd".to_string(), "/C".to_string()],
+        None => vec!["sh".to_string(), "-c".to_string()],
     }
-    Ok(current_dir
+}
+
+pub(crate) fn default_root() -> PathBuf {
+    std::env::temp_dir().join("derrick")
+}
+
+fn init_path(root: &Path, name: &str) -> Result<String> {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let path = root.join(format!("{}-{}", name, uuid::Uuid::new_v4()));
+
+    std::fs::create_dir_all(&path).context("Could not create local temp directory")?;
+    Ok(path
         .canonicalize()?
         .to_str()
         .context("Could not convert to string")?
@@ -112,10 +389,39 @@ impl WorkspaceController for LocalTempSyncController {
     }
 
     async fn stop(&self) -> Result<()> {
-        todo!();
+        // Ends the daemons still running in the sandbox
+        self.sandbox_namespaces.lock().unwrap().take();
+        if let Some(overlay) = &self.overlay {
+            let status = tokio::process::Command::new("umount")
+                .arg(&self.path)
+                .status()
+                .await
+                .context("Could not unmount overlay")?;
+            if !status.success() {
+                anyhow::bail!("Could not unmount overlay {}", self.path);
+            }
+            tokio::fs::remove_dir_all(overlay)
+                .await
+                .with_context(|| format!("Could not remove {}", overlay.display()))?;
+        }
+        let tmp = self.sandbox_tmp();
+        if tmp.exists() {
+            tokio::fs::remove_dir_all(&tmp)
+                .await
+                .with_context(|| format!("Could not remove {}", tmp.display()))?;
+        }
+        let seccomp = self.sandbox_seccomp();
+        if seccomp.exists() {
+            tokio::fs::remove_file(&seccomp)
+                .await
+                .with_context(|| format!("Could not remove {}", seccomp.display()))?;
+        }
+        tokio::fs::remove_dir_all(&self.path)
+            .await
+            .with_context(|| format!("Could not remove {}", self.path))
     }
 
-    #[tracing::instrument(skip(self), fields(cmd = scrub(cmd)))]
+    #[tracing::instrument(skip(self, env), fields(cmd = scrub(cmd)))]
     async fn cmd(
         &self,
         cmd: &str,
@@ -130,7 +436,7 @@ impl WorkspaceController for LocalTempSyncController {
             .map(|_| ())
     }
 
-    #[tracing::instrument(skip(self), fields(cmd = scrub(cmd)))]
+    #[tracing::instrument(skip(self, env), fields(cmd = scrub(cmd)))]
     async fn cmd_with_output(
         &self,
         cmd: &str,
@@ -144,6 +450,30 @@ impl WorkspaceController for LocalTempSyncController {
             .map(handle_command_result)?
     }
 
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        self.run_with_limits(cmd, working_dir, env, timeout, limits, None)
+            .await
+    }
+
+    async fn cmd_with_streamed_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        on_line: &OutputLineCallback,
+    ) -> Result<CommandOutput> {
+        let limits = CommandLimits::default();
+        self.run_with_limits(cmd, working_dir, env, None, &limits, Some(on_line))
+            .await
+    }
+
     #[tracing::instrument(skip_all)]
     async fn write_file(
         &self,
@@ -151,7 +481,7 @@ impl WorkspaceController for LocalTempSyncController {
         content: &[u8],
         working_dir: Option<&str>,
     ) -> Result<()> {
-        let path = self.path(working_dir).as_path().join(file);
+        let path = self.file_path(working_dir, file)?;
 
         // Create directory if it doesn't exist
         if let Some(parent) = path.parent() {
@@ -160,10 +490,260 @@ impl WorkspaceController for LocalTempSyncController {
         std::fs::write(path, content).context("Could not write file")
     }
 
+    #[tracing::instrument(skip_all)]
+    async fn write_file_with_mode(
+        &self,
+        file: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.write_file(file, content, working_dir).await?;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            let path = self.file_path(working_dir, file)?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .context("Could not set file permissions")?;
+        }
+        Ok(())
+    }
+
+    // Every file is written next to where it goes first, and only moved in place once all of them
+    // are written
+    #[tracing::instrument(skip_all)]
+    async fn write_files(&self, files: &[FileWrite], working_dir: Option<&str>) -> Result<()> {
+        let mut written = Vec::with_capacity(files.len());
+        let result = files.iter().try_for_each(|file| -> Result<()> {
+            let path = self.file_path(working_dir, &file.path)?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).context("Could not create directory")?;
+            }
+            let file_name = path
+                .file_name()
+                .context("No file name specified in path")?
+                .to_string_lossy()
+                .to_string();
+            let temp =
+                path.with_file_name(format!(".{}.derrick-{}", file_name, uuid::Uuid::new_v4()));
+            std::fs::write(&temp, &file.content).context("Could not write file")?;
+            written.push((temp.clone(), path.clone()));
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = file.mode.or_else(|| {
+                    path.metadata()
+                        .ok()
+                        .map(|metadata| metadata.permissions().mode())
+                });
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(mode))
+                        .context("Could not set file permissions")?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            for (temp, _) in &written {
+                let _ = std::fs::remove_file(temp);
+            }
+            return Err(e);
+        }
+
+        for (temp, path) in written {
+            std::fs::rename(temp, path).context("Could not write file")?;
+        }
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn append_file(
+        &self,
+        file: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        use std::io::Write;
+        let path = self.file_path(working_dir, file)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).context("Could not create directory")?;
+        }
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(content))
+            .context("Could not append to file")
+    }
+
+    #[cfg(unix)]
+    #[tracing::instrument(skip_all)]
+    async fn symlink(&self, file: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        // The symlink itself is replaced rather than followed, only its directory has to be in
+        // the workspace. Reading through a symlink that points outside of it fails.
+        let path = self.path(working_dir)?.join(file);
+        let (Some(parent), Some(file_name)) = (path.parent(), path.file_name()) else {
+            anyhow::bail!("No file name specified in path");
+        };
+        let parent = contained_path(Path::new(&self.path), parent)?;
+        std::fs::create_dir_all(&parent).context("Could not create directory")?;
+        let path = parent.join(file_name);
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(&path).context("Could not replace file with symlink")?;
+        }
+        std::os::unix::fs::symlink(target, path).context("Could not create symlink")
+    }
+
     #[tracing::instrument(skip_all)]
     async fn read_file(&self, file: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
-        let path = self.path(working_dir).as_path().join(file);
-        std::fs::read(path).context("Could not read file")
+        let path = self.file_path(working_dir, file)?;
+        std::fs::read(path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => FileNotFound {
+                path: file.to_string(),
+            }
+            .into(),
+            _ => anyhow::Error::new(e).context("Could not read file"),
+        })
+    }
+
+    // Commands run on the network of the host, so only the ports of the context are the ones of
+    // the workspace
+    async fn network_address(&self, port: u16) -> Result<String> {
+        if !self.ports.contains(&port) {
+            anyhow::bail!(
+                "Port {} is not one of the ports of the workspace context, other ports of local workspaces are ports of the host",
+                port
+            );
+        }
+        Ok("127.0.0.1".to_string())
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn disk_usage(&self) -> Result<u64> {
+        let path = PathBuf::from(&self.path);
+        tokio::task::spawn_blocking(move || directory_size(&path)).await?
+    }
+
+    // Runs the shell through `script`, which gives it a pseudo terminal
+    #[tracing::instrument(skip_all)]
+    async fn shell(&self, cols: u16, rows: u16) -> Result<ShellSession> {
+        if !cfg!(target_os = "linux") {
+            anyhow::bail!("Interactive shells are only supported on Linux hosts");
+        }
+
+        let shell_cmd = format!(
+            "stty cols {} rows {}; exec {} -i",
+            cols, rows, self.shell[0]
+        );
+        let path = self.path(None)?;
+        let args = self.command_args(
+            &["script", "-q", "-e", "-f", "-c", &shell_cmd, "/dev/null"],
+            &path,
+        )?;
+        let mut child = tokio::process::Command::new(&args[0])
+            .args(&args[1..])
+            .env_clear()
+            .envs(self.whitelisted_env.read().await.iter())
+            .env("TERM", "xterm-256color")
+            .current_dir(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Could not start shell")?;
+
+        let input = child.stdin.take().context("Could not attach to shell")?;
+        let output = child.stdout.take().context("Could not attach to shell")?;
+        // The shell exits when its input is closed
+        tokio::spawn(async move { child.wait().await });
+
+        Ok(ShellSession {
+            input: Box::pin(input),
+            output: ReaderStream::new(output)
+                .map(|chunk| Ok(chunk?.to_vec()))
+                .boxed(),
+        })
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn attach(&self, cmd: &str, working_dir: Option<&str>) -> Result<ShellSession> {
+        let path = self.path(working_dir)?;
+        let args = self.command_args(&shell_command(&self.shell, cmd), &path)?;
+        let mut child = tokio::process::Command::new(&args[0])
+            .args(&args[1..])
+            .env_clear()
+            .envs(self.whitelisted_env.read().await.iter())
+            .current_dir(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Could not start process")?;
+
+        let input = child.stdin.take().context("Could not attach to process")?;
+        let output = child.stdout.take().context("Could not attach to process")?;
+        // Processes like language servers exit when their input is closed
+        tokio::spawn(async move { child.wait().await });
+
+        Ok(ShellSession {
+            input: Box::pin(input),
+            output: ReaderStream::new(output)
+                .map(|chunk| Ok(chunk?.to_vec()))
+                .boxed(),
+        })
+    }
+
+    fn egress_requests(&self) -> Result<Vec<EgressRequest>> {
+        match &self.egress_proxy {
+            Some(proxy) => Ok(proxy.requests()),
+            None => anyhow::bail!("The workspace does not use the egress proxy"),
+        }
+    }
+
+    // In the temp directory next to the directory, which is removed when the workspace is
+    // stopped. Sandboxed commands see it as their /tmp, the others run on the /tmp of the host.
+    fn outputs_dir(&self) -> String {
+        match &self.sandbox {
+            Some(_) => crate::outputs::OUTPUTS_DIR.to_string(),
+            None => self
+                .sandbox_tmp()
+                .join("derrick-outputs")
+                .to_string_lossy()
+                .to_string(),
+        }
+    }
+
+    // Copies the directory next to this one, preserving permissions and symlinks
+    #[tracing::instrument(skip_all)]
+    async fn fork(&self) -> Result<Box<dyn WorkspaceController>> {
+        let path = format!("{}-fork-{}", self.path, uuid::Uuid::new_v4());
+        let output = tokio::process::Command::new("cp")
+            .arg("-a")
+            .arg(&self.path)
+            .arg(&path)
+            .output()
+            .await
+            .context("Could not copy local temp directory")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Could not copy local temp directory: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let fork = Self {
+            path,
+            whitelisted_env: RwLock::new(self.whitelisted_env.read().await.clone()),
+            shell: self.shell.clone(),
+            mirror_dir: self.mirror_dir.clone(),
+            overlay: None,
+            sandbox: self.sandbox.clone(),
+            sandbox_namespaces: Mutex::new(None),
+            egress_proxy: self.egress_proxy.clone(),
+            ports: self.ports.clone(),
+        };
+        Ok(Box::new(fork))
     }
 
     #[tracing::instrument(skip_all)]
@@ -172,7 +752,7 @@ impl WorkspaceController for LocalTempSyncController {
         repositories: Vec<crate::repository::Repository>,
     ) -> Result<()> {
         for repo in repositories {
-            let path = self.path(None);
+            let path = self.path(None)?;
             // Join the path with the repository path but remove the leading / if it exists
             let path = path.join(repo.path.strip_prefix("/").unwrap_or(&repo.path));
             let path = path.to_string_lossy();
@@ -180,18 +760,50 @@ impl WorkspaceController for LocalTempSyncController {
             self.cmd(&format!("mkdir -p {}", path), None, HashMap::new(), None)
                 .await?;
             info!("Cloning repository {}", repo.url);
-            self.cmd(
-                &format!("git clone {} {}", repo.url, path),
-                None,
-                HashMap::new(),
-                None,
-            )
-            .await?;
+            let auth = RepositoryAuth::prepare(self, &repo).await?;
+            // The credentials are removed again whether or not the clone succeeded
+            let cloned = async {
+                self.cmd(
+                    &git::clone_cmd(&repo, &auth.url, &path, self.mirror_dir.as_deref()),
+                    None,
+                    auth.env.clone(),
+                    None,
+                )
+                .await?;
+                git::sparse_checkout(self, &repo, &path, &auth).await?;
+                git::update_submodules_and_lfs(self, &repo, &path, &auth).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            auth.cleanup(self).await?;
+            cloned?;
         }
         Ok(())
     }
 }
 
+// Exit code like a shell reports it, 128 plus the signal for killed processes
+fn shell_exit_code(status: std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128 + signal;
+    }
+    status.code().unwrap_or(-1)
+}
+
+async fn kill_process_group(child: &mut tokio::process::Child) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        tokio::process::Command::new("kill")
+            .args(["-9", "--", &format!("-{}", pid)])
+            .status()
+            .await
+            .context("Could not kill command")?;
+        return Ok(());
+    }
+    child.kill().await.context("Could not kill command")
+}
+
 #[tracing::instrument(skip_all)]
 fn handle_command_result(result: std::process::Output) -> Result<CommandOutput> {
     let stdout = String::from_utf8_lossy(&result.stdout).to_string();
@@ -200,7 +812,10 @@ fn handle_command_result(result: std::process::Output) -> Result<CommandOutput>
         debug!(stdout = &stdout, stderr = &stderr, "Command succeeded");
         Ok(CommandOutput {
             output: stdout,
+            stdout: result.stdout,
+            stderr: result.stderr,
             exit_code: result.status.code().unwrap_or(0),
+            limit_exceeded: None,
         })
     } else {
         warn!(stdout = &stdout, stderr = &stderr, "Command failed");
@@ -211,6 +826,7 @@ fn handle_command_result(result: std::process::Output) -> Result<CommandOutput>
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::workspace_controllers::{FileEdit, FileWrite, LimitExceeded};
     use test_log::test;
 
     #[tokio::test]
@@ -222,7 +838,7 @@ mod tests {
             .await;
         assert!(result.is_ok());
         let stdout = result.unwrap();
-        assert!(stdout.output.contains("tmp/test"));
+        assert!(stdout.output.contains("derrick/test-"));
     }
 
     #[tokio::test]
@@ -231,7 +847,7 @@ mod tests {
         adapter.init().await.unwrap();
         let output = adapter.spawn_cmd("pwd", None, &Default::default()).unwrap();
         let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        assert!(stdout.contains("tmp/test"));
+        assert!(stdout.contains("derrick/test-"));
     }
 
     #[tokio::test]
@@ -248,15 +864,277 @@ mod tests {
 
         assert_eq!(
             stdout.trim(),
-            adapter.path(Some("subdir")).to_string_lossy()
+            adapter.path(Some("subdir")).unwrap().to_string_lossy()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streamed_output() {
+        let adapter = LocalTempSyncController::initialize("streamed").await;
+        let lines = Mutex::new(Vec::new());
+        let output = adapter
+            .cmd_with_streamed_output(
+                "echo one; echo two; printf three",
+                None,
+                HashMap::new(),
+                &|line: &str| lines.lock().unwrap().push(line.to_string()),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(*lines.lock().unwrap(), ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn test_configured_shell() {
+        let adapter = LocalTempSyncController::initialize("shell")
+            .await
+            .with_shell(Some(vec!["sh".to_string(), "-c".to_string()]));
+        let result = adapter
+            .cmd_with_output("echo $0", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(result.output.trim(), "sh");
+    }
+
+    #[tokio::test]
+    async fn test_write_files() {
+        use std::os::unix::fs::PermissionsExt;
+        let adapter = LocalTempSyncController::initialize("write-files").await;
+        let files = [
+            FileWrite {
+                path: "src/main.rs".to_string(),
+                content: b"fn main() {}".to_vec(),
+                mode: None,
+            },
+            FileWrite {
+                path: "bin/setup".to_string(),
+                content: b"#!/bin/sh".to_vec(),
+                mode: Some(0o755),
+            },
+        ];
+        adapter.write_files(&files, Some("repo")).await.unwrap();
+
+        assert_eq!(
+            adapter.read_file("repo/src/main.rs", None).await.unwrap(),
+            b"fn main() {}"
+        );
+        let setup = adapter.path(Some("repo/bin/setup")).unwrap();
+        assert_eq!(
+            setup.metadata().unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        // A file that cannot be written leaves the others untouched
+        let files = [
+            FileWrite {
+                path: "src/main.rs".to_string(),
+                content: b"changed".to_vec(),
+                mode: None,
+            },
+            FileWrite {
+                path: "src/main.rs/nested".to_string(),
+                content: Vec::new(),
+                mode: None,
+            },
+        ];
+        assert!(adapter.write_files(&files, Some("repo")).await.is_err());
+        assert_eq!(
+            adapter.read_file("repo/src/main.rs", None).await.unwrap(),
+            b"fn main() {}"
+        );
+        let entries = std::fs::read_dir(adapter.path(Some("repo/src")).unwrap())
+            .unwrap()
+            .count();
+        assert_eq!(entries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_file_operations_stay_in_the_workspace() {
+        let adapter = LocalTempSyncController::initialize("escape").await;
+        let depth = adapter.path.matches('/').count();
+        let traversal = "../".repeat(depth);
+
+        assert!(adapter
+            .read_file(&format!("{}etc/passwd", traversal), None)
+            .await
+            .is_err());
+        assert!(adapter.read_file("/etc/passwd", None).await.is_err());
+        assert!(adapter
+            .read_file("passwd", Some(&format!("{}etc", traversal)))
+            .await
+            .is_err());
+        assert!(adapter
+            .write_file(&format!("{}tmp/escaped", traversal), b"", None)
+            .await
+            .is_err());
+
+        // Commands cannot run outside of it either
+        let etc = format!("{}etc", traversal);
+        assert!(adapter
+            .cmd_with_output("cat passwd", Some(&etc), HashMap::new(), None)
+            .await
+            .is_err());
+        assert!(adapter
+            .spawn_cmd("cat passwd", Some(&etc), &Default::default())
+            .is_err());
+        assert!(adapter.attach("cat passwd", Some(&etc)).await.is_err());
+
+        // Symlinks can point outside of the workspace, but not be followed there
+        std::os::unix::fs::symlink("/etc", adapter.path(Some("etc")).unwrap()).unwrap();
+        assert!(adapter.read_file("etc/passwd", None).await.is_err());
+        assert!(adapter.write_file("etc/derrick", b"", None).await.is_err());
+        adapter
+            .symlink("passwd", "/etc/passwd", None)
+            .await
+            .unwrap();
+        assert!(adapter.read_file("passwd", None).await.is_err());
+        assert!(adapter.edit_file("passwd", &[], None).await.is_err());
+
+        // Paths that stay inside are fine
+        adapter
+            .write_file("repo/../README.md", b"hi", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            adapter
+                .read_file("README.md", Some("repo/.."))
+                .await
+                .unwrap(),
+            b"hi"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_missing_file() {
+        let adapter = LocalTempSyncController::initialize("missing").await;
+        let error = adapter
+            .read_file("missing.txt", Some("repo"))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<FileNotFound>().unwrap().path,
+            "missing.txt"
         );
     }
 
+    #[tokio::test]
+    async fn test_append_and_edit_file() {
+        let adapter = LocalTempSyncController::initialize("edits").await;
+        adapter
+            .append_file("log/out.txt", b"one\n", None)
+            .await
+            .unwrap();
+        adapter
+            .append_file("log/out.txt", b"two\n", None)
+            .await
+            .unwrap();
+        adapter
+            .edit_file(
+                "out.txt",
+                &[FileEdit::Lines {
+                    start: 2,
+                    end: 2,
+                    content: "2".to_string(),
+                }],
+                Some("log"),
+            )
+            .await
+            .unwrap();
+
+        let content = adapter.read_file("log/out.txt", None).await.unwrap();
+        assert_eq!(content, b"one\n2\n");
+    }
+
+    #[tokio::test]
+    async fn test_write_file_with_mode_and_symlink() {
+        use std::os::unix::fs::PermissionsExt;
+        let adapter = LocalTempSyncController::initialize("modes").await;
+        adapter
+            .write_file_with_mode("bin/run.sh", b"echo ran", None, Some(0o755))
+            .await
+            .unwrap();
+        adapter.symlink("run", "bin/run.sh", None).await.unwrap();
+
+        let path = adapter.path(Some("bin/run.sh")).unwrap();
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+        let output = adapter
+            .cmd_with_output("./run", None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(output.output, "ran\n");
+
+        // Writing again keeps the permissions
+        adapter
+            .write_file("bin/run.sh", b"echo again", None)
+            .await
+            .unwrap();
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o755);
+    }
+
+    #[tokio::test]
+    async fn test_fork_copies_directory() {
+        let adapter = LocalTempSyncController::initialize("fork").await;
+        adapter
+            .write_file("state.txt", b"prepared", None)
+            .await
+            .unwrap();
+
+        let fork = adapter.fork().await.unwrap();
+        fork.write_file("state.txt", b"forked", None).await.unwrap();
+
+        assert_eq!(
+            adapter.read_file("state.txt", None).await.unwrap(),
+            b"prepared"
+        );
+        assert_eq!(fork.read_file("state.txt", None).await.unwrap(), b"forked");
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_only_reach_declared_ports() {
+        // A service of the host, on the same loopback as the workspace
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let adapter = LocalTempSyncController::initialize("ports").await;
+        assert!(adapter.network_address(port).await.is_err());
+
+        let adapter = adapter.with_ports(vec![port]);
+        let address = adapter.network_address(port).await.unwrap();
+        assert!(tokio::net::TcpStream::connect((address.as_str(), port))
+            .await
+            .is_ok());
+        assert!(adapter.network_address(port.wrapping_add(1)).await.is_err());
+        adapter.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_workspaces_with_same_name_get_own_directory() {
+        let root = default_root().join(format!("test-root-{}", uuid::Uuid::new_v4()));
+        let first = LocalTempSyncController::initialize_in(&root, "same/name").await;
+        let second = LocalTempSyncController::initialize_in(&root, "same/name").await;
+        assert_ne!(first.path, second.path);
+        assert!(first.path.contains("same-name-"));
+
+        first.stop().await.unwrap();
+        assert!(!Path::new(&first.path).exists());
+        assert!(second.path(None).unwrap().exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_detect_shell() {
+        let shell = detect_shell();
+        assert_eq!(shell.len(), 2);
+        assert!(["bash", "sh"].contains(&shell[0].as_str()));
+    }
+
     #[test]
     fn test_init_path() {
-        let path = init_path("test").unwrap();
+        let path = init_path(&default_root(), "test").unwrap();
         dbg!(&path);
-        let regex = regex::Regex::new(r"^.*/tmp/test-\d+$").unwrap();
+        let regex = regex::Regex::new(r"^.*/derrick/test-[0-9a-f-]{36}$").unwrap();
         assert!(regex.is_match(&path));
         assert!(std::path::PathBuf::from(&path).exists())
     }
@@ -266,7 +1144,7 @@ mod tests {
         let adapter = LocalTempSyncController::initialize("test").await;
         let result = adapter.init().await;
         assert!(result.is_ok());
-        let path = adapter.path(None);
+        let path = adapter.path(None).unwrap();
         assert!(path.exists());
     }
 
@@ -289,6 +1167,52 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_kills_command_exceeding_output_limit() {
+        let adapter = LocalTempSyncController::initialize("test").await;
+        let limits = CommandLimits {
+            max_output_bytes: Some(1000),
+            ..Default::default()
+        };
+        let result = adapter
+            .cmd_with_limits("yes", None, HashMap::new(), None, &limits)
+            .await
+            .unwrap();
+        assert_eq!(result.output.len(), 1000);
+        assert_eq!(result.limit_exceeded, Some(LimitExceeded::OutputSize));
+    }
+
+    #[tokio::test]
+    async fn test_kills_command_exceeding_cpu_limit() {
+        let adapter = LocalTempSyncController::initialize("test").await;
+        let limits = CommandLimits {
+            cpu_time: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        let result = adapter
+            .cmd_with_limits("while :; do :; done", None, HashMap::new(), None, &limits)
+            .await
+            .unwrap();
+        assert_eq!(result.limit_exceeded, Some(LimitExceeded::CpuTime));
+    }
+
+    #[tokio::test]
+    async fn test_kills_command_exceeding_timeout() {
+        let adapter = LocalTempSyncController::initialize("test").await;
+        let result = adapter
+            .cmd_with_limits(
+                "echo started; sleep 10",
+                None,
+                HashMap::new(),
+                Some(Duration::from_millis(200)),
+                &CommandLimits::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.output, "started\n");
+        assert_eq!(result.limit_exceeded, Some(LimitExceeded::WallTime));
+    }
+
     #[tokio::test]
     async fn test_piping_a_command() {
         let adapter = LocalTempSyncController::initialize("test").await;
@@ -3,23 +3,161 @@ use std::time::Duration;
 
 use anyhow::Result;
 use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+use std::pin::Pin;
+use tokio::io::AsyncWrite;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CommandOutput {
+    // Stdout and stderr in the order they were written, invalid UTF-8 is replaced
     pub output: String,
+    // The raw bytes of each stream, for output that is not text
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
     pub exit_code: i32,
+    // Set when the command was killed for exceeding one of its limits
+    pub limit_exceeded: Option<LimitExceeded>,
+}
+
+// A file written by `WorkspaceController::write_files`
+#[derive(Debug, Clone)]
+pub struct FileWrite {
+    pub path: String,
+    pub content: Vec<u8>,
+    // Existing files keep their permissions when not set
+    pub mode: Option<u32>,
+}
+
+// The file to read does not exist. Use `error.downcast_ref::<FileNotFound>()` to tell it apart
+// from a workspace that could not be reached.
+#[derive(Debug)]
+pub struct FileNotFound {
+    pub path: String,
+}
+
+impl std::fmt::Display for FileNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File {} does not exist", self.path)
+    }
+}
+
+impl std::error::Error for FileNotFound {}
+
+// A file is over the size the server accepts or returns
+#[derive(Debug)]
+pub struct FileTooLarge {
+    pub path: String,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl std::fmt::Display for FileTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "File {} is {} bytes, over the limit of {} bytes",
+            self.path, self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for FileTooLarge {}
+
+// Result of the health check of a workspace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    // Within the start period, or before the first check
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+// The container of the workspace is no longer running, like when it was killed for running out of
+// memory
+#[derive(Debug)]
+pub struct ContainerStopped {
+    pub container_id: String,
+    pub exit_code: Option<i64>,
+    pub oom_killed: bool,
 }
 
-mod local_temp_sync;
+impl std::fmt::Display for ContainerStopped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Container {} exited", self.container_id)?;
+        if let Some(exit_code) = self.exit_code {
+            write!(f, " with code {}", exit_code)?;
+        }
+        if self.oom_killed {
+            write!(f, " after running out of memory")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ContainerStopped {}
+
+pub(crate) mod cleanup;
+pub use cleanup::flush_cleanups;
+
+mod limits;
+pub use limits::{CommandLimits, LimitExceeded};
+
+mod sandbox;
+pub(crate) use sandbox::SandboxNamespaces;
+pub use sandbox::{Sandbox, SandboxTool};
+
+mod edits;
+pub use edits::FileEdit;
+
+pub(crate) mod local_temp_sync;
 pub use local_temp_sync::LocalTempSyncController;
 
 #[cfg(test)]
-mod testing;
+pub(crate) mod testing;
 
 pub mod docker;
-// mod remote_nats;
+pub(crate) mod git;
+pub(crate) mod remote_http;
+pub(crate) mod remote_nats;
 pub use docker::DockerController;
 
+/// Shells tried, in order, when a workspace context does not configure one explicitly.
+pub(crate) const FALLBACK_SHELLS: &[&[&str]] = &[&["bash", "-c"], &["sh", "-c"]];
+
+/// Builds the full argument vector for running `cmd` with the given shell, e.g.
+/// `["sh", "-c"]` becomes `["sh", "-c", cmd]`.
+pub(crate) fn shell_command<'a>(shell: &'a [String], cmd: &'a str) -> Vec<&'a str> {
+    shell
+        .iter()
+        .map(String::as_str)
+        .chain(std::iter::once(cmd))
+        .collect()
+}
+
+// Resources a workspace used since it was started, see `WorkspaceController::resource_counters`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceCounters {
+    pub cpu_time: Duration,
+    // Received and sent over all networks
+    pub network_bytes: u64,
+}
+
+// Gets the lines a command prints while it runs, see `WorkspaceController::cmd_with_streamed_output`
+pub type OutputLineCallback = dyn Fn(&str) + Send + Sync;
+
+// Interactive shell with a terminal, see `WorkspaceController::shell`, or a process attached with
+// `WorkspaceController::attach`
+pub struct ShellSession {
+    // Keyboard input for the terminal, closing it ends the shell
+    pub input: Pin<Box<dyn AsyncWrite + Send>>,
+    // Everything written to the terminal
+    pub output: BoxStream<'static, Result<Vec<u8>>>,
+}
+
 #[async_trait]
 pub trait WorkspaceController: Send + Sync + std::fmt::Debug {
     async fn init(&self) -> Result<()>;
@@ -42,7 +180,168 @@ pub trait WorkspaceController: Send + Sync + std::fmt::Debug {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<CommandOutput>;
+    // Runs a command that is killed when it exceeds one of the limits, which is reported in
+    // `limit_exceeded`. Unlike `cmd_with_output`, a non-zero exit code is never an error.
+    async fn cmd_with_limits(
+        &self,
+        _cmd: &str,
+        _working_dir: Option<&str>,
+        _env: HashMap<String, String>,
+        _timeout: Option<Duration>,
+        _limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        anyhow::bail!("Command limits are not supported by this workspace")
+    }
+    // Runs a command like `cmd_with_output`, calling `on_line` with every line of its output as
+    // it is printed. Workspaces that cannot stream the output call it once the command exited.
+    async fn cmd_with_streamed_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        on_line: &OutputLineCallback,
+    ) -> Result<CommandOutput> {
+        let output = self.cmd_with_output(cmd, working_dir, env, None).await?;
+        output.output.lines().for_each(on_line);
+        Ok(output)
+    }
     async fn write_file(&self, path: &str, content: &[u8], working_dir: Option<&str>)
         -> Result<()>;
     async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>>;
+
+    // Writes the file with the given permissions, e.g. 0o755 for scripts. Without a mode, an
+    // existing file keeps its permissions.
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.write_file(path, content, working_dir).await?;
+        if let Some(mode) = mode {
+            self.cmd(
+                &format!("chmod {:o} {}", mode, escape(path.into())),
+                working_dir,
+                HashMap::new(),
+                None,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    // Writes several files at once. Workspaces that can write them in one go do so, so that
+    // either all or none of them are written.
+    async fn write_files(&self, files: &[FileWrite], working_dir: Option<&str>) -> Result<()> {
+        for file in files {
+            self.write_file_with_mode(&file.path, &file.content, working_dir, file.mode)
+                .await?;
+        }
+        Ok(())
+    }
+
+    // Creates a symlink at `path` that points to `target`, replacing the file that is there
+    async fn symlink(&self, path: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        self.cmd(
+            &format!("ln -sfn {} {}", escape(target.into()), escape(path.into())),
+            working_dir,
+            HashMap::new(),
+            None,
+        )
+        .await
+    }
+
+    // Appends to the file, creating it when it does not exist
+    async fn append_file(
+        &self,
+        _path: &str,
+        _content: &[u8],
+        _working_dir: Option<&str>,
+    ) -> Result<()> {
+        anyhow::bail!("Appending to files is not supported by this workspace")
+    }
+
+    // Changes parts of the file, see `FileEdit`. The file is only written when all edits apply.
+    async fn edit_file(
+        &self,
+        path: &str,
+        edits: &[FileEdit],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        let content = self.read_file(path, working_dir).await?;
+        let content = edits::apply_edits(&content, edits)?;
+        self.write_file(path, &content, working_dir).await
+    }
+
+    // Bytes written to disk by the workspace, used to enforce disk quotas
+    async fn disk_usage(&self) -> Result<u64> {
+        anyhow::bail!("Disk usage is not supported by this workspace")
+    }
+
+    // CPU time and network traffic since the workspace was (re)started, None for workspaces that
+    // do not count them. Used to account the usage of workspaces.
+    async fn resource_counters(&self) -> Result<Option<ResourceCounters>> {
+        Ok(None)
+    }
+
+    // Opens an interactive shell with a terminal of the given size, for humans to inspect the
+    // workspace
+    async fn shell(&self, _cols: u16, _rows: u16) -> Result<ShellSession> {
+        anyhow::bail!("Interactive shells are not supported by this workspace")
+    }
+
+    // Starts a command with its stdin and stdout attached and without a terminal, for processes
+    // that speak a protocol over stdio like language servers. Its stderr is not returned.
+    async fn attach(&self, _cmd: &str, _working_dir: Option<&str>) -> Result<ShellSession> {
+        anyhow::bail!("Attaching to processes is not supported by this workspace")
+    }
+
+    // Creates a new workspace from the current state of this one, without the processes running
+    // in it
+    async fn fork(&self) -> Result<Box<dyn WorkspaceController>> {
+        anyhow::bail!("Forking is not supported by this workspace")
+    }
+
+    // Result of the last health check, None for workspaces without one
+    async fn health(&self) -> Result<Option<Health>> {
+        Ok(None)
+    }
+
+    // Whether the workspace can still run commands
+    async fn is_running(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    // Starts the workspace again after it stopped, with the files it had but none of its processes
+    async fn restart(&self) -> Result<()> {
+        anyhow::bail!("Restarting is not supported by this workspace")
+    }
+
+    // What the main process of the workspace wrote to stdout and stderr, the last `tail` lines
+    // or everything. With `follow` the stream stays open for new output until the workspace stops.
+    fn logs(
+        &self,
+        _follow: bool,
+        _tail: Option<usize>,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        anyhow::bail!("Logs are not supported by this workspace")
+    }
+
+    // Host or IP address the server reaches the port of the workspace at, failing for ports the
+    // server should not reach
+    async fn network_address(&self, _port: u16) -> Result<String> {
+        anyhow::bail!("Previews are not supported by this workspace")
+    }
+
+    // Outbound requests recorded by the egress proxy of the workspace, oldest first
+    fn egress_requests(&self) -> Result<Vec<crate::egress_proxy::EgressRequest>> {
+        anyhow::bail!("Egress logs are not supported by this workspace")
+    }
+
+    // Directory the output of commands is written to when it is not returned whole, as the
+    // commands see it. It has to be removed along with the workspace.
+    fn outputs_dir(&self) -> String {
+        crate::outputs::OUTPUTS_DIR.to_string()
+    }
 }
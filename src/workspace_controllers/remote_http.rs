@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::io::AsyncReadExt;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::client::{ApiError, Client};
+use crate::workspace_controllers::{
+    CommandLimits, CommandOutput, FileNotFound, Health, LimitExceeded, ShellSession,
+    WorkspaceController,
+};
+
+// A workspace of another derrick, every operation is a request to its HTTP API. Interactive
+// shells are passed through, logs and previews are not supported.
+#[derive(Debug)]
+pub struct RemoteHttpController {
+    client: Client,
+    // Id of the workspace on the remote server
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RemoteOutput {
+    output: String,
+    exit_code: i32,
+    #[serde(default)]
+    limit_exceeded: Option<LimitExceeded>,
+}
+
+impl From<RemoteOutput> for CommandOutput {
+    // The API only returns the streams together
+    fn from(output: RemoteOutput) -> Self {
+        Self {
+            stdout: output.output.clone().into_bytes(),
+            stderr: Vec::new(),
+            output: output.output,
+            exit_code: output.exit_code,
+            limit_exceeded: output.limit_exceeded,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RemoteUsage {
+    bytes: u64,
+}
+
+#[derive(Deserialize)]
+struct RemoteStatus {
+    #[serde(default)]
+    health: Option<Health>,
+}
+
+impl RemoteHttpController {
+    pub fn new(client: Client, id: String) -> Self {
+        Self { client, id }
+    }
+
+    fn path(&self, action: &str) -> String {
+        format!("/workspaces/{}/{}", self.id, action)
+    }
+
+    async fn run(
+        &self,
+        action: &str,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: Option<&CommandLimits>,
+    ) -> Result<reqwest::Response> {
+        self.client
+            .post(
+                &self.path(action),
+                json!({
+                    "cmd": cmd,
+                    "working_dir": working_dir,
+                    "env": env,
+                    "timeout": timeout.map(seconds),
+                    "cpu_time": limits.and_then(|limits| limits.cpu_time).map(seconds),
+                    "max_output_bytes": limits.and_then(|limits| limits.max_output_bytes),
+                }),
+            )
+            .await
+    }
+}
+
+// The API takes whole seconds, shorter durations are rounded up
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+#[async_trait]
+impl WorkspaceController for RemoteHttpController {
+    async fn init(&self) -> Result<()> {
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(remote_id = %self.id))]
+    async fn stop(&self) -> Result<()> {
+        if !self.client.destroy_workspace(&self.id).await? {
+            tracing::warn!("Remote workspace was already gone");
+        }
+        Ok(())
+    }
+
+    async fn provision_repositories(
+        &self,
+        _repositories: Vec<crate::repository::Repository>,
+    ) -> Result<()> {
+        anyhow::bail!("Repositories of remote workspaces are provisioned by the remote server")
+    }
+
+    async fn cmd(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.run("cmd", cmd, working_dir, env, timeout, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn cmd_with_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        let output: RemoteOutput = self
+            .run("cmd_with_output", cmd, working_dir, env, timeout, None)
+            .await?
+            .json()
+            .await?;
+        Ok(output.into())
+    }
+
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        let output: RemoteOutput = self
+            .run(
+                "cmd_with_output",
+                cmd,
+                working_dir,
+                env,
+                timeout,
+                Some(limits),
+            )
+            .await?
+            .json()
+            .await?;
+        Ok(output.into())
+    }
+
+    async fn write_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.write_file_with_mode(path, content, working_dir, None)
+            .await
+    }
+
+    // The remote server sets the mode, so it takes a single request
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.client
+            .post(
+                &self.path("write_file"),
+                json!({
+                    "path": path,
+                    "working_dir": working_dir,
+                    "content": base64::engine::general_purpose::STANDARD.encode(content),
+                    "mode": mode.map(|mode| format!("{:o}", mode)),
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn symlink(&self, path: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        self.client
+            .post(
+                &self.path("write_file"),
+                json!({ "path": path, "working_dir": working_dir, "symlink": target }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn append_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.client
+            .post(
+                &self.path("append_file"),
+                json!({
+                    "path": path,
+                    "working_dir": working_dir,
+                    "content": base64::engine::general_purpose::STANDARD.encode(content),
+                }),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .post(
+                &self.path("read_file"),
+                json!({ "path": path, "working_dir": working_dir }),
+            )
+            .await
+            .map_err(|e| match e.downcast_ref::<ApiError>() {
+                Some(error) if error.status == reqwest::StatusCode::NOT_FOUND => FileNotFound {
+                    path: path.to_string(),
+                }
+                .into(),
+                _ => e,
+            })?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn disk_usage(&self) -> Result<u64> {
+        let usage: RemoteUsage = self.client.get(&self.path("usage")).await?.json().await?;
+        Ok(usage.bytes)
+    }
+
+    async fn health(&self) -> Result<Option<Health>> {
+        let status: RemoteStatus = self.client.get(&self.path("status")).await?.json().await?;
+        Ok(status.health)
+    }
+
+    // Keyboard input is written to a pipe that is forwarded to the socket of the remote shell
+    async fn shell(&self, cols: u16, rows: u16) -> Result<ShellSession> {
+        let (mut sink, stream) = self.client.shell(&self.id, cols, rows).await?.split();
+        let (input, mut reader) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 64 * 1024];
+            while let Ok(read) = reader.read(&mut buf).await {
+                if read == 0 {
+                    break;
+                }
+                if sink
+                    .send(Message::Binary(buf[..read].to_vec().into()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            let _ = sink.send(Message::Close(None)).await;
+        });
+        let output = stream
+            .filter_map(|message| async move {
+                match message {
+                    Ok(Message::Binary(data)) => Some(Ok(data.to_vec())),
+                    Ok(Message::Text(text)) => Some(Ok(text.as_bytes().to_vec())),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                }
+            })
+            .boxed();
+        Ok(ShellSession {
+            input: Box::pin(input),
+            output,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seconds() {
+        assert_eq!(seconds(Duration::from_secs(30)), 30);
+        assert_eq!(seconds(Duration::from_millis(1500)), 2);
+        assert_eq!(seconds(Duration::from_millis(1)), 1);
+        assert_eq!(seconds(Duration::ZERO), 0);
+    }
+}
@@ -1,86 +1,65 @@
-use crate::workspace_controllers::WorkspaceController;
-use anyhow::{Context, Result};
-// use async_nats::jetstream::response;
-use crate::messaging;
-use async_trait::async_trait;
-use regex;
-use serde::{de::DeserializeOwned, Serialize};
-use std::sync::OnceLock;
+use std::collections::HashMap;
 use std::time::Duration;
-use std::{collections::HashMap, fmt::Debug};
-use tracing::{debug, warn};
 
-// Runs commands on a remote workspace using nats
+use anyhow::Result;
+use async_trait::async_trait;
+use base64::Engine;
+
+use crate::remote_nats::{self, RemoteCommand, RemoteOutput, Request};
+use crate::workspace_controllers::{CommandLimits, CommandOutput, Health, WorkspaceController};
+
+// A workspace of another derrick serving over NATS, every operation is a request to the server
+// that created it. Shells, logs and previews need a connection to the workspace and are not
+// supported.
 #[derive(Debug)]
 pub struct RemoteNatsController {
-    name: String,
-    path: OnceLock<String>,
-    channel: OnceLock<messaging::Channel>,
-    subscriber: OnceLock<messaging::Subscriber>,
+    client: async_nats::Client,
+    // Subject of the remote server
+    subject: String,
+    // Id of the workspace on the remote server
+    id: String,
 }
 
 impl RemoteNatsController {
-    #[tracing::instrument]
-    pub fn new(name: &str) -> Self {
+    pub fn new(client: async_nats::Client, subject: String, id: String) -> Self {
         Self {
-            name: name.into(),
-            path: OnceLock::new(),
-            channel: OnceLock::new(),
-            subscriber: OnceLock::new(),
+            client,
+            subject,
+            id,
         }
     }
 
-    fn spawn_cmd(
-        &self,
-        cmd: &str,
-        _working_dir: Option<&str>,
-        _env: HashMap<String, String>,
-    ) -> std::result::Result<std::process::Output, std::io::Error> {
-        debug!(cmd = scrub(cmd), "Running command");
-        todo!()
-    }
-
-    async fn rpc_call<CmdType: Serialize, ResponseType: DeserializeOwned>(
-        &self,
-        cmd: CmdType,
-    ) -> Result<ResponseType> {
-        let channel = self
-            .channel
-            .get()
-            .ok_or_else(|| anyhow::anyhow!("Channel not set"))?;
-
-        let cmd_str = serde_json::to_string(&cmd).context("Could not serialize command")?;
-
-        let response_str = channel
-            .request(cmd_str)
-            .await
-            .context("Could not send request")?;
-
-        let response =
-            serde_json::from_str(&response_str).context("Could not deserialize response")?;
-
-        Ok(response)
+    async fn request<T: serde::de::DeserializeOwned>(&self, request: Request) -> Result<T> {
+        remote_nats::request(&self.client, &self.subject, &request).await
     }
 }
 
 #[async_trait]
 impl WorkspaceController for RemoteNatsController {
-    #[tracing::instrument]
     async fn init(&self) -> Result<()> {
-        let channel = messaging::Channel::establish("workspace.init".to_string()).await?;
-
-        self.channel
-            .set(channel)
-            .map_err(|_| anyhow::anyhow!("Channel already set"))?;
-
         Ok(())
     }
 
+    #[tracing::instrument(skip_all, fields(remote_id = %self.id))]
     async fn stop(&self) -> Result<()> {
-        todo!();
+        let destroyed: bool = self
+            .request(Request::Destroy {
+                id: self.id.clone(),
+            })
+            .await?;
+        if !destroyed {
+            tracing::warn!("Remote workspace was already gone");
+        }
+        Ok(())
+    }
+
+    async fn provision_repositories(
+        &self,
+        _repositories: Vec<crate::repository::Repository>,
+    ) -> Result<()> {
+        anyhow::bail!("Repositories of remote workspaces are provisioned by the remote server")
     }
 
-    #[tracing::instrument(fields(cmd = scrub(cmd)))]
     async fn cmd(
         &self,
         cmd: &str,
@@ -88,66 +67,113 @@ impl WorkspaceController for RemoteNatsController {
         env: HashMap<String, String>,
         timeout: Option<Duration>,
     ) -> Result<()> {
-        self.spawn_cmd(cmd, working_dir, env)
-            .map(handle_command_result)?
-            .map(|_| ())
+        self.request(Request::Cmd {
+            id: self.id.clone(),
+            cmd: RemoteCommand::new(cmd, working_dir, env, timeout),
+        })
+        .await
     }
 
-    #[tracing::instrument(fields(cmd = scrub(cmd)))]
     async fn cmd_with_output(
         &self,
         cmd: &str,
         working_dir: Option<&str>,
         env: HashMap<String, String>,
         timeout: Option<Duration>,
-    ) -> Result<String> {
-        self.spawn_cmd(cmd, working_dir, env)
-            .map(handle_command_result)?
+    ) -> Result<CommandOutput> {
+        let output: RemoteOutput = self
+            .request(Request::CmdWithOutput {
+                id: self.id.clone(),
+                cmd: RemoteCommand::new(cmd, working_dir, env, timeout),
+            })
+            .await?;
+        output.try_into()
+    }
+
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        let output: RemoteOutput = self
+            .request(Request::CmdWithLimits {
+                id: self.id.clone(),
+                cmd: RemoteCommand::new(cmd, working_dir, env, timeout),
+                cpu_time_ms: limits.cpu_time.map(|limit| limit.as_millis() as u64),
+                max_output_bytes: limits.max_output_bytes,
+            })
+            .await?;
+        output.try_into()
     }
 
-    #[tracing::instrument]
     async fn write_file(
         &self,
-        file: &str,
-        content: &str,
-        _working_dir: Option<&str>,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
     ) -> Result<()> {
-        // std::fs::write(format!("{}/{}", &self.path(working_dir), file), content)
-        //     .context("Could not write file")
-        todo!()
+        self.write_file_with_mode(path, content, working_dir, None)
+            .await
     }
 
-    #[tracing::instrument]
-    async fn read_file(&self, file: &str, _working_dir: Option<&str>) -> Result<String> {
-        // std::fs::read_to_string(format!("{}/{}", &self.path(working_dir), file))
-        //     .context("Could not read file")
-        todo!()
+    // The remote server sets the mode, so it takes a single request
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.request(Request::WriteFile {
+            id: self.id.clone(),
+            path: path.to_string(),
+            content: base64::prelude::BASE64_STANDARD.encode(content),
+            working_dir: working_dir.map(str::to_string),
+            mode,
+        })
+        .await
     }
 
-    #[tracing::instrument(skip_all)]
-    async fn provision_repositories(
+    async fn append_file(
         &self,
-        _repositories: Vec<crate::repository::Repository>,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
     ) -> Result<()> {
-        todo!()
+        self.request(Request::AppendFile {
+            id: self.id.clone(),
+            path: path.to_string(),
+            content: base64::prelude::BASE64_STANDARD.encode(content),
+            working_dir: working_dir.map(str::to_string),
+        })
+        .await
     }
-}
 
-#[tracing::instrument]
-fn handle_command_result(result: std::process::Output) -> Result<String> {
-    let stdout = String::from_utf8_lossy(&result.stdout).to_string();
-    let stderr = String::from_utf8_lossy(&result.stderr).to_string();
-    if result.status.success() {
-        debug!(stdout = &stdout, stderr = &stderr, "Command succeeded");
-        Ok(stdout)
-    } else {
-        warn!(stdout = &stdout, stderr = &stderr, "Command failed");
-        Err(anyhow::anyhow!(stderr))
+    async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
+        let content: String = self
+            .request(Request::ReadFile {
+                id: self.id.clone(),
+                path: path.to_string(),
+                working_dir: working_dir.map(str::to_string),
+            })
+            .await?;
+        Ok(base64::prelude::BASE64_STANDARD.decode(content)?)
     }
-}
 
-// scrub removes x-access-token:<token> from a string like x-access-token:1234@github.com
-fn scrub(output: &str) -> String {
-    let re = regex::Regex::new(r"x-access-token:[^@]+@").unwrap();
-    re.replace_all(output, "x-access-token:***@").to_string()
+    async fn disk_usage(&self) -> Result<u64> {
+        self.request(Request::DiskUsage {
+            id: self.id.clone(),
+        })
+        .await
+    }
+
+    async fn health(&self) -> Result<Option<Health>> {
+        self.request(Request::Health {
+            id: self.id.clone(),
+        })
+        .await
+    }
 }
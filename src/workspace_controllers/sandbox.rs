@@ -0,0 +1,632 @@
+use std::io::BufRead;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use shell_escape::escape;
+
+// System directories that are visible, read only, in every sandbox when they exist on the host
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64"];
+// What programs need of /etc, to resolve hosts and users, load libraries and verify certificates.
+// The rest of it, like the configuration and keys of the host, is not visible.
+const ETC_PATHS: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/alternatives",
+    "/etc/ssl/certs",
+    "/etc/ssl/openssl.cnf",
+    "/etc/ca-certificates",
+    "/etc/pki/tls/certs",
+    "/etc/pki/ca-trust",
+];
+// Devices bound into nsjail sandboxes, bubblewrap creates its own minimal /dev
+const DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+    "/dev/ptmx",
+    "/dev/pts",
+];
+// File descriptor the seccomp program is passed to bubblewrap on
+const SECCOMP_FD: &str = "3";
+// File descriptors the namespaces of the workspace are passed to bubblewrap on
+const USERNS_FD: &str = "4";
+const PIDNS_FD: &str = "5";
+
+// System calls the default seccomp profile denies with EPERM, with their numbers on x86_64 and
+// aarch64. Building and testing code does not need to mount file systems, load kernel modules,
+// set the clock or create namespaces, and each of them exposes more of the kernel.
+const DENIED_SYSCALLS: &[(&str, u32, u32)] = &[
+    ("mount", 165, 40),
+    ("umount2", 166, 39),
+    ("pivot_root", 155, 41),
+    ("swapon", 167, 224),
+    ("swapoff", 168, 225),
+    ("reboot", 169, 142),
+    ("kexec_load", 246, 104),
+    ("kexec_file_load", 320, 294),
+    ("init_module", 175, 105),
+    ("finit_module", 313, 273),
+    ("delete_module", 176, 106),
+    ("bpf", 321, 280),
+    ("perf_event_open", 298, 241),
+    ("keyctl", 250, 219),
+    ("add_key", 248, 217),
+    ("request_key", 249, 218),
+    ("userfaultfd", 323, 282),
+    ("open_by_handle_at", 304, 265),
+    ("acct", 163, 89),
+    ("settimeofday", 164, 170),
+    ("clock_settime", 227, 112),
+    ("clock_adjtime", 305, 266),
+    ("adjtimex", 159, 171),
+    ("syslog", 103, 116),
+    ("quotactl", 179, 60),
+    ("setns", 308, 268),
+    ("unshare", 272, 97),
+];
+// Architecture of the host as seccomp reports it, the default profile only knows these
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+// x32 system calls run with the architecture of x86_64, but have this bit set in their number
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+// BPF instructions and seccomp return values, from linux/filter.h and linux/seccomp.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO_EPERM: u32 = 0x0005_0001;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxTool {
+    #[default]
+    Bubblewrap,
+    Nsjail,
+}
+
+impl SandboxTool {
+    fn program(&self) -> &'static str {
+        match self {
+            SandboxTool::Bubblewrap => "bwrap",
+            SandboxTool::Nsjail => "nsjail",
+        }
+    }
+}
+
+// Runs the commands of local workspaces with bubblewrap or nsjail. They only see the system
+// directories, read only, and the directory of the workspace. The workspace gets a /tmp of its
+// own, which lives as long as the workspace.
+//
+// Commands run in a pid namespace, so they cannot see the processes of the host (or read their
+// environment through /proc). With bubblewrap the commands of a workspace share one, held by a
+// process that lives as long as the workspace, so daemons outlive the command that started them.
+// nsjail cannot join an existing namespace, so there everything a command started is killed
+// when it exits.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema, PartialEq)]
+pub struct Sandbox {
+    #[serde(default)]
+    pub tool: SandboxTool,
+    // Commands have no network unless enabled
+    #[serde(default)]
+    pub network: bool,
+    // A compiled seccomp BPF program for bubblewrap, or a kafel policy file for nsjail. Without
+    // one the default profile applies, which denies `DENIED_SYSCALLS`.
+    #[serde(default)]
+    pub seccomp_profile: Option<String>,
+    // Runs the commands without the default seccomp profile when there is no other one
+    #[serde(default)]
+    pub disable_default_seccomp: bool,
+    // Other host paths the commands can read, e.g. a shared cargo home
+    #[serde(default)]
+    pub read_only_paths: Vec<String>,
+}
+
+impl Sandbox {
+    // Fails when the sandbox tool is not installed on the host
+    pub fn check(&self) -> Result<()> {
+        let program = self.tool.program();
+        let found = std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|dir| dir.join(program).is_file())
+        });
+        if !found {
+            anyhow::bail!("Sandbox tool {} is not installed", program);
+        }
+        if let Some(profile) = &self.seccomp_profile {
+            if !Path::new(profile).is_file() {
+                anyhow::bail!("Seccomp profile {} does not exist", profile);
+            }
+        }
+        self.default_seccomp_program()?;
+        Ok(())
+    }
+
+    fn read_only(&self) -> impl Iterator<Item = &Path> {
+        SYSTEM_DIRS
+            .iter()
+            .chain(ETC_PATHS)
+            .map(Path::new)
+            .chain(self.read_only_paths.iter().map(Path::new))
+            .filter(|path| path.exists())
+    }
+
+    fn default_seccomp(&self) -> bool {
+        self.seccomp_profile.is_none() && !self.disable_default_seccomp
+    }
+
+    // The BPF program of the default seccomp profile, when bubblewrap uses it. It has to be
+    // written to a file the commands cannot change, which is passed to `wrap`.
+    pub(crate) fn default_seccomp_program(&self) -> Result<Option<Vec<u8>>> {
+        if self.tool != SandboxTool::Bubblewrap || !self.default_seccomp() {
+            return Ok(None);
+        }
+        compile_default_seccomp().map(Some)
+    }
+
+    // Starts the process holding the user and pid namespace the commands of a workspace share.
+    // There is none for nsjail.
+    pub(crate) fn namespaces(&self) -> Result<Option<SandboxNamespaces>> {
+        if self.tool != SandboxTool::Bubblewrap {
+            return Ok(None);
+        }
+
+        let mut command = Command::new(self.tool.program());
+        for path in self.read_only() {
+            command.arg("--ro-bind").arg(path).arg(path);
+        }
+        // `cat` exits when derrick does, as its input is closed
+        let holder = command
+            .args([
+                "--dev",
+                "/dev",
+                "--proc",
+                "/proc",
+                "--unshare-user",
+                "--unshare-pid",
+                "--die-with-parent",
+                "--new-session",
+                "--info-fd",
+                "1",
+                "--",
+                "cat",
+            ])
+            // Its first process is visible in the sandbox, environment included
+            .env_clear()
+            .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Could not start sandbox")?;
+        let mut namespaces = SandboxNamespaces { holder, pid: 0 };
+
+        // bubblewrap reports the pid of its child once the namespaces exist
+        let stdout = namespaces
+            .holder
+            .stdout
+            .take()
+            .context("Could not start sandbox")?;
+        let mut info = String::new();
+        for line in std::io::BufReader::new(stdout).lines() {
+            let line = line.context("Could not start sandbox")?;
+            info.push_str(&line);
+            if line.trim() == "}" {
+                break;
+            }
+        }
+        let info: SandboxInfo = serde_json::from_str(&info)
+            .with_context(|| format!("Could not start sandbox: {}", info))?;
+        namespaces.pid = info.child_pid;
+        Ok(Some(namespaces))
+    }
+
+    // Wraps the command so that it runs in the sandbox, with the workspace directory writable
+    // and `tmp` mounted at /tmp. With bubblewrap it joins the namespaces when they are given, and
+    // gets a pid namespace of its own otherwise. `default_seccomp` is where the program of
+    // `default_seccomp_program` was written.
+    pub(crate) fn wrap(
+        &self,
+        args: &[&str],
+        directory: &Path,
+        tmp: &Path,
+        default_seccomp: &Path,
+        working_dir: &Path,
+        namespaces: Option<&SandboxNamespaces>,
+    ) -> Vec<String> {
+        let read_only = self.read_only();
+        let directory = directory.to_string_lossy().to_string();
+        let tmp = tmp.to_string_lossy().to_string();
+        let working_dir = working_dir.to_string_lossy().to_string();
+        let seccomp = match &self.seccomp_profile {
+            Some(profile) => Some(profile.clone()),
+            None if self.default_seccomp() => Some(default_seccomp.to_string_lossy().to_string()),
+            None => None,
+        };
+
+        let mut wrapped = vec![self.tool.program().to_string()];
+        match self.tool {
+            SandboxTool::Bubblewrap => {
+                for path in read_only {
+                    let path = path.to_string_lossy().to_string();
+                    wrapped.extend(["--ro-bind".to_string(), path.clone(), path]);
+                }
+                wrapped.extend(
+                    [
+                        "--bind",
+                        directory.as_str(),
+                        directory.as_str(),
+                        "--bind",
+                        tmp.as_str(),
+                        "/tmp",
+                        "--dev",
+                        "/dev",
+                        "--proc",
+                        "/proc",
+                        "--die-with-parent",
+                        "--new-session",
+                        "--unshare-ipc",
+                        "--unshare-uts",
+                        "--unshare-cgroup-try",
+                        "--chdir",
+                        working_dir.as_str(),
+                    ]
+                    .map(String::from),
+                );
+                match namespaces {
+                    Some(_) => wrapped
+                        .extend(["--userns", USERNS_FD, "--pidns", PIDNS_FD].map(String::from)),
+                    None => {
+                        wrapped.extend(["--unshare-user-try", "--unshare-pid"].map(String::from))
+                    }
+                }
+                if !self.network {
+                    wrapped.push("--unshare-net".to_string());
+                }
+                if seccomp.is_some() {
+                    wrapped.extend(["--seccomp".to_string(), SECCOMP_FD.to_string()]);
+                }
+            }
+            SandboxTool::Nsjail => {
+                wrapped.extend(
+                    [
+                        "--mode",
+                        "o",
+                        "--quiet",
+                        "--keep_env",
+                        // nsjail limits time and resources by default, those are up to the
+                        // command limits of the workspace
+                        "--time_limit",
+                        "0",
+                        "--rlimit_as",
+                        "inf",
+                        "--rlimit_cpu",
+                        "inf",
+                        "--rlimit_fsize",
+                        "inf",
+                        "--rlimit_nofile",
+                        "max",
+                    ]
+                    .map(String::from),
+                );
+                for path in read_only {
+                    wrapped.extend(["-R".to_string(), path.to_string_lossy().to_string()]);
+                }
+                for device in DEVICES.iter().filter(|path| Path::new(path).exists()) {
+                    wrapped.extend(["-B".to_string(), device.to_string()]);
+                }
+                wrapped.extend(
+                    [
+                        "-B",
+                        directory.as_str(),
+                        "-B",
+                        format!("{}:/tmp", tmp).as_str(),
+                        "--cwd",
+                        working_dir.as_str(),
+                    ]
+                    .map(String::from),
+                );
+                if self.network {
+                    wrapped.push("--disable_clone_newnet".to_string());
+                }
+                if let Some(profile) = &self.seccomp_profile {
+                    wrapped.extend(["--seccomp_policy".to_string(), profile.clone()]);
+                } else if self.default_seccomp() {
+                    wrapped.extend(["--seccomp_string".to_string(), default_seccomp_policy()]);
+                }
+            }
+        }
+        wrapped.push("--".to_string());
+        wrapped.extend(args.iter().map(|arg| arg.to_string()));
+
+        // bubblewrap reads the seccomp program and the namespaces from file descriptors, which
+        // the shell opens
+        if self.tool != SandboxTool::Bubblewrap {
+            return wrapped;
+        }
+        let mut redirects = Vec::new();
+        if let Some(profile) = &seccomp {
+            redirects.push(format!(
+                "{}< {}",
+                SECCOMP_FD,
+                escape(profile.as_str().into())
+            ));
+        }
+        if let Some(namespaces) = namespaces {
+            redirects.push(format!(
+                "{}< /proc/{}/ns/user {}< /proc/{}/ns/pid",
+                USERNS_FD, namespaces.pid, PIDNS_FD, namespaces.pid
+            ));
+        }
+        if redirects.is_empty() {
+            return wrapped;
+        }
+        [
+            "sh".to_string(),
+            "-c".to_string(),
+            format!("exec \"$@\" {}", redirects.join(" ")),
+            "sh".to_string(),
+        ]
+        .into_iter()
+        .chain(wrapped)
+        .collect()
+    }
+}
+
+// The default seccomp profile as a kafel policy, for nsjail
+fn default_seccomp_policy() -> String {
+    let names = DENIED_SYSCALLS.iter().map(|(name, _, _)| *name);
+    format!(
+        "ERRNO(1) {{ {} }} DEFAULT ALLOW",
+        names.collect::<Vec<_>>().join(", ")
+    )
+}
+
+// The default seccomp profile as a BPF program, for bubblewrap. System calls of other
+// architectures than the one of the host are denied as well, as their numbers differ.
+fn compile_default_seccomp() -> Result<Vec<u8>> {
+    let Some(arch) = AUDIT_ARCH else {
+        anyhow::bail!(
+            "There is no default seccomp profile for this architecture, set seccomp_profile or \
+             disable_default_seccomp"
+        );
+    };
+    let instruction = |code: u16, jt: u8, jf: u8, k: u32| (code, jt, jf, k);
+    let deny = instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ERRNO_EPERM);
+
+    // Loads the architecture and then the number of the system call from `seccomp_data`
+    let mut program = vec![
+        instruction(BPF_LD_W_ABS, 0, 0, 4),
+        instruction(BPF_JMP_JEQ_K, 1, 0, arch),
+        deny,
+        instruction(BPF_LD_W_ABS, 0, 0, 0),
+    ];
+    if cfg!(target_arch = "x86_64") {
+        program.extend([instruction(BPF_JMP_JGE_K, 0, 1, X32_SYSCALL_BIT), deny]);
+    }
+    for (_, x86_64, aarch64) in DENIED_SYSCALLS {
+        let number = if cfg!(target_arch = "aarch64") {
+            *aarch64
+        } else {
+            *x86_64
+        };
+        program.extend([instruction(BPF_JMP_JEQ_K, 0, 1, number), deny]);
+    }
+    program.push(instruction(BPF_RET_K, 0, 0, SECCOMP_RET_ALLOW));
+
+    // Laid out as `struct sock_filter`
+    Ok(program
+        .into_iter()
+        .flat_map(|(code, jt, jf, k)| {
+            [&code.to_ne_bytes()[..], &[jt, jf][..], &k.to_ne_bytes()[..]].concat()
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct SandboxInfo {
+    #[serde(rename = "child-pid")]
+    child_pid: u32,
+}
+
+// Holds the namespaces of the sandbox of a workspace. Everything running in them is killed when
+// it is dropped.
+#[derive(Debug)]
+pub(crate) struct SandboxNamespaces {
+    holder: Child,
+    // A process in the namespaces, as the host sees it
+    pid: u32,
+}
+
+impl SandboxNamespaces {
+    pub(crate) fn is_running(&mut self) -> bool {
+        matches!(self.holder.try_wait(), Ok(None))
+    }
+}
+
+impl Drop for SandboxNamespaces {
+    fn drop(&mut self) {
+        // The first process of the pid namespace dies with bubblewrap, which ends the namespace
+        let _ = self.holder.kill();
+        let _ = self.holder.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap(sandbox: &Sandbox) -> Vec<String> {
+        sandbox.wrap(
+            &["sh", "-c", "pwd"],
+            Path::new("/work/ws"),
+            Path::new("/work/ws.tmp"),
+            Path::new("/work/ws.seccomp"),
+            Path::new("/work/ws/repo"),
+            None,
+        )
+    }
+
+    fn contains(args: &[String], expected: &[&str]) -> bool {
+        args.windows(expected.len())
+            .any(|window| window == expected)
+    }
+
+    #[test]
+    fn test_bubblewrap_without_network_by_default() {
+        let args = wrap(&Sandbox {
+            disable_default_seccomp: true,
+            ..Default::default()
+        });
+
+        assert_eq!(args[0], "bwrap");
+        assert!(contains(&args, &["--bind", "/work/ws", "/work/ws"]));
+        assert!(contains(&args, &["--bind", "/work/ws.tmp", "/tmp"]));
+        assert!(contains(&args, &["--chdir", "/work/ws/repo"]));
+        assert!(args.contains(&"--unshare-net".to_string()));
+        assert!(args.contains(&"--unshare-pid".to_string()));
+        assert!(args.contains(&"--die-with-parent".to_string()));
+        assert!(args.ends_with(&["--", "sh", "-c", "pwd"].map(String::from)));
+
+        let args = wrap(&Sandbox {
+            network: true,
+            ..Default::default()
+        });
+        assert!(!args.contains(&"--unshare-net".to_string()));
+    }
+
+    #[test]
+    fn test_only_parts_of_etc_are_visible() {
+        let args = wrap(&Sandbox::default());
+
+        assert!(!args.contains(&"/etc".to_string()));
+        if Path::new("/etc/passwd").exists() {
+            assert!(contains(
+                &args,
+                &["--ro-bind", "/etc/passwd", "/etc/passwd"]
+            ));
+        }
+        if Path::new("/etc/shadow").exists() {
+            assert!(!args.contains(&"/etc/shadow".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_default_seccomp_profile() {
+        let args = wrap(&Sandbox::default());
+        assert!(args[2].contains("3< /work/ws.seccomp"));
+        assert!(contains(&args, &["--seccomp", "3"]));
+
+        let args = wrap(&Sandbox {
+            tool: SandboxTool::Nsjail,
+            ..Default::default()
+        });
+        let policy = &args[args
+            .iter()
+            .position(|arg| arg == "--seccomp_string")
+            .unwrap()
+            + 1];
+        assert!(policy.starts_with("ERRNO(1) { mount, umount2,"));
+        assert!(policy.ends_with("} DEFAULT ALLOW"));
+
+        for tool in [SandboxTool::Bubblewrap, SandboxTool::Nsjail] {
+            let args = wrap(&Sandbox {
+                tool,
+                disable_default_seccomp: true,
+                ..Default::default()
+            });
+            assert!(!args.iter().any(|arg| arg.starts_with("--seccomp")));
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_default_seccomp_program() {
+        let program = Sandbox::default()
+            .default_seccomp_program()
+            .unwrap()
+            .unwrap();
+
+        // An architecture check, a check and a return per system call, and the final return
+        assert_eq!(program.len() % 8, 0);
+        assert!(program.len() / 8 >= 5 + 2 * DENIED_SYSCALLS.len());
+        let last = &program[program.len() - 8..];
+        assert_eq!(last[..2], BPF_RET_K.to_ne_bytes());
+        assert_eq!(last[4..], SECCOMP_RET_ALLOW.to_ne_bytes());
+
+        let nsjail = Sandbox {
+            tool: SandboxTool::Nsjail,
+            ..Default::default()
+        };
+        assert!(nsjail.default_seccomp_program().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_bubblewrap_reads_seccomp_program_from_fd() {
+        let args = wrap(&Sandbox {
+            seccomp_profile: Some("/etc/derrick/seccomp.bpf".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(args[..2], ["sh", "-c"]);
+        assert!(args[2].ends_with("3< /etc/derrick/seccomp.bpf"));
+        assert_eq!(args[4], "bwrap");
+        assert!(contains(&args, &["--seccomp", "3"]));
+    }
+
+    #[test]
+    fn test_bubblewrap_joins_namespaces() {
+        let namespaces = SandboxNamespaces {
+            holder: Command::new("true").spawn().unwrap(),
+            pid: 42,
+        };
+        let args = Sandbox::default().wrap(
+            &["pwd"],
+            Path::new("/work/ws"),
+            Path::new("/work/ws.tmp"),
+            Path::new("/work/ws.seccomp"),
+            Path::new("/work/ws"),
+            Some(&namespaces),
+        );
+
+        assert_eq!(args[..2], ["sh", "-c"]);
+        assert!(args[2].ends_with("4< /proc/42/ns/user 5< /proc/42/ns/pid"));
+        assert!(contains(&args, &["--userns", "4", "--pidns", "5"]));
+        assert!(!args.contains(&"--unshare-pid".to_string()));
+    }
+
+    #[test]
+    fn test_nsjail() {
+        let args = wrap(&Sandbox {
+            tool: SandboxTool::Nsjail,
+            seccomp_profile: Some("/etc/derrick/policy.kafel".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(args[0], "nsjail");
+        assert!(contains(&args, &["-B", "/work/ws.tmp:/tmp"]));
+        assert!(contains(&args, &["--cwd", "/work/ws/repo"]));
+        assert!(contains(
+            &args,
+            &["--seccomp_policy", "/etc/derrick/policy.kafel"]
+        ));
+        assert!(!args.contains(&"--disable_clone_newnet".to_string()));
+        assert!(!args.contains(&"--disable_clone_newpid".to_string()));
+    }
+}
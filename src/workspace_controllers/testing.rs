@@ -153,7 +153,10 @@ fn handle_command_result(result: std::process::Output) -> Result<CommandOutput>
         debug!(stdout = &stdout, stderr = &stderr, "Command succeeded");
         Ok(CommandOutput {
             output: stdout,
+            stdout: result.stdout,
+            stderr: result.stderr,
             exit_code: result.status.code().unwrap_or(0),
+            limit_exceeded: None,
         })
     } else {
         warn!(stdout = &stdout, stderr = &stderr, "Command failed");
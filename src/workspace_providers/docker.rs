@@ -1,23 +1,66 @@
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 use async_trait::async_trait;
 
-use anyhow::Result;
-use bollard::image::{CommitContainerOptions, CreateImageOptions};
+use anyhow::{Context, Result};
+use bollard::auth::DockerCredentials;
+use bollard::container::ListContainersOptions;
+use bollard::image::{CommitContainerOptions, CreateImageOptions, RemoveImageOptions};
 use bollard::Docker;
 use futures_util::TryStreamExt;
+use itertools::Itertools;
+use serde::Deserialize;
 
 use crate::{Repository, WorkspaceController};
 use tracing::debug;
 
-use crate::workspace_controllers::docker::BASE_IMAGE;
+use crate::credential_providers::{credential_provider, CredentialSource};
+use crate::dotfiles::install_dotfiles;
+use crate::egress_proxy::EgressProxy;
+use crate::network::NetworkPolicy;
+use crate::workspace_controllers::docker::{ContainerOptions, BASE_IMAGE, EGRESS_PROXY_IMAGE};
 use crate::workspace_controllers::DockerController;
 
-use super::{WorkspaceContext, WorkspaceProvider};
+use super::image_locks::ImageLocks;
+use super::image_scan::{ImageScan, ImageScanner};
+use super::{
+    run_post_create_script, run_verify_script, run_with_progress, ProgressReporter,
+    ProvisioningPhase, SetupStep, WorkspaceContext, WorkspaceProvider,
+};
+
+// Where the host mirror directory is mounted while cloning repositories
+static MIRROR_MOUNT: &str = "/var/cache/derrick-mirrors";
 
 pub struct DockerProvider {
     docker: Docker,
     base_image: String,
+    // Held while an image is built, so workspaces provisioned at the same time build it once
+    image_locks: ImageLocks,
+    registry_credentials: Vec<RegistryCredentials>,
+    // Replaces the scanner contexts with an image scan name
+    image_scanner: Option<Arc<dyn ImageScanner>>,
+    // Latest scan of every image that was scanned, images are not scanned again while cached.
+    // Not async, as it is read by `image_scans`.
+    image_scans: RwLock<HashMap<String, ImageScan>>,
+}
+
+// How to log in to a registry images are pulled from, like a private one with the base image
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RegistryCredentials {
+    // Host of the registry as it appears in image names, e.g. ghcr.io or registry.internal:5000
+    pub registry: String,
+    pub username: String,
+    // Where the password or token comes from
+    pub password: CredentialSource,
+}
+
+// The images a workspace is created from, the one with the repositories first and the one of the
+// last setup step last
+struct PreparedImages {
+    names: Vec<String>,
+    // Whether any of them was built rather than taken from the cache
+    built: bool,
 }
 
 // We want to be able to quickly provision a workspace. There are time consuming steps:
@@ -36,51 +79,132 @@ pub struct DockerProvider {
 impl DockerProvider {
     pub async fn initialize(base_image: Option<&str>) -> Result<DockerProvider> {
         let docker = crate::docker::establish_connection().await?;
+        Self::initialize_with(docker, base_image).await
+    }
 
+    // Provisions with the given docker daemon, like one on another host
+    pub async fn initialize_with(
+        docker: Docker,
+        base_image: Option<&str>,
+    ) -> Result<DockerProvider> {
         let base_image: &str = base_image.unwrap_or(BASE_IMAGE);
         Self::create_base_image(&docker, base_image)
             .await
             .expect("Could not create base image");
 
         let provider = DockerProvider {
+            image_locks: ImageLocks::new(&docker, base_image),
             docker,
             base_image: base_image.to_string(),
+            registry_credentials: Vec::new(),
+            image_scanner: None,
+            image_scans: RwLock::new(HashMap::new()),
         };
         Ok(provider)
     }
 
+    /// Logs in to these registries when pulling images from them. The base image is pulled
+    /// with them when it is not there yet the first time a workspace is provisioned.
+    pub fn with_registry_credentials(mut self, credentials: Vec<RegistryCredentials>) -> Self {
+        self.registry_credentials = credentials;
+        self
+    }
+
+    /// Scans images with this scanner instead of trivy or grype, for contexts with an image
+    /// scan.
+    pub fn with_image_scanner(mut self, scanner: Arc<dyn ImageScanner>) -> Self {
+        self.image_scanner = Some(scanner);
+        self
+    }
+
     pub async fn create_base_image(docker: &Docker, base_image: &str) -> Result<()> {
-        debug!("Creating container with image: {}", base_image);
+        pull_image(docker, base_image, None, &ProgressReporter::default()).await
+    }
 
-        docker
-            .create_image(
-                Some(CreateImageOptions {
-                    from_image: base_image,
+    async fn pull_image(&self, image: &str, progress: &ProgressReporter) -> Result<()> {
+        let registry = image_registry(image);
+        let credentials = match self
+            .registry_credentials
+            .iter()
+            .find(|credentials| credentials.registry == registry)
+        {
+            Some(credentials) => {
+                let password = credential_provider(&credentials.password)
+                    .credential()
+                    .await?;
+                Some(DockerCredentials {
+                    username: Some(credentials.username.clone()),
+                    password: Some(password.secret),
+                    serveraddress: Some(credentials.registry.clone()),
                     ..Default::default()
-                }),
-                None,
-                None,
-            )
-            .try_collect::<Vec<_>>()
-            .await?;
-        Ok(())
+                })
+            }
+            None => None,
+        };
+        pull_image(&self.docker, image, credentials, progress).await
     }
 
     pub async fn prepare_base_image_repositories(
         &self,
-        repositories: Vec<Repository>,
+        context: &WorkspaceContext,
+        progress: &ProgressReporter,
     ) -> Result<String> {
-        let repositories_hash = repositories_hash(&repositories);
-        let image_name = format!(
-            "{}-cache-{}",
-            self.base_image.replace("/", "-"),
-            repositories_hash
-        );
+        let repositories = context.repositories.clone();
+        let image_name = self.repositories_image(context);
+
+        if self.docker.inspect_image(&image_name).await.is_ok() {
+            tracing::info!(
+                "Base image with repositories already exists: {}",
+                image_name
+            );
+            return Ok(image_name);
+        }
 
-        if !self.docker.inspect_image(&image_name).await.is_ok() {
+        // The lock is created from the base image
+        if self.docker.inspect_image(&self.base_image).await.is_err() {
+            progress.phase(
+                ProvisioningPhase::PullingImage,
+                format!("Pulling {}", self.base_image),
+            );
+            self.pull_image(&self.base_image, progress).await?;
+        }
+        let lock = self.image_locks.lock(&image_name).await?;
+        // Another workspace may have built it while this one waited for the lock
+        if self.docker.inspect_image(&image_name).await.is_err() {
             tracing::info!("Creating base image with repositories: {}", image_name);
-            let controller =
-                DockerController::start(&self.docker, &self.base_image, &image_name).await?;
+
+            progress.phase(
+                ProvisioningPhase::CloningRepositories,
+                "Updating repository mirrors",
+            );
+            let controller = match context.update_mirrors().await? {
+                // Mounts aren't part of committed images, and the clones are dissociated from
+                // the mirrors, so the image does not depend on the mirrors afterwards
+                Some(mirrors) => {
+                    let mirror_root = mirrors.root().to_string_lossy();
+                    DockerController::start_with_mounts(
+                        &self.docker,
+                        &self.base_image,
+                        &image_name,
+                        vec![(mirror_root.as_ref(), MIRROR_MOUNT)],
+                    )
+                    .await?
+                    .with_mirror_dir(Some(MIRROR_MOUNT.to_string()))
+                }
+                None => {
+                    DockerController::start(&self.docker, &self.base_image, &image_name).await?
+                }
+            };
+            progress.phase(
+                ProvisioningPhase::CloningRepositories,
+                format!(
+                    "Cloning {}",
+                    repositories
+                        .iter()
+                        .map(|repo| repo.path.as_str())
+                        .join(", ")
+                ),
+            );
             controller.provision_repositories(repositories).await?;
 
             self.docker
@@ -95,67 +219,322 @@ impl DockerProvider {
                 .await?;
 
             controller.stop().await?;
-        } else {
-            tracing::info!(
-                "Base image with repositories already exists: {}",
-                image_name
-            );
         }
+        lock.release().await;
 
         Ok(image_name)
     }
 
+    fn repositories_image(&self, context: &WorkspaceContext) -> String {
+        format!(
+            "{}-cache-{}",
+            self.base_image.replace("/", "-"),
+            repositories_hash(&context.repositories)
+        )
+    }
+
+    // Every setup step is committed as its own image on top of the image of the previous step.
+    // The name of each image is derived from a hash chained over all steps up to and including it,
+    // so only the changed step and the ones after it need to run again. A user of the context is
+    // created in a step of its own before the others.
     pub async fn prepare_image(
         &self,
         context: &WorkspaceContext,
         env: HashMap<String, String>,
+        progress: &ProgressReporter,
     ) -> Result<String> {
-        let context_hash = context_hash(context, &env);
-        let image_name = format!(
-            "{}-{}-cache-{}",
-            context.name,
-            self.base_image.replace("/", "-"),
-            context_hash
-        );
+        let images = self.prepare_images(context, env, progress).await?;
+        Ok(images.names.last().cloned().unwrap_or_default())
+    }
 
-        if !self.docker.inspect_image(&image_name).await.is_ok() {
-            tracing::info!("Creating image with context: {}", image_name);
-            let base_image = self
-                .prepare_base_image_repositories(context.repositories.clone())
-                .await?;
+    async fn prepare_images(
+        &self,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<PreparedImages> {
+        let mut built = self
+            .docker
+            .inspect_image(&self.repositories_image(context))
+            .await
+            .is_err();
+        let mut image_name = self
+            .prepare_base_image_repositories(context, progress)
+            .await?;
+        let mut names = vec![image_name.clone()];
 
-            let controller =
-                DockerController::start(&self.docker, &base_image, &context.name).await?;
+        let user_step = context
+            .user
+            .as_ref()
+            .and_then(|user| {
+                let paths = context
+                    .repositories
+                    .iter()
+                    .map(|repository| repository.path.as_str())
+                    .collect::<Vec<_>>();
+                user.create_script(&paths)
+            })
+            .map(|script| SetupStep {
+                name: "user".to_string(),
+                script,
+                root: true,
+                ..Default::default()
+            });
 
-            controller
-                .write_file("/tmp/setup.sh", context.setup_script.as_bytes(), None)
-                .await?;
-            controller
-                .cmd_with_output("chmod +x /tmp/setup.sh", Some("/"), env.clone(), None)
-                .await?;
-            controller
-                .cmd_with_output("/tmp/setup.sh", Some("/"), env, None)
-                .await?;
+        // The files steps depend on are looked up in the mirrors, which only the base image
+        // updates when it is built
+        let file_ids = if context
+            .steps()
+            .iter()
+            .any(|step| !step.cache_files.is_empty())
+        {
+            context.update_mirrors().await?;
+            context.cache_file_ids().await?
+        } else {
+            HashMap::new()
+        };
 
-            self.docker
-                .commit_container(
-                    CommitContainerOptions {
-                        container: controller.container_id.clone(),
-                        repo: image_name.clone(),
+        for step in user_step.into_iter().chain(context.steps()) {
+            let step_image_name = format!(
+                "{}-{}-{}-cache-{}",
+                context.name,
+                self.base_image.replace("/", "-"),
+                sanitize_step_name(&step.name),
+                step_hash(&image_name, &step, &env, &file_ids)
+            );
 
-                        ..Default::default()
-                    },
-                    bollard::container::Config::<String>::default(),
+            if self.docker.inspect_image(&step_image_name).await.is_ok() {
+                tracing::info!("Image for setup step already exists: {}", step_image_name);
+            } else {
+                let lock = self.image_locks.lock(&step_image_name).await?;
+                if self.docker.inspect_image(&step_image_name).await.is_err() {
+                    tracing::info!("Creating image for setup step: {}", step_image_name);
+                    built = true;
+                    self.run_setup_step(
+                        context,
+                        &image_name,
+                        &step_image_name,
+                        &step,
+                        &env,
+                        progress,
+                    )
+                    .await?;
+                }
+                lock.release().await;
+            }
+
+            names.push(step_image_name.clone());
+            image_name = step_image_name;
+        }
+
+        self.scan_image(context, &image_name, progress).await?;
+        Ok(PreparedImages { names, built })
+    }
+
+    // Scans the image when the context asks for it and it was not scanned yet, and fails when
+    // it has findings above the threshold of the context
+    async fn scan_image(
+        &self,
+        context: &WorkspaceContext,
+        image: &str,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        let Some(config) = &context.image_scan else {
+            return Ok(());
+        };
+        let scanned = self.image_scans.read().unwrap().get(image).cloned();
+        let scan = match scanned {
+            Some(scan) => scan,
+            None => {
+                let scanner = match &self.image_scanner {
+                    Some(scanner) => Arc::clone(scanner),
+                    None => config.scanner.scanner(),
+                };
+                progress.phase(
+                    ProvisioningPhase::RunningSetup,
+                    format!("Scanning {} with {}", image, scanner.name()),
+                );
+                let scan = ImageScan {
+                    image: image.to_string(),
+                    scanner: scanner.name().to_string(),
+                    scanned_at: crate::accounting::now_ms(),
+                    vulnerabilities: scanner
+                        .scan(image)
+                        .await
+                        .with_context(|| format!("Could not scan image {}", image))?,
+                };
+                tracing::info!(
+                    image,
+                    vulnerabilities = scan.vulnerabilities.len(),
+                    "Scanned image"
+                );
+                self.image_scans
+                    .write()
+                    .unwrap()
+                    .insert(image.to_string(), scan.clone());
+                scan
+            }
+        };
+        scan.check(config)
+    }
+
+    // Untags the images, so that the next workspace builds them again. Workspaces running from
+    // them keep running.
+    async fn remove_images(&self, images: &[String]) {
+        for image in images.iter().rev() {
+            let options = RemoveImageOptions {
+                force: true,
+                ..Default::default()
+            };
+            if let Err(e) = self.docker.remove_image(image, Some(options), None).await {
+                tracing::warn!("Could not remove image {}: {:?}", image, e);
+            }
+        }
+    }
+
+    // Starts the container of a workspace from the image, with the egress proxy or squid
+    // container its network policy needs
+    async fn start_workspace(
+        &self,
+        context: &WorkspaceContext,
+        image_name: &str,
+        network: &NetworkPolicy,
+        progress: &ProgressReporter,
+    ) -> Result<DockerController> {
+        progress.phase(
+            ProvisioningPhase::StartingWorkspace,
+            format!("Starting container from {}", image_name),
+        );
+        // The proxy of derrick enforces the allowlist instead of the squid container
+        let controller = match &context.egress_proxy {
+            Some(config) => {
+                let proxy = EgressProxy::start(config, network.allowed_hosts(), true).await?;
+                DockerController::start_with_egress_proxy(
+                    &self.docker,
+                    image_name,
+                    &context.name,
+                    Arc::new(proxy),
+                    &container_options(context),
                 )
-                .await?;
+                .await?
+            }
+            None => {
+                DockerController::start_with_network(
+                    &self.docker,
+                    image_name,
+                    &context.name,
+                    network,
+                    &container_options(context),
+                )
+                .await?
+            }
+        };
+        Ok(controller
+            .with_shell(context.shell.clone())
+            .with_user(context.user.clone()))
+    }
 
-            controller.stop().await?;
+    async fn run_setup_step(
+        &self,
+        context: &WorkspaceContext,
+        from_image: &str,
+        image_name: &str,
+        step: &SetupStep,
+        env: &HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        let user = if step.root {
+            None
         } else {
-            tracing::info!("Image with context already exists: {}", image_name);
+            context.user.clone()
+        };
+        let controller = DockerController::start(&self.docker, from_image, &context.name)
+            .await?
+            .with_shell(context.shell.clone())
+            .with_user(user);
+
+        controller
+            .write_file_with_mode("/tmp/setup.sh", step.script.as_bytes(), None, Some(0o755))
+            .await?;
+        progress.phase(
+            ProvisioningPhase::RunningSetup,
+            format!("Running setup step {}", step.name),
+        );
+        let output = run_with_progress(
+            &controller,
+            "/tmp/setup.sh",
+            Some("/"),
+            env.clone(),
+            progress,
+        )
+        .await?;
+
+        if output.exit_code != 0 {
+            controller.stop().await?;
+            anyhow::bail!(
+                "Setup step {} failed with exit code {}: {}",
+                step.name,
+                output.exit_code,
+                output.output
+            );
         }
 
-        Ok(image_name)
+        self.docker
+            .commit_container(
+                CommitContainerOptions {
+                    container: controller.container_id.clone(),
+                    repo: image_name.to_string(),
+
+                    ..Default::default()
+                },
+                bollard::container::Config::<String>::default(),
+            )
+            .await?;
+
+        controller.stop().await
+    }
+}
+
+// Registry of an image, like Docker does it: the first part of the name when it looks like a host
+fn image_registry(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((host, _)) if host.contains(['.', ':']) || host == "localhost" => host,
+        _ => "docker.io",
+    }
+}
+
+// Pulls an image, reporting the combined download progress of its layers
+async fn pull_image(
+    docker: &Docker,
+    image: &str,
+    credentials: Option<DockerCredentials>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    debug!("Pulling image: {}", image);
+
+    let mut layers: HashMap<String, (i64, i64)> = HashMap::new();
+    let mut stream = docker.create_image(
+        Some(CreateImageOptions {
+            from_image: image,
+            ..Default::default()
+        }),
+        None,
+        credentials,
+    );
+
+    while let Some(info) = stream.try_next().await? {
+        let (Some(id), Some(detail)) = (info.id, info.progress_detail) else {
+            continue;
+        };
+        if let (Some(current), Some(total)) = (detail.current, detail.total) {
+            layers.insert(id, (current, total));
+            let (current, total) = layers
+                .values()
+                .fold((0, 0), |(c, t), (current, total)| (c + current, t + total));
+            progress.progress(current as u64, total as u64);
+        }
     }
+    Ok(())
 }
 
 fn repositories_hash(repositories: &Vec<Repository>) -> String {
@@ -167,25 +546,36 @@ fn repositories_hash(repositories: &Vec<Repository>) -> String {
         if let Some(reference) = repo.reference.clone() {
             hasher.update(reference.as_str());
         }
+        hasher.update([repo.lfs as u8, repo.submodules as u8]);
+        repo.sparse_paths
+            .iter()
+            .for_each(|path| hasher.update(path.as_str()));
     });
     let mut result = hex::encode(hasher.finalize());
     result.truncate(16);
     result
 }
 
-fn context_hash(context: &WorkspaceContext, env: &HashMap<String, String>) -> String {
+fn step_hash(
+    from_image: &str,
+    step: &SetupStep,
+    env: &HashMap<String, String>,
+    file_ids: &HashMap<String, String>,
+) -> String {
     use sha2::{Digest, Sha256};
     let mut hasher = Sha256::new();
-    hasher.update(context.name.as_str());
-    context.repositories.iter().for_each(|repo| {
-        hasher.update(repo.url.as_str());
-        hasher.update(repo.path.as_str());
-        if let Some(reference) = repo.reference.clone() {
-            hasher.update(reference.as_str());
-        }
-    });
-    hasher.update(context.setup_script.as_str());
-    env.iter().for_each(|(key, value)| {
+    hasher.update(from_image);
+    hasher.update(step.name.as_str());
+    hasher.update(step.script.as_str());
+    // Only hashed when set, so that the images of existing steps stay valid
+    if step.root {
+        hasher.update("root");
+    }
+    for file in &step.cache_files {
+        hasher.update(file.as_str());
+        hasher.update(file_ids.get(file).map(String::as_str).unwrap_or_default());
+    }
+    env.iter().sorted().for_each(|(key, value)| {
         hasher.update(key.as_str());
         hasher.update(value.as_str());
     });
@@ -194,15 +584,287 @@ fn context_hash(context: &WorkspaceContext, env: &HashMap<String, String>) -> St
     result
 }
 
+// Docker image names only allow lowercase alphanumerics and a few separators
+fn container_options(context: &WorkspaceContext) -> ContainerOptions {
+    ContainerOptions {
+        healthcheck: context.healthcheck.clone(),
+        shm_size: context.shm_size,
+        tmpfs: context.tmpfs.clone(),
+        mounts: context.mounts.clone(),
+    }
+}
+
+fn sanitize_step_name(name: &str) -> String {
+    name.to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
 #[async_trait]
 impl WorkspaceProvider for DockerProvider {
     async fn provision(
-        &mut self,
+        &self,
         context: &WorkspaceContext,
         env: HashMap<String, String>,
+        progress: &ProgressReporter,
     ) -> Result<Box<dyn WorkspaceController>> {
-        let image_name = self.prepare_image(context, env).await?;
-        let controller = DockerController::start(&self.docker, &image_name, &context.name).await?;
+        let network = context.network.clone().unwrap_or_default();
+        network.validate()?;
+        if let Some(user) = &context.user {
+            user.validate()?;
+        }
+        container_options(context).validate()?;
+        if context.egress_proxy.is_some() && network == NetworkPolicy::Disabled {
+            anyhow::bail!("The egress proxy cannot be used without network access");
+        }
+        let mut images = self.prepare_images(context, env.clone(), progress).await?;
+        if matches!(network, NetworkPolicy::Allowlist { .. })
+            && context.egress_proxy.is_none()
+            && self.docker.inspect_image(EGRESS_PROXY_IMAGE).await.is_err()
+        {
+            progress.phase(
+                ProvisioningPhase::PullingImage,
+                format!("Pulling {}", EGRESS_PROXY_IMAGE),
+            );
+            self.pull_image(EGRESS_PROXY_IMAGE, progress).await?;
+        }
+        let image_name = images.names.last().cloned().unwrap_or_default();
+        let mut controller = self
+            .start_workspace(context, &image_name, &network, progress)
+            .await?;
+
+        if let Err(e) = run_verify_script(&controller, context, env.clone(), progress).await {
+            controller.stop().await?;
+            // Images that were just built fail the same way when built again
+            if images.built {
+                return Err(e);
+            }
+            tracing::warn!("Rebuilding the cached images of {}: {:?}", image_name, e);
+            self.remove_images(&images.names).await;
+            images = self.prepare_images(context, env.clone(), progress).await?;
+            let image_name = images.names.last().cloned().unwrap_or_default();
+            controller = self
+                .start_workspace(context, &image_name, &network, progress)
+                .await?;
+            if let Err(e) = run_verify_script(&controller, context, env.clone(), progress).await {
+                controller.stop().await?;
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = run_post_create_script(&controller, context, env, progress).await {
+            controller.stop().await?;
+            return Err(e);
+        }
+
+        if let Some(url) = &context.dotfiles_repo {
+            progress.phase(ProvisioningPhase::StartingWorkspace, "Installing dotfiles");
+            if let Err(e) = install_dotfiles(&controller, url).await {
+                tracing::warn!("Could not install dotfiles: {:?}", e);
+                progress.output(&format!("{:#}", e));
+            }
+        }
+
+        Ok(Box::new(controller))
+    }
+
+    // Builds the images of the setup steps, and pulls the image of the squid proxy when the
+    // network policy needs it
+    async fn prepare(
+        &self,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        let image_name = self.prepare_image(context, env, progress).await?;
+        tracing::info!("Prepared image {}", image_name);
+        let network = context.network.clone().unwrap_or_default();
+        if matches!(network, NetworkPolicy::Allowlist { .. })
+            && context.egress_proxy.is_none()
+            && self.docker.inspect_image(EGRESS_PROXY_IMAGE).await.is_err()
+        {
+            self.pull_image(EGRESS_PROXY_IMAGE, progress).await?;
+        }
+        Ok(())
+    }
+
+    fn image_scans(&self) -> Vec<ImageScan> {
+        let mut scans = self
+            .image_scans
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        scans.sort_by(|a, b| a.image.cmp(&b.image));
+        scans
+    }
+
+    // Takes a container id or name, or a `key=value` label that a single running container has
+    async fn adopt(&self, target: &str) -> Result<Box<dyn WorkspaceController>> {
+        let container = if target.contains('=') {
+            find_container_by_label(&self.docker, target).await?
+        } else {
+            target.to_string()
+        };
+        let controller = DockerController::adopt(&self.docker, &container).await?;
         Ok(Box::new(controller))
     }
 }
+
+async fn find_container_by_label(docker: &Docker, label: &str) -> Result<String> {
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            filters: HashMap::from([("label".to_string(), vec![label.to_string()])]),
+            ..Default::default()
+        }))
+        .await?;
+    match containers.as_slice() {
+        [container] => container
+            .id
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Container with label {} has no id", label)),
+        [] => anyhow::bail!("No running container has the label {}", label),
+        _ => anyhow::bail!(
+            "{} running containers have the label {}, expected one",
+            containers.len(),
+            label
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_controllers::docker::WorkspaceUser;
+
+    fn step(name: &str, script: &str) -> SetupStep {
+        SetupStep {
+            name: name.to_string(),
+            script: script.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_user_is_created_before_setup_steps() {
+        let user = WorkspaceUser {
+            name: Some("agent".to_string()),
+            uid: Some(1000),
+            gid: Some(1000),
+            create: true,
+        };
+        assert!(user.validate().is_ok());
+
+        let script = user.create_script(&["/code"]).unwrap();
+        assert!(script.contains("useradd -m -u 1000 -g 1000 agent"));
+        assert!(script.contains("groupadd -g 1000 agent"));
+        assert!(script.ends_with("chown -R \"$(id -u agent):$(id -g agent)\" /code\n"));
+
+        let existing = WorkspaceUser {
+            create: false,
+            ..user.clone()
+        };
+        assert!(existing.create_script(&["/code"]).is_none());
+
+        let invalid = WorkspaceUser {
+            name: Some("agent; rm -rf /".to_string()),
+            ..user
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_root_steps_get_their_own_image() {
+        let env = HashMap::new();
+        let files = HashMap::new();
+        let install = step("install", "apt-get install -y jq");
+        let as_root = SetupStep {
+            root: true,
+            ..install.clone()
+        };
+        assert_ne!(
+            step_hash("base", &install, &env, &files),
+            step_hash("base", &as_root, &env, &files)
+        );
+    }
+
+    #[test]
+    fn test_step_hash_depends_on_previous_image_and_step() {
+        let env = HashMap::new();
+        let files = HashMap::new();
+        let toolchain = step("toolchain", "apt-get install -y build-essential");
+
+        let hash = step_hash("base", &toolchain, &env, &files);
+        assert_eq!(hash, step_hash("base", &toolchain, &env, &files));
+        assert_ne!(hash, step_hash("other-base", &toolchain, &env, &files));
+        assert_ne!(
+            hash,
+            step_hash(
+                "base",
+                &step("toolchain", "apt-get install -y clang"),
+                &env,
+                &files
+            )
+        );
+    }
+
+    #[test]
+    fn test_step_hash_depends_on_cache_files() {
+        let env = HashMap::new();
+        let build = SetupStep {
+            cache_files: vec!["/code/Cargo.lock".to_string()],
+            ..step("build", "cargo build")
+        };
+        let files =
+            |entry: &str| HashMap::from([("/code/Cargo.lock".to_string(), entry.to_string())]);
+
+        let hash = step_hash("base", &build, &env, &files("100644 blob 1a2b\tCargo.lock"));
+        assert_ne!(
+            hash,
+            step_hash("base", &build, &env, &files("100644 blob 3c4d\tCargo.lock"))
+        );
+        // Steps without cache files keep the hash they had before
+        assert_eq!(
+            step_hash("base", &step("build", "cargo build"), &env, &HashMap::new()),
+            step_hash("base", &step("build", "cargo build"), &env, &files(""))
+        );
+    }
+
+    #[test]
+    fn test_step_hash_is_independent_of_env_order() {
+        let step = step("seed", "./seed.sh");
+        let files = HashMap::new();
+        let env_a: HashMap<String, String> = [("A", "1"), ("B", "2")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let env_b: HashMap<String, String> = [("B", "2"), ("A", "1")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+
+        assert_eq!(
+            step_hash("base", &step, &env_a, &files),
+            step_hash("base", &step, &env_b, &files)
+        );
+    }
+
+    #[test]
+    fn test_image_registry() {
+        assert_eq!(image_registry("ubuntu:24.04"), "docker.io");
+        assert_eq!(image_registry("bosunai/derrick"), "docker.io");
+        assert_eq!(image_registry("ghcr.io/bosun-ai/derrick:latest"), "ghcr.io");
+        assert_eq!(
+            image_registry("registry.internal:5000/base"),
+            "registry.internal:5000"
+        );
+        assert_eq!(image_registry("localhost/base"), "localhost");
+    }
+
+    #[test]
+    fn test_sanitize_step_name() {
+        assert_eq!(sanitize_step_name("Seed Test_Data"), "seed-test-data");
+    }
+}
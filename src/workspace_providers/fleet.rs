@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use serde::Deserialize;
+
+use crate::client::Client;
+use crate::egress_proxy::EgressRequest;
+use crate::workspace_controllers::{
+    CommandLimits, CommandOutput, FileEdit, FileWrite, Health, OutputLineCallback,
+    ResourceCounters, ShellSession,
+};
+use crate::workspace_providers::docker::DockerProvider;
+use crate::workspace_providers::{
+    ImageScan, ProgressReporter, ProvisioningPhase, RemoteHttpProvider, WorkspaceContext,
+    WorkspaceProvider,
+};
+use crate::WorkspaceController;
+
+// A host of the fleet, either another derrick or a docker daemon, like
+// `{"name": "big-1", "url": "http://big-1:50080", "token": "...", "capacity": 20}` or
+// `{"name": "docker-2", "docker_host": "tcp://10.0.0.2:2375", "capacity": 8}`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct FleetBackend {
+    pub name: String,
+    // Url of the derrick server
+    #[serde(default)]
+    pub url: Option<String>,
+    // Token for the derrick server, when it requires one
+    #[serde(default)]
+    pub token: Option<String>,
+    // Docker daemon that workspaces are provisioned with directly
+    #[serde(default)]
+    pub docker_host: Option<String>,
+    // Workspaces the backend runs at most
+    pub capacity: usize,
+}
+
+impl FleetBackend {
+    fn validate(&self) -> Result<()> {
+        if self.url.is_some() == self.docker_host.is_some() {
+            anyhow::bail!("Backend {} needs either a url or a docker_host", self.name);
+        }
+        if self.capacity == 0 {
+            anyhow::bail!("Backend {} has no capacity", self.name);
+        }
+        Ok(())
+    }
+
+    async fn provider(&self) -> Result<Box<dyn WorkspaceProvider>> {
+        if let Some(url) = &self.url {
+            let client = match &self.token {
+                Some(token) => Client::new(url).with_token(token)?,
+                None => Client::new(url),
+            };
+            return Ok(Box::new(RemoteHttpProvider::new(client)));
+        }
+        let host = self.docker_host.as_deref().unwrap_or_default();
+        let docker = crate::docker::connect_to(host)
+            .with_context(|| format!("Could not connect to docker at {}", host))?;
+        Ok(Box::new(
+            DockerProvider::initialize_with(docker, None).await?,
+        ))
+    }
+}
+
+struct Backend {
+    name: String,
+    capacity: usize,
+    provider: Box<dyn WorkspaceProvider>,
+    // Workspaces provisioned on the backend, and being provisioned, that were not dropped yet
+    active: Arc<AtomicUsize>,
+}
+
+// Places new workspaces on the backend with the most room left, relative to its capacity. A
+// workspace stays on the backend it was placed on, and counts towards its load until it is
+// destroyed.
+pub struct FleetProvider {
+    backends: Vec<Backend>,
+}
+
+impl FleetProvider {
+    pub async fn new(backends: Vec<FleetBackend>) -> Result<Self> {
+        if backends.is_empty() {
+            anyhow::bail!("The fleet has no backends");
+        }
+        let mut connected = Vec::new();
+        for backend in backends {
+            backend.validate()?;
+            connected.push(Backend {
+                provider: backend
+                    .provider()
+                    .await
+                    .with_context(|| format!("Could not set up backend {}", backend.name))?,
+                name: backend.name,
+                capacity: backend.capacity,
+                active: Arc::default(),
+            });
+        }
+        Ok(Self {
+            backends: connected,
+        })
+    }
+
+    // Reads a JSON list of backends
+    pub async fn from_file(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Could not open {}", path))?;
+        let backends: Vec<FleetBackend> = serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Invalid fleet in {}", path))?;
+        Self::new(backends).await
+    }
+
+    // The backends in DERRICK_FLEET
+    pub async fn from_env() -> Result<Self> {
+        let path = std::env::var("DERRICK_FLEET")
+            .context("DERRICK_FLEET is required for the fleet provisioning mode")?;
+        Self::from_file(&path).await
+    }
+
+    // Reserves room on the least loaded backend, None when every backend is full
+    fn place(&self) -> Option<(&Backend, Placement)> {
+        let loads = self
+            .backends
+            .iter()
+            .map(|backend| (backend.active.load(Ordering::SeqCst), backend.capacity))
+            .collect::<Vec<_>>();
+        let backend = &self.backends[least_loaded(&loads)?];
+        let placement = Placement::reserve(&backend.active, backend.capacity)?;
+        Some((backend, placement))
+    }
+}
+
+// Index of the backend with the lowest share of its capacity in use, the first one on a tie
+fn least_loaded(loads: &[(usize, usize)]) -> Option<usize> {
+    loads
+        .iter()
+        .enumerate()
+        .filter(|(_, (active, capacity))| active < capacity)
+        .min_by(|(_, (a, a_capacity)), (_, (b, b_capacity))| {
+            (a * b_capacity).cmp(&(b * a_capacity))
+        })
+        .map(|(index, _)| index)
+}
+
+// Room taken on a backend, given back when it is dropped
+#[derive(Debug)]
+struct Placement(Arc<AtomicUsize>);
+
+impl Placement {
+    // Another workspace may have taken the last room since the loads were read
+    fn reserve(active: &Arc<AtomicUsize>, capacity: usize) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < capacity).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| Placement(Arc::clone(active)))
+    }
+
+    // Forks of a workspace run on its backend, even when it is full
+    fn another(&self) -> Self {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Placement(Arc::clone(&self.0))
+    }
+}
+
+impl Drop for Placement {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl WorkspaceProvider for FleetProvider {
+    #[tracing::instrument(skip_all)]
+    async fn provision(
+        &self,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<Box<dyn WorkspaceController>> {
+        let (backend, placement) = self
+            .place()
+            .context("Every backend of the fleet is at its capacity")?;
+        tracing::info!(backend = backend.name, "Placing workspace");
+        progress.phase(
+            ProvisioningPhase::StartingWorkspace,
+            format!("Placed on backend {}", backend.name),
+        );
+        // The room is given back when provisioning fails
+        let controller = backend.provider.provision(context, env, progress).await?;
+        Ok(Box::new(FleetController {
+            inner: controller,
+            backend: backend.name.clone(),
+            placement,
+        }))
+    }
+
+    async fn prepare(
+        &self,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        for backend in &self.backends {
+            backend
+                .provider
+                .prepare(context, env.clone(), progress)
+                .await
+                .with_context(|| format!("Could not prepare backend {}", backend.name))?;
+        }
+        Ok(())
+    }
+
+    fn image_scans(&self) -> Vec<ImageScan> {
+        self.backends
+            .iter()
+            .flat_map(|backend| backend.provider.image_scans())
+            .collect()
+    }
+}
+
+// A workspace on one of the backends, which holds its room there
+#[derive(Debug)]
+struct FleetController {
+    inner: Box<dyn WorkspaceController>,
+    backend: String,
+    placement: Placement,
+}
+
+#[async_trait]
+impl WorkspaceController for FleetController {
+    async fn init(&self) -> Result<()> {
+        self.inner.init().await
+    }
+
+    async fn stop(&self) -> Result<()> {
+        tracing::debug!(backend = self.backend, "Stopping workspace");
+        self.inner.stop().await
+    }
+
+    async fn provision_repositories(
+        &self,
+        repositories: Vec<crate::repository::Repository>,
+    ) -> Result<()> {
+        self.inner.provision_repositories(repositories).await
+    }
+
+    async fn cmd(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        self.inner.cmd(cmd, working_dir, env, timeout).await
+    }
+
+    async fn cmd_with_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_output(cmd, working_dir, env, timeout)
+            .await
+    }
+
+    async fn cmd_with_limits(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        timeout: Option<Duration>,
+        limits: &CommandLimits,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_limits(cmd, working_dir, env, timeout, limits)
+            .await
+    }
+
+    async fn cmd_with_streamed_output(
+        &self,
+        cmd: &str,
+        working_dir: Option<&str>,
+        env: HashMap<String, String>,
+        on_line: &OutputLineCallback,
+    ) -> Result<CommandOutput> {
+        self.inner
+            .cmd_with_streamed_output(cmd, working_dir, env, on_line)
+            .await
+    }
+
+    async fn write_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.write_file(path, content, working_dir).await
+    }
+
+    async fn read_file(&self, path: &str, working_dir: Option<&str>) -> Result<Vec<u8>> {
+        self.inner.read_file(path, working_dir).await
+    }
+
+    async fn write_file_with_mode(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+        mode: Option<u32>,
+    ) -> Result<()> {
+        self.inner
+            .write_file_with_mode(path, content, working_dir, mode)
+            .await
+    }
+
+    async fn write_files(&self, files: &[FileWrite], working_dir: Option<&str>) -> Result<()> {
+        self.inner.write_files(files, working_dir).await
+    }
+
+    async fn symlink(&self, path: &str, target: &str, working_dir: Option<&str>) -> Result<()> {
+        self.inner.symlink(path, target, working_dir).await
+    }
+
+    async fn append_file(
+        &self,
+        path: &str,
+        content: &[u8],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.append_file(path, content, working_dir).await
+    }
+
+    async fn edit_file(
+        &self,
+        path: &str,
+        edits: &[FileEdit],
+        working_dir: Option<&str>,
+    ) -> Result<()> {
+        self.inner.edit_file(path, edits, working_dir).await
+    }
+
+    async fn disk_usage(&self) -> Result<u64> {
+        self.inner.disk_usage().await
+    }
+
+    async fn resource_counters(&self) -> Result<Option<ResourceCounters>> {
+        self.inner.resource_counters().await
+    }
+
+    async fn shell(&self, cols: u16, rows: u16) -> Result<ShellSession> {
+        self.inner.shell(cols, rows).await
+    }
+
+    async fn attach(&self, cmd: &str, working_dir: Option<&str>) -> Result<ShellSession> {
+        self.inner.attach(cmd, working_dir).await
+    }
+
+    async fn fork(&self) -> Result<Box<dyn WorkspaceController>> {
+        let placement = self.placement.another();
+        Ok(Box::new(FleetController {
+            inner: self.inner.fork().await?,
+            backend: self.backend.clone(),
+            placement,
+        }))
+    }
+
+    async fn health(&self) -> Result<Option<Health>> {
+        self.inner.health().await
+    }
+
+    async fn is_running(&self) -> Result<bool> {
+        self.inner.is_running().await
+    }
+
+    async fn restart(&self) -> Result<()> {
+        self.inner.restart().await
+    }
+
+    fn logs(
+        &self,
+        follow: bool,
+        tail: Option<usize>,
+    ) -> Result<BoxStream<'static, Result<Vec<u8>>>> {
+        self.inner.logs(follow, tail)
+    }
+
+    async fn network_address(&self, port: u16) -> Result<String> {
+        self.inner.network_address(port).await
+    }
+
+    fn egress_requests(&self) -> Result<Vec<EgressRequest>> {
+        self.inner.egress_requests()
+    }
+
+    fn outputs_dir(&self) -> String {
+        self.inner.outputs_dir()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_loaded() {
+        // A third of 3 in use leaves more room than half of 10
+        assert_eq!(least_loaded(&[(5, 10), (1, 3)]), Some(1));
+        assert_eq!(least_loaded(&[(2, 4), (1, 2)]), Some(0));
+        assert_eq!(least_loaded(&[(3, 3), (2, 2)]), None);
+        assert_eq!(least_loaded(&[]), None);
+    }
+
+    #[test]
+    fn test_placement() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let first = Placement::reserve(&active, 2).unwrap();
+        let second = Placement::reserve(&active, 2).unwrap();
+        assert!(Placement::reserve(&active, 2).is_none());
+
+        let fork = second.another();
+        assert_eq!(active.load(Ordering::SeqCst), 3);
+        drop(first);
+        drop(fork);
+        assert_eq!(active.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_validate_backend() {
+        let backend: FleetBackend = serde_json::from_value(serde_json::json!({
+            "name": "big-1",
+            "url": "http://big-1:50080",
+            "capacity": 20
+        }))
+        .unwrap();
+        assert!(backend.validate().is_ok());
+        assert!(FleetBackend {
+            docker_host: Some("tcp://10.0.0.2:2375".to_string()),
+            ..backend.clone()
+        }
+        .validate()
+        .is_err());
+        assert!(FleetBackend {
+            capacity: 0,
+            ..backend
+        }
+        .validate()
+        .is_err());
+    }
+}
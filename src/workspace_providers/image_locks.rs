@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use bollard::container::{
+    Config, CreateContainerOptions, InspectContainerOptions, RemoveContainerOptions,
+};
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::workspace_controllers::cleanup;
+
+// Label of the containers that hold the lock of an image, with the name of the image
+const LOCK_LABEL: &str = "derrick.image-lock";
+// How often a process waiting for an image checks whether it was built
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+// A lock this old is taken to be left behind by a process that died while building the image
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+// Makes sure an image is built once, when several workspaces of a context are provisioned at
+// the same time. Within the server an async lock per image is enough; across servers using the
+// same docker daemon, the lock is a container named after the image, as creating a container with
+// a name that is taken fails. The container is only created, never started.
+pub(crate) struct ImageLocks {
+    docker: Docker,
+    // Image the lock containers are created from, it has to exist locally
+    lock_image: String,
+    locks: std::sync::Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+// Released when dropped, but `release` waits until the other processes can take the lock
+pub(crate) struct ImageLock {
+    _guard: OwnedMutexGuard<()>,
+    docker: Docker,
+    container: Option<String>,
+}
+
+impl ImageLocks {
+    pub(crate) fn new(docker: &Docker, lock_image: &str) -> Self {
+        Self {
+            docker: docker.clone(),
+            lock_image: lock_image.to_string(),
+            locks: Default::default(),
+        }
+    }
+
+    // Waits until nothing else builds the image. The image may have been built in the meantime,
+    // so callers check again whether it exists once they hold the lock.
+    pub(crate) async fn lock(&self, image: &str) -> Result<ImageLock> {
+        let lock = Arc::clone(
+            self.locks
+                .lock()
+                .unwrap()
+                .entry(image.to_string())
+                .or_default(),
+        );
+        let guard = lock.lock_owned().await;
+
+        let name = lock_container_name(image);
+        let mut waiting = false;
+        loop {
+            let created = self
+                .docker
+                .create_container(
+                    Some(CreateContainerOptions {
+                        name: name.as_str(),
+                        platform: None,
+                    }),
+                    Config {
+                        image: Some(self.lock_image.as_str()),
+                        labels: Some(HashMap::from([(LOCK_LABEL, image)])),
+                        ..Default::default()
+                    },
+                )
+                .await;
+            match created {
+                Ok(response) => {
+                    return Ok(ImageLock {
+                        _guard: guard,
+                        docker: self.docker.clone(),
+                        container: Some(response.id),
+                    })
+                }
+                Err(bollard::errors::Error::DockerResponseServerError {
+                    status_code: 409, ..
+                }) => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            // Another process holds the lock
+            if self.docker.inspect_image(image).await.is_ok() {
+                return Ok(ImageLock {
+                    _guard: guard,
+                    docker: self.docker.clone(),
+                    container: None,
+                });
+            }
+            let holder_created = self
+                .docker
+                .inspect_container(&name, None::<InspectContainerOptions>)
+                .await
+                .ok()
+                .and_then(|container| container.created);
+            if holder_created.is_some_and(|created| is_stale(&created, Utc::now())) {
+                tracing::warn!("Taking over the stale lock of image {}", image);
+                remove_container(&self.docker, &name).await;
+                continue;
+            }
+            if !waiting {
+                tracing::info!("Waiting for another server to build image {}", image);
+                waiting = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl ImageLock {
+    pub(crate) async fn release(mut self) {
+        if let Some(container) = self.container.take() {
+            remove_container(&self.docker, &container).await;
+        }
+    }
+}
+
+impl Drop for ImageLock {
+    // When provisioning fails or is cancelled while building the image
+    fn drop(&mut self) {
+        if let Some(container) = self.container.take() {
+            let docker = self.docker.clone();
+            cleanup::spawn(async move { remove_container(&docker, &container).await });
+        }
+    }
+}
+
+async fn remove_container(docker: &Docker, container: &str) {
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    if let Err(e) = docker.remove_container(container, Some(options)).await {
+        tracing::warn!("Could not remove image lock {}: {:?}", container, e);
+    }
+}
+
+// Container names only allow some characters, and image names can be long
+fn lock_container_name(image: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hash = hex::encode(Sha256::digest(image.as_bytes()));
+    hash.truncate(16);
+    format!("derrick-image-lock-{}", hash)
+}
+
+fn is_stale(created: &str, now: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(created).is_ok_and(|created| {
+        now.signed_duration_since(created)
+            .to_std()
+            .is_ok_and(|age| age > LOCK_TIMEOUT)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_container_name() {
+        let name = lock_container_name("ubuntu:24.04-cache-0123456789abcdef");
+        assert_eq!(name.len(), "derrick-image-lock-".len() + 16);
+        assert_ne!(
+            name,
+            lock_container_name("ubuntu:24.04-cache-fedcba9876543210")
+        );
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let now = DateTime::parse_from_rfc3339("2024-05-01T12:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert!(!is_stale("2024-05-01T11:30:00.123456789Z", now));
+        assert!(is_stale("2024-05-01T09:59:59Z", now));
+        assert!(!is_stale("not a date", now));
+    }
+}
@@ -0,0 +1,376 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+// Findings named in the error when a scan fails provisioning
+const MAX_REPORTED_FINDINGS: usize = 5;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum VulnerabilitySeverity {
+    Unknown,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl VulnerabilitySeverity {
+    // Severities as trivy and grype report them, like `HIGH` or `Negligible`
+    fn parse(severity: &str) -> Self {
+        match severity.to_ascii_lowercase().as_str() {
+            "critical" => Self::Critical,
+            "high" => Self::High,
+            "medium" => Self::Medium,
+            "low" | "negligible" => Self::Low,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Vulnerability {
+    // Like `CVE-2024-3094`
+    pub id: String,
+    pub package: String,
+    pub installed_version: String,
+    // Not set when there is no fix yet
+    pub fixed_version: Option<String>,
+    pub severity: VulnerabilitySeverity,
+    pub title: Option<String>,
+}
+
+// What a scanner found in an image
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct ImageScan {
+    pub image: String,
+    pub scanner: String,
+    // Unix time in milliseconds
+    pub scanned_at: u64,
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+impl ImageScan {
+    // The findings at or above the severity, leaving out the ones without a fix when asked to
+    pub fn findings(
+        &self,
+        severity: VulnerabilitySeverity,
+        ignore_unfixed: bool,
+    ) -> impl Iterator<Item = &Vulnerability> {
+        self.vulnerabilities.iter().filter(move |vulnerability| {
+            vulnerability.severity >= severity
+                && !(ignore_unfixed && vulnerability.fixed_version.is_none())
+        })
+    }
+
+    // Fails when the config has a threshold and the image has findings at or above it
+    pub(crate) fn check(&self, config: &ImageScanConfig) -> Result<()> {
+        let Some(threshold) = config.fail_on else {
+            return Ok(());
+        };
+        let findings = self
+            .findings(threshold, config.ignore_unfixed)
+            .collect::<Vec<_>>();
+        if findings.is_empty() {
+            return Ok(());
+        }
+        let ids = findings
+            .iter()
+            .take(MAX_REPORTED_FINDINGS)
+            .map(|finding| format!("{} ({})", finding.id, finding.package))
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow::bail!(
+            "Image {} has {} vulnerabilities of severity {:?} or higher: {}{}",
+            self.image,
+            findings.len(),
+            threshold,
+            ids,
+            if findings.len() > MAX_REPORTED_FINDINGS {
+                ", ..."
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+// Scans the images of a context once they are prepared, like
+// `{"scanner": "trivy", "fail_on": "high", "ignore_unfixed": true}`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
+pub struct ImageScanConfig {
+    #[serde(default)]
+    pub scanner: ScannerKind,
+    // Fails provisioning when the image has a finding this severe or worse. Without it findings
+    // are only recorded.
+    #[serde(default)]
+    pub fail_on: Option<VulnerabilitySeverity>,
+    // Findings without a fixed version do not fail provisioning
+    #[serde(default)]
+    pub ignore_unfixed: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ScannerKind {
+    #[default]
+    Trivy,
+    Grype,
+}
+
+impl ScannerKind {
+    pub(crate) fn scanner(&self) -> Arc<dyn ImageScanner> {
+        match self {
+            ScannerKind::Trivy => Arc::new(Trivy),
+            ScannerKind::Grype => Arc::new(Grype),
+        }
+    }
+}
+
+// Finds vulnerabilities in an image of the docker daemon the workspaces run on
+#[async_trait]
+pub trait ImageScanner: Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn scan(&self, image: &str) -> Result<Vec<Vulnerability>>;
+}
+
+// Runs the trivy cli of the host
+pub struct Trivy;
+
+#[async_trait]
+impl ImageScanner for Trivy {
+    fn name(&self) -> &str {
+        "trivy"
+    }
+
+    async fn scan(&self, image: &str) -> Result<Vec<Vulnerability>> {
+        let output = run(
+            "trivy",
+            &[
+                "image",
+                "--quiet",
+                "--format",
+                "json",
+                "--image-src",
+                "docker",
+                image,
+            ],
+        )
+        .await?;
+        parse_trivy(&output)
+    }
+}
+
+// Runs the grype cli of the host
+pub struct Grype;
+
+#[async_trait]
+impl ImageScanner for Grype {
+    fn name(&self) -> &str {
+        "grype"
+    }
+
+    async fn scan(&self, image: &str) -> Result<Vec<Vulnerability>> {
+        let source = format!("docker:{}", image);
+        let output = run("grype", &[&source, "--quiet", "--output", "json"]).await?;
+        parse_grype(&output)
+    }
+}
+
+async fn run(program: &str, args: &[&str]) -> Result<String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Could not run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyReport {
+    #[serde(default)]
+    results: Vec<TrivyResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyResult {
+    // Null when the target has none
+    vulnerabilities: Option<Vec<TrivyVulnerability>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TrivyVulnerability {
+    #[serde(rename = "VulnerabilityID")]
+    vulnerability_id: String,
+    pkg_name: String,
+    #[serde(default)]
+    installed_version: String,
+    fixed_version: Option<String>,
+    #[serde(default)]
+    severity: String,
+    title: Option<String>,
+}
+
+fn parse_trivy(output: &str) -> Result<Vec<Vulnerability>> {
+    let report: TrivyReport = serde_json::from_str(output).context("Invalid trivy report")?;
+    Ok(report
+        .results
+        .into_iter()
+        .flat_map(|result| result.vulnerabilities.unwrap_or_default())
+        .map(|vulnerability| Vulnerability {
+            id: vulnerability.vulnerability_id,
+            package: vulnerability.pkg_name,
+            installed_version: vulnerability.installed_version,
+            fixed_version: vulnerability
+                .fixed_version
+                .filter(|version| !version.is_empty()),
+            severity: VulnerabilitySeverity::parse(&vulnerability.severity),
+            title: vulnerability.title,
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct GrypeReport {
+    #[serde(default)]
+    matches: Vec<GrypeMatch>,
+}
+
+#[derive(Deserialize)]
+struct GrypeMatch {
+    vulnerability: GrypeVulnerability,
+    artifact: GrypeArtifact,
+}
+
+#[derive(Deserialize)]
+struct GrypeVulnerability {
+    id: String,
+    #[serde(default)]
+    severity: String,
+    description: Option<String>,
+    #[serde(default)]
+    fix: GrypeFix,
+}
+
+#[derive(Deserialize, Default)]
+struct GrypeFix {
+    #[serde(default)]
+    versions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct GrypeArtifact {
+    name: String,
+    #[serde(default)]
+    version: String,
+}
+
+fn parse_grype(output: &str) -> Result<Vec<Vulnerability>> {
+    let report: GrypeReport = serde_json::from_str(output).context("Invalid grype report")?;
+    Ok(report
+        .matches
+        .into_iter()
+        .map(|found| Vulnerability {
+            id: found.vulnerability.id,
+            package: found.artifact.name,
+            installed_version: found.artifact.version,
+            fixed_version: found.vulnerability.fix.versions.into_iter().next(),
+            severity: VulnerabilitySeverity::parse(&found.vulnerability.severity),
+            title: found.vulnerability.description,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trivy() {
+        let output = r#"{
+            "SchemaVersion": 2,
+            "Results": [
+                {"Target": "debian 12", "Vulnerabilities": [
+                    {"VulnerabilityID": "CVE-2024-3094", "PkgName": "xz-utils", "InstalledVersion": "5.6.0", "FixedVersion": "5.6.1", "Severity": "CRITICAL", "Title": "backdoor"},
+                    {"VulnerabilityID": "CVE-2023-0001", "PkgName": "libc6", "InstalledVersion": "2.36", "FixedVersion": "", "Severity": "LOW"}
+                ]},
+                {"Target": "app", "Vulnerabilities": null}
+            ]
+        }"#;
+        let vulnerabilities = parse_trivy(output).unwrap();
+        assert_eq!(vulnerabilities.len(), 2);
+        assert_eq!(vulnerabilities[0].id, "CVE-2024-3094");
+        assert_eq!(vulnerabilities[0].severity, VulnerabilitySeverity::Critical);
+        assert_eq!(vulnerabilities[0].fixed_version.as_deref(), Some("5.6.1"));
+        assert_eq!(vulnerabilities[1].fixed_version, None);
+    }
+
+    #[test]
+    fn test_parse_grype() {
+        let output = r#"{"matches": [
+            {"vulnerability": {"id": "GHSA-xxxx", "severity": "Negligible", "fix": {"versions": [], "state": "not-fixed"}},
+             "artifact": {"name": "openssl", "version": "3.0.11"}}
+        ]}"#;
+        let vulnerabilities = parse_grype(output).unwrap();
+        assert_eq!(vulnerabilities[0].package, "openssl");
+        assert_eq!(vulnerabilities[0].severity, VulnerabilitySeverity::Low);
+        assert_eq!(vulnerabilities[0].fixed_version, None);
+    }
+
+    #[test]
+    fn test_check() {
+        let finding = |id: &str, severity, fixed_version: Option<&str>| Vulnerability {
+            id: id.to_string(),
+            package: "xz-utils".to_string(),
+            installed_version: "5.6.0".to_string(),
+            fixed_version: fixed_version.map(str::to_string),
+            severity,
+            title: None,
+        };
+        let scan = ImageScan {
+            image: "app-cache-1".to_string(),
+            scanner: "trivy".to_string(),
+            scanned_at: 0,
+            vulnerabilities: vec![
+                finding("CVE-1", VulnerabilitySeverity::High, None),
+                finding("CVE-2", VulnerabilitySeverity::Medium, Some("5.6.1")),
+            ],
+        };
+
+        assert!(scan.check(&ImageScanConfig::default()).is_ok());
+        let config = ImageScanConfig {
+            fail_on: Some(VulnerabilitySeverity::High),
+            ..Default::default()
+        };
+        let error = scan.check(&config).unwrap_err().to_string();
+        assert!(error.contains("CVE-1 (xz-utils)"), "{}", error);
+
+        let config = ImageScanConfig {
+            ignore_unfixed: true,
+            ..config
+        };
+        assert!(scan.check(&config).is_ok());
+        let config = ImageScanConfig {
+            fail_on: Some(VulnerabilitySeverity::Medium),
+            ..config
+        };
+        assert!(scan.check(&config).is_err());
+    }
+}
@@ -1,39 +1,470 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use itertools::Itertools;
 
+use crate::egress_proxy::{EgressProxy, EgressProxyConfig};
+use crate::network::NetworkPolicy;
+use crate::workspace_controllers::local_temp_sync::default_root;
+use crate::workspace_controllers::Sandbox;
 use crate::{workspace_controllers::LocalTempSyncController, WorkspaceController};
 
-use super::{WorkspaceContext, WorkspaceProvider};
+use super::{
+    run_post_create_script, run_verify_script, run_with_progress, ProgressReporter,
+    ProvisioningPhase, WorkspaceContext, WorkspaceProvider,
+};
 
-pub struct LocalTempSyncProvider {}
+// Directories of workspaces not modified for this long are left over from a previous run
+const DEFAULT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+// Directories under the root with the pristine copies of copy-on-write contexts, and the upper
+// and work directories of overlay mounts
+const PRISTINE_DIR: &str = ".pristine";
+const OVERLAY_DIR: &str = ".overlay";
+
+pub struct LocalTempSyncProvider {
+    root: PathBuf,
+}
 
 impl LocalTempSyncProvider {
+    // Workspaces go in `DERRICK_LOCAL_ROOT`, or `$TMPDIR/derrick` when it is not set
     pub fn new() -> LocalTempSyncProvider {
-        LocalTempSyncProvider {}
+        let root = std::env::var_os("DERRICK_LOCAL_ROOT")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_root);
+        LocalTempSyncProvider { root }
+    }
+
+    /// Creates the workspace directories in the given directory.
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Self {
+        self.root = root.into();
+        self
+    }
+
+    // How long directories of earlier runs are kept, `DERRICK_LOCAL_RETENTION` in seconds
+    pub fn retention() -> Duration {
+        std::env::var("DERRICK_LOCAL_RETENTION")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RETENTION)
+    }
+
+    // Removes the workspace directories that were not modified within the retention, which were
+    // left behind by servers that did not destroy their workspaces. Returns how many were removed.
+    pub fn remove_stale(&self, retention: Duration) -> Result<usize> {
+        let mut removed = remove_stale_in(&self.root, retention)?;
+        for dir in [PRISTINE_DIR, OVERLAY_DIR] {
+            removed += remove_stale_in(&self.root.join(dir), retention)?;
+        }
+        Ok(removed)
+    }
+
+    // Clones the repositories and runs the setup steps once per context and environment, in a
+    // pristine directory that workspaces are copied from
+    async fn prepare_pristine(
+        &self,
+        context: &WorkspaceContext,
+        env: &HashMap<String, String>,
+        mirror_dir: Option<String>,
+        progress: &ProgressReporter,
+    ) -> Result<PathBuf> {
+        // The mirrors were updated before
+        let file_ids = context.cache_file_ids().await?;
+        let pristine_root = self.root.join(PRISTINE_DIR);
+        let pristine = pristine_root.join(format!(
+            "{}-{}",
+            sanitize_name(&context.name),
+            pristine_hash(context, env, &file_ids)
+        ));
+        if pristine.exists() {
+            tracing::info!("Pristine copy already exists: {}", pristine.display());
+            return Ok(pristine);
+        }
+
+        let build = LocalTempSyncController::initialize_in(&pristine_root, "building")
+            .await
+            .with_shell(context.shell.clone())
+            .with_mirror_dir(mirror_dir);
+        if let Err(e) = setup(&build, context, env.clone(), progress).await {
+            build.stop().await?;
+            return Err(e);
+        }
+        // Another workspace of the context may have built it at the same time
+        if std::fs::rename(build.directory(), &pristine).is_err() {
+            build.stop().await?;
+        }
+        Ok(pristine)
+    }
+}
+
+fn remove_stale_in(dir: &Path, retention: Duration) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        // Swept on their own, by the age of what is in them
+        let shared = [PRISTINE_DIR, OVERLAY_DIR]
+            .iter()
+            .any(|name| path.file_name() == Some(name.as_ref()));
+        if shared || !path.is_dir() || !is_stale(&path, retention) {
+            continue;
+        }
+        match std::fs::remove_dir_all(&path) {
+            Ok(()) => removed += 1,
+            Err(e) => tracing::warn!("Could not remove {}: {:?}", path.display(), e),
+        }
     }
+    Ok(removed)
+}
+
+fn is_stale(path: &Path, retention: Duration) -> bool {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age >= retention)
 }
 
 #[async_trait]
 impl WorkspaceProvider for LocalTempSyncProvider {
+    // Only copy-on-write contexts have something to prepare, the pristine copy
+    async fn prepare(
+        &self,
+        context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<()> {
+        if !context.copy_on_write {
+            return Ok(());
+        }
+        let mirrors = context.update_mirrors().await?;
+        let mirror_dir = mirrors.map(|mirrors| mirrors.root().to_string_lossy().to_string());
+        let pristine = self
+            .prepare_pristine(context, &env, mirror_dir, progress)
+            .await?;
+        tracing::info!("Prepared pristine copy {}", pristine.display());
+        Ok(())
+    }
+
     async fn provision(
-        &mut self,
+        &self,
         context: &WorkspaceContext,
         env: HashMap<String, String>,
+        progress: &ProgressReporter,
     ) -> Result<Box<dyn WorkspaceController>> {
-        let controller = Box::new(LocalTempSyncController::initialize(&context.name).await);
-        controller.init().await?;
-        for repository in &context.repositories {
-            controller
-                .provision_repositories(vec![repository.clone()])
-                .await?;
+        let sandbox = sandbox(context)?;
+        if let Some(sandbox) = &sandbox {
+            sandbox.check()?;
+        }
+        let egress_proxy = match egress_proxy_config(context) {
+            Some(config) => {
+                let allowed_hosts = context.network.as_ref().and_then(|n| n.allowed_hosts());
+                Some(Arc::new(
+                    EgressProxy::start(&config, allowed_hosts, false).await?,
+                ))
+            }
+            None => None,
+        };
+        let mirrors = context.update_mirrors().await?;
+        let mirror_dir = mirrors.map(|mirrors| mirrors.root().to_string_lossy().to_string());
+        let pristine = if context.copy_on_write {
+            Some(
+                self.prepare_pristine(context, &env, mirror_dir.clone(), progress)
+                    .await?,
+            )
+        } else {
+            None
+        };
+
+        let mut controller = LocalTempSyncController::initialize_in(&self.root, &context.name)
+            .await
+            .with_shell(context.shell.clone())
+            .with_mirror_dir(mirror_dir)
+            .with_ports(context.ports.clone());
+        let overlay = match &pristine {
+            Some(pristine) => {
+                progress.phase(
+                    ProvisioningPhase::StartingWorkspace,
+                    "Copying the pristine workspace",
+                );
+                copy_on_write(pristine, &self.root.join(OVERLAY_DIR), &controller).await
+            }
+            None => setup(&controller, context, env.clone(), progress)
+                .await
+                .map(|()| None),
+        };
+        let result = match overlay {
+            Ok(overlay) => {
+                controller = controller
+                    .with_overlay(overlay)
+                    .with_sandbox(sandbox)
+                    .with_egress_proxy(egress_proxy);
+                match run_verify_script(&controller, context, env.clone(), progress).await {
+                    Ok(()) => run_post_create_script(&controller, context, env, progress).await,
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            controller.stop().await?;
+            return Err(e);
+        }
+
+        Ok(Box::new(controller))
+    }
+}
+
+// Creates the workspace from the pristine copy as an overlay mount when mounting is allowed,
+// which only needs space for what changes. Otherwise the files are copied, as reflinks that
+// share their data on file systems that support them (btrfs, xfs, APFS). Returns the upper and
+// work directory of the overlay, if one was mounted.
+async fn copy_on_write(
+    pristine: &Path,
+    overlays: &Path,
+    controller: &LocalTempSyncController,
+) -> Result<Option<PathBuf>> {
+    let target = controller.directory();
+    if cfg!(target_os = "linux") {
+        let overlay = overlays.join(target.file_name().context("Workspace without a name")?);
+        std::fs::create_dir_all(overlay.join("upper"))?;
+        std::fs::create_dir_all(overlay.join("work"))?;
+        let mounted = tokio::process::Command::new("mount")
+            .args(["-t", "overlay", "overlay", "-o"])
+            .arg(format!(
+                "lowerdir={},upperdir={},workdir={}",
+                pristine.display(),
+                overlay.join("upper").display(),
+                overlay.join("work").display()
+            ))
+            .arg(target)
+            .stderr(std::process::Stdio::null())
+            .status()
+            .await
+            .is_ok_and(|status| status.success());
+        if mounted {
+            return Ok(Some(overlay));
         }
+        tracing::debug!("Could not mount overlay, copying instead");
+        std::fs::remove_dir_all(&overlay)?;
+    }
 
+    // Copies the contents, as the directory of the workspace exists already
+    let source = pristine.join(".");
+    let reflink = if cfg!(target_os = "macos") {
+        vec!["-a", "-c"]
+    } else {
+        vec!["-a", "--reflink=auto"]
+    };
+    for args in [reflink, vec!["-a"]] {
+        let output = tokio::process::Command::new("cp")
+            .args(&args)
+            .arg(&source)
+            .arg(target)
+            .output()
+            .await
+            .context("Could not copy pristine workspace")?;
+        if output.status.success() {
+            return Ok(None);
+        }
+        tracing::debug!(
+            "Copying with {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    anyhow::bail!("Could not copy pristine workspace {}", pristine.display())
+}
+
+// The sandbox of the context, with the network the policy allows. Local workspaces can only be
+// cut off from the network as a whole, by the sandbox. An allowlist is enforced by the egress
+// proxy, for the traffic that goes through it only: the sandbox keeps its network, so programs
+// that ignore HTTP_PROXY reach any host.
+fn sandbox(context: &WorkspaceContext) -> Result<Option<Sandbox>> {
+    let mut sandbox = context.sandbox.clone();
+    match (&context.network, &mut sandbox) {
+        (None, _) | (Some(NetworkPolicy::Full), None) => {}
+        (Some(NetworkPolicy::Full), Some(sandbox)) => sandbox.network = true,
+        (Some(NetworkPolicy::Disabled), _) if context.egress_proxy.is_some() => {
+            anyhow::bail!("The egress proxy cannot be used without network access")
+        }
+        (Some(NetworkPolicy::Disabled), Some(sandbox)) => sandbox.network = false,
+        (Some(NetworkPolicy::Disabled), None) => {
+            anyhow::bail!("Local workspaces need a sandbox to disable their network")
+        }
+        (Some(NetworkPolicy::Allowlist { .. }), sandbox) => {
+            if let Some(sandbox) = sandbox {
+                sandbox.network = true;
+            }
+        }
+    }
+    Ok(sandbox)
+}
+
+// The egress proxy of the context, or the default one when it has an allowlist, as that is what
+// enforces it for local workspaces
+fn egress_proxy_config(context: &WorkspaceContext) -> Option<EgressProxyConfig> {
+    match (&context.egress_proxy, &context.network) {
+        (Some(config), _) => Some(config.clone()),
+        (None, Some(NetworkPolicy::Allowlist { .. })) => Some(EgressProxyConfig::default()),
+        (None, _) => None,
+    }
+}
+
+// Identifies the pristine copy by everything that goes into it
+fn pristine_hash(
+    context: &WorkspaceContext,
+    env: &HashMap<String, String>,
+    file_ids: &HashMap<String, String>,
+) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for repository in &context.repositories {
+        hasher.update(repository.url.as_str());
+        hasher.update(repository.path.as_str());
+        if let Some(reference) = &repository.reference {
+            hasher.update(reference.as_str());
+        }
+        hasher.update([repository.lfs as u8, repository.submodules as u8]);
+        for path in &repository.sparse_paths {
+            hasher.update(path.as_str());
+        }
+    }
+    for step in context.steps() {
+        hasher.update(step.name.as_str());
+        hasher.update(step.script.as_str());
+        for file in &step.cache_files {
+            hasher.update(file.as_str());
+            hasher.update(file_ids.get(file).map(String::as_str).unwrap_or_default());
+        }
+    }
+    env.iter().sorted().for_each(|(key, value)| {
+        hasher.update(key.as_str());
+        hasher.update(value.as_str());
+    });
+    let mut result = hex::encode(hasher.finalize());
+    result.truncate(16);
+    result
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect()
+}
+
+// Clones the repositories and runs the setup steps
+async fn setup(
+    controller: &LocalTempSyncController,
+    context: &WorkspaceContext,
+    env: HashMap<String, String>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    controller.init().await?;
+    for repository in &context.repositories {
+        progress.phase(
+            ProvisioningPhase::CloningRepositories,
+            format!("Cloning {}", repository.path),
+        );
         controller
-            .cmd_with_output(context.setup_script.as_str(), Some("/"), env, None)
+            .provision_repositories(vec![repository.clone()])
             .await?;
+    }
+
+    for step in context.steps() {
+        progress.phase(
+            ProvisioningPhase::RunningSetup,
+            format!("Running setup step {}", step.name),
+        );
+        let output =
+            run_with_progress(controller, &step.script, Some("/"), env.clone(), progress).await?;
+
+        if output.exit_code != 0 {
+            anyhow::bail!(
+                "Setup step {} failed with exit code {}: {}",
+                step.name,
+                output.exit_code,
+                output.output
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_removes_stale_directories() {
+        let root = default_root().join(format!("test-root-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("old-workspace")).unwrap();
+        let provider = LocalTempSyncProvider::new().with_root(&root);
+
+        assert_eq!(provider.remove_stale(Duration::from_secs(3600)).unwrap(), 0);
+        assert!(root.join("old-workspace").exists());
+
+        assert_eq!(provider.remove_stale(Duration::ZERO).unwrap(), 1);
+        assert!(!root.join("old-workspace").exists());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_network_policy_applies_to_sandbox() {
+        let mut context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "network",
+            "repositories": [],
+            "sandbox": { "network": true },
+            "network": { "mode": "none" }
+        }))
+        .unwrap();
+        assert!(!sandbox(&context).unwrap().unwrap().network);
+
+        context.network = Some(NetworkPolicy::Allowlist {
+            hosts: vec!["github.com".to_string()],
+        });
+        assert!(sandbox(&context).unwrap().unwrap().network);
+        assert_eq!(
+            egress_proxy_config(&context),
+            Some(EgressProxyConfig::default())
+        );
+
+        context.network = Some(NetworkPolicy::Disabled);
+        context.sandbox = None;
+        assert!(sandbox(&context).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_copies_pristine_without_changing_it() {
+        let root = default_root().join(format!("test-root-{}", uuid::Uuid::new_v4()));
+        let pristine = root.join(PRISTINE_DIR).join("context");
+        std::fs::create_dir_all(pristine.join("repo")).unwrap();
+        std::fs::write(pristine.join("repo/README.md"), "pristine").unwrap();
+
+        let controller = LocalTempSyncController::initialize_in(&root, "workspace").await;
+        let overlay = copy_on_write(&pristine, &root.join(OVERLAY_DIR), &controller)
+            .await
+            .unwrap();
+        let controller = controller.with_overlay(overlay);
+        controller
+            .write_file("repo/README.md", b"changed", None)
+            .await
+            .unwrap();
 
-        Ok(controller)
+        assert_eq!(
+            std::fs::read_to_string(pristine.join("repo/README.md")).unwrap(),
+            "pristine"
+        );
+        controller.stop().await.unwrap();
+        std::fs::remove_dir_all(root).unwrap();
     }
 }
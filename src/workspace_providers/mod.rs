@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use async_trait::async_trait;
 
@@ -6,40 +7,597 @@ mod local_temp_sync;
 pub use local_temp_sync::LocalTempSyncProvider;
 
 mod docker;
+pub use docker::{DockerProvider, RegistryCredentials};
+mod image_locks;
+mod image_scan;
+pub use image_scan::{
+    Grype, ImageScan, ImageScanConfig, ImageScanner, ScannerKind, Trivy, Vulnerability,
+    VulnerabilitySeverity,
+};
 
-use crate::{repository::Repository, WorkspaceController};
-use anyhow::Result;
+mod remote_http;
+pub use remote_http::RemoteHttpProvider;
+
+mod fleet;
+pub use fleet::{FleetBackend, FleetProvider};
+
+mod remote_nats;
+pub use remote_nats::RemoteNatsProvider;
+
+mod progress;
+pub use progress::{ProgressReporter, ProvisioningPhase, ProvisioningStatus};
+
+mod validation;
+pub use validation::{context_schema, validate_context};
+
+use crate::{
+    cloud_credentials::CloudCredentials,
+    coverage::CoverageConfig,
+    daemons::Daemon,
+    egress_proxy::EgressProxyConfig,
+    lint::Linter,
+    lsp::LanguageServer,
+    mirror::MirrorCache,
+    network::NetworkPolicy,
+    repository::Repository,
+    secrets::WorkspaceSecret,
+    usage::DiskQuota,
+    workspace_controllers::{
+        docker::{Healthcheck, Mount, WorkspaceUser},
+        CommandOutput, Sandbox,
+    },
+    WorkspaceController,
+};
+use anyhow::{Context, Result};
+use schemars::JsonSchema;
 use serde::Deserialize;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
 pub struct WorkspaceContext {
     pub name: String, // Unique name for the workspace (for inspection/debugging)
     pub repositories: Vec<Repository>,
-    pub setup_script: String,
+    // Ordered setup steps, each cached separately so changing a late step does not re-run the
+    // earlier ones
+    #[serde(default)]
+    pub setup_steps: Vec<SetupStep>,
+    // Shorthand for a single setup step named "setup", run before any `setup_steps`
+    #[serde(default)]
+    pub setup_script: Option<String>,
+    // Runs in every new workspace after it is created, and is never cached
+    #[serde(default)]
+    pub post_create_script: Option<String>,
+    // Checks every new workspace before the post create script, like `cargo --version`. With the
+    // docker provider, a workspace created from cached images that fails it has the images
+    // rebuilt once, in case they were corrupted.
+    #[serde(default)]
+    pub verify_script: Option<String>,
+    // Directory on the host with bare mirrors of the repositories, used to speed up cloning
+    #[serde(default)]
+    pub git_mirror_dir: Option<String>,
+    // Shell used to run commands, e.g. ["sh", "-c"]. Detected per workspace when not set.
+    #[serde(default, deserialize_with = "deserialize_shell")]
+    #[schemars(length(min = 1))]
+    pub shell: Option<Vec<String>>,
+    // Disk space each workspace may use, and what happens when it uses more
+    #[serde(default)]
+    pub disk_quota: Option<DiskQuota>,
+    // Command that collects test coverage, used by the coverage endpoint
+    #[serde(default)]
+    pub coverage: Option<CoverageConfig>,
+    // Linters run by the lint endpoint, detected from the files in the repository when empty
+    #[serde(default)]
+    pub linters: Vec<Linter>,
+    // Build output that can be downloaded from workspaces, as globs like `dist/**`
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+    // Started in every new workspace after it is created, and restarted when they exit
+    #[serde(default)]
+    pub daemons: Vec<Daemon>,
+    // Docker provider only: restarts workspaces whose container exited, like when it ran out of
+    // memory. Without it they stay stopped, and commands fail until they are destroyed.
+    #[serde(default)]
+    pub restart: Option<RestartPolicy>,
+    // Language servers that clients can talk to over the lsp endpoint
+    #[serde(default)]
+    pub language_servers: Vec<LanguageServer>,
+    // Indexes the symbols of the repositories with universal-ctags once the workspace is
+    // provisioned. Without it, the index is built on the first lookup.
+    #[serde(default)]
+    pub index_symbols: bool,
+    // Local provider only: runs the setup once in a pristine directory, and creates workspaces
+    // as copy-on-write copies of it
+    #[serde(default)]
+    pub copy_on_write: bool,
+    // Local provider only: runs the commands with bubblewrap or nsjail. The repositories are
+    // cloned and the setup steps run before the sandbox applies, as they usually need the network.
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    // Local provider only: the ports previews and tunnels can reach. Local workspaces listen on
+    // the loopback of the host, so any other port could be a service of the host.
+    #[serde(default)]
+    pub ports: Vec<u16>,
+    // What workspaces can reach over the network once they are set up, full access by default.
+    // Local workspaces need a sandbox for anything but full access.
+    #[serde(default)]
+    pub network: Option<NetworkPolicy>,
+    // Sends the HTTP(S) traffic of workspaces through a proxy of the server, which records it
+    // and enforces the allowlist of the network policy
+    #[serde(default)]
+    pub egress_proxy: Option<EgressProxyConfig>,
+    // Docker provider only: runs the setup steps and commands as this user instead of root
+    #[serde(default)]
+    pub user: Option<WorkspaceUser>,
+    // Docker provider only: repository with the shell configuration and tools of the people
+    // working in the workspaces. It is cloned into ~/dotfiles of the user after the post create
+    // script, and installed like Codespaces does. Workspaces that fail to install it are still
+    // created.
+    #[serde(default)]
+    pub dotfiles_repo: Option<String>,
+    // Docker provider only: checks the health of workspaces, a HEALTHCHECK of the image is used
+    // when not set
+    #[serde(default)]
+    pub healthcheck: Option<Healthcheck>,
+    // Docker provider only: size of /dev/shm of workspaces in bytes, which browsers and databases
+    // may need more of than the 64MB docker gives
+    #[serde(default)]
+    pub shm_size: Option<u64>,
+    // Docker provider only: paths of workspaces mounted as tmpfs, with their mount options like
+    // `size=1g`. Their contents are not part of clones.
+    #[serde(default)]
+    pub tmpfs: HashMap<String, String>,
+    // Docker provider only: paths of the host mounted into workspaces, like a local source tree.
+    // Changes in workspaces end up on the host unless the mount is read only.
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    // Docker provider only: mounts a directory of the host into every workspace for build output
+    // and reports, which is kept when the workspace is destroyed
+    #[serde(default)]
+    pub output_dir: Option<OutputDir>,
+    // Docker provider only: scans the image of the last setup step for vulnerabilities once it
+    // is prepared, see `ImageScanConfig`
+    #[serde(default)]
+    pub image_scan: Option<ImageScanConfig>,
+    // Provider of the workspaces, like `"docker"` or `"local"`, the one the server was started
+    // with when not set. Other providers have to be enabled on the server.
+    #[serde(default)]
+    pub provisioning_mode: Option<String>,
+    // Named sets of settings that create requests can choose from, e.g. `"safe"` and `"full"`
+    #[serde(default)]
+    pub profiles: HashMap<String, ExecutionProfile>,
+    // The profile the context was created with by `with_profile`
+    #[serde(skip)]
+    pub profile: Option<String>,
+    // Variables that create requests give values for, by name with an optional default. Their
+    // `{{name}}` placeholders are replaced in the urls and references of the repositories and in
+    // the scripts, so the images are cached per value.
+    #[serde(default)]
+    pub variables: HashMap<String, Option<String>>,
+    // Secrets from secret stores that commands get once the workspace is provisioned, see
+    // `WorkspaceSecret`
+    #[serde(default)]
+    pub secrets: Vec<WorkspaceSecret>,
+    // Short-lived cloud credentials minted for every workspace, see `CloudCredentials`
+    #[serde(default)]
+    pub cloud_credentials: Vec<CloudCredentials>,
+}
+
+// Settings of a profile replace the ones of the context, what it leaves out is taken from the
+// context
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, PartialEq)]
+pub struct ExecutionProfile {
+    #[serde(default)]
+    pub provisioning_mode: Option<String>,
+    #[serde(default)]
+    pub sandbox: Option<Sandbox>,
+    #[serde(default)]
+    pub network: Option<NetworkPolicy>,
+    #[serde(default)]
+    pub egress_proxy: Option<EgressProxyConfig>,
+    #[serde(default)]
+    pub user: Option<WorkspaceUser>,
+    #[serde(default)]
+    pub disk_quota: Option<DiskQuota>,
+    // Limits of every command run in the workspace, requests can set lower ones but not higher
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub cpu_time: Option<u64>,
+    #[serde(default)]
+    pub max_output_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema, PartialEq)]
+pub struct SetupStep {
+    pub name: String,
+    pub script: String,
+    // Runs as root in docker workspaces with a user, e.g. to install packages
+    #[serde(default)]
+    pub root: bool,
+    // Files of the repositories the step depends on, like `/code/Cargo.lock`, as the path of the
+    // repository followed by the path in it. The step runs again when one of them changes, which
+    // is looked up in the mirrors of `git_mirror_dir`.
+    #[serde(default)]
+    pub cache_files: Vec<String>,
+}
+
+// Every workspace gets its own subdirectory of `host_dir`, named after its id
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct OutputDir {
+    pub host_dir: String,
+    // Where the subdirectory is mounted in the workspace
+    #[serde(default = "default_output_target")]
+    pub target: String,
+}
+
+fn default_output_target() -> String {
+    "/output".to_string()
+}
+
+// Restarting a workspace runs the post create script and starts the daemons again, without the
+// environment of the create request
+#[derive(Debug, Clone, Deserialize, JsonSchema, PartialEq)]
+pub struct RestartPolicy {
+    // The workspace stays stopped when its container exits after this many restarts
+    #[serde(default = "default_max_restarts")]
+    pub max_restarts: u32,
+}
+
+fn default_max_restarts() -> u32 {
+    3
+}
+
+// The first element of the shell is the program the commands are run with, so it cannot be empty
+fn deserialize_shell<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let shell = Option::<Vec<String>>::deserialize(deserializer)?;
+    if shell.as_ref().is_some_and(Vec::is_empty) {
+        return Err(serde::de::Error::custom(
+            "shell needs at least the program to run commands with",
+        ));
+    }
+    Ok(shell)
 }
 
 impl WorkspaceContext {
+    /// Updates the host mirrors of all repositories, if a mirror directory is configured.
+    pub async fn update_mirrors(&self) -> Result<Option<MirrorCache>> {
+        let Some(dir) = &self.git_mirror_dir else {
+            return Ok(None);
+        };
+
+        let cache = MirrorCache::new(dir)?;
+        for repository in &self.repositories {
+            cache.update(repository).await?;
+        }
+        Ok(Some(cache))
+    }
+
+    /// Entries of the `cache_files` of all setup steps in the mirrors, by file. The mirrors should
+    /// be updated first.
+    pub async fn cache_file_ids(&self) -> Result<HashMap<String, String>> {
+        let mut files = self
+            .steps()
+            .into_iter()
+            .flat_map(|step| step.cache_files)
+            .collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+        if files.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let Some(dir) = &self.git_mirror_dir else {
+            anyhow::bail!("Setup steps with cache_files need a git_mirror_dir");
+        };
+
+        let mut by_repository: HashMap<usize, Vec<(String, String)>> = HashMap::new();
+        for file in files {
+            let Some((index, path)) = repository_file(&self.repositories, &file) else {
+                anyhow::bail!("Cache file {} is not in any of the repositories", file);
+            };
+            by_repository.entry(index).or_default().push((file, path));
+        }
+
+        let cache = MirrorCache::new(dir)?;
+        let mut ids = HashMap::new();
+        for (index, files) in by_repository {
+            let paths = files
+                .iter()
+                .map(|(_, path)| path.as_str())
+                .collect::<Vec<_>>();
+            let entries = cache.file_ids(&self.repositories[index], &paths).await?;
+            ids.extend(files.into_iter().map(|(file, _)| file).zip(entries));
+        }
+        Ok(ids)
+    }
+
     pub fn from_file(path: String) -> Result<WorkspaceContext> {
         let file = std::fs::File::open(path)?;
         let reader = std::io::BufReader::new(file);
         let context = serde_json::from_reader(reader)?;
         Ok(context)
     }
+
+    /// The context with the settings of the named profile in place of its own.
+    pub fn with_profile(&self, name: &str) -> Result<WorkspaceContext> {
+        let Some(profile) = self.profiles.get(name) else {
+            let mut names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            anyhow::bail!(
+                "Unknown profile {}, expected one of: {}",
+                name,
+                names.join(", ")
+            );
+        };
+        let profile = profile.clone();
+        Ok(WorkspaceContext {
+            provisioning_mode: profile
+                .provisioning_mode
+                .or_else(|| self.provisioning_mode.clone()),
+            sandbox: profile.sandbox.or_else(|| self.sandbox.clone()),
+            network: profile.network.or_else(|| self.network.clone()),
+            egress_proxy: profile.egress_proxy.or_else(|| self.egress_proxy.clone()),
+            user: profile.user.or_else(|| self.user.clone()),
+            disk_quota: profile.disk_quota.or_else(|| self.disk_quota.clone()),
+            profile: Some(name.to_string()),
+            ..self.clone()
+        })
+    }
+
+    /// The context with the placeholders of its variables replaced by the given values, or the
+    /// defaults of the variables.
+    pub fn with_variables(&self, values: &HashMap<String, String>) -> Result<WorkspaceContext> {
+        let mut unknown = values
+            .keys()
+            .filter(|name| !self.variables.contains_key(*name))
+            .collect::<Vec<_>>();
+        unknown.sort();
+        if let Some(name) = unknown.first() {
+            anyhow::bail!("Unknown variable {}", name);
+        }
+        // Without variables, the scripts may use the braces for something else
+        if self.variables.is_empty() {
+            return Ok(self.clone());
+        }
+
+        let mut resolved = HashMap::new();
+        for (name, default) in &self.variables {
+            let Some(value) = values.get(name).or(default.as_ref()) else {
+                anyhow::bail!("Variable {} needs a value", name);
+            };
+            resolved.insert(name.as_str(), value.as_str());
+        }
+        let resolve = |template: &str| render(template, &resolved);
+
+        let mut context = self.clone();
+        for repository in &mut context.repositories {
+            repository.url = resolve(&repository.url)?;
+            repository.reference = repository.reference.as_deref().map(resolve).transpose()?;
+        }
+        for step in &mut context.setup_steps {
+            step.script = resolve(&step.script)?;
+        }
+        context.setup_script = context.setup_script.as_deref().map(resolve).transpose()?;
+        context.post_create_script = context
+            .post_create_script
+            .as_deref()
+            .map(resolve)
+            .transpose()?;
+        context.verify_script = context.verify_script.as_deref().map(resolve).transpose()?;
+        Ok(context)
+    }
+
+    /// The context with the directory of the workspace in the `output_dir` created and added to its
+    /// mounts.
+    pub fn with_output_dir(&self, workspace_id: &str) -> Result<WorkspaceContext> {
+        let Some(output_dir) = &self.output_dir else {
+            return Ok(self.clone());
+        };
+        let source = Path::new(&output_dir.host_dir).join(workspace_id);
+        std::fs::create_dir_all(&source)
+            .with_context(|| format!("Could not create output directory {}", source.display()))?;
+        let mut context = self.clone();
+        context.mounts.push(Mount {
+            source: source.to_string_lossy().to_string(),
+            target: output_dir.target.clone(),
+            read_only: false,
+        });
+        Ok(context)
+    }
+
+    /// The provisioning modes the context and its profiles ask for.
+    pub fn provisioning_modes(&self) -> Vec<String> {
+        let mut modes = self
+            .profiles
+            .values()
+            .filter_map(|profile| profile.provisioning_mode.clone())
+            .chain(self.provisioning_mode.clone())
+            .collect::<Vec<_>>();
+        modes.sort();
+        modes.dedup();
+        modes
+    }
+
+    /// The profile the context was created with, if any.
+    pub fn execution_profile(&self) -> Option<&ExecutionProfile> {
+        self.profiles.get(self.profile.as_deref()?)
+    }
+
+    /// All setup steps in the order they should run, including the `setup_script` shorthand.
+    pub fn steps(&self) -> Vec<SetupStep> {
+        self.setup_script
+            .iter()
+            .map(|script| SetupStep {
+                name: "setup".to_string(),
+                script: script.clone(),
+                ..Default::default()
+            })
+            .chain(self.setup_steps.iter().cloned())
+            .collect()
+    }
+}
+
+// Replaces the `{{name}}` placeholders in the template, which may have spaces inside the braces
+fn render(template: &str, values: &HashMap<&str, &str>) -> Result<String> {
+    let placeholder = regex::Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap();
+    let mut unknown = None;
+    let rendered = placeholder.replace_all(template, |captures: &regex::Captures| {
+        let name = &captures[1];
+        values.get(name).copied().unwrap_or_else(|| {
+            unknown.get_or_insert_with(|| name.to_string());
+            ""
+        })
+    });
+    if let Some(name) = unknown {
+        anyhow::bail!("Unknown variable {} in {:?}", name, template);
+    }
+    Ok(rendered.into_owned())
+}
+
+// The repository a file is in, by index, with the path of the file in it. Nested repositories
+// take precedence over the ones they are in.
+fn repository_file(repositories: &[Repository], file: &str) -> Option<(usize, String)> {
+    let file = file.trim_start_matches('/');
+    repositories
+        .iter()
+        .enumerate()
+        .filter_map(|(index, repository)| {
+            let root = repository.path.trim_matches('/');
+            let path = if root.is_empty() {
+                file
+            } else {
+                file.strip_prefix(root)?.strip_prefix('/')?
+            };
+            Some((root.len(), index, path))
+        })
+        .max_by_key(|(length, _, _)| *length)
+        .map(|(_, index, path)| (index, path.to_string()))
 }
 
+// Runs a setup step or script, with its output in the status while it runs
+pub(crate) async fn run_with_progress(
+    controller: &dyn WorkspaceController,
+    script: &str,
+    working_dir: Option<&str>,
+    env: HashMap<String, String>,
+    progress: &ProgressReporter,
+) -> Result<CommandOutput> {
+    progress.output("");
+    let on_line = |line: &str| progress.output_line(line);
+    let output = controller
+        .cmd_with_streamed_output(script, working_dir, env, &on_line)
+        .await?;
+    progress.output(&output.output);
+    Ok(output)
+}
+
+// Runs the (uncached) post create script of the context in a freshly created workspace
+pub(crate) async fn run_post_create_script(
+    controller: &dyn WorkspaceController,
+    context: &WorkspaceContext,
+    env: HashMap<String, String>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    let Some(script) = &context.post_create_script else {
+        return Ok(());
+    };
+
+    progress.phase(
+        ProvisioningPhase::StartingWorkspace,
+        "Running post create script",
+    );
+    let output = run_with_progress(controller, script, None, env, progress).await?;
+
+    if output.exit_code != 0 {
+        anyhow::bail!(
+            "Post create script failed with exit code {}: {}",
+            output.exit_code,
+            output.output
+        );
+    }
+    Ok(())
+}
+
+// Runs the verify script of the context in a freshly created workspace, before the post create
+// script
+async fn run_verify_script(
+    controller: &dyn WorkspaceController,
+    context: &WorkspaceContext,
+    env: HashMap<String, String>,
+    progress: &ProgressReporter,
+) -> Result<()> {
+    let Some(script) = &context.verify_script else {
+        return Ok(());
+    };
+
+    progress.phase(ProvisioningPhase::StartingWorkspace, "Verifying workspace");
+    let output = run_with_progress(controller, script, None, env, progress).await?;
+
+    if output.exit_code != 0 {
+        anyhow::bail!(
+            "Verify script failed with exit code {}: {}",
+            output.exit_code,
+            output.output
+        );
+    }
+    Ok(())
+}
+
+// The server provisions several workspaces at the same time, up to its
+// `max_concurrent_provisions`
 #[async_trait]
 pub trait WorkspaceProvider: Send + Sync {
     async fn provision(
-        &mut self,
+        &self,
         context: &WorkspaceContext,
         env: HashMap<String, String>,
+        progress: &ProgressReporter,
     ) -> Result<Box<dyn WorkspaceController>>;
+
+    // Builds what workspaces of the context are provisioned from ahead of time, so that the first
+    // workspace is not slower to create than the ones after it. Only workspaces created with the
+    // same environment for the setup steps benefit.
+    async fn prepare(
+        &self,
+        _context: &WorkspaceContext,
+        _env: HashMap<String, String>,
+        _progress: &ProgressReporter,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    // Wraps an environment created by other tooling, without creating or provisioning anything.
+    // The environment is left as is when the workspace is destroyed.
+    async fn adopt(&self, _target: &str) -> Result<Box<dyn WorkspaceController>> {
+        anyhow::bail!("Adopting existing environments is not supported by this provider")
+    }
+
+    // Vulnerability scans of the images the provider prepared, for contexts with an image scan
+    fn image_scans(&self) -> Vec<ImageScan> {
+        Vec::new()
+    }
 }
 
+// Provisioning modes `get_provider` knows
+pub(crate) const PROVISIONING_MODES: &[&str] =
+    &["local", "docker", "remote_http", "fleet", "remote_nats"];
+
 pub async fn get_provider(provisioning_mode: String) -> Result<Box<dyn WorkspaceProvider>> {
     match provisioning_mode.as_str() {
-        "local" => Ok(Box::new(LocalTempSyncProvider::new())),
+        "local" => {
+            let provider = LocalTempSyncProvider::new();
+            match provider.remove_stale(LocalTempSyncProvider::retention()) {
+                Ok(0) => {}
+                Ok(removed) => tracing::info!("Removed {} stale workspace directories", removed),
+                Err(e) => tracing::warn!("Could not remove stale workspace directories: {:?}", e),
+            }
+            Ok(Box::new(provider))
+        }
         "docker" => Ok(Box::new(docker::DockerProvider::initialize(None).await?)),
+        "remote_http" => Ok(Box::new(RemoteHttpProvider::from_env()?)),
+        "fleet" => Ok(Box::new(FleetProvider::from_env().await?)),
+        "remote_nats" => Ok(Box::new(RemoteNatsProvider::from_env().await?)),
         _ => {
             return Err(anyhow::anyhow!(
                 "Unsupported provisioning mode: {}",
@@ -48,3 +606,179 @@ pub async fn get_provider(provisioning_mode: String) -> Result<Box<dyn Workspace
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_profile() {
+        let context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repositories": [],
+            "disk_quota": { "max_bytes": 1000 },
+            "profiles": {
+                "safe": { "network": { "mode": "none" }, "timeout": 60 },
+                "full": {}
+            }
+        }))
+        .unwrap();
+
+        let safe = context.with_profile("safe").unwrap();
+        assert_eq!(safe.network, Some(NetworkPolicy::Disabled));
+        assert_eq!(safe.disk_quota, context.disk_quota);
+        assert_eq!(safe.execution_profile().unwrap().timeout, Some(60));
+        assert!(context.execution_profile().is_none());
+
+        let error = context.with_profile("unsafe").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unknown profile unsafe, expected one of: full, safe"
+        );
+    }
+
+    #[test]
+    fn test_provisioning_mode_of_profile() {
+        let context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repositories": [],
+            "provisioning_mode": "docker",
+            "profiles": {
+                "untrusted": {},
+                "trusted": { "provisioning_mode": "local" }
+            }
+        }))
+        .unwrap();
+
+        let untrusted = context.with_profile("untrusted").unwrap();
+        assert_eq!(untrusted.provisioning_mode.as_deref(), Some("docker"));
+        let trusted = context.with_profile("trusted").unwrap();
+        assert_eq!(trusted.provisioning_mode.as_deref(), Some("local"));
+        assert_eq!(context.provisioning_modes(), vec!["docker", "local"]);
+    }
+
+    #[test]
+    fn test_with_variables() {
+        let context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repositories": [{
+                "url": "https://github.com/bosun-ai/derrick",
+                "path": "/code",
+                "reference": "{{ branch }}"
+            }],
+            "setup_script": "cargo +{{toolchain}} fetch",
+            "post_create_script": "echo {{.Names}}",
+            "verify_script": "cargo +{{toolchain}} --version",
+            "variables": { "branch": null, "toolchain": "stable" }
+        }))
+        .unwrap();
+
+        let values = HashMap::from([("branch".to_string(), "fix-login".to_string())]);
+        let resolved = context.with_variables(&values).unwrap();
+        assert_eq!(
+            resolved.repositories[0].reference.as_deref(),
+            Some("fix-login")
+        );
+        assert_eq!(
+            resolved.setup_script.as_deref(),
+            Some("cargo +stable fetch")
+        );
+        // Braces around anything but a name are left alone
+        assert_eq!(
+            resolved.post_create_script.as_deref(),
+            Some("echo {{.Names}}")
+        );
+        assert_eq!(
+            resolved.verify_script.as_deref(),
+            Some("cargo +stable --version")
+        );
+
+        let error = context.with_variables(&HashMap::new()).unwrap_err();
+        assert_eq!(error.to_string(), "Variable branch needs a value");
+        let values = HashMap::from([("pr".to_string(), "12".to_string())]);
+        let error = context.with_variables(&values).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown variable pr");
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repositories": [],
+            "restart": {}
+        }))
+        .unwrap();
+        assert_eq!(context.restart, Some(RestartPolicy { max_restarts: 3 }));
+    }
+
+    #[test]
+    fn test_with_output_dir() {
+        let host_dir =
+            std::env::temp_dir().join(format!("derrick-output-{}", uuid::Uuid::new_v4()));
+        let context: WorkspaceContext = serde_json::from_value(serde_json::json!({
+            "name": "test",
+            "repositories": [],
+            "output_dir": { "host_dir": host_dir }
+        }))
+        .unwrap();
+        let context = context.with_output_dir("ws").unwrap();
+        let source = host_dir.join("ws");
+        assert!(source.is_dir());
+        assert_eq!(
+            context.mounts,
+            vec![Mount {
+                source: source.to_string_lossy().to_string(),
+                target: "/output".to_string(),
+                read_only: false,
+            }]
+        );
+        std::fs::remove_dir_all(host_dir).unwrap();
+    }
+
+    #[test]
+    fn test_repository_file() {
+        let repositories = ["/code", "/code/vendor/lib", ""]
+            .into_iter()
+            .map(|path| {
+                Repository::from_url("https://github.com/bosun-ai/derrick")
+                    .path(path)
+                    .build()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            repository_file(&repositories, "/code/Cargo.lock"),
+            Some((0, "Cargo.lock".to_string()))
+        );
+        assert_eq!(
+            repository_file(&repositories, "/code/vendor/lib/go.sum"),
+            Some((1, "go.sum".to_string()))
+        );
+        assert_eq!(
+            repository_file(&repositories, "/codegen/Dockerfile"),
+            Some((2, "codegen/Dockerfile".to_string()))
+        );
+        assert_eq!(
+            repository_file(&repositories[..2], "/codegen/Dockerfile"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rejects_empty_shell() {
+        let context = |shell: serde_json::Value| {
+            serde_json::from_value::<WorkspaceContext>(serde_json::json!({
+                "name": "test",
+                "repositories": [],
+                "shell": shell
+            }))
+        };
+        assert!(context(serde_json::json!([])).is_err());
+        assert_eq!(
+            context(serde_json::json!(["bash", "-c"])).unwrap().shell,
+            Some(vec!["bash".to_string(), "-c".to_string()])
+        );
+        assert_eq!(context(serde_json::Value::Null).unwrap().shell, None);
+    }
+}
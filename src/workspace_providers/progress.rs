@@ -0,0 +1,233 @@
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::credentials::scrub;
+use crate::events::Event;
+use crate::workspace_controllers::Health;
+
+// The status keeps the end of the output of the running step, up to this many bytes
+const MAX_STATUS_OUTPUT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProvisioningPhase {
+    Pending,
+    // Waiting for a provisioning slot
+    Queued,
+    PullingImage,
+    CloningRepositories,
+    RunningSetup,
+    StartingWorkspace,
+    Ready,
+    Failed,
+    // The container of the workspace exited after it was ready
+    Stopped,
+    Restarting,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProvisioningStatus {
+    pub phase: ProvisioningPhase,
+    // Human readable description of the current step, e.g. the repository being cloned
+    pub message: Option<String>,
+    // Progress of the current step between 0.0 and 1.0, when known
+    pub progress: Option<f32>,
+    // Output of the setup step that is running or that ran last, up to its last 64 KiB while it
+    // runs
+    pub output: Option<String>,
+    // Position in the provisioning queue while queued, starting at 1
+    pub queue_position: Option<usize>,
+    // Of ready workspaces with a health check
+    pub health: Option<Health>,
+}
+
+impl Default for ProvisioningStatus {
+    fn default() -> Self {
+        Self {
+            phase: ProvisioningPhase::Pending,
+            message: None,
+            progress: None,
+            output: None,
+            queue_position: None,
+            health: None,
+        }
+    }
+}
+
+// Shared handle that providers use to report how far along provisioning a workspace is.
+// Clones report to the same status.
+#[derive(Debug, Clone)]
+pub struct ProgressReporter {
+    status: Arc<watch::Sender<ProvisioningStatus>>,
+    // The workspace and where the lines of output of its steps are published, for workspaces of
+    // the server
+    events: Option<(String, broadcast::Sender<Event>)>,
+}
+
+impl Default for ProgressReporter {
+    fn default() -> Self {
+        Self {
+            status: Arc::new(watch::Sender::new(ProvisioningStatus::default())),
+            events: None,
+        }
+    }
+}
+
+impl ProgressReporter {
+    // Also publishes the output of the steps, line by line, as `provisioning_output` events
+    pub(crate) fn for_workspace(workspace_id: &str, events: broadcast::Sender<Event>) -> Self {
+        Self {
+            events: Some((workspace_id.to_string(), events)),
+            ..Default::default()
+        }
+    }
+
+    pub fn status(&self) -> ProvisioningStatus {
+        self.status.borrow().clone()
+    }
+
+    pub fn phase(&self, phase: ProvisioningPhase, message: impl Into<String>) {
+        tracing::info!(?phase, "Provisioning progress");
+        self.status.send_modify(|status| {
+            status.phase = phase;
+            status.message = Some(message.into());
+            status.progress = None;
+            status.queue_position = None;
+        });
+    }
+
+    pub fn queued(&self, position: usize) {
+        self.status.send_modify(|status| {
+            status.phase = ProvisioningPhase::Queued;
+            status.message = Some(format!(
+                "Waiting for a provisioning slot, position {} in the queue",
+                position
+            ));
+            status.queue_position = Some(position);
+        });
+    }
+
+    pub fn progress(&self, current: u64, total: u64) {
+        if total == 0 {
+            return;
+        }
+        self.status.send_modify(|status| {
+            status.progress = Some((current as f32 / total as f32).min(1.0));
+        });
+    }
+
+    pub fn output(&self, output: &str) {
+        self.status.send_modify(|status| {
+            status.output = Some(output.to_string());
+        });
+    }
+
+    // Adds a line the running step printed to its output. Call `output` with an empty output when
+    // the step starts, and with all of it when it finished.
+    pub fn output_line(&self, line: &str) {
+        self.status.send_modify(|status| {
+            let output = status.output.get_or_insert_with(String::new);
+            output.push_str(line);
+            output.push('\n');
+            if output.len() > MAX_STATUS_OUTPUT {
+                let mut start = output.len() - MAX_STATUS_OUTPUT;
+                while !output.is_char_boundary(start) {
+                    start += 1;
+                }
+                output.drain(..start);
+            }
+        });
+        if let Some((workspace_id, events)) = &self.events {
+            // Sending only fails when nobody is subscribed
+            let _ = events.send(Event::ProvisioningOutput {
+                workspace_id: workspace_id.clone(),
+                line: scrub(line),
+            });
+        }
+    }
+
+    pub fn failed(&self, error: &anyhow::Error) {
+        self.phase(ProvisioningPhase::Failed, format!("{:#}", error));
+    }
+
+    // Waits until the workspace is ready or failed to provision
+    pub async fn finished(&self) -> ProvisioningStatus {
+        let mut receiver = self.status.subscribe();
+        let finished = receiver
+            .wait_for(|status| {
+                matches!(
+                    status.phase,
+                    ProvisioningPhase::Ready | ProvisioningPhase::Failed
+                )
+            })
+            .await;
+        match finished {
+            Ok(status) => status.clone(),
+            // The sender lives as long as this reporter
+            Err(_) => self.status(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_phase_and_progress() {
+        let reporter = ProgressReporter::default();
+        assert_eq!(reporter.status().phase, ProvisioningPhase::Pending);
+
+        reporter.phase(ProvisioningPhase::PullingImage, "Pulling base image");
+        reporter.clone().progress(50, 200);
+
+        let status = reporter.status();
+        assert_eq!(status.phase, ProvisioningPhase::PullingImage);
+        assert_eq!(status.message.as_deref(), Some("Pulling base image"));
+        assert_eq!(status.progress, Some(0.25));
+
+        reporter.phase(ProvisioningPhase::RunningSetup, "Running setup script");
+        assert_eq!(reporter.status().progress, None);
+    }
+
+    #[test]
+    fn test_streams_output_lines() {
+        let events = broadcast::Sender::new(16);
+        let mut subscriber = events.subscribe();
+        let reporter = ProgressReporter::for_workspace("ws", events);
+
+        reporter.output("");
+        reporter.output_line("Compiling derrick");
+        reporter.output_line("Finished");
+        assert_eq!(
+            reporter.status().output.as_deref(),
+            Some("Compiling derrick\nFinished\n")
+        );
+        let Ok(Event::ProvisioningOutput { workspace_id, line }) = subscriber.try_recv() else {
+            panic!("Expected an output event");
+        };
+        assert_eq!(
+            (workspace_id.as_str(), line.as_str()),
+            ("ws", "Compiling derrick")
+        );
+
+        reporter.output_line(&"a".repeat(MAX_STATUS_OUTPUT));
+        assert_eq!(reporter.status().output.unwrap().len(), MAX_STATUS_OUTPUT);
+    }
+
+    #[tokio::test]
+    async fn test_waits_until_finished() {
+        let reporter = ProgressReporter::default();
+        let provisioning = reporter.clone();
+        tokio::spawn(async move {
+            provisioning.phase(ProvisioningPhase::RunningSetup, "Running setup script");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            provisioning.phase(ProvisioningPhase::Ready, "Workspace is ready");
+        });
+
+        assert_eq!(reporter.finished().await.phase, ProvisioningPhase::Ready);
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::client::Client;
+use crate::workspace_controllers::remote_http::RemoteHttpController;
+use crate::workspace_providers::{
+    ProgressReporter, ProvisioningPhase, WorkspaceContext, WorkspaceProvider,
+};
+use crate::WorkspaceController;
+
+// Provisions workspaces on another derrick through its HTTP API, so that a lightweight server can
+// leave the heavy workspaces to a bigger host. The remote server provisions them with its own
+// context, only the environment is passed on.
+pub struct RemoteHttpProvider {
+    client: Client,
+}
+
+impl RemoteHttpProvider {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    // The server at DERRICK_REMOTE_URL, with the token in DERRICK_REMOTE_TOKEN when it needs one
+    pub fn from_env() -> Result<Self> {
+        let url = std::env::var("DERRICK_REMOTE_URL")
+            .context("DERRICK_REMOTE_URL is required for the remote_http provisioning mode")?;
+        let client = match std::env::var("DERRICK_REMOTE_TOKEN") {
+            Ok(token) => Client::new(&url).with_token(&token)?,
+            Err(_) => Client::new(&url),
+        };
+        Ok(Self::new(client))
+    }
+}
+
+#[async_trait]
+impl WorkspaceProvider for RemoteHttpProvider {
+    #[tracing::instrument(skip_all)]
+    async fn provision(
+        &self,
+        _context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<Box<dyn WorkspaceController>> {
+        progress.phase(
+            ProvisioningPhase::StartingWorkspace,
+            "Provisioning on the remote server",
+        );
+        // Waits until the remote server has provisioned it
+        let id = self
+            .client
+            .create_workspace(env, HashMap::new(), None, None, HashMap::new(), false)
+            .await
+            .context("Could not create the remote workspace")?;
+        tracing::info!(remote_id = %id, "Provisioned remote workspace");
+        Ok(Box::new(RemoteHttpController::new(self.client.clone(), id)))
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+
+use crate::remote_nats::{self, Created, Request, DEFAULT_SUBJECT};
+use crate::workspace_controllers::remote_nats::RemoteNatsController;
+use crate::workspace_providers::{
+    ProgressReporter, ProvisioningPhase, WorkspaceContext, WorkspaceProvider,
+};
+use crate::WorkspaceController;
+
+// Provisions workspaces on other derricks serving over NATS (`--server-mode nats`), so that one
+// server can hand out workspaces that run on other machines. The remote servers provision them
+// with their own context, only the environment is passed on.
+pub struct RemoteNatsProvider {
+    client: async_nats::Client,
+    subject: String,
+}
+
+impl RemoteNatsProvider {
+    pub async fn connect(url: &str, subject: impl Into<String>) -> Result<Self> {
+        Ok(Self {
+            client: remote_nats::connect(url).await?,
+            subject: subject.into(),
+        })
+    }
+
+    // Connects to NATS_ENDPOINT, and creates workspaces on DERRICK_NATS_SUBJECT or the default
+    // subject
+    pub async fn from_env() -> Result<Self> {
+        let url = std::env::var("NATS_ENDPOINT")
+            .context("NATS_ENDPOINT is required for the remote_nats provisioning mode")?;
+        let subject =
+            std::env::var("DERRICK_NATS_SUBJECT").unwrap_or_else(|_| DEFAULT_SUBJECT.to_string());
+        Self::connect(&url, subject).await
+    }
+}
+
+#[async_trait]
+impl WorkspaceProvider for RemoteNatsProvider {
+    #[tracing::instrument(skip_all, fields(subject = %self.subject))]
+    async fn provision(
+        &self,
+        _context: &WorkspaceContext,
+        env: HashMap<String, String>,
+        progress: &ProgressReporter,
+    ) -> Result<Box<dyn WorkspaceController>> {
+        progress.phase(
+            ProvisioningPhase::StartingWorkspace,
+            format!("Provisioning on a remote server on {}", self.subject),
+        );
+        let created: Created =
+            remote_nats::request(&self.client, &self.subject, &Request::Create { env }).await?;
+        tracing::info!(remote_id = %created.id, "Provisioned remote workspace");
+        Ok(Box::new(RemoteNatsController::new(
+            self.client.clone(),
+            created.subject,
+            created.id,
+        )))
+    }
+}
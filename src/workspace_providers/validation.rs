@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject, SingleOrVec};
+use schemars::Map;
+use serde_json::Value;
+
+use super::{repository_file, WorkspaceContext, PROVISIONING_MODES};
+
+// JSON Schema of context files, for editors and CI
+pub fn context_schema() -> RootSchema {
+    schemars::schema_for!(WorkspaceContext)
+}
+
+// Problems with a context file, empty when it is valid. Besides what the server refuses on
+// startup, this finds what it would only notice when provisioning, and fields it ignores like
+// misspelled optional ones.
+pub fn validate_context(content: &str) -> Vec<String> {
+    let value: Value = match serde_json::from_str(content) {
+        Ok(value) => value,
+        Err(e) => return vec![format!("Invalid JSON: {}", e)],
+    };
+    let schema = context_schema();
+    let mut unknown = Vec::new();
+    object_fields(
+        &value,
+        &schema.schema,
+        &schema.definitions,
+        "",
+        &mut unknown,
+    );
+    unknown.sort();
+    let mut problems = unknown
+        .into_iter()
+        .map(|path| format!("Unknown field {}", path))
+        .collect::<Vec<_>>();
+
+    match serde_json::from_value::<WorkspaceContext>(value) {
+        Ok(context) => problems.extend(check_context(&context)),
+        Err(e) => problems.push(format!("Invalid context: {}", e)),
+    }
+    problems
+}
+
+// What the server only checks when it provisions a workspace with the context or a profile
+fn check_context(context: &WorkspaceContext) -> Vec<String> {
+    let mut problems = Vec::new();
+    for mode in context.provisioning_modes() {
+        if !PROVISIONING_MODES.contains(&mode.as_str()) {
+            problems.push(format!("Unsupported provisioning mode: {}", mode));
+        }
+    }
+
+    // Profiles take what they leave out from the context, which is checked once
+    let mut profiles = context.profiles.iter().collect::<Vec<_>>();
+    profiles.sort_by_key(|(name, _)| name.as_str());
+    let settings = std::iter::once((String::new(), &context.network, &context.user)).chain(
+        profiles.into_iter().map(|(name, profile)| {
+            (
+                format!("Profile {}: ", name),
+                &profile.network,
+                &profile.user,
+            )
+        }),
+    );
+    for (prefix, network, user) in settings {
+        if let Some(Err(e)) = network.as_ref().map(|network| network.validate()) {
+            problems.push(format!("{}{:#}", prefix, e));
+        }
+        if let Some(Err(e)) = user.as_ref().map(|user| user.validate()) {
+            problems.push(format!("{}{:#}", prefix, e));
+        }
+    }
+
+    let mut daemons = HashSet::new();
+    for daemon in &context.daemons {
+        if let Err(e) = daemon.validate() {
+            problems.push(format!("{:#}", e));
+        }
+        if !daemons.insert(&daemon.name) {
+            problems.push(format!("Duplicate daemon {}", daemon.name));
+        }
+    }
+
+    for secret in &context.secrets {
+        if let Err(e) = secret.validate() {
+            problems.push(format!("{:#}", e));
+        }
+    }
+    for credentials in &context.cloud_credentials {
+        if let Err(e) = credentials.validate() {
+            problems.push(format!("{:#}", e));
+        }
+    }
+
+    for file in context.steps().iter().flat_map(|step| &step.cache_files) {
+        if context.git_mirror_dir.is_none() {
+            problems.push("Setup steps with cache_files need a git_mirror_dir".to_string());
+            break;
+        }
+        if repository_file(&context.repositories, file).is_none() {
+            problems.push(format!(
+                "Cache file {} is not in any of the repositories",
+                file
+            ));
+        }
+    }
+
+    // Stand-ins for the variables without a default, to find the placeholders that are not
+    // variables
+    let values = context
+        .variables
+        .iter()
+        .filter(|(_, default)| default.is_none())
+        .map(|(name, _)| (name.clone(), "x".to_string()))
+        .collect::<HashMap<_, _>>();
+    if let Err(e) = context.with_variables(&values) {
+        problems.push(format!("{:#}", e));
+    }
+    problems
+}
+
+// Adds the paths of the fields of the value that the schema does not have
+fn unknown_fields(
+    value: &Value,
+    schema: &Schema,
+    definitions: &Map<String, Schema>,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    if let Some(schema) = resolve(schema, definitions) {
+        object_fields(value, schema, definitions, path, unknown);
+    }
+}
+
+fn object_fields(
+    value: &Value,
+    schema: &SchemaObject,
+    definitions: &Map<String, Schema>,
+    path: &str,
+    unknown: &mut Vec<String>,
+) {
+    if let Some(subschemas) = &schema.subschemas {
+        for schema in subschemas.all_of.iter().flatten() {
+            unknown_fields(value, schema, definitions, path, unknown);
+        }
+        // Like an option or the variants of an enum, the value is taken to be the variant it
+        // matches best
+        let best = subschemas
+            .any_of
+            .iter()
+            .chain(&subschemas.one_of)
+            .flatten()
+            .filter(|variant| accepts(variant, value, definitions))
+            .map(|variant| {
+                let mut fields = Vec::new();
+                unknown_fields(value, variant, definitions, path, &mut fields);
+                fields
+            })
+            .min_by_key(Vec::len);
+        unknown.extend(best.into_iter().flatten());
+    }
+
+    match value {
+        Value::Object(fields) => {
+            let Some(object) = &schema.object else {
+                return;
+            };
+            for (key, value) in fields {
+                let path = match path {
+                    "" => key.clone(),
+                    _ => format!("{}.{}", path, key),
+                };
+                match (
+                    object.properties.get(key),
+                    object.additional_properties.as_deref(),
+                ) {
+                    (Some(schema), _) | (None, Some(schema @ Schema::Object(_))) => {
+                        unknown_fields(value, schema, definitions, &path, unknown)
+                    }
+                    (None, Some(Schema::Bool(true))) => {}
+                    _ => unknown.push(path),
+                }
+            }
+        }
+        Value::Array(items) => {
+            let Some(SingleOrVec::Single(schema)) =
+                schema.array.as_ref().and_then(|array| array.items.as_ref())
+            else {
+                return;
+            };
+            for (index, item) in items.iter().enumerate() {
+                let path = format!("{}[{}]", path, index);
+                unknown_fields(item, schema, definitions, &path, unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Follows references to the definitions
+fn resolve<'a>(
+    schema: &'a Schema,
+    definitions: &'a Map<String, Schema>,
+) -> Option<&'a SchemaObject> {
+    let Schema::Object(object) = schema else {
+        return None;
+    };
+    match &object.reference {
+        Some(reference) => resolve(
+            definitions.get(reference.strip_prefix("#/definitions/")?)?,
+            definitions,
+        ),
+        None => Some(object),
+    }
+}
+
+// Whether the value has the type of the schema, and the tag of the variant for tagged enums
+fn accepts(schema: &Schema, value: &Value, definitions: &Map<String, Schema>) -> bool {
+    let Some(schema) = resolve(schema, definitions) else {
+        return true;
+    };
+    let instance_type = match value {
+        Value::Null => InstanceType::Null,
+        Value::Bool(_) => InstanceType::Boolean,
+        Value::Number(number) if number.is_f64() => InstanceType::Number,
+        Value::Number(_) => InstanceType::Integer,
+        Value::String(_) => InstanceType::String,
+        Value::Array(_) => InstanceType::Array,
+        Value::Object(_) => InstanceType::Object,
+    };
+    if let Some(types) = &schema.instance_type {
+        let integer_as_number =
+            instance_type == InstanceType::Integer && types.contains(&InstanceType::Number);
+        if !types.contains(&instance_type) && !integer_as_number {
+            return false;
+        }
+    }
+    let (Value::Object(fields), Some(object)) = (value, &schema.object) else {
+        return true;
+    };
+    object.properties.iter().all(|(key, property)| {
+        let allowed = resolve(property, definitions).and_then(|p| p.enum_values.as_ref());
+        match (fields.get(key), allowed) {
+            (Some(value), Some(allowed)) => allowed.contains(value),
+            _ => true,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_context() {
+        let content = r#"{
+            "name": "app",
+            "repositories": [{"url": "https://github.com/bosun-ai/derrick", "path": "/code", "sparsePaths": ["src"]}],
+            "setup_steps": [{"name": "deps", "script": "cargo fetch", "cache_files": ["/code/Cargo.lock"]}],
+            "git_mirror_dir": "/var/cache/mirrors",
+            "network": {"mode": "allowlist", "hosts": ["crates.io"]},
+            "tmpfs": {"/tmp": "size=1g"},
+            "profiles": {"safe": {"network": {"mode": "none"}, "timeout": 60}},
+            "variables": {"branch": "main"},
+            "secrets": [{"source": {"type": "vault", "path": "secret/data/npm", "field": "token"}, "env": "NPM_TOKEN"}],
+            "cloud_credentials": [{"provider": "aws", "role_arn": "arn:aws:iam::123456789012:role/deployer", "region": "eu-west-1"}]
+        }"#;
+        assert_eq!(validate_context(content), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let content = r#"{
+            "name": "app",
+            "repositories": [{"url": "https://github.com/bosun-ai/derrick", "path": "/code", "refrence": "main",
+                "credentials": {"type": "token", "env": "GITHUB_TOKEN", "usrname": "bot"}}],
+            "netwrok": {"mode": "none"},
+            "network": {"mode": "allowlist", "hosts": ["crates.io"], "ports": [443]},
+            "profiles": {"safe": {"sandbox": {"tool": "nsjail", "networks": true}}},
+            "restart": null
+        }"#;
+        assert_eq!(
+            validate_context(content),
+            vec![
+                "Unknown field network.ports",
+                "Unknown field netwrok",
+                "Unknown field profiles.safe.sandbox.networks",
+                "Unknown field repositories[0].credentials.usrname",
+                "Unknown field repositories[0].refrence",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_context() {
+        assert!(validate_context("{")[0].starts_with("Invalid JSON"));
+        assert!(validate_context(r#"{"name": "app"}"#)[0].starts_with("Invalid context"));
+
+        let content = r#"{
+            "name": "app",
+            "repositories": [],
+            "setup_script": "git checkout {{ branch }}",
+            "variables": {"tag": null},
+            "provisioning_mode": "kubernetes",
+            "daemons": [{"name": "lsp", "command": "a"}, {"name": "lsp", "command": "b"}],
+            "profiles": {"ci": {"user": {"create": true}}}
+        }"#;
+        assert_eq!(
+            validate_context(content),
+            vec![
+                "Unsupported provisioning mode: kubernetes",
+                "Profile ci: The user needs a name or a uid",
+                "Duplicate daemon lsp",
+                "Unknown variable branch in \"git checkout {{ branch }}\"",
+            ]
+        );
+    }
+}